@@ -0,0 +1,215 @@
+#!/usr/bin/env python3
+"""Generates the remote storage payloads used by the tests of `remote`.
+
+The messages are encoded by hand from prometheus/prompb (remote.proto and
+types.proto), independently of the Rust encoder, so that the tests check
+the crate against the protocol rather than against itself:
+
+  write_request.bin   snappy WriteRequest, as sent to /api/v1/write
+  read_request.bin    snappy ReadRequest, as sent to /api/v1/read
+  read_response.pb    ReadResponse expected for read_request.bin once
+                      write_request.bin has been written, uncompressed
+
+The snappy blocks are compressed the way Prometheus compresses them. To
+check the crate against a live server instead, point the remote_write and
+remote_read settings of Prometheus at a listener dumping request bodies,
+and replace the files with what it receives.
+Run from this directory: python3 generate.py
+"""
+
+import struct
+
+STALE_NAN = struct.unpack('<d', struct.pack('<Q', 0x7ff0000000000002))[0]
+
+
+def varint(value):
+    value &= (1 << 64) - 1
+    out = bytearray()
+    while value >= 0x80:
+        out.append(value & 0x7f | 0x80)
+        value >>= 7
+    out.append(value)
+    return bytes(out)
+
+
+def key(field, wire_type):
+    return varint(field << 3 | wire_type)
+
+
+def field_varint(field, value):
+    return key(field, 0) + varint(value)
+
+
+def field_double(field, value):
+    return key(field, 1) + struct.pack('<d', value)
+
+
+def field_bytes(field, value):
+    if isinstance(value, str):
+        value = value.encode()
+    return key(field, 2) + varint(len(value)) + value
+
+
+def label(name, value):
+    return field_bytes(1, name) + field_bytes(2, value)
+
+
+def sample(value, ts):
+    return field_double(1, value) + field_varint(2, ts)
+
+
+def timeseries(labels, samples):
+    body = b''.join(field_bytes(1, label(n, v)) for n, v in labels)
+    return body + b''.join(field_bytes(2, sample(v, ts)) for v, ts in samples)
+
+
+def matcher(kind, name, value):
+    # EQ = 0, NEQ = 1, RE = 2, NRE = 3
+    return field_varint(1, kind) + field_bytes(2, name) + field_bytes(3, value)
+
+
+def query(start, end, matchers):
+    body = field_varint(1, start) + field_varint(2, end)
+    return body + b''.join(field_bytes(3, matcher(*m)) for m in matchers)
+
+
+# Prometheus compresses remote storage messages with snappy.Encode of
+# github.com/golang/snappy. Its block encoder is ported below as is, so that
+# the payloads hold the literals and copies a Prometheus server sends.
+
+def emit_literal(out, literal):
+    n = len(literal) - 1
+    if n < 60:
+        out.append(n << 2)
+    elif n < 256:
+        out += bytes([60 << 2, n])
+    else:
+        out += bytes([61 << 2]) + struct.pack('<H', n)
+    out += literal
+
+
+def emit_copy(out, offset, length):
+    while length >= 68:
+        out += bytes([63 << 2 | 2]) + struct.pack('<H', offset)
+        length -= 64
+    if length > 64:
+        out += bytes([59 << 2 | 2]) + struct.pack('<H', offset)
+        length -= 60
+    if length >= 12 or offset >= 2048:
+        out += bytes([(length - 1) << 2 | 2]) + struct.pack('<H', offset)
+    else:
+        out += bytes([(offset >> 8) << 5 | (length - 4) << 2 | 1, offset & 0xff])
+
+
+def load32(data, i):
+    return struct.unpack_from('<I', data, i)[0]
+
+
+def snappy_hash(u, shift):
+    return (u * 0x1e35a7bd & 0xffffffff) >> shift
+
+
+def encode_block(out, src):
+    max_table_size = 1 << 14
+    input_margin = 16 - 1
+    shift = 32 - 8
+    table_size = 1 << 8
+    while table_size < max_table_size and table_size < len(src):
+        shift -= 1
+        table_size *= 2
+    table = [0] * max_table_size
+    mask = max_table_size - 1
+    s_limit = len(src) - input_margin
+    next_emit = 0
+    s = 1
+    next_hash = snappy_hash(load32(src, s), shift)
+    while True:
+        skip = 32
+        next_s = s
+        while True:
+            s = next_s
+            between = skip >> 5
+            next_s = s + between
+            skip += between
+            if next_s > s_limit:
+                break
+            candidate = table[next_hash & mask]
+            table[next_hash & mask] = s
+            next_hash = snappy_hash(load32(src, next_s), shift)
+            if load32(src, s) == load32(src, candidate):
+                break
+        if next_s > s_limit:
+            break
+        emit_literal(out, src[next_emit:s])
+        while True:
+            base = s
+            s += 4
+            i = candidate + 4
+            while s < len(src) and src[i] == src[s]:
+                i += 1
+                s += 1
+            emit_copy(out, base - candidate, s - base)
+            next_emit = s
+            if s >= s_limit:
+                break
+            x = struct.unpack_from('<Q', src + bytes(8), s - 1)[0]
+            table[snappy_hash(x & 0xffffffff, shift) & mask] = s - 1
+            curr_hash = snappy_hash(x >> 8 & 0xffffffff, shift)
+            candidate = table[curr_hash & mask]
+            table[curr_hash & mask] = s
+            if x >> 8 & 0xffffffff != load32(src, candidate):
+                next_hash = snappy_hash(x >> 16 & 0xffffffff, shift)
+                s += 1
+                break
+        if s >= s_limit:
+            break
+    if next_emit < len(src):
+        emit_literal(out, src[next_emit:])
+
+
+def snappy(data):
+    out = bytearray(varint(len(data)))
+    for start in range(0, len(data), 65536):
+        block = data[start:start + 65536]
+        if len(block) < 1 + 1 + 15:
+            emit_literal(out, block)
+        else:
+            encode_block(out, block)
+    return bytes(out)
+
+
+API_200 = [('__name__', 'http_requests_total'), ('code', '200'), ('instance', '10.0.0.1:9090'), ('job', 'api')]
+API_500 = [('__name__', 'http_requests_total'), ('code', '500'), ('instance', '10.0.0.1:9090'), ('job', 'api')]
+WEB_200 = [('__name__', 'http_requests_total'), ('code', '200'), ('instance', '10.0.0.2:9090'), ('job', 'web')]
+UP = [('__name__', 'up'), ('instance', '10.0.0.1:9090'), ('job', 'api')]
+
+write_request = b''.join([
+    field_bytes(1, timeseries(API_200, [(1027, 1690000000000), (1031, 1690000015250), (1040.5, 1690000030000)])),
+    field_bytes(1, timeseries(API_500, [(3, 1690000000000), (4, 1690000030000)])),
+    field_bytes(1, timeseries(WEB_200, [(7, 1690000000000)])),
+    # the stale marker written once the target disappears isn't stored
+    field_bytes(1, timeseries(UP, [(1, 1690000000000), (STALE_NAN, 1690000045000)])),
+    # MetricMetadata, skipped by the receiver
+    field_bytes(3, field_varint(1, 1) + field_bytes(2, 'http_requests_total') + field_bytes(4, 'Requests handled.')),
+])
+
+read_request = b''.join([
+    field_bytes(1, query(1690000000000, 1690000030000, [
+        (0, '__name__', 'http_requests_total'), (2, 'code', '2..'), (1, 'job', 'web')])),
+    field_bytes(1, query(1690000000000, 1690000060000, [(2, '__name__', 'up|missing')])),
+    # accepted_response_types, packed: SAMPLES
+    field_bytes(2, varint(0)),
+])
+
+read_response = b''.join([
+    field_bytes(1, field_bytes(1, timeseries(API_200, [
+        (1027, 1690000000000), (1031, 1690000015000), (1040.5, 1690000030000)]))),
+    field_bytes(1, field_bytes(1, timeseries(UP, [(1, 1690000000000)]))),
+])
+
+with open('write_request.bin', 'wb') as f:
+    f.write(snappy(write_request))
+with open('read_request.bin', 'wb') as f:
+    f.write(snappy(read_request))
+with open('read_response.pb', 'wb') as f:
+    f.write(read_response)
@@ -0,0 +1,198 @@
+use std::f64;
+
+use {Point, TSBlock};
+
+/// Functions aggregating the points of a window into a single value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Function {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    /// Population standard deviation
+    Stddev,
+    Last,
+}
+
+/// Running summary of the points of a window, updated in constant space
+#[derive(Clone, Copy, Debug)]
+pub struct Accumulator {
+    count: u64,
+    sum: f64,
+    mean: f64,
+    // sum of squared differences from the mean (Welford's algorithm)
+    m2: f64,
+    min: f64,
+    max: f64,
+    last: f64,
+}
+
+impl Default for Accumulator {
+    fn default() -> Accumulator {
+        Accumulator::new()
+    }
+}
+
+impl Accumulator {
+    pub fn new() -> Accumulator {
+        Accumulator {
+            count: 0,
+            sum: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            last: f64::NAN,
+        }
+    }
+
+    /// Adds a value to the summary
+    pub fn push(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+
+    /// Returns the number of values added to the summary
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the value of `function` over every value added so far
+    pub fn get(&self, function: Function) -> f64 {
+        if self.count == 0 {
+            return match function {
+                Function::Sum | Function::Count => 0.0,
+                _ => f64::NAN,
+            };
+        }
+        match function {
+            Function::Sum => self.sum,
+            Function::Avg => self.mean,
+            Function::Min => self.min,
+            Function::Max => self.max,
+            Function::Count => self.count as f64,
+            Function::Stddev => (self.m2 / self.count as f64).sqrt(),
+            Function::Last => self.last,
+        }
+    }
+}
+
+/// Aggregates a time ordered stream of points into `step` second windows
+/// aligned to the epoch, yielding one point per non-empty window
+/// timestamped with the start of the window.
+///
+/// Points are consumed one at a time, so aggregating a block never
+/// requires decoding all of its points up front.
+pub struct Window<I> {
+    points: I,
+    function: Function,
+    step: i64,
+    current: Option<(i64, Accumulator)>,
+}
+
+impl<I: Iterator<Item = Point>> Window<I> {
+    /// Creates a window operator applying `function` over `points`
+    pub fn new(points: I, function: Function, step: i64) -> Window<I> {
+        assert!(step > 0, "window step must be positive");
+        Window {
+            points,
+            function,
+            step,
+            current: None,
+        }
+    }
+
+    /// Compresses the aggregated points into a new block whose header is
+    /// the start of the first window. Returns `None` if there are no points
+    pub fn into_block(mut self) -> Option<TSBlock> {
+        let first = self.next()?;
+        let mut block = TSBlock::at(first.ts);
+        block.publish_at(first.value, first.ts);
+        for point in self {
+            block.publish_at(point.value, point.ts);
+        }
+        Some(block)
+    }
+}
+
+impl<I: Iterator<Item = Point>> Iterator for Window<I> {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        for point in self.points.by_ref() {
+            let start = point.ts - point.ts.rem_euclid(self.step);
+            match self.current {
+                Some((current, ref mut acc)) if current == start => acc.push(point.value),
+                _ => {
+                    let mut acc = Accumulator::new();
+                    acc.push(point.value);
+                    if let Some((ts, done)) = self.current.replace((start, acc)) {
+                        return Some(Point {
+                            ts,
+                            value: done.get(self.function),
+                        });
+                    }
+                }
+            }
+        }
+        self.current.take().map(|(ts, acc)| {
+            Point {
+                ts,
+                value: acc.get(self.function),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Function, Window};
+    use {Point, TSBlock};
+
+    fn block() -> TSBlock {
+        let mut block = TSBlock::at(0);
+        for &(ts, value) in &[(0, 2.0), (20, 4.0), (40, 4.0), (60, 4.0), (80, 5.0), (100, 5.0), (130, 7.0)] {
+            block.publish_at(value, ts);
+        }
+        block
+    }
+
+    fn values(function: Function) -> Vec<f64> {
+        Window::new(block().iter(), function, 60).map(|p| p.value).collect()
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(vec![10.0, 14.0, 7.0], values(Function::Sum));
+        assert_eq!(vec![10.0 / 3.0, 14.0 / 3.0, 7.0], values(Function::Avg));
+        assert_eq!(vec![2.0, 4.0, 7.0], values(Function::Min));
+        assert_eq!(vec![4.0, 5.0, 7.0], values(Function::Max));
+        assert_eq!(vec![3.0, 3.0, 1.0], values(Function::Count));
+        assert_eq!(vec![4.0, 5.0, 7.0], values(Function::Last));
+        let stddev = values(Function::Stddev);
+        assert!((stddev[0] - (8.0f64 / 9.0).sqrt()).abs() < 1e-12);
+        assert_eq!(0.0, stddev[2]);
+    }
+
+    #[test]
+    fn test_window_timestamps() {
+        let ts: Vec<i64> = Window::new(block().iter(), Function::Count, 60).map(|p| p.ts).collect();
+        assert_eq!(vec![0, 60, 120], ts);
+    }
+
+    #[test]
+    fn test_into_block() {
+        let block = Window::new(block().iter(), Function::Max, 60).into_block().unwrap();
+        assert_eq!(0, block.header());
+        assert_eq!(vec![Point { ts: 0, value: 4.0 }, Point { ts: 60, value: 5.0 }, Point { ts: 120, value: 7.0 }],
+                   block.iter().collect::<Vec<Point>>());
+        assert!(Window::new(TSBlock::at(0).iter(), Function::Max, 60).into_block().is_none());
+    }
+}
@@ -0,0 +1,494 @@
+//! HTTP API for writing to and querying a `Store` from other processes.
+//!
+//! ```text
+//! POST /write                                        publishes a batch of points
+//! GET  /query?query=...&start=...&end=...&step=...   evaluates a query
+//! GET  /series[?match=...]                           lists the series stored
+//! GET  /metrics                                      counters of the ingestion validator
+//! ```
+//!
+//! The body of a write is a JSON array of series, or a single one, with
+//! their points as `[timestamp, value]` pairs in seconds:
+//!
+//! ```text
+//! [{"metric": "cpu", "tags": {"host": "a"}, "points": [[1690000000, 0.5], [1690000015, 0.7]]}]
+//! ```
+//!
+//! A write is validated as a whole and published under a single write lock
+//! of the store, so it is either applied entirely or not at all and shows
+//! up in queries as soon as it is answered. Values are always stored as
+//! floats, which is what queries read. Points rejected by the validator of
+//! the store fail the whole write, while those it drops are left out.
+//!
+//! Queries are written in the language of `query` and evaluated over the
+//! grid from `start` to `end` by `step` seconds. Results and errors are
+//! returned as JSON, with NaN values as `null`.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use eval::{self, Timeseries};
+use group::Grid;
+use http::{Request, Response, Server};
+use ingest::{self, Sample, Value};
+use json::{self, Json};
+use limits::Limits;
+use query::{self, Expr};
+use store::{SeriesKey, Store};
+use time;
+use validate::{Reason, Verdict};
+
+/// Path of the write endpoint
+pub const WRITE_PATH: &str = "/write";
+
+/// Path of the query endpoint
+pub const QUERY_PATH: &str = "/query";
+
+/// Path of the series listing
+pub const SERIES_PATH: &str = "/series";
+
+/// Path of the validation counters, in the Prometheus text format
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Default maximum number of timestamps on the grid of a query, as in
+/// Prometheus
+pub const DEFAULT_MAX_STEPS: usize = 11_000;
+
+/// Reasons a request can't be answered
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    InvalidJson(json::Error),
+    /// The body of a write isn't made of series and points
+    InvalidWrite(String),
+    /// A point of a write was rejected by the validator of the store
+    Rejected(SeriesKey, i64, Reason),
+    MissingParam(&'static str),
+    InvalidParam(&'static str, String),
+    /// The grid of a query has more timestamps than allowed
+    TooManySteps(usize),
+    /// `match` isn't a plain series selector
+    InvalidSelector(String),
+    Query(eval::Error),
+}
+
+impl Error {
+    /// Returns the HTTP status reporting the error
+    pub fn status(&self) -> u16 {
+        match *self {
+            Error::Query(eval::Error::Limit(_)) => 422,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidJson(ref err) => write!(f, "invalid JSON: {}", err),
+            Error::InvalidWrite(ref message) => write!(f, "invalid write: {}", message),
+            Error::Rejected(ref key, ts, reason) => write!(f, "point of {} at {} rejected: {}", key, ts, reason.name()),
+            Error::MissingParam(name) => write!(f, "missing parameter {}", name),
+            Error::InvalidParam(name, ref value) => write!(f, "invalid parameter {}={:?}", name, value),
+            Error::TooManySteps(steps) => write!(f, "query grid of {} steps is too large", steps),
+            Error::InvalidSelector(ref selector) => write!(f, "invalid series selector {:?}", selector),
+            Error::Query(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<json::Error> for Error {
+    fn from(err: json::Error) -> Error {
+        Error::InvalidJson(err)
+    }
+}
+
+impl From<eval::Error> for Error {
+    fn from(err: eval::Error) -> Error {
+        Error::Query(err)
+    }
+}
+
+/// Parses the body of a write into samples
+pub fn parse_write(body: &Json) -> Result<Vec<Sample>, Error> {
+    let invalid = |message: &str| Error::InvalidWrite(message.to_string());
+    let series = match *body {
+        Json::Array(ref series) => &series[..],
+        _ => std::slice::from_ref(body),
+    };
+    let mut samples = Vec::new();
+    for series in series {
+        let metric = match series.get("metric").and_then(Json::as_str) {
+            Some(metric) if !metric.is_empty() => metric,
+            _ => return Err(invalid("series without a metric")),
+        };
+        let mut key = SeriesKey::new(metric);
+        for (name, value) in series.get("tags").and_then(Json::as_object).unwrap_or(&[]) {
+            let value = value.as_str().ok_or_else(|| Error::InvalidWrite(format!("tag {} isn't a string", name)))?;
+            key = key.tag(name.as_str(), value);
+        }
+        let points = series.get("points").and_then(Json::as_array).ok_or_else(|| invalid("series without points"))?;
+        for point in points {
+            let (ts, value) = match point.as_array() {
+                Some([ts, value]) => (ts.as_i64(), value.as_f64()),
+                _ => (None, None),
+            };
+            match (ts, value) {
+                (Some(ts), Some(value)) => samples.push(Sample { key: key.clone(), value: Value::Float(value), ts }),
+                _ => return Err(Error::InvalidWrite(format!("invalid point {} of {}", point, metric))),
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Returns the series of a query result as JSON
+pub fn series_json(series: &[Timeseries]) -> Json {
+    let series = series.iter()
+        .map(|series| {
+            let points = series.points.iter().map(|p| Json::Array(vec![Json::Int(p.ts), Json::Float(p.value)])).collect();
+            let mut fields = key_fields(&series.key);
+            fields.push(("points".to_string(), Json::Array(points)));
+            Json::Object(fields)
+        })
+        .collect();
+    Json::Object(vec![("series".to_string(), Json::Array(series))])
+}
+
+fn key_fields(key: &SeriesKey) -> Vec<(String, Json)> {
+    let tags = key.tags.iter().map(|(name, value)| (name.clone(), Json::from(value.as_str()))).collect();
+    vec![("metric".to_string(), Json::from(key.metric.as_str())), ("tags".to_string(), Json::Object(tags))]
+}
+
+/// Lists the series of `store` whose key `selector` accepts, every series
+/// if there is none, with their type, number of blocks and the timestamps
+/// of their first and last points. Integer series are queried as floats
+pub fn list_series(store: &Store, selector: Option<&query::Selector>) -> Json {
+    let selected = |key: &SeriesKey| selector.is_none_or(|selector| selector.matches(key));
+    let mut series = Vec::new();
+    for (key, float) in store.iter().filter(|(key, _)| selected(key)) {
+        let first = float.blocks().find_map(|block| block.iter().next()).map(|p| p.ts);
+        let last = float.blocks().filter_map(|block| block.last_ts()).last();
+        series.push((key, "float", series_entry(key, "float", float.blocks().count(), first, last)));
+    }
+    for (key, int) in store.iter_ints().filter(|(key, _)| selected(key)) {
+        let first = int.blocks().find_map(|block| block.iter().next()).map(|p| p.ts);
+        let last = int.blocks().filter_map(|block| block.last_ts()).last();
+        series.push((key, "int", series_entry(key, "int", int.blocks().count(), first, last)));
+    }
+    series.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    let series = series.into_iter().map(|(_, _, entry)| entry).collect();
+    Json::Object(vec![("series".to_string(), Json::Array(series))])
+}
+
+fn series_entry(key: &SeriesKey, kind: &str, blocks: usize, first: Option<i64>, last: Option<i64>) -> Json {
+    let ts = |ts: Option<i64>| ts.map_or(Json::Null, Json::Int);
+    let mut fields = key_fields(key);
+    fields.push(("type".to_string(), Json::from(kind)));
+    fields.push(("blocks".to_string(), Json::Int(blocks as i64)));
+    fields.push(("first".to_string(), ts(first)));
+    fields.push(("last".to_string(), ts(last)));
+    Json::Object(fields)
+}
+
+/// Settings of an API server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Limits of every query
+    pub limits: Limits,
+    /// Maximum number of timestamps on the grid of a query
+    pub max_steps: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            limits: Limits::none(),
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+}
+
+/// Counters of an API server
+#[derive(Debug, Default)]
+pub struct Stats {
+    writes: AtomicU64,
+    points: AtomicU64,
+    queries: AtomicU64,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of write requests received
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of points published
+    pub fn points(&self) -> u64 {
+        self.points.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of query and series requests received
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests failed
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last error
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn fail(&self, err: &Error) -> Response {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(err.to_string());
+        let body = Json::Object(vec![("error".to_string(), Json::from(err.to_string()))]);
+        json_response(err.status(), &body)
+    }
+}
+
+fn json_response(status: u16, json: &Json) -> Response {
+    Response::with_body(status, "application/json", json.to_string())
+}
+
+/// HTTP server exposing a store
+pub struct Api {
+    server: Server,
+    stats: Arc<Stats>,
+}
+
+impl Api {
+    /// Starts serving `store` on `addr`
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Api> {
+        let stats = Arc::new(Stats::default());
+        let endpoint = Endpoint {
+            store,
+            config,
+            stats: stats.clone(),
+        };
+        let server = Server::bind(addr, move |request: &Request| endpoint.handle(request))?;
+        Ok(Api { server, stats })
+    }
+
+    /// Returns the address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+struct Endpoint {
+    store: Arc<RwLock<Store>>,
+    config: Config,
+    stats: Arc<Stats>,
+}
+
+impl Endpoint {
+    fn handle(&self, request: &Request) -> Response {
+        let result = match (request.path.as_str(), request.method.as_str()) {
+            (WRITE_PATH, "POST") => self.write(request),
+            (QUERY_PATH, "GET") => self.query(request),
+            (SERIES_PATH, "GET") => self.series(request),
+            (METRICS_PATH, "GET") => Ok(self.metrics()),
+            (WRITE_PATH, _) | (QUERY_PATH, _) | (SERIES_PATH, _) | (METRICS_PATH, _) => {
+                return Response::text(405, "method not allowed")
+            }
+            _ => return Response::text(404, "not found"),
+        };
+        result.unwrap_or_else(|err| self.stats.fail(&err))
+    }
+
+    fn write(&self, request: &Request) -> Result<Response, Error> {
+        self.stats.writes.fetch_add(1, Ordering::SeqCst);
+        let body = String::from_utf8_lossy(&request.body);
+        let samples = parse_write(&Json::parse(&body)?)?;
+        let mut store = self.store.write().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut rejected = None;
+        for sample in &samples {
+            if let verdict @ Verdict::Reject(reason) = store.validator().check(sample, now) {
+                store.validator().record(verdict);
+                rejected = rejected.or_else(|| Some(Error::Rejected(sample.key.clone(), sample.ts, reason)));
+            }
+        }
+        if let Some(err) = rejected {
+            return Err(err);
+        }
+        let published = ingest::apply(&mut store, &samples);
+        self.stats.points.fetch_add(published as u64, Ordering::SeqCst);
+        Ok(Response::new(204))
+    }
+
+    fn metrics(&self) -> Response {
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        Response::with_body(200, "text/plain; version=0.0.4", store.validator().exposition())
+    }
+
+    fn query(&self, request: &Request) -> Result<Response, Error> {
+        self.stats.queries.fetch_add(1, Ordering::SeqCst);
+        let query = request.param("query").ok_or(Error::MissingParam("query"))?;
+        let start = int_param(request, "start")?;
+        let end = int_param(request, "end")?;
+        let step = int_param(request, "step")?;
+        if step <= 0 {
+            return Err(Error::InvalidParam("step", step.to_string()));
+        }
+        if end < start {
+            return Err(Error::InvalidParam("end", end.to_string()));
+        }
+        let mut grid = Grid::new(start, end, step);
+        if grid.len() > self.config.max_steps {
+            return Err(Error::TooManySteps(grid.len()));
+        }
+        if request.param("lookback").is_some() {
+            grid = grid.with_lookback(int_param(request, "lookback")?);
+        }
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        let series = eval::query_with_limits(&store, &query, &grid, self.config.limits)?;
+        Ok(json_response(200, &series_json(&series)))
+    }
+
+    fn series(&self, request: &Request) -> Result<Response, Error> {
+        self.stats.queries.fetch_add(1, Ordering::SeqCst);
+        let selector = match request.param("match") {
+            Some(selector) => match query::parse(&selector).map_err(eval::Error::from)? {
+                Expr::Selector(ref parsed) if parsed.range.is_none() && parsed.offset == 0 => Some(parsed.clone()),
+                _ => return Err(Error::InvalidSelector(selector)),
+            },
+            None => None,
+        };
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        Ok(json_response(200, &list_series(&store, selector.as_ref())))
+    }
+}
+
+fn int_param(request: &Request, name: &'static str) -> Result<i64, Error> {
+    let value = request.param(name).ok_or(Error::MissingParam(name))?;
+    value.parse().map_err(|_| Error::InvalidParam(name, value))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    use super::{parse_write, Api, Config, Error, METRICS_PATH, QUERY_PATH, SERIES_PATH, WRITE_PATH};
+    use http::{self, percent_encode, Url};
+    use ingest::Value;
+    use json::Json;
+    use limits::Limits;
+    use store::{SeriesKey, Store};
+    use time;
+    use validate::{Reason, Validation};
+
+    #[test]
+    fn test_parse_write() {
+        let body = Json::parse(r#"[{"metric": "cpu", "tags": {"host": "a"}, "points": [[10, 0.5], [20, 1]]},
+                                   {"metric": "up", "points": []}]"#).unwrap();
+        let samples = parse_write(&body).unwrap();
+        assert_eq!(2, samples.len());
+        assert_eq!(SeriesKey::new("cpu").tag("host", "a"), samples[1].key);
+        assert_eq!((Value::Float(1.0), 20), (samples[1].value, samples[1].ts));
+
+        for body in [r#"{"points": []}"#, r#"{"metric": "cpu"}"#, r#"{"metric": "cpu", "points": [[1.5, 1]]}"#,
+                     r#"{"metric": "cpu", "tags": {"host": 1}, "points": []}"#, r#"{"metric": "cpu", "points": [[1]]}"#] {
+            match parse_write(&Json::parse(body).unwrap()) {
+                Err(Error::InvalidWrite(_)) => {}
+                other => panic!("{}: {:?}", body, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_api() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().publish_int_at(&SeriesKey::new("requests").tag("host", "a"), 3, 0);
+        let config = Config { limits: Limits::none().max_points(100), max_steps: 100 };
+        let api = Api::bind("127.0.0.1:0", store, config).unwrap();
+        let url = |path: &str| Url::parse(&format!("http://{}{}", api.local_addr(), path)).unwrap();
+        let timeout = Duration::from_secs(5);
+        let get = |path: &str| {
+            let response = http::get(&url(path), &[], timeout).unwrap();
+            (response.status, String::from_utf8_lossy(&response.body).into_owned())
+        };
+
+        let body = br#"[{"metric": "cpu", "tags": {"host": "a"}, "points": [[0, 0.5], [10, 0.7], [20, 0.9]]},
+                        {"metric": "cpu", "tags": {"host": "b"}, "points": [[0, 0.25]]}]"#;
+        assert_eq!(204, http::post(&url(WRITE_PATH), &[], body, timeout).unwrap().status);
+        let response = http::post(&url(WRITE_PATH), &[], b"[{\"metric\": \"cpu\"}]", timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(r#"{"error":"invalid write: series without points"}"#, String::from_utf8_lossy(&response.body));
+
+        let query = percent_encode("cpu{host=\"a\"} * 2");
+        assert_eq!((200, r#"{"series":[{"metric":"","tags":{"host":"a"},"points":[[0,1],[10,1.4],[20,1.8]]}]}"#.to_string()),
+                   get(&format!("{}?query={}&start=0&end=20&step=10", QUERY_PATH, query)));
+        assert_eq!((200, r#"{"series":[{"metric":"","tags":{},"points":[[0,0.75]]}]}"#.to_string()),
+                   get(&format!("{}?query=sum(cpu)&start=0&end=0&step=10&lookback=5", QUERY_PATH)));
+        // integer series listed by /series are queried as floats
+        assert_eq!((200, r#"{"series":[{"metric":"requests","tags":{"host":"a"},"points":[[0,3]]}]}"#.to_string()),
+                   get(&format!("{}?query=requests&start=0&end=0&step=10", QUERY_PATH)));
+
+        let (status, body) = get(&format!("{}?query=cpu&start=0&end=20", QUERY_PATH));
+        assert_eq!((400, r#"{"error":"missing parameter step"}"#), (status, body.as_str()));
+        let (status, body) = get(&format!("{}?query=cpu&start=0&end=2000&step=1", QUERY_PATH));
+        assert_eq!((400, r#"{"error":"query grid of 2001 steps is too large"}"#), (status, body.as_str()));
+        assert_eq!(400, get(&format!("{}?query=cpu{{&start=0&end=20&step=10", QUERY_PATH)).0);
+
+        assert_eq!((200, concat!(r#"{"series":[{"metric":"cpu","tags":{"host":"a"},"type":"float","blocks":1,"first":0,"last":20},"#,
+                                 r#"{"metric":"cpu","tags":{"host":"b"},"type":"float","blocks":1,"first":0,"last":0},"#,
+                                 r#"{"metric":"requests","tags":{"host":"a"},"type":"int","blocks":1,"first":0,"last":0}]}"#)
+                        .to_string()),
+                   get(SERIES_PATH));
+        let (status, body) = get(&format!("{}?match={}", SERIES_PATH, percent_encode("{host=\"b\"}")));
+        assert_eq!(200, status);
+        assert_eq!(r#"{"series":[{"metric":"cpu","tags":{"host":"b"},"type":"float","blocks":1,"first":0,"last":0}]}"#, body);
+        assert_eq!(400, get(&format!("{}?match={}", SERIES_PATH, percent_encode("rate(cpu[1m])"))).0);
+
+        assert_eq!(404, get("/unknown").0);
+        assert_eq!(405, http::post(&url(QUERY_PATH), &[], b"", timeout).unwrap().status);
+        let stats = api.stats();
+        assert_eq!((2, 4, 9, 5), (stats.writes(), stats.points(), stats.queries(), stats.failures()));
+    }
+
+    #[test]
+    fn test_validation() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().set_validation(Validation { max_future_skew: Some(3600), ..Validation::default() });
+        let api = Api::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = |path: &str| Url::parse(&format!("http://{}{}", api.local_addr(), path)).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let future = time::get_time().sec + 86400;
+        let body = format!(r#"[{{"metric": "cpu", "points": [[0, 1], [{}, 2]]}}]"#, future);
+        let response = http::post(&url(WRITE_PATH), &[], body.as_bytes(), timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(format!(r#"{{"error":"point of cpu at {} rejected: future"}}"#, future),
+                   String::from_utf8_lossy(&response.body));
+        assert!(store.read().unwrap().get(&SeriesKey::new("cpu")).is_none());
+        let body = br#"[{"metric": "cpu", "points": [[0, 1]]}]"#;
+        assert_eq!(204, http::post(&url(WRITE_PATH), &[], body, timeout).unwrap().status);
+
+        let validator = |store: &Store| (store.validator().accepted(), store.validator().rejected(Reason::Future));
+        assert_eq!((1, 1), validator(&store.read().unwrap()));
+        let response = http::get(&url(METRICS_PATH), &[], timeout).unwrap();
+        assert_eq!(200, response.status);
+        let body = String::from_utf8_lossy(&response.body);
+        assert!(body.contains("ingest_samples_accepted_total 1\n"), "{}", body);
+        assert!(body.contains("ingest_samples_rejected_total{reason=\"future\"} 1\n"), "{}", body);
+        assert_eq!((1, 2), (api.stats().points(), api.stats().writes()));
+    }
+}
@@ -0,0 +1,312 @@
+//! Arithmetic and comparisons between series.
+//!
+//! Two series are combined point by point, pairing points whose timestamps
+//! are within a tolerance of each other. Sets of series are first paired
+//! up by their tags, following the vector matching rules of Prometheus:
+//! series match when their tags are equal, either on the listed tags only
+//! (`on`) or on every tag but the listed ones (`ignoring`). Each series
+//! matches at most one series of the other side, unless `group_left`
+//! allows many series of the left side to match the same series of the
+//! right side.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error;
+use std::fmt;
+
+use eval::Timeseries;
+use store::SeriesKey;
+use Point;
+
+/// Arithmetic and comparison operators
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Equal,
+    NotEqual,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+}
+
+impl BinaryOp {
+    /// Returns whether the operator is a comparison
+    pub fn is_comparison(self) -> bool {
+        !matches!(self, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod)
+    }
+
+    /// Applies the operator to two values. Comparisons filter: they return
+    /// `lhs` if the comparison holds and `None` otherwise, or 1 and 0 if
+    /// `bool` is set
+    pub fn apply(self, lhs: f64, rhs: f64, bool: bool) -> Option<f64> {
+        let holds = match self {
+            BinaryOp::Add => return Some(lhs + rhs),
+            BinaryOp::Sub => return Some(lhs - rhs),
+            BinaryOp::Mul => return Some(lhs * rhs),
+            BinaryOp::Div => return Some(lhs / rhs),
+            BinaryOp::Mod => return Some(lhs % rhs),
+            BinaryOp::Equal => lhs == rhs,
+            BinaryOp::NotEqual => lhs != rhs,
+            BinaryOp::Greater => lhs > rhs,
+            BinaryOp::Less => lhs < rhs,
+            BinaryOp::GreaterEqual => lhs >= rhs,
+            BinaryOp::LessEqual => lhs <= rhs,
+        };
+        if bool {
+            Some(if holds { 1.0 } else { 0.0 })
+        } else if holds {
+            Some(lhs)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tags on which series of both sides are matched
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchTags {
+    /// Only the given tags
+    On(Vec<String>),
+    /// Every tag but the given ones
+    Ignoring(Vec<String>),
+}
+
+/// How two sets of series are paired up and combined
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Matching {
+    pub tags: MatchTags,
+    /// Allows many series of the left side to match a single series of the
+    /// right side, copying the given tags of the right series into the
+    /// results. `None` for one to one matching
+    pub group_left: Option<Vec<String>>,
+    /// Makes comparisons return 1 or 0 rather than filter
+    pub bool: bool,
+}
+
+impl Default for Matching {
+    fn default() -> Matching {
+        Matching {
+            tags: MatchTags::Ignoring(Vec::new()),
+            group_left: None,
+            bool: false,
+        }
+    }
+}
+
+impl Matching {
+    // Returns the tags of `key` that series are matched on
+    fn signature(&self, key: &SeriesKey) -> BTreeMap<String, String> {
+        let mut tags = key.tags.clone();
+        match self.tags {
+            MatchTags::On(ref names) => tags.retain(|name, _| names.contains(name)),
+            MatchTags::Ignoring(ref names) => tags.retain(|name, _| !names.contains(name)),
+        }
+        tags
+    }
+
+    // Returns the key of the result of combining `lhs` with `rhs`
+    fn result_key(&self, op: BinaryOp, lhs: &SeriesKey, rhs: &SeriesKey) -> SeriesKey {
+        let mut key = match self.group_left {
+            Some(ref include) => {
+                let mut key = lhs.clone();
+                for name in include {
+                    match rhs.tags.get(name) {
+                        Some(value) => key.tags.insert(name.clone(), value.clone()),
+                        None => key.tags.remove(name),
+                    };
+                }
+                key
+            }
+            None => SeriesKey {
+                metric: lhs.metric.clone(),
+                tags: self.signature(lhs),
+            },
+        };
+        // filtering keeps the series as is, anything else transforms it
+        if !op.is_comparison() || self.bool {
+            key.metric.clear();
+        }
+        key
+    }
+}
+
+/// Error raised when series can't be paired up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchError {
+    /// Several series of the right side match the same tags
+    DuplicateRight(SeriesKey),
+    /// Several series of the left side match the same tags, but
+    /// `group_left` wasn't given
+    DuplicateLeft(SeriesKey),
+    /// Several matched pairs produce the same result series
+    DuplicateResult(SeriesKey),
+}
+
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchError::DuplicateRight(ref key) => {
+                write!(f, "found duplicate series for the match group of {} on the right side", key)
+            }
+            MatchError::DuplicateLeft(ref key) => {
+                write!(f, "many to one matching of {} must be explicit with group_left", key)
+            }
+            MatchError::DuplicateResult(ref key) => write!(f, "duplicate result series {}", key),
+        }
+    }
+}
+
+impl error::Error for MatchError {}
+
+/// Combines two time ordered series point by point. Every point of `lhs`
+/// is paired with the point of `rhs` nearest in time, if it is at most
+/// `tolerance` seconds away, and the result takes the timestamp of the
+/// point of `lhs`
+pub fn combine(op: BinaryOp, bool: bool, lhs: &[Point], rhs: &[Point], tolerance: i64) -> Vec<Point> {
+    let mut result = Vec::new();
+    // index of the first point of rhs at or after the current point of lhs
+    let mut next = 0;
+    for point in lhs {
+        while next < rhs.len() && rhs[next].ts < point.ts {
+            next += 1;
+        }
+        let before = if next > 0 { rhs.get(next - 1) } else { None };
+        let nearest = match (before, rhs.get(next)) {
+            (Some(before), Some(after)) => {
+                if point.ts - before.ts < after.ts - point.ts { before } else { after }
+            }
+            (Some(only), None) | (None, Some(only)) => only,
+            (None, None) => break,
+        };
+        if (nearest.ts - point.ts).abs() > tolerance {
+            continue;
+        }
+        if let Some(value) = op.apply(point.value, nearest.value, bool) {
+            result.push(Point { ts: point.ts, value });
+        }
+    }
+    result
+}
+
+/// Pairs up the series of `lhs` and `rhs` according to `matching` and
+/// combines every pair with `combine`. Series without a match are
+/// dropped, and so are results without any point
+pub fn join(op: BinaryOp,
+            lhs: &[Timeseries],
+            rhs: &[Timeseries],
+            matching: &Matching,
+            tolerance: i64)
+            -> Result<Vec<Timeseries>, MatchError> {
+    let mut right = BTreeMap::new();
+    for series in rhs {
+        if right.insert(matching.signature(&series.key), series).is_some() {
+            return Err(MatchError::DuplicateRight(series.key.clone()));
+        }
+    }
+
+    let mut matched = BTreeSet::new();
+    let mut results = BTreeMap::new();
+    for series in lhs {
+        let signature = matching.signature(&series.key);
+        let other = match right.get(&signature) {
+            Some(other) => other,
+            None => continue,
+        };
+        if !matched.insert(signature) && matching.group_left.is_none() {
+            return Err(MatchError::DuplicateLeft(series.key.clone()));
+        }
+        let key = matching.result_key(op, &series.key, &other.key);
+        let points = combine(op, matching.bool, &series.points, &other.points, tolerance);
+        if results.contains_key(&key) {
+            return Err(MatchError::DuplicateResult(key));
+        }
+        results.insert(key, points);
+    }
+    Ok(results.into_iter()
+        .filter(|(_, points)| !points.is_empty())
+        .map(|(key, points)| Timeseries { key, points })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{combine, join, BinaryOp, MatchError, MatchTags, Matching};
+    use eval::Timeseries;
+    use store::SeriesKey;
+    use Point;
+
+    fn points(values: &[(i64, f64)]) -> Vec<Point> {
+        values.iter().map(|&(ts, value)| Point { ts, value }).collect()
+    }
+
+    fn series(key: SeriesKey, value: f64) -> Timeseries {
+        Timeseries { key, points: points(&[(0, value), (10, value)]) }
+    }
+
+    #[test]
+    fn test_combine() {
+        let lhs = points(&[(0, 1.0), (10, 6.0), (20, 3.0), (30, 8.0)]);
+        let rhs = points(&[(1, 2.0), (19, 2.0), (30, 4.0)]);
+        assert_eq!(points(&[(30, 2.0)]), combine(BinaryOp::Div, false, &lhs, &rhs, 0));
+        assert_eq!(points(&[(0, 0.5), (20, 1.5), (30, 2.0)]),
+                   combine(BinaryOp::Div, false, &lhs, &rhs, 1));
+        assert_eq!(points(&[(0, 3.0), (10, 8.0), (20, 5.0), (30, 12.0)]),
+                   combine(BinaryOp::Add, false, &lhs, &rhs, 10));
+        assert_eq!(points(&[(20, 3.0), (30, 8.0)]),
+                   combine(BinaryOp::Greater, false, &lhs, &rhs, 1));
+        assert_eq!(points(&[(0, 0.0), (20, 1.0), (30, 1.0)]),
+                   combine(BinaryOp::Greater, true, &lhs, &rhs, 1));
+    }
+
+    #[test]
+    fn test_join_one_to_one() {
+        let errors = vec![series(SeriesKey::new("errors").tag("host", "a").tag("code", "500"), 5.0),
+                          series(SeriesKey::new("errors").tag("host", "b").tag("code", "500"), 1.0)];
+        let requests = vec![series(SeriesKey::new("requests").tag("host", "a"), 10.0),
+                            series(SeriesKey::new("requests").tag("host", "c"), 10.0)];
+        let matching = Matching { tags: MatchTags::Ignoring(vec!["code".to_string()]), ..Matching::default() };
+        let result = join(BinaryOp::Div, &errors, &requests, &matching, 0).unwrap();
+        assert_eq!(vec![Timeseries { key: SeriesKey::new("").tag("host", "a"), points: points(&[(0, 0.5), (10, 0.5)]) }],
+                   result);
+
+        let matching = Matching { tags: MatchTags::On(vec!["host".to_string()]), ..Matching::default() };
+        assert_eq!(result, join(BinaryOp::Div, &errors, &requests, &matching, 0).unwrap());
+        // without ignoring the code tag nothing matches
+        assert!(join(BinaryOp::Div, &errors, &requests, &Matching::default(), 0).unwrap().is_empty());
+
+        // comparisons keep the series of the left side as is
+        let result = join(BinaryOp::Greater, &requests, &errors, &matching, 0).unwrap();
+        assert_eq!(SeriesKey::new("requests").tag("host", "a"), result[0].key);
+    }
+
+    #[test]
+    fn test_join_group_left() {
+        let used = vec![series(SeriesKey::new("used").tag("host", "a").tag("disk", "0"), 20.0),
+                        series(SeriesKey::new("used").tag("host", "a").tag("disk", "1"), 40.0)];
+        let total = vec![series(SeriesKey::new("total").tag("host", "a").tag("zone", "z1"), 80.0)];
+        let mut matching = Matching { tags: MatchTags::On(vec!["host".to_string()]), ..Matching::default() };
+        assert_eq!(Err(MatchError::DuplicateLeft(used[1].key.clone())),
+                   join(BinaryOp::Div, &used, &total, &matching, 0));
+
+        matching.group_left = Some(vec!["zone".to_string()]);
+        let result = join(BinaryOp::Div, &used, &total, &matching, 0).unwrap();
+        assert_eq!(2, result.len());
+        assert_eq!(SeriesKey::new("").tag("host", "a").tag("disk", "0").tag("zone", "z1"), result[0].key);
+        assert_eq!(0.25, result[0].points[0].value);
+        assert_eq!(0.5, result[1].points[1].value);
+
+        assert_eq!(Err(MatchError::DuplicateRight(used[1].key.clone())),
+                   join(BinaryOp::Div, &total, &used, &matching, 0));
+    }
+
+    #[test]
+    fn test_to_block() {
+        let result = series(SeriesKey::new("a"), 1.5);
+        let block = result.to_block().unwrap();
+        assert_eq!(result.points, block.iter().collect::<Vec<Point>>());
+    }
+}
@@ -1,10 +1,20 @@
+use std::cmp;
+use std::mem;
 use std::ptr;
+use std::slice;
 
+#[derive(Clone)]
 pub struct AppendOnlyBitVec {
     vec: BitVec,
     len: usize,
 }
 
+impl Default for AppendOnlyBitVec {
+    fn default() -> AppendOnlyBitVec {
+        AppendOnlyBitVec::new()
+    }
+}
+
 impl AppendOnlyBitVec {
     pub fn new() -> AppendOnlyBitVec {
         AppendOnlyBitVec {
@@ -20,10 +30,41 @@ impl AppendOnlyBitVec {
         }
     }
 
+    /// Creates a vector holding a copy of the bits in `slice`. Further
+    /// appends continue after the last bit of the slice
+    pub fn from_slice(slice: BitSlice) -> AppendOnlyBitVec {
+        let mut data = slice.data()[..blocks(slice.len())].to_vec();
+        if let Some(last) = data.last_mut() {
+            // zero out any bits past the end of the slice
+            let used = slice.len() % 64;
+            if used != 0 {
+                *last &= !0 << (64 - used);
+            }
+        }
+        AppendOnlyBitVec {
+            vec: BitVec { data },
+            len: slice.len(),
+        }
+    }
+
     pub fn data(&self) -> &[u64] {
         self.vec.data()
     }
 
+    /// Returns the number of bits appended so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a borrowed, read-only view over the appended bits
+    pub fn as_slice(&self) -> BitSlice<'_> {
+        BitSlice::with_len(self.vec.data(), self.len)
+    }
+
     pub fn clear(&mut self) {
         self.vec.clear();
     }
@@ -57,10 +98,17 @@ impl AppendOnlyBitVec {
     }
 }
 
+#[derive(Clone)]
 pub struct BitVec {
     data: Vec<u64>,
 }
 
+impl Default for BitVec {
+    fn default() -> BitVec {
+        BitVec::new()
+    }
+}
+
 impl BitVec {
     pub fn new() -> BitVec {
         BitVec { data: Vec::new() }
@@ -74,6 +122,11 @@ impl BitVec {
         &self.data
     }
 
+    /// Returns a borrowed, read-only view over the bits of the vector
+    pub fn as_slice(&self) -> BitSlice<'_> {
+        BitSlice::new(&self.data)
+    }
+
     pub fn clear(&mut self) {
         unsafe {
             let vec_ptr = self.data.as_mut_ptr();
@@ -82,14 +135,7 @@ impl BitVec {
     }
 
     pub fn get_bit(&self, index: usize) -> bool {
-        if self.out_of_bounds(index) {
-            return false;
-        }
-
-        let block = unsafe { self.block(index) };
-        let offset = offset_i(index);
-        let mask = 1 << offset;
-        (block & mask) >> offset == 1
+        self.as_slice().get_bit(index)
     }
 
     pub fn set_bit(&mut self, index: usize, value: bool) {
@@ -97,7 +143,7 @@ impl BitVec {
             self.data.resize(blocks(index + 1), 0);
         }
 
-        let mut block = unsafe { self.block_mut(index) };
+        let block = unsafe { self.block_mut(index) };
         let offset = offset_i(index);
         let mask = 1 << offset;
         if value {
@@ -108,87 +154,7 @@ impl BitVec {
     }
 
     pub fn get_block(&self, index: usize) -> u64 {
-        // Algorithm example:
-        //
-        // Let index = 64 (block aligned)
-        //
-        // Blocks:
-        //
-        // |--------||--------|
-        // |   0    ||   1    |
-        // |--------||--------|
-        //                ^
-        //                |
-        //             block (64 / 64 == 1)
-        //
-        // Block 1:
-        //
-        // |----------------------------------------------------------------|
-        // |xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|
-        // |----------------------------------------------------------------|
-        //  ^
-        //  |
-        // offset (canonical index: 0, actual bit index: 63)
-        // 63 - 64 % 64 = 63
-        //
-        // Since offset is block aligned we simply return the block
-        //
-        // Let index = 67 (not block aligned)
-        //
-        // Block index is the same as when index = 64
-        //
-        // Block 1:
-        //
-        // |----------------------------------------------------------------|
-        // |xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|
-        // |----------------------------------------------------------------|
-        //     ^
-        //     |
-        //  offset (canonical index: 3, actual bit index: 60)
-        //  63 - 67 % 64 = 60
-        //
-        // Step 1: Shift current block 3 (63 - offset) bits to the left
-        // Step 2: OR shifted block with empty result block
-        //
-        // Let block 1 be:
-        //
-        // 10101100 11110000 00000000 11111111 00000000 000000000 00000000 11111111
-        //
-        // Result block after OR:
-        //
-        // 01100111 10000000 00000111 11111999 00000000 00000000 00000111 11111000
-        //
-        // Step 3: Grab next block
-        // Step 4: Calculate amount of desired bits (64 - (offset + 1) == 3)
-        // Step 5: Right shift next block 61 bits (offset + 1)
-        // Step 6: OR result
-        //
-        // Let next block be:
-        //
-        // 01011000 10001111 00001111 11110000 00000000 1111111 11111111 00000000
-        //
-        // Block after shifting:
-        // 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000010
-        //
-        // Returned block:
-        //
-        // 01100111 10000000 00000111 11111999 00000000 00000000 00000111 11111010
-        if block_i(index) >= self.data.len() {
-            // returns 0 if out of bounds
-            return 0;
-        }
-        let block = unsafe { self.block(index) };
-        let offset = offset_i(index);
-        if offset == 63 {
-            return *block;
-        }
-        let result = 0 | (*block << (63 - offset));
-        if block_i(index) + 1 >= self.data.len() {
-            // return early if out of bounds
-            return result;
-        }
-        let next_block = unsafe { self.next_block(index) };
-        result | (*next_block >> (offset + 1))
+        self.as_slice().get_block(index)
     }
 
     pub fn set_block(&mut self, index: usize, block: u64) {
@@ -265,7 +231,7 @@ impl BitVec {
     // Sets 64 - block_offset bits in the block indicated by index at
     // block_offset. Returns true if index is blocked aligned, false otherwise
     fn set_cur_block(&mut self, index: usize, block: u64) -> bool {
-        let mut cur_block = unsafe { self.block_mut(index) };
+        let cur_block = unsafe { self.block_mut(index) };
         if block_aligned(index) {
             *cur_block = block;
             true
@@ -281,28 +247,18 @@ impl BitVec {
     // Sets 64 - (64 - block_offset) bits at index 0 of the block
     // after the block indicated by index
     fn set_next_block(&mut self, index: usize, block: u64) {
-        let mut cur_block = unsafe { self.next_block_mut(index) };
+        let cur_block = unsafe { self.next_block_mut(index) };
         let offset = offset_i(index);
         let mask = !0 >> (64 - (offset + 1));
         let data = block << (offset + 1);
         *cur_block = (*cur_block & mask) | data;
     }
 
-    // Retrieves a reference to the block for the given index
-    unsafe fn block(&self, index: usize) -> &u64 {
-        self.data.get_unchecked(block_i(index))
-    }
-
     // Retrieves a mutable reference to the block for the given index
     unsafe fn block_mut(&mut self, index: usize) -> &mut u64 {
         self.data.get_unchecked_mut(block_i(index))
     }
 
-    // Retrives a reference to the block after the index block
-    unsafe fn next_block(&self, index: usize) -> &u64 {
-        self.data.get_unchecked(block_i(index) + 1)
-    }
-
     // Retrieves a mutable reference to the block after the index block
     unsafe fn next_block_mut(&mut self, index: usize) -> &mut u64 {
         self.data.get_unchecked_mut(block_i(index) + 1)
@@ -315,6 +271,208 @@ impl BitVec {
     }
 }
 
+/// A borrowed, read-only view over a sequence of 64-bit blocks.
+///
+/// `BitSlice` never owns its data, which allows bits to be read directly
+/// out of memory that lives elsewhere (e.g. a memory-mapped block file).
+#[derive(Clone, Copy)]
+pub struct BitSlice<'a> {
+    data: &'a [u64],
+    len: usize,
+}
+
+impl<'a> BitSlice<'a> {
+    /// Creates a view over every bit in `data`
+    pub fn new(data: &'a [u64]) -> BitSlice<'a> {
+        BitSlice {
+            data,
+            len: data.len() * 64,
+        }
+    }
+
+    /// Creates a view over the first `len` bits of `data`.
+    /// `len` is clamped to the number of bits available in `data`
+    pub fn with_len(data: &'a [u64], len: usize) -> BitSlice<'a> {
+        BitSlice {
+            data,
+            len: cmp::min(len, data.len() * 64),
+        }
+    }
+
+    /// Reinterprets `bytes` as a slice of little-endian 64-bit blocks
+    /// without copying. Returns `None` if `bytes` is not 8-byte aligned,
+    /// its length is not a multiple of 8 or the host is big-endian, where
+    /// the blocks would have to be swapped
+    pub fn from_bytes(bytes: &'a [u8]) -> Option<BitSlice<'a>> {
+        let align = mem::align_of::<u64>();
+        if cfg!(target_endian = "big") || !bytes.len().is_multiple_of(8) || !(bytes.as_ptr() as usize).is_multiple_of(align) {
+            return None;
+        }
+        let data = unsafe { slice::from_raw_parts(bytes.as_ptr() as *const u64, bytes.len() / 8) };
+        Some(BitSlice::new(data))
+    }
+
+    pub fn data(&self) -> &'a [u64] {
+        self.data
+    }
+
+    /// Returns the number of readable bits in the slice
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a reader positioned at the first bit of the slice
+    pub fn reader(&self) -> BitReader<'a> {
+        BitReader::new(*self)
+    }
+
+    pub fn get_bit(&self, index: usize) -> bool {
+        if blocks(index + 1) > self.data.len() {
+            return false;
+        }
+
+        let block = unsafe { self.data.get_unchecked(block_i(index)) };
+        let offset = offset_i(index);
+        let mask = 1 << offset;
+        (block & mask) >> offset == 1
+    }
+
+    pub fn get_block(&self, index: usize) -> u64 {
+        // Algorithm example:
+        //
+        // Let index = 64 (block aligned)
+        //
+        // Blocks:
+        //
+        // |--------||--------|
+        // |   0    ||   1    |
+        // |--------||--------|
+        //                ^
+        //                |
+        //             block (64 / 64 == 1)
+        //
+        // Block 1:
+        //
+        // |----------------------------------------------------------------|
+        // |xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|
+        // |----------------------------------------------------------------|
+        //  ^
+        //  |
+        // offset (canonical index: 0, actual bit index: 63)
+        // 63 - 64 % 64 = 63
+        //
+        // Since offset is block aligned we simply return the block
+        //
+        // Let index = 67 (not block aligned)
+        //
+        // Block index is the same as when index = 64
+        //
+        // Block 1:
+        //
+        // |----------------------------------------------------------------|
+        // |xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx|
+        // |----------------------------------------------------------------|
+        //     ^
+        //     |
+        //  offset (canonical index: 3, actual bit index: 60)
+        //  63 - 67 % 64 = 60
+        //
+        // Step 1: Shift current block 3 (63 - offset) bits to the left
+        // Step 2: OR shifted block with empty result block
+        //
+        // Let block 1 be:
+        //
+        // 10101100 11110000 00000000 11111111 00000000 000000000 00000000 11111111
+        //
+        // Result block after OR:
+        //
+        // 01100111 10000000 00000111 11111999 00000000 00000000 00000111 11111000
+        //
+        // Step 3: Grab next block
+        // Step 4: Calculate amount of desired bits (64 - (offset + 1) == 3)
+        // Step 5: Right shift next block 61 bits (offset + 1)
+        // Step 6: OR result
+        //
+        // Let next block be:
+        //
+        // 01011000 10001111 00001111 11110000 00000000 1111111 11111111 00000000
+        //
+        // Block after shifting:
+        // 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000010
+        //
+        // Returned block:
+        //
+        // 01100111 10000000 00000111 11111999 00000000 00000000 00000111 11111010
+        if block_i(index) >= self.data.len() {
+            // returns 0 if out of bounds
+            return 0;
+        }
+        let block = unsafe { self.data.get_unchecked(block_i(index)) };
+        let offset = offset_i(index);
+        if offset == 63 {
+            return *block;
+        }
+        let result = *block << (63 - offset);
+        if block_i(index) + 1 >= self.data.len() {
+            // return early if out of bounds
+            return result;
+        }
+        let next_block = unsafe { self.data.get_unchecked(block_i(index) + 1) };
+        result | (*next_block >> (offset + 1))
+    }
+}
+
+/// Sequentially reads bits out of a `BitSlice`, most significant bit first
+pub struct BitReader<'a> {
+    slice: BitSlice<'a>,
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(slice: BitSlice<'a>) -> BitReader<'a> {
+        BitReader { slice, pos: 0 }
+    }
+
+    /// Returns the index of the next bit to be read
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the number of bits left to read
+    pub fn remaining(&self) -> usize {
+        self.slice.len() - self.pos
+    }
+
+    /// Reads a single bit. Returns `None` if the slice is exhausted
+    pub fn read_bit(&mut self) -> Option<bool> {
+        if self.remaining() == 0 {
+            return None;
+        }
+        let bit = self.slice.get_bit(self.pos);
+        self.pos += 1;
+        Some(bit)
+    }
+
+    /// Reads the next `bits` bits (at most 64) into the low bits of
+    /// the result. Returns `None` if fewer than `bits` bits remain
+    pub fn read(&mut self, bits: usize) -> Option<u64> {
+        if bits > self.remaining() {
+            return None;
+        }
+        let block = match bits {
+            0 => 0,
+            64 => self.slice.get_block(self.pos),
+            _ => self.slice.get_block(self.pos) >> (64 - bits),
+        };
+        self.pos += bits;
+        Some(block)
+    }
+}
+
 /// Returns the 0-based index of the block given the index
 fn block_i(index: usize) -> usize {
     index / 64
@@ -326,7 +484,7 @@ fn offset_i(index: usize) -> u64 {
 }
 
 fn block_aligned(index: usize) -> bool {
-    index % 64 == 0
+    index.is_multiple_of(64)
 }
 
 /// Returns the number of 32 bit blocks it takes to contain
@@ -339,8 +497,10 @@ fn blocks(nbits: usize) -> usize {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
-    use super::{AppendOnlyBitVec, BitVec};
+    use super::{AppendOnlyBitVec, BitSlice, BitVec};
+    use aligned;
 
     #[test]
     fn test_get_set() {
@@ -406,4 +566,61 @@ mod test {
         assert_eq!(true, vec.get_bit(69));
         assert_eq!(false, vec.get_bit(70));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_slice() {
+        let data = [!0u64, 1];
+        let slice = BitSlice::new(&data);
+        assert_eq!(128, slice.len());
+        assert!(slice.get_bit(63));
+        assert!(!slice.get_bit(64));
+        assert!(slice.get_bit(127));
+        assert!(!slice.get_bit(128));
+        assert_eq!(!0 << 1, slice.get_block(1));
+        assert_eq!(1, slice.get_block(64));
+        assert_eq!(64, BitSlice::with_len(&data, 64).len());
+        assert_eq!(128, BitSlice::with_len(&data, 1000).len());
+    }
+
+    #[test]
+    fn test_slice_from_bytes() {
+        let data = [0x0102030405060708u64, !0];
+        let le: Vec<u8> = data.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut buf = Vec::new();
+        let bytes = aligned(&le, &mut buf);
+        let slice = BitSlice::from_bytes(bytes).unwrap();
+        assert_eq!(&data[..], slice.data());
+        assert!(BitSlice::from_bytes(&bytes[..15]).is_none());
+        assert!(BitSlice::from_bytes(&bytes[1..9]).is_none());
+    }
+
+    #[test]
+    fn test_reader() {
+        let mut vec = AppendOnlyBitVec::new();
+        vec.append(1, 1);
+        vec.append(7, 0b1010101);
+        vec.append(64, !0);
+        vec.append(3, 0b010);
+        let mut reader = vec.as_slice().reader();
+        assert_eq!(75, reader.remaining());
+        assert_eq!(Some(true), reader.read_bit());
+        assert_eq!(Some(0b1010101), reader.read(7));
+        assert_eq!(Some(!0), reader.read(64));
+        assert_eq!(72, reader.position());
+        assert_eq!(None, reader.read(4));
+        assert_eq!(Some(0b010), reader.read(3));
+        assert_eq!(None, reader.read_bit());
+        assert_eq!(Some(0), reader.read(0));
+    }
+
+    #[test]
+    fn test_append_from_slice() {
+        let data = [!0u64, !0];
+        let mut vec = AppendOnlyBitVec::from_slice(BitSlice::with_len(&data, 66));
+        assert_eq!(66, vec.len());
+        assert_eq!(!0 << 62, vec.get_block(64));
+        vec.append(2, 0b01);
+        assert!(!vec.get_bit(66));
+        assert!(vec.get_bit(67));
+    }
+}
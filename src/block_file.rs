@@ -0,0 +1,341 @@
+//! On-disk representation of a single `TSBlock` or `IntBlock`.
+//!
+//! A block file is a sequence of little-endian 64-bit words:
+//!
+//! |-------||---------||----------------------|
+//! | magic || # bits  || compressed block ... |
+//! |-------||---------||----------------------|
+//!
+//! Since every field is word aligned, a memory-mapped block file can be
+//! handed to `BitSlice::from_bytes` and decoded in place on little-endian
+//! hosts. Big-endian hosts read it through `decode`, which copies and
+//! swaps the words.
+//!
+//! The compressed block is stored as produced by `TSBlock`, with values
+//! whose window of meaningful bits changes prefixed by the control bits
+//! `11`. Block files have always used this layout, so `MAGIC` identifies
+//! it; a later change to the encoding must come with a new magic so that
+//! older files are rejected rather than misread.
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bit_vec::BitSlice;
+use int_block::{IntBlock, IntBlockIter, IntPoint};
+use {Point, TSBlock, TSBlockIter};
+
+/// Identifies a block file ("CNTRBLK1")
+pub const MAGIC: u64 = 0x434e_5452_424c_4b31;
+
+/// Identifies a block file holding an `IntBlock` ("CNTRINT1")
+pub const INT_MAGIC: u64 = 0x434e_5452_494e_5431;
+
+/// File extension used for block files
+pub const EXTENSION: &str = "block";
+
+/// A compressed block of points, as chained by a `Series` and stored in
+/// a block file
+pub trait Block: Sized {
+    /// Identifies the kind of block held by a block file
+    const MAGIC: u64;
+
+    /// The type of the values published to the block
+    type Value: Copy;
+
+    /// The type of the points read back from the block
+    type Point: Copy;
+
+    /// Iterates over the points of the block
+    type Iter<'a>: Iterator<Item = Self::Point> where Self: 'a;
+
+    /// Creates an empty block whose header is `ts`
+    fn at(ts: i64) -> Self;
+
+    fn header(&self) -> u64;
+
+    fn last_ts(&self) -> Option<i64>;
+
+    fn is_empty(&self) -> bool;
+
+    fn publish_at(&mut self, value: Self::Value, ts: i64);
+
+    fn iter(&self) -> Self::Iter<'_>;
+
+    /// Returns the timestamp of `point`
+    fn timestamp(point: &Self::Point) -> i64;
+
+    fn as_slice(&self) -> BitSlice<'_>;
+
+    fn from_slice(slice: BitSlice) -> Option<Self>;
+}
+
+impl Block for TSBlock {
+    const MAGIC: u64 = MAGIC;
+
+    type Value = f64;
+
+    type Point = Point;
+
+    type Iter<'a> = TSBlockIter<'a>;
+
+    fn at(ts: i64) -> TSBlock {
+        TSBlock::at(ts)
+    }
+
+    fn header(&self) -> u64 {
+        TSBlock::header(self)
+    }
+
+    fn last_ts(&self) -> Option<i64> {
+        TSBlock::last_ts(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        TSBlock::is_empty(self)
+    }
+
+    fn publish_at(&mut self, value: f64, ts: i64) {
+        TSBlock::publish_at(self, value, ts)
+    }
+
+    fn iter(&self) -> TSBlockIter<'_> {
+        TSBlock::iter(self)
+    }
+
+    fn timestamp(point: &Point) -> i64 {
+        point.ts
+    }
+
+    fn as_slice(&self) -> BitSlice<'_> {
+        TSBlock::as_slice(self)
+    }
+
+    fn from_slice(slice: BitSlice) -> Option<TSBlock> {
+        TSBlock::from_slice(slice)
+    }
+}
+
+impl Block for IntBlock {
+    const MAGIC: u64 = INT_MAGIC;
+
+    type Value = i64;
+
+    type Point = IntPoint;
+
+    type Iter<'a> = IntBlockIter<'a>;
+
+    fn at(ts: i64) -> IntBlock {
+        IntBlock::at(ts)
+    }
+
+    fn header(&self) -> u64 {
+        IntBlock::header(self)
+    }
+
+    fn last_ts(&self) -> Option<i64> {
+        IntBlock::last_ts(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        IntBlock::is_empty(self)
+    }
+
+    fn publish_at(&mut self, value: i64, ts: i64) {
+        IntBlock::publish_at(self, value, ts)
+    }
+
+    fn iter(&self) -> IntBlockIter<'_> {
+        IntBlock::iter(self)
+    }
+
+    fn timestamp(point: &IntPoint) -> i64 {
+        point.ts
+    }
+
+    fn as_slice(&self) -> BitSlice<'_> {
+        IntBlock::as_slice(self)
+    }
+
+    fn from_slice(slice: BitSlice) -> Option<IntBlock> {
+        IntBlock::from_slice(slice)
+    }
+}
+
+/// Serializes `block` into the block file format
+pub fn encode<B: Block>(block: &B) -> Vec<u8> {
+    let slice = block.as_slice();
+    let words = blocks(slice.len());
+    let mut buf = Vec::with_capacity((words + 2) * 8);
+    buf.extend_from_slice(&B::MAGIC.to_le_bytes());
+    buf.extend_from_slice(&(slice.len() as u64).to_le_bytes());
+    for word in &slice.data()[..words] {
+        buf.extend_from_slice(&word.to_le_bytes());
+    }
+    buf
+}
+
+/// Returns a borrowed view over the compressed block held in `bytes`
+/// without copying. `bytes` must be 8-byte aligned, which is always the
+/// case for a memory-mapped file. Returns `None` if `bytes` is not a
+/// `TSBlock` file or the host is big-endian, see `decode`
+pub fn view(bytes: &[u8]) -> Option<BitSlice<'_>> {
+    view_words::<TSBlock>(BitSlice::from_bytes(bytes)?.data())
+}
+
+fn view_words<B: Block>(words: &[u64]) -> Option<BitSlice<'_>> {
+    if words.len() < 2 || words[0] != B::MAGIC {
+        return None;
+    }
+    let len = words[1] as usize;
+    if blocks(len) != words.len() - 2 {
+        return None;
+    }
+    Some(BitSlice::with_len(&words[2..], len))
+}
+
+/// Deserializes a block from the block file format
+pub fn decode<B: Block>(bytes: &[u8]) -> Option<B> {
+    if !bytes.len().is_multiple_of(8) {
+        return None;
+    }
+    // copy into word storage so the words are aligned and in native order
+    let words: Vec<u64> = bytes.chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("8 bytes")))
+        .collect();
+    view_words::<B>(&words).and_then(B::from_slice)
+}
+
+/// Reads the block file at `path`
+pub fn read<B: Block, P: AsRef<Path>>(path: P) -> io::Result<B> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    decode(&bytes).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid block file"))
+}
+
+/// Atomically writes `block` to `path`. The block is first written to a
+/// temporary file next to `path` which is then renamed over `path`, so
+/// readers observe either the old file or the complete new one
+pub fn write<B: Block, P: AsRef<Path>>(path: P, block: &B) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp = tmp_path(path);
+    let result = File::create(&tmp).and_then(|mut file| {
+        file.write_all(&encode(block))?;
+        file.sync_all()
+    });
+    if let Err(err) = result.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(err);
+    }
+    sync_dir(path)
+}
+
+/// Returns the paths of every block file in `dir`, sorted by name
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns an unused path in `dir` for a block starting at `header`
+pub fn next_path<P: AsRef<Path>>(dir: P, header: u64) -> PathBuf {
+    let dir = dir.as_ref();
+    let mut seq = 0;
+    loop {
+        let path = dir.join(format!("{:020}-{:04}.{}", header, seq, EXTENSION));
+        if !path.exists() {
+            return path;
+        }
+        seq += 1;
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// Flushes the directory entry of `path` so that a rename survives a crash
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => {
+            // not every platform allows opening a directory for syncing
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Returns the number of 64 bit words it takes to contain nbits
+fn blocks(nbits: usize) -> usize {
+    nbits.div_ceil(64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use super::{decode, encode, list, next_path, read, view, write, MAGIC};
+    use int_block::IntBlock;
+    use {aligned, TSBlock};
+
+    #[test]
+    fn test_encode_decode() {
+        let mut block = TSBlock::at(60);
+        block.publish_at(1.0, 61);
+        block.publish_at(2.0, 122);
+        let bytes = encode(&block);
+        let decoded: TSBlock = decode(&bytes).unwrap();
+        assert_eq!(block.as_slice().len(), decoded.as_slice().len());
+        assert_eq!(block.iter().collect::<Vec<_>>(), decoded.iter().collect::<Vec<_>>());
+
+        assert!(decode::<TSBlock>(&bytes[..bytes.len() - 8]).is_none());
+        assert!(decode::<TSBlock>(&bytes[8..]).is_none());
+        // blocks of another kind aren't mistaken for this one
+        assert!(decode::<IntBlock>(&bytes).is_none());
+    }
+
+    #[test]
+    fn test_little_endian() {
+        let mut block = TSBlock::at(60);
+        block.publish_at(1.0, 61);
+        let bytes = encode(&block);
+        assert_eq!(&MAGIC.to_le_bytes(), &bytes[..8]);
+        assert_eq!(&(block.as_slice().len() as u64).to_le_bytes(), &bytes[8..16]);
+
+        let mut buf = Vec::new();
+        let slice = view(aligned(&bytes, &mut buf)).unwrap();
+        assert_eq!(block.as_slice().len(), slice.len());
+        assert_eq!(block.as_slice().data(), slice.data());
+    }
+
+    #[test]
+    fn test_write_read() {
+        let dir = env::temp_dir().join(format!("counter-block-file-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut block = TSBlock::at(0);
+        block.publish_at(5.0, 10);
+        let path = next_path(&dir, block.header());
+        write(&path, &block).unwrap();
+        assert_eq!(vec![path.clone()], list(&dir).unwrap());
+        assert_ne!(path, next_path(&dir, block.header()));
+
+        block.publish_at(6.0, 20);
+        write(&path, &block).unwrap();
+        assert_eq!(2, read::<TSBlock, _>(&path).unwrap().iter().count());
+        assert_eq!(vec![path], list(&dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
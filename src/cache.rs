@@ -0,0 +1,274 @@
+//! Least recently used cache of query results computed from sealed blocks.
+//!
+//! Sealed blocks never change, so the points decoded from a block, or the
+//! windows aggregated from them, can be reused until compaction or
+//! retention replaces the block. Entries are identified by the series and
+//! header of the block they were computed from, and the cache evicts the
+//! least recently used entries once the memory they hold exceeds a budget.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+
+use fnv::FnvHashMap;
+
+use aggregate::Function;
+use store::SeriesKey;
+use Point;
+
+/// Default memory budget of a cache in bytes (64 MiB)
+pub const DEFAULT_BUDGET: usize = 64 << 20;
+
+/// Identifies the result computed from a single sealed block
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub series: SeriesKey,
+    pub header: u64,
+    /// Step in seconds of the aggregated windows, 0 for decoded points
+    pub step: i64,
+    /// Function aggregating the windows, `None` for decoded points
+    pub function: Option<Function>,
+}
+
+impl CacheKey {
+    /// Key of the points decoded from a block
+    pub fn decoded(series: &SeriesKey, header: u64) -> CacheKey {
+        CacheKey {
+            series: series.clone(),
+            header,
+            step: 0,
+            function: None,
+        }
+    }
+
+    /// Key of the windows aggregated from a block
+    pub fn aggregated(series: &SeriesKey, header: u64, function: Function, step: i64) -> CacheKey {
+        CacheKey {
+            series: series.clone(),
+            header,
+            step,
+            function: Some(function),
+        }
+    }
+
+    // Approximates the memory held by the key
+    fn size(&self) -> usize {
+        let tags: usize = self.series.tags.iter().map(|(k, v)| k.len() + v.len()).sum();
+        mem::size_of::<CacheKey>() + self.series.metric.len() + tags
+    }
+}
+
+struct Entry {
+    points: Arc<Vec<Point>>,
+    size: usize,
+    // recency of the entry, the least recently used entry has the lowest
+    used: u64,
+}
+
+/// Hit and miss counters of a cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// LRU cache of results bounded by an approximate memory budget
+pub struct Cache {
+    budget: usize,
+    size: usize,
+    clock: u64,
+    entries: FnvHashMap<CacheKey, Entry>,
+    // keys of the entries ordered by recency
+    recency: BTreeMap<u64, CacheKey>,
+    stats: Stats,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new(DEFAULT_BUDGET)
+    }
+}
+
+impl Cache {
+    /// Creates an empty cache holding at most about `budget` bytes. A
+    /// budget of 0 disables caching
+    pub fn new(budget: usize) -> Cache {
+        Cache {
+            budget,
+            size: 0,
+            clock: 0,
+            entries: FnvHashMap::default(),
+            recency: BTreeMap::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Returns the memory budget of the cache in bytes
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Changes the memory budget, evicting entries if needed
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    /// Returns the approximate memory held by the entries in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Retrieves the entry for `key`, marking it as the most recently used
+    pub fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<Point>>> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                let key = self.recency.remove(&entry.used).expect("cache entries are ordered by recency");
+                self.recency.insert(clock, key);
+                entry.used = clock;
+                self.stats.hits += 1;
+                Some(entry.points.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Inserts an entry, evicting the least recently used entries if the
+    /// budget is exceeded. Entries larger than the budget aren't cached
+    pub fn insert(&mut self, key: CacheKey, points: Arc<Vec<Point>>) {
+        let size = key.size() + mem::size_of::<Entry>() + points.len() * mem::size_of::<Point>();
+        self.remove(&key);
+        if size > self.budget {
+            return;
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.clone());
+        self.entries.insert(key, Entry { points, size, used: self.clock });
+        self.size += size;
+        self.evict();
+    }
+
+    /// Removes the entry for `key`
+    pub fn remove(&mut self, key: &CacheKey) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.used);
+                self.size -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every entry computed from the block of `series` starting at
+    /// `header`. Returns the number of entries removed
+    pub fn invalidate_block(&mut self, series: &SeriesKey, header: u64) -> usize {
+        self.invalidate(|key| key.series == *series && key.header == header)
+    }
+
+    /// Removes every entry computed from blocks of `series`. Returns the
+    /// number of entries removed
+    pub fn invalidate_series(&mut self, series: &SeriesKey) -> usize {
+        self.invalidate(|key| key.series == *series)
+    }
+
+    /// Removes every entry
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+
+    fn invalidate<F: Fn(&CacheKey) -> bool>(&mut self, f: F) -> usize {
+        let keys: Vec<CacheKey> = self.entries.keys().filter(|key| f(key)).cloned().collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys.len()
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.budget {
+            let key = match self.recency.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{Cache, CacheKey};
+    use aggregate::Function;
+    use store::SeriesKey;
+    use Point;
+
+    fn points(n: usize) -> Arc<Vec<Point>> {
+        Arc::new((0..n as i64).map(|ts| Point { ts, value: 1.0 }).collect())
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cpu = SeriesKey::new("cpu");
+        let mut cache = Cache::new(0);
+        cache.insert(CacheKey::decoded(&cpu, 0), points(10));
+        assert!(cache.is_empty());
+
+        cache.set_budget(1000);
+        for header in 0..3 {
+            cache.insert(CacheKey::decoded(&cpu, header), points(10));
+        }
+        let size = cache.size() / 3;
+        cache.set_budget(size * 2);
+        // the least recently used entry goes first
+        assert_eq!(2, cache.len());
+        assert!(cache.get(&CacheKey::decoded(&cpu, 0)).is_none());
+        assert!(cache.get(&CacheKey::decoded(&cpu, 1)).is_some());
+        cache.insert(CacheKey::decoded(&cpu, 3), points(10));
+        assert!(cache.get(&CacheKey::decoded(&cpu, 2)).is_none());
+        assert_eq!(10, cache.get(&CacheKey::decoded(&cpu, 1)).unwrap().len());
+        assert!(cache.get(&CacheKey::decoded(&cpu, 3)).is_some());
+        assert_eq!(3, cache.stats().hits);
+        assert_eq!(2, cache.stats().misses);
+        assert_eq!(2, cache.stats().evictions);
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cpu = SeriesKey::new("cpu");
+        let mem = SeriesKey::new("mem");
+        let mut cache = Cache::default();
+        cache.insert(CacheKey::decoded(&cpu, 0), points(1));
+        cache.insert(CacheKey::aggregated(&cpu, 0, Function::Sum, 60), points(1));
+        cache.insert(CacheKey::aggregated(&cpu, 100, Function::Sum, 60), points(1));
+        cache.insert(CacheKey::decoded(&mem, 0), points(1));
+        assert_eq!(2, cache.invalidate_block(&cpu, 0));
+        assert_eq!(1, cache.invalidate_series(&cpu));
+        assert_eq!(1, cache.len());
+        cache.clear();
+        assert_eq!(0, cache.size());
+    }
+}
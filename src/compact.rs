@@ -0,0 +1,295 @@
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use block_file;
+use series::Series;
+use {Point, TSBlock};
+
+/// Default window of compacted blocks (24 hours)
+pub const DEFAULT_WINDOW: i64 = 24 * 60 * 60;
+
+// The first point of a block is stored as a 14 bit delta from its header
+const MAX_FIRST_DELTA: i64 = 1 << 14;
+
+// Later points are stored as 32 bit deltas of deltas, which the gaps
+// between the points of a window must fit in
+const MAX_WINDOW: i64 = i32::MAX as i64;
+
+/// Merges consecutive sealed blocks of a series into larger blocks.
+///
+/// Blocks whose headers fall into the same compaction window are decoded,
+/// merged in time order and re-encoded into a single block, saving the
+/// per-block header and uncompressed first value. Points sharing a
+/// timestamp are deduplicated, keeping the value from the latest block.
+///
+/// A compactor may also be given a retention cutoff, in which case points
+/// published before the cutoff are trimmed from the blocks it rewrites.
+#[derive(Clone, Copy)]
+pub struct Compactor {
+    window: i64,
+    expire_before: Option<i64>,
+}
+
+impl Default for Compactor {
+    fn default() -> Compactor {
+        Compactor::new(DEFAULT_WINDOW)
+    }
+}
+
+impl Compactor {
+    /// Creates a compactor merging blocks into `window` second windows.
+    /// Panics if `window` is not within (0, 2^31 - 1]
+    pub fn new(window: i64) -> Compactor {
+        assert!(window > 0 && window <= MAX_WINDOW,
+                "compaction window must be within (0, 2^31 - 1] seconds");
+        Compactor {
+            window,
+            expire_before: None,
+        }
+    }
+
+    /// Returns a compactor that also trims points published before `cutoff`.
+    /// Blocks left without any points are dropped entirely
+    pub fn expire_before(self, cutoff: i64) -> Compactor {
+        Compactor {
+            expire_before: Some(cutoff),
+            ..self
+        }
+    }
+
+    /// Compacts `blocks`, returning the compacted blocks ordered by header.
+    /// Windows holding a single unexpired block are returned untouched
+    pub fn compact(&self, blocks: Vec<TSBlock>) -> Vec<TSBlock> {
+        self.groups(blocks)
+            .into_iter()
+            .filter_map(|mut group| {
+                if group.len() == 1 && !self.is_expiring(&group[0]) {
+                    group.pop()
+                } else {
+                    let merged = self.merge(&group);
+                    if merged.is_empty() { None } else { Some(merged) }
+                }
+            })
+            .collect()
+    }
+
+    /// Compacts the sealed blocks of `series`. The open block is left alone
+    pub fn compact_series(&self, series: &mut Series) {
+        let sealed = series.sealed_mut();
+        let blocks = mem::take(sealed);
+        *sealed = self.compact(blocks);
+    }
+
+    /// Compacts the block files in `dir`, which are assumed to belong to a
+    /// single series. Each merged block is written atomically to a new file
+    /// before the files it replaces are removed, so a crash never loses
+    /// data. At worst it leaves duplicate points behind, which are removed
+    /// by the next compaction
+    pub fn compact_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut files = Vec::new();
+        for path in block_file::list(dir)? {
+            let block: TSBlock = block_file::read(&path)?;
+            files.push((path, block));
+        }
+        files.sort_by_key(|(_, block)| block.header());
+
+        let mut files = files.into_iter().peekable();
+        while let Some((path, block)) = files.next() {
+            let window = self.window_of(&block);
+            let mut paths = vec![path];
+            let mut group = vec![block];
+            while let Some((path, block)) = files.next_if(|(_, b)| self.window_of(b) == window) {
+                paths.push(path);
+                group.push(block);
+            }
+            if group.len() == 1 && !self.is_expiring(&group[0]) {
+                continue;
+            }
+            let merged = self.merge(&group);
+            if !merged.is_empty() {
+                block_file::write(block_file::next_path(dir, merged.header()), &merged)?;
+            }
+            for path in paths {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Splits blocks into groups of blocks in the same window
+    fn groups(&self, mut blocks: Vec<TSBlock>) -> Vec<Vec<TSBlock>> {
+        // stable so that blocks sharing a header keep their publish order
+        blocks.sort_by_key(|block| block.header());
+        let mut groups: Vec<Vec<TSBlock>> = Vec::new();
+        for block in blocks {
+            let window = self.window_of(&block);
+            match groups.last_mut() {
+                Some(ref mut group) if self.window_of(&group[0]) == window => group.push(block),
+                _ => groups.push(vec![block]),
+            }
+        }
+        groups
+    }
+
+    // Returns true if some points of the block need to be trimmed
+    fn is_expiring(&self, block: &TSBlock) -> bool {
+        match (self.expire_before, block.iter().next()) {
+            (Some(cutoff), Some(first)) => first.ts < cutoff,
+            _ => false,
+        }
+    }
+
+    fn merge(&self, blocks: &[TSBlock]) -> TSBlock {
+        match self.expire_before {
+            Some(cutoff) => merge_after(blocks, cutoff),
+            None => merge(blocks),
+        }
+    }
+
+    fn window_of(&self, block: &TSBlock) -> i64 {
+        (block.header() as i64).div_euclid(self.window)
+    }
+}
+
+/// Merges `blocks` into a single block. Points are ordered by timestamp and
+/// points sharing a timestamp keep the value of the last block holding them
+pub fn merge(blocks: &[TSBlock]) -> TSBlock {
+    merge_after(blocks, i64::MIN)
+}
+
+// Merges `blocks`, dropping any points published before `cutoff`
+fn merge_after(blocks: &[TSBlock], cutoff: i64) -> TSBlock {
+    let mut points: Vec<Point> = blocks.iter()
+        .flat_map(|block| block.iter())
+        .filter(|point| point.ts >= cutoff)
+        .collect();
+    // stable so that later blocks win when deduplicating below
+    points.sort_by_key(|point| point.ts);
+    let mut deduped: Vec<Point> = Vec::with_capacity(points.len());
+    for point in points {
+        match deduped.last_mut() {
+            Some(last) if last.ts == point.ts => *last = point,
+            _ => deduped.push(point),
+        }
+    }
+
+    // every point lies within 2^14 seconds of the header of the non-empty
+    // block it was published to, so the smallest such header can hold the
+    // first point of the merged block unless earlier points were trimmed
+    let header = blocks.iter()
+        .filter(|block| !block.is_empty())
+        .map(|block| block.header() as i64)
+        .min()
+        .or_else(|| blocks.iter().map(|block| block.header() as i64).min())
+        .unwrap_or(0);
+    let header = match deduped.first() {
+        Some(first) if first.ts - header >= MAX_FIRST_DELTA => first.ts,
+        _ => header,
+    };
+    let mut merged = TSBlock::at(header);
+    for point in deduped {
+        merged.publish_at(point.value, point.ts);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use super::Compactor;
+    use block_file;
+    use series::Series;
+    use {test_block, Point, TSBlock};
+
+    #[test]
+    fn test_compact() {
+        let compactor = Compactor::new(100);
+        let blocks = vec![test_block(50, &[(60, 3.0), (70, 4.0)]),
+                          test_block(0, &[(10, 1.0), (20, 2.0)]),
+                          test_block(50, &[(70, 5.0), (80, 6.0)]),
+                          test_block(100, &[(100, 7.0)])];
+        let compacted = compactor.compact(blocks);
+        assert_eq!(2, compacted.len());
+        assert_eq!(0, compacted[0].header());
+        assert_eq!(vec![Point { ts: 10, value: 1.0 },
+                        Point { ts: 20, value: 2.0 },
+                        Point { ts: 60, value: 3.0 },
+                        Point { ts: 70, value: 5.0 },
+                        Point { ts: 80, value: 6.0 }],
+                   compacted[0].iter().collect::<Vec<Point>>());
+        assert_eq!(100, compacted[1].header());
+    }
+
+    #[test]
+    fn test_max_window() {
+        let compactor = Compactor::new(i32::MAX as i64);
+        let far = 1 << 30;
+        let blocks = vec![test_block(0, &[(10, 1.0), (20, 2.0)]),
+                          test_block(far, &[(far + 5, 3.0)])];
+        let compacted = compactor.compact(blocks);
+        assert_eq!(1, compacted.len());
+        assert_eq!(vec![Point { ts: 10, value: 1.0 },
+                        Point { ts: 20, value: 2.0 },
+                        Point { ts: far + 5, value: 3.0 }],
+                   compacted[0].iter().collect::<Vec<Point>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "compaction window")]
+    fn test_window_too_large() {
+        Compactor::new(i32::MAX as i64 + 1);
+    }
+
+    #[test]
+    fn test_compact_expire_before() {
+        let compactor = Compactor::new(100).expire_before(65);
+        let blocks = vec![test_block(0, &[(10, 1.0), (20, 2.0)]),
+                          test_block(50, &[(60, 3.0), (70, 4.0)]),
+                          test_block(100, &[(100, 5.0)])];
+        let compacted = compactor.compact(blocks);
+        assert_eq!(2, compacted.len());
+        assert_eq!(vec![Point { ts: 70, value: 4.0 }],
+                   compacted[0].iter().collect::<Vec<Point>>());
+        assert_eq!(100, compacted[1].header());
+
+        // trimming may move the first point too far from the old header
+        let compactor = Compactor::new(100_000).expire_before(50_000);
+        let compacted = compactor.compact(vec![test_block(0, &[(10, 1.0)]), test_block(49_990, &[(50_000, 2.0)])]);
+        assert_eq!(vec![Point { ts: 50_000, value: 2.0 }],
+                   compacted[0].iter().collect::<Vec<Point>>());
+    }
+
+    #[test]
+    fn test_compact_series() {
+        let mut series: Series = Series::with_block_size(7200);
+        for i in 0..(24 * 60) {
+            series.publish_at(i as f64, i * 60);
+        }
+        assert_eq!(11, series.sealed().len());
+        let expected: Vec<Point> = series.sealed().iter().flat_map(|b| b.iter()).collect();
+        Compactor::default().compact_series(&mut series);
+        assert_eq!(1, series.sealed().len());
+        assert_eq!(expected, series.sealed()[0].iter().collect::<Vec<Point>>());
+        assert!(series.open().is_some());
+    }
+
+    #[test]
+    fn test_compact_dir() {
+        let dir = env::temp_dir().join(format!("counter-compact-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for b in &[test_block(0, &[(10, 1.0)]), test_block(50, &[(60, 2.0)]), test_block(100, &[(110, 3.0)])] {
+            block_file::write(block_file::next_path(&dir, b.header()), b).unwrap();
+        }
+        Compactor::new(100).compact_dir(&dir).unwrap();
+        let paths = block_file::list(&dir).unwrap();
+        assert_eq!(2, paths.len());
+        let merged: TSBlock = block_file::read(&paths[0]).unwrap();
+        assert_eq!(vec![10, 60], merged.iter().map(|p| p.ts).collect::<Vec<i64>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
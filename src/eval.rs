@@ -0,0 +1,434 @@
+//! Evaluation of parsed queries against a `Store`.
+//!
+//! Queries are evaluated over a whole `Grid` at once: every expression
+//! evaluates to either a number or a set of series holding one optional
+//! value per grid timestamp. Like Prometheus, the value of an instant vector
+//! selector at a timestamp is the latest sample within the lookback period
+//! of the grid, and series lose their metric name once transformed. The
+//! points of integer series are read as floats.
+//!
+//! Evaluation stops with an error as soon as the query exceeds its
+//! `Limits`.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+use aggregate::{Accumulator, Function};
+use binary::{self, BinaryOp, MatchError, Matching};
+use group::{align, Grid, Interpolation};
+use limits::{Limits, QueryLimitExceeded, Tracker};
+use query::{self, Call, Expr, Grouping, Selector};
+use rate;
+use series::{IntSeries, Series};
+use store::{SeriesKey, Store};
+use {Point, TSBlock};
+
+/// A series produced by a query
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeseries {
+    /// Key of the series, whose metric is empty unless it was selected as is
+    pub key: SeriesKey,
+    /// One point per grid timestamp at which the series has a value
+    pub points: Vec<Point>,
+}
+
+impl Timeseries {
+    /// Compresses the points into a new block whose header is the timestamp
+    /// of the first point. Returns `None` if there are no points
+    pub fn to_block(&self) -> Option<TSBlock> {
+        let first = self.points.first()?;
+        let mut block = TSBlock::at(first.ts);
+        for point in &self.points {
+            block.publish_at(point.value, point.ts);
+        }
+        Some(block)
+    }
+}
+
+/// Error raised by a query that failed to parse or evaluate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Parse(query::Error),
+    Match(MatchError),
+    Limit(QueryLimitExceeded),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref err) => err.fmt(f),
+            Error::Match(ref err) => err.fmt(f),
+            Error::Limit(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<query::Error> for Error {
+    fn from(err: query::Error) -> Error {
+        Error::Parse(err)
+    }
+}
+
+impl From<MatchError> for Error {
+    fn from(err: MatchError) -> Error {
+        Error::Match(err)
+    }
+}
+
+impl From<QueryLimitExceeded> for Error {
+    fn from(err: QueryLimitExceeded) -> Error {
+        Error::Limit(err)
+    }
+}
+
+/// Parses and evaluates `query` over `grid` without any limits
+pub fn query(store: &Store, query: &str, grid: &Grid) -> Result<Vec<Timeseries>, Error> {
+    query_with_limits(store, query, grid, Limits::none())
+}
+
+/// Parses and evaluates `query` over `grid` within `limits`
+pub fn query_with_limits(store: &Store, query: &str, grid: &Grid, limits: Limits) -> Result<Vec<Timeseries>, Error> {
+    let expr = query::parse(query)?;
+    evaluate(store, &expr, grid, &mut Tracker::new(limits))
+}
+
+/// Evaluates `expr` over `grid`, counting its cost with `tracker`. A number
+/// evaluates to a single series with an empty key. Series are returned
+/// ordered by key
+pub fn evaluate(store: &Store, expr: &Expr, grid: &Grid, tracker: &mut Tracker) -> Result<Vec<Timeseries>, Error> {
+    let rows = match eval(store, expr, grid, tracker)? {
+        Value::Scalar(value) => vec![Row { key: SeriesKey::new(""), values: vec![Some(value); grid.len()] }],
+        Value::Vector(rows) => rows,
+    };
+    let mut series: Vec<Timeseries> = rows.into_iter().map(|row| row.into_timeseries(grid)).collect();
+    series.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(series)
+}
+
+// One series of an instant vector
+struct Row {
+    key: SeriesKey,
+    // one value per grid timestamp
+    values: Vec<Option<f64>>,
+}
+
+impl Row {
+    fn into_timeseries(self, grid: &Grid) -> Timeseries {
+        let points = grid.timestamps()
+            .zip(self.values)
+            .filter_map(|(ts, value)| value.map(|value| Point { ts, value }))
+            .collect();
+        Timeseries { key: self.key, points }
+    }
+
+    fn from_timeseries(series: Timeseries, grid: &Grid) -> Row {
+        let mut values = vec![None; grid.len()];
+        for point in series.points {
+            values[((point.ts - grid.start) / grid.step) as usize] = Some(point.value);
+        }
+        Row { key: series.key, values }
+    }
+}
+
+enum Value {
+    Scalar(f64),
+    Vector(Vec<Row>),
+}
+
+fn eval(store: &Store, expr: &Expr, grid: &Grid, tracker: &mut Tracker) -> Result<Value, Error> {
+    let value = match *expr {
+        Expr::Number(n) => Value::Scalar(n),
+        Expr::Selector(ref selector) => {
+            let shifted = shift(grid, selector.offset);
+            let rows = select(store, selector)
+                .map(|(key, series)| {
+                    let points = series.decode(tracker, shifted.start - shifted.lookback, shifted.end + 1)?;
+                    Ok(Row {
+                        key: key.clone(),
+                        values: align(&points, &shifted, Interpolation::Previous),
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            Value::Vector(rows)
+        }
+        Expr::Call(call, ref arg) => {
+            match **arg {
+                Expr::Selector(ref selector) => Value::Vector(eval_call(store, call, selector, grid, tracker)?),
+                _ => unreachable!("the parser only accepts range vector selectors as arguments"),
+            }
+        }
+        Expr::Aggregate(function, ref grouping, ref expr) => {
+            Value::Vector(aggregate(function, grouping, vector(eval(store, expr, grid, tracker)?), grid))
+        }
+        Expr::Binary(op, ref matching, ref lhs, ref rhs) => {
+            // filtering comparisons keep series as is
+            let keep_metric = op.is_comparison() && !matching.bool;
+            match (eval(store, lhs, grid, tracker)?, eval(store, rhs, grid, tracker)?) {
+                (Value::Scalar(lhs), Value::Scalar(rhs)) => {
+                    let value = op.apply(lhs, rhs, matching.bool);
+                    Value::Scalar(value.expect("the parser requires bool for comparisons between scalars"))
+                }
+                (Value::Vector(rows), Value::Scalar(rhs)) => {
+                    Value::Vector(map(rows, keep_metric, |value| op.apply(value, rhs, matching.bool)))
+                }
+                (Value::Scalar(lhs), Value::Vector(rows)) => {
+                    Value::Vector(map(rows, keep_metric, |value| {
+                        op.apply(lhs, value, matching.bool).map(|result| if keep_metric { value } else { result })
+                    }))
+                }
+                (Value::Vector(lhs), Value::Vector(rhs)) => Value::Vector(join(op, matching, lhs, rhs, grid)?),
+            }
+        }
+        Expr::Negate(ref expr) => {
+            match eval(store, expr, grid, tracker)? {
+                Value::Scalar(value) => Value::Scalar(-value),
+                Value::Vector(rows) => Value::Vector(map(rows, false, |value| Some(-value))),
+            }
+        }
+    };
+    Ok(value)
+}
+
+fn vector(value: Value) -> Vec<Row> {
+    match value {
+        Value::Vector(rows) => rows,
+        Value::Scalar(_) => unreachable!("the parser only accepts instant vectors here"),
+    }
+}
+
+fn shift(grid: &Grid, offset: i64) -> Grid {
+    Grid {
+        start: grid.start - offset,
+        end: grid.end - offset,
+        ..*grid
+    }
+}
+
+// A selected series of either value type
+enum Selected<'a> {
+    Float(&'a Series),
+    Int(&'a IntSeries),
+}
+
+impl<'a> Selected<'a> {
+    // Integer points are read as floats
+    fn decode(&self, tracker: &mut Tracker, start: i64, end: i64) -> Result<Vec<Point>, QueryLimitExceeded> {
+        match *self {
+            Selected::Float(series) => tracker.decode(series, start, end),
+            Selected::Int(series) => tracker.decode_int(series, start, end),
+        }
+    }
+}
+
+fn select<'a>(store: &'a Store, selector: &'a Selector) -> impl Iterator<Item = (&'a SeriesKey, Selected<'a>)> {
+    let floats = store.iter().map(|(key, series)| (key, Selected::Float(series)));
+    let ints = store.iter_ints().map(|(key, series)| (key, Selected::Int(series)));
+    floats.chain(ints).filter(move |&(key, _)| selector.matches(key))
+}
+
+// Applies `call` over the window (ts - range, ts] of every grid timestamp
+fn eval_call(store: &Store, call: Call, selector: &Selector, grid: &Grid, tracker: &mut Tracker) -> Result<Vec<Row>, Error> {
+    let range = selector.range.expect("the parser only accepts range vector selectors as arguments");
+    let shifted = shift(grid, selector.offset);
+    select(store, selector)
+        .map(|(key, series)| {
+            let points = series.decode(tracker, shifted.start - range + 1, shifted.end + 1)?;
+            let values = shifted.timestamps()
+                .map(|ts| {
+                    let from = points.partition_point(|p| p.ts <= ts - range);
+                    let to = points.partition_point(|p| p.ts <= ts);
+                    let window = points[from..to].iter().cloned();
+                    match call {
+                        Call::Rate => rate::rate(window, ts - range, ts),
+                        Call::Irate => rate::irate(window, ts - range, ts),
+                        Call::Increase => rate::increase(window, ts - range, ts),
+                        Call::OverTime(function) => {
+                            let mut acc = Accumulator::new();
+                            window.for_each(|p| acc.push(p.value));
+                            if acc.count() > 0 { Some(acc.get(function)) } else { None }
+                        }
+                    }
+                })
+                .collect();
+            Ok(Row { key: without_metric(key), values })
+        })
+        .collect()
+}
+
+fn without_metric(key: &SeriesKey) -> SeriesKey {
+    SeriesKey {
+        metric: String::new(),
+        tags: key.tags.clone(),
+    }
+}
+
+fn map<F: Fn(f64) -> Option<f64>>(rows: Vec<Row>, keep_metric: bool, f: F) -> Vec<Row> {
+    rows.into_iter()
+        .map(|row| {
+            Row {
+                key: if keep_metric { row.key } else { without_metric(&row.key) },
+                values: row.values.into_iter().map(|value| value.and_then(&f)).collect(),
+            }
+        })
+        .collect()
+}
+
+fn aggregate(function: Function, grouping: &Grouping, rows: Vec<Row>, grid: &Grid) -> Vec<Row> {
+    let mut groups: BTreeMap<SeriesKey, Vec<Accumulator>> = BTreeMap::new();
+    for row in rows {
+        let mut key = without_metric(&row.key);
+        match *grouping {
+            Grouping::By(ref tags) => key.tags.retain(|name, _| tags.contains(name)),
+            Grouping::Without(ref tags) => key.tags.retain(|name, _| !tags.contains(name)),
+        }
+        let accumulators = groups.entry(key).or_insert_with(|| vec![Accumulator::new(); grid.len()]);
+        for (acc, value) in accumulators.iter_mut().zip(row.values) {
+            if let Some(value) = value {
+                acc.push(value);
+            }
+        }
+    }
+    groups.into_iter()
+        .map(|(key, accumulators)| {
+            let values = accumulators.into_iter()
+                .map(|acc| if acc.count() > 0 { Some(acc.get(function)) } else { None })
+                .collect();
+            Row { key, values }
+        })
+        .collect()
+}
+
+fn join(op: BinaryOp, matching: &Matching, lhs: Vec<Row>, rhs: Vec<Row>, grid: &Grid) -> Result<Vec<Row>, Error> {
+    let lhs: Vec<Timeseries> = lhs.into_iter().map(|row| row.into_timeseries(grid)).collect();
+    let rhs: Vec<Timeseries> = rhs.into_iter().map(|row| row.into_timeseries(grid)).collect();
+    // both sides share the grid so timestamps match exactly
+    let joined = binary::join(op, &lhs, &rhs, matching, 0)?;
+    Ok(joined.into_iter().map(|series| Row::from_timeseries(series, grid)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{query, query_with_limits, Error};
+    use binary::MatchError;
+    use limits::{Limits, QueryLimitExceeded};
+    use group::Grid;
+    use store::{SeriesKey, Store};
+
+    fn store() -> Store {
+        let mut store = Store::new();
+        for &(host, region, rate) in &[("a", "us", 1.0), ("b", "us", 2.0), ("c", "eu", 4.0)] {
+            let requests = SeriesKey::new("requests").tag("host", host).tag("region", region);
+            let errors = SeriesKey::new("errors").tag("host", host).tag("region", region);
+            for i in 0..=60 {
+                store.publish_at(&requests, rate * (i * 10) as f64, i * 10);
+                store.publish_at(&errors, (i % 2) as f64, i * 10);
+            }
+        }
+        store
+    }
+
+    fn values(store: &Store, q: &str, grid: &Grid) -> Vec<(String, Vec<f64>)> {
+        query(store, q, grid)
+            .unwrap()
+            .into_iter()
+            .map(|ts| (ts.key.to_string(), ts.points.iter().map(|p| p.value).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_selector() {
+        let store = store();
+        let grid = Grid::new(300, 600, 150);
+        assert_eq!(vec![("requests{host=\"b\",region=\"us\"}".to_string(), vec![600.0, 900.0, 1200.0])],
+                   values(&store, "requests{host=\"b\"}", &grid));
+        assert_eq!(vec![("requests{host=\"b\",region=\"us\"}".to_string(), vec![0.0, 300.0, 600.0])],
+                   values(&store, "requests{host='b'} offset 5m", &grid));
+        assert_eq!(2, values(&store, "requests{region!=\"eu\"}", &grid).len());
+        assert_eq!(2, values(&store, "requests{host=~\"a|c\"}", &grid).len());
+        assert_eq!(1, values(&store, "requests{host!~\"[ab]\"}", &grid).len());
+        assert!(values(&store, "missing", &grid).is_empty());
+    }
+
+    #[test]
+    fn test_int_series() {
+        let mut store = store();
+        let hits = SeriesKey::new("hits").tag("host", "a");
+        for i in 0..=60 {
+            store.publish_int_at(&hits, i * 10, i * 10);
+        }
+        let grid = Grid::new(300, 600, 150);
+        assert_eq!(vec![("hits{host=\"a\"}".to_string(), vec![300.0, 450.0, 600.0])],
+                   values(&store, "hits", &grid));
+        assert_eq!(vec![("{host=\"a\"}".to_string(), vec![1.0, 1.0, 1.0])],
+                   values(&store, "rate(hits[1m])", &grid));
+    }
+
+    #[test]
+    fn test_functions() {
+        let store = store();
+        let grid = Grid::new(300, 600, 150);
+        let rates = values(&store, "sum by (region) (rate(requests[5m]))", &grid);
+        assert_eq!(vec!["{region=\"eu\"}", "{region=\"us\"}"], rates.iter().map(|r| r.0.as_str()).collect::<Vec<_>>());
+        assert!(rates[0].1.iter().all(|rate| (rate - 4.0).abs() < 1e-9));
+        assert!(rates[1].1.iter().all(|rate| (rate - 3.0).abs() < 1e-9));
+        assert_eq!(vec![("".to_string(), vec![7.0; 3])],
+                   values(&store, "sum(irate(requests[1m]))", &grid));
+        assert_eq!(vec![("{host=\"a\",region=\"us\"}".to_string(), vec![255.0, 405.0, 555.0])],
+                   values(&store, "avg_over_time(requests{host=\"a\"}[1m] offset 20s)", &grid));
+        assert_eq!(vec![("".to_string(), vec![3.0; 3])],
+                   values(&store, "count without (host, region) (requests)", &grid));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let store = store();
+        let grid = Grid::new(300, 310, 10);
+        let ratios = values(&store, "errors / requests * 100", &grid);
+        assert_eq!(3, ratios.len());
+        assert_eq!("{host=\"a\",region=\"us\"}", ratios[0].0);
+        assert_eq!(vec![0.0, 100.0 / 310.0], ratios[0].1);
+        assert_eq!(vec![("".to_string(), vec![7.0, 7.0])], values(&store, "1 + 2 * 3", &grid));
+        assert_eq!(vec![-1240.0], values(&store, "-requests{host=\"c\"}", &grid)[0].1[1..].to_vec());
+        assert!(query(&store, "errors / ", &grid).is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let store = store();
+        let grid = Grid::new(300, 330, 10);
+        let errors = values(&store, "errors{host=\"a\"} > 0", &grid);
+        assert_eq!(vec![("errors{host=\"a\",region=\"us\"}".to_string(), vec![1.0, 1.0])], errors);
+        assert_eq!(vec![0.0, 1.0, 0.0, 1.0], values(&store, "errors{host=\"a\"} == bool 1", &grid)[0].1);
+        assert_eq!(vec![1.0, 1.0], values(&store, "0 < errors{host=\"a\"}", &grid)[0].1);
+
+        let ratios = values(&store, "sum by (region) (errors) / ignoring (host) group_left sum by (region) (requests)", &grid);
+        assert_eq!(2, ratios.len());
+        let totals = values(&store, "requests / on (region) group_left sum by (region) (requests)", &grid);
+        assert_eq!(3, totals.len());
+        assert_eq!(vec![1.0; 4], totals[2].1);
+        match query(&store, "requests / on (region) sum by (region) (requests)", &grid) {
+            Err(Error::Match(MatchError::DuplicateLeft(_))) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let store = store();
+        let grid = Grid::new(300, 600, 150);
+        let limited = |q, limits| query_with_limits(&store, q, &grid, limits);
+        assert!(limited("sum(requests)", Limits::none().max_series(3)).is_ok());
+        assert_eq!(Err(Error::Limit(QueryLimitExceeded::Series { limit: 3 })),
+                   limited("requests / errors", Limits::none().max_series(3)));
+        assert_eq!(Err(Error::Limit(QueryLimitExceeded::Points { limit: 100 })),
+                   limited("rate(requests[5m])", Limits::none().max_points(100)));
+        let timeout = Duration::from_secs(0);
+        assert_eq!(Err(Error::Limit(QueryLimitExceeded::Deadline { timeout })),
+                   limited("requests", Limits::none().timeout(timeout)));
+    }
+}
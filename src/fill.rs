@@ -0,0 +1,190 @@
+//! Gap filling of decoded series onto a regular grid.
+//!
+//! Every point is assigned to the grid timestamp at or before it, the last
+//! point wins when several share a grid timestamp, so the output of an
+//! `aggregate::Window` over the same step maps one to one onto the grid.
+//! Grid timestamps without a point are gaps, filled according to a `Fill`.
+
+use std::iter::{Fuse, Peekable};
+
+use Point;
+
+/// How gaps are filled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fill {
+    /// Gaps are left empty
+    Null,
+    /// Gaps take the value before them
+    Previous,
+    /// Gaps take a value interpolated linearly between the values around
+    /// them, gaps before the first or after the last value are left empty
+    Linear,
+    /// Gaps take the given value
+    Constant(f64),
+}
+
+/// Adapter over a time ordered stream of points yielding one timestamp and
+/// optional value per grid timestamp from `start` to `end` inclusive
+pub struct GapFill<I: Iterator<Item = Point>> {
+    points: Peekable<Fuse<I>>,
+    ts: i64,
+    end: i64,
+    step: i64,
+    fill: Fill,
+    max_gap: Option<i64>,
+    // last value at a grid timestamp before `ts`
+    prev: Option<Point>,
+    // first value at a grid timestamp at or after `ts`
+    next: Option<Point>,
+}
+
+impl<I: Iterator<Item = Point>> GapFill<I> {
+    /// Creates an adapter filling the gaps of `points` with `fill`
+    pub fn new(points: I, start: i64, end: i64, step: i64, fill: Fill) -> GapFill<I> {
+        assert!(step > 0, "grid step must be positive");
+        GapFill {
+            points: points.fuse().peekable(),
+            ts: start,
+            end,
+            step,
+            fill,
+            max_gap: None,
+            prev: None,
+            next: None,
+        }
+    }
+
+    /// Stops filling gaps with previous or interpolated values when the
+    /// values around them are more than `seconds` apart. For `Previous`,
+    /// a gap is filled as long as it is at most `seconds` after the value
+    /// before it
+    pub fn max_gap(mut self, seconds: i64) -> GapFill<I> {
+        self.max_gap = Some(seconds);
+        self
+    }
+
+    // Returns the grid timestamp a point at `ts` is assigned to
+    fn slot(&self, ts: i64) -> i64 {
+        ts - (ts - self.ts).rem_euclid(self.step)
+    }
+
+    // Returns the value of the next grid timestamp holding points
+    fn read_slot(&mut self) -> Option<Point> {
+        let first = self.points.next()?;
+        let ts = self.slot(first.ts);
+        let end = ts + self.step;
+        let mut value = first.value;
+        while let Some(point) = self.points.next_if(|p| p.ts < end) {
+            value = point.value;
+        }
+        Some(Point { ts, value })
+    }
+
+    fn within_max_gap(&self, from: i64, to: i64) -> bool {
+        self.max_gap.is_none_or(|max_gap| to - from <= max_gap)
+    }
+
+    fn fill_gap(&self) -> Option<f64> {
+        match self.fill {
+            Fill::Null => None,
+            Fill::Constant(value) => Some(value),
+            Fill::Previous => {
+                let prev = self.prev?;
+                if self.within_max_gap(prev.ts, self.ts) { Some(prev.value) } else { None }
+            }
+            Fill::Linear => {
+                let (prev, next) = (self.prev?, self.next?);
+                if !self.within_max_gap(prev.ts, next.ts) {
+                    return None;
+                }
+                let weight = (self.ts - prev.ts) as f64 / (next.ts - prev.ts) as f64;
+                Some(prev.value + (next.value - prev.value) * weight)
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = Point>> Iterator for GapFill<I> {
+    type Item = (i64, Option<f64>);
+
+    fn next(&mut self) -> Option<(i64, Option<f64>)> {
+        if self.ts > self.end {
+            return None;
+        }
+        while self.next.is_none_or(|next| next.ts < self.ts) {
+            if self.next.is_some() {
+                self.prev = self.next;
+            }
+            self.next = self.read_slot();
+            if self.next.is_none() {
+                break;
+            }
+        }
+        let value = match self.next {
+            Some(next) if next.ts == self.ts => Some(next.value),
+            _ => self.fill_gap(),
+        };
+        let ts = self.ts;
+        self.ts += self.step;
+        Some((ts, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fill, GapFill};
+    use TSBlock;
+
+    fn block() -> TSBlock {
+        let mut block = TSBlock::at(0);
+        for &(ts, value) in &[(5, 0.0), (10, 1.0), (14, 2.0), (40, 5.0), (95, 9.0)] {
+            block.publish_at(value, ts);
+        }
+        block
+    }
+
+    fn fill(fill: Fill, max_gap: Option<i64>) -> Vec<Option<f64>> {
+        let block = block();
+        let gap_fill = GapFill::new(block.iter(), 10, 100, 10, fill);
+        let gap_fill = match max_gap {
+            Some(max_gap) => gap_fill.max_gap(max_gap),
+            None => gap_fill,
+        };
+        gap_fill.map(|(_, value)| value).collect()
+    }
+
+    #[test]
+    fn test_fill_modes() {
+        let timestamps: Vec<i64> = GapFill::new(block().iter(), 10, 100, 10, Fill::Null).map(|(ts, _)| ts).collect();
+        assert_eq!((1..11).map(|i| i * 10).collect::<Vec<i64>>(), timestamps);
+        assert_eq!(vec![Some(2.0), None, None, Some(5.0), None, None, None, None, Some(9.0), None],
+                   fill(Fill::Null, None));
+        assert_eq!(vec![Some(2.0), Some(2.0), Some(2.0), Some(5.0), Some(5.0), Some(5.0), Some(5.0), Some(5.0),
+                        Some(9.0), Some(9.0)],
+                   fill(Fill::Previous, None));
+        assert_eq!(vec![Some(2.0), Some(3.0), Some(4.0), Some(5.0), Some(5.8), Some(6.6), Some(7.4), Some(8.2),
+                        Some(9.0), None],
+                   fill(Fill::Linear, None)
+                       .into_iter()
+                       .map(|v| v.map(|v| (v * 10.0).round() / 10.0))
+                       .collect::<Vec<Option<f64>>>());
+        assert_eq!(vec![Some(2.0), Some(0.0), Some(0.0), Some(5.0), Some(0.0), Some(0.0), Some(0.0), Some(0.0),
+                        Some(9.0), Some(0.0)],
+                   fill(Fill::Constant(0.0), None));
+    }
+
+    #[test]
+    fn test_max_gap() {
+        assert_eq!(vec![Some(2.0), Some(2.0), Some(2.0), Some(5.0), Some(5.0), Some(5.0), None, None, Some(9.0),
+                        Some(9.0)],
+                   fill(Fill::Previous, Some(20)));
+        assert_eq!(vec![Some(2.0), Some(3.0), Some(4.0), Some(5.0), None, None, None, None, Some(9.0), None],
+                   fill(Fill::Linear, Some(30)));
+    }
+
+    #[test]
+    fn test_points_before_start() {
+        let filled: Vec<Option<f64>> = GapFill::new(block().iter(), 20, 30, 10, Fill::Previous).map(|(_, v)| v).collect();
+        assert_eq!(vec![Some(2.0), Some(2.0)], filled);
+    }
+}
@@ -0,0 +1,336 @@
+//! Graphite plaintext protocol.
+//!
+//! Clients send one sample per line over TCP:
+//!
+//! ```text
+//! servers.a.cpu 0.5 1500000000
+//! servers.a.cpu;region=us;dc=1 0.5 1500000000
+//! ```
+//!
+//! The path is the metric name and the optional `;name=value` suffixes are
+//! tags, as in Graphite 1.1. Timestamps are seconds from the epoch, and a
+//! timestamp of -1 stands for the time the line was received. Malformed
+//! lines are counted and skipped without closing the connection.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use time;
+
+use ingest::{self, Batcher, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use store::{SeriesKey, Store};
+
+// How often connections check whether the listener is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reasons a line can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line doesn't have exactly a path, a value and a timestamp
+    FieldCount(usize),
+    EmptyPath,
+    InvalidTag(String),
+    InvalidValue(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::FieldCount(n) => write!(f, "expected 3 fields, found {}", n),
+            ParseError::EmptyPath => write!(f, "empty metric path"),
+            ParseError::InvalidTag(ref tag) => write!(f, "invalid tag {:?}", tag),
+            ParseError::InvalidValue(ref value) => write!(f, "invalid value {:?}", value),
+            ParseError::InvalidTimestamp(ref ts) => write!(f, "invalid timestamp {:?}", ts),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a line received at `now` seconds from the epoch
+pub fn parse_line(line: &str, now: i64) -> Result<Sample, ParseError> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 3 {
+        return Err(ParseError::FieldCount(fields.len()));
+    }
+    let mut parts = fields[0].split(';');
+    let path = parts.next().unwrap_or("");
+    if path.is_empty() {
+        return Err(ParseError::EmptyPath);
+    }
+    let mut key = SeriesKey::new(path);
+    for tag in parts {
+        match tag.split_once('=') {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => key = key.tag(name, value),
+            _ => return Err(ParseError::InvalidTag(tag.to_string())),
+        }
+    }
+    let value = fields[1].parse().map_err(|_| ParseError::InvalidValue(fields[1].to_string()))?;
+    let ts = match fields[2].parse::<f64>() {
+        Ok(-1.0) => now,
+        Ok(ts) if ts.is_finite() && ts >= 0.0 => ts as i64,
+        _ => return Err(ParseError::InvalidTimestamp(fields[2].to_string())),
+    };
+    Ok(Sample { key, value: Value::Float(value), ts })
+}
+
+/// Settings of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Samples per batch handed to the writer
+    pub batch_size: usize,
+    /// Batches queued up for the writer before connections stop reading
+    pub queue: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            batch_size: DEFAULT_BATCH_SIZE,
+            queue: DEFAULT_QUEUE,
+        }
+    }
+}
+
+/// Counters of a listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    lines: AtomicU64,
+    malformed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of connections accepted
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of lines received, malformed or not
+    pub fn lines(&self) -> u64 {
+        self.lines.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of malformed lines skipped
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last malformed line
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn report(&self, line: &str, err: &ParseError) {
+        self.malformed.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(format!("{}: {:?}", err, line));
+    }
+}
+
+/// TCP listener publishing the samples it receives into a store
+pub struct Listener {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    handle: Option<JoinHandle<()>>,
+    writer: Option<Writer>,
+}
+
+impl Listener {
+    /// Starts listening on `addr` for samples to publish into `store`
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store, config.queue);
+        let accept = Accept {
+            listener,
+            stop: stop.clone(),
+            stats: stats.clone(),
+            writer: writer.handle(),
+            batch_size: config.batch_size,
+        };
+        let handle = thread::spawn(move || accept.run());
+        Ok(Listener {
+            addr,
+            stop,
+            stats,
+            handle: Some(handle),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.writer.take();
+    }
+}
+
+struct Accept {
+    listener: TcpListener,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    writer: ingest::Handle,
+    batch_size: usize,
+}
+
+impl Accept {
+    fn run(self) {
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        for stream in self.listener.incoming() {
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            self.stats.connections.fetch_add(1, Ordering::SeqCst);
+            let stop = self.stop.clone();
+            let stats = self.stats.clone();
+            let batcher = self.writer.batcher(self.batch_size);
+            connections.retain(|handle| !handle.is_finished());
+            connections.push(thread::spawn(move || {
+                let _ = serve(stream, &stop, &stats, batcher);
+            }));
+        }
+        for handle in connections {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(stream: TcpStream, stop: &AtomicBool, stats: &Stats, mut batcher: Batcher) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while !stop.load(Ordering::SeqCst) {
+        // a timed out read keeps what it read so far in `line`
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) if line.ends_with(b"\n") => {}
+            Ok(_) => break,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                // idle connections shouldn't hold on to their samples
+                if batcher.flush().is_err() {
+                    break;
+                }
+                continue;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+        if handle_line(&line, stats, &mut batcher).is_err() {
+            break;
+        }
+        line.clear();
+    }
+    // a last line without newline
+    if !line.is_empty() && !stop.load(Ordering::SeqCst) {
+        let _ = handle_line(&line, stats, &mut batcher);
+    }
+    Ok(())
+}
+
+fn handle_line(line: &[u8], stats: &Stats, batcher: &mut Batcher) -> Result<(), ingest::Closed> {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+    stats.lines.fetch_add(1, Ordering::SeqCst);
+    match parse_line(line, time::get_time().sec) {
+        Ok(sample) => batcher.push(sample),
+        Err(err) => {
+            stats.report(line, &err);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse_line, Config, Listener, ParseError};
+    use ingest::Value;
+    use store::{SeriesKey, Store};
+
+    #[test]
+    fn test_parse_line() {
+        let sample = parse_line("servers.a.cpu 0.5 1500000000", 0).unwrap();
+        assert_eq!(SeriesKey::new("servers.a.cpu"), sample.key);
+        assert_eq!(Value::Float(0.5), sample.value);
+        assert_eq!(1500000000, sample.ts);
+        let sample = parse_line("cpu;region=us;dc=1  2\t1500000000.5", 0).unwrap();
+        assert_eq!(SeriesKey::new("cpu").tag("region", "us").tag("dc", "1"), sample.key);
+        assert_eq!(1500000000, sample.ts);
+        assert_eq!(42, parse_line("cpu 1 -1", 42).unwrap().ts);
+
+        assert_eq!(Err(ParseError::FieldCount(2)), parse_line("cpu 1", 0));
+        assert_eq!(Err(ParseError::InvalidValue("one".to_string())), parse_line("cpu one 1", 0));
+        assert_eq!(Err(ParseError::InvalidTimestamp("x".to_string())), parse_line("cpu 1 x", 0));
+        assert_eq!(Err(ParseError::InvalidTag("dc".to_string())), parse_line("cpu;dc 1 1", 0));
+        assert_eq!(Err(ParseError::EmptyPath), parse_line(";dc=1 1 1", 0));
+    }
+
+    #[test]
+    fn test_listener() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let config = Config { batch_size: 2, queue: 1 };
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), config).unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+        stream.write_all(b"cpu;host=a 1 100\ncpu;host=a 2 110\nbroken line\n\ncpu;host=a 3 120").unwrap();
+        drop(stream);
+
+        for _ in 0..1000 {
+            if listener.written() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(3, listener.written());
+        assert_eq!(4, listener.stats().lines());
+        assert_eq!(1, listener.stats().malformed());
+        assert!(listener.stats().last_error().unwrap().contains("broken line"));
+        drop(listener);
+
+        let store = store.read().unwrap();
+        let points = store.get(&SeriesKey::new("cpu").tag("host", "a")).unwrap().points(0, 200);
+        assert_eq!(vec![1.0, 2.0, 3.0], points.iter().map(|p| p.value).collect::<Vec<f64>>());
+    }
+}
@@ -0,0 +1,212 @@
+//! Aggregation across series grouped by tags.
+//!
+//! Series rarely share timestamps, so before they can be combined every
+//! series is aligned onto a common grid of timestamps, filling in values
+//! between samples according to an `Interpolation`.
+
+use std::collections::BTreeMap;
+
+use aggregate::{Accumulator, Function};
+use series::Series;
+use store::SeriesKey;
+use Point;
+
+/// Default age in seconds beyond which a sample no longer counts
+/// towards a grid timestamp (5 minutes)
+pub const DEFAULT_LOOKBACK: i64 = 5 * 60;
+
+/// How values are derived at grid timestamps without a sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Only samples within the step leading up to a grid timestamp count
+    None,
+    /// The latest sample within the lookback period is carried forward
+    Previous,
+    /// Values are interpolated linearly between the surrounding samples
+    /// when they are within the lookback period of the grid timestamp
+    Linear,
+}
+
+/// Evenly spaced timestamps from `start` to `end` inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Grid {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+    pub lookback: i64,
+}
+
+impl Grid {
+    /// Creates a grid with the default lookback period
+    pub fn new(start: i64, end: i64, step: i64) -> Grid {
+        assert!(step > 0, "grid step must be positive");
+        Grid {
+            start,
+            end,
+            step,
+            lookback: DEFAULT_LOOKBACK,
+        }
+    }
+
+    /// Sets the age in seconds beyond which samples are ignored
+    pub fn with_lookback(self, lookback: i64) -> Grid {
+        Grid { lookback, ..self }
+    }
+
+    /// Returns the number of timestamps on the grid
+    pub fn len(&self) -> usize {
+        if self.end < self.start {
+            0
+        } else {
+            ((self.end - self.start) / self.step + 1) as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the timestamps of the grid
+    pub fn timestamps(&self) -> impl Iterator<Item = i64> {
+        let grid = *self;
+        (0..self.len() as i64).map(move |i| grid.start + i * grid.step)
+    }
+
+    // Returns the points of `series` that may contribute to the grid
+    fn points(&self, series: &Series, interpolation: Interpolation) -> Vec<Point> {
+        let end = match interpolation {
+            Interpolation::Linear => self.end + self.lookback + 1,
+            _ => self.end + 1,
+        };
+        series.points(self.start - self.lookback, end)
+    }
+}
+
+/// Aligns time ordered `points` onto `grid`, returning the value at every
+/// grid timestamp if there is one
+pub fn align(points: &[Point], grid: &Grid, interpolation: Interpolation) -> Vec<Option<f64>> {
+    let mut values = Vec::with_capacity(grid.len());
+    // index of the first point after the current grid timestamp
+    let mut next = 0;
+    for ts in grid.timestamps() {
+        while next < points.len() && points[next].ts <= ts {
+            next += 1;
+        }
+        let prev = if next > 0 { Some(points[next - 1]) } else { None };
+        let value = match interpolation {
+            Interpolation::None => prev.filter(|p| p.ts > ts - grid.step).map(|p| p.value),
+            Interpolation::Previous => prev.filter(|p| ts - p.ts <= grid.lookback).map(|p| p.value),
+            Interpolation::Linear => {
+                match (prev, points.get(next)) {
+                    (Some(prev), _) if prev.ts == ts => Some(prev.value),
+                    (Some(prev), Some(after)) if ts - prev.ts <= grid.lookback &&
+                                                 after.ts - ts <= grid.lookback => {
+                        let weight = (ts - prev.ts) as f64 / (after.ts - prev.ts) as f64;
+                        Some(prev.value + (after.value - prev.value) * weight)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        values.push(value);
+    }
+    values
+}
+
+/// The aggregated points of one group of series
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    /// Values of the grouping tags shared by every series of the group
+    pub tags: BTreeMap<String, String>,
+    /// One point per grid timestamp at which some series had a value
+    pub points: Vec<Point>,
+}
+
+/// Aligns every series onto `grid`, then groups series by the values of
+/// the `by` tags and applies `function` across the series of each group at
+/// every grid timestamp. Series without one of the `by` tags are grouped
+/// as if the tag were empty, which is also how it is reported. Groups are
+/// returned ordered by their tags
+pub fn group_by<'a, I>(series: I, by: &[&str], function: Function, grid: &Grid, interpolation: Interpolation) -> Vec<Group>
+    where I: IntoIterator<Item = (&'a SeriesKey, &'a Series)>
+{
+    let mut groups: BTreeMap<BTreeMap<String, String>, Vec<Accumulator>> = BTreeMap::new();
+    for (key, series) in series {
+        let tags = by.iter()
+            .map(|&name| (name.to_string(), key.tags.get(name).cloned().unwrap_or_default()))
+            .collect();
+        let accumulators = groups.entry(tags).or_insert_with(|| vec![Accumulator::new(); grid.len()]);
+        let points = grid.points(series, interpolation);
+        for (acc, value) in accumulators.iter_mut().zip(align(&points, grid, interpolation)) {
+            if let Some(value) = value {
+                acc.push(value);
+            }
+        }
+    }
+    groups.into_iter()
+        .map(|(tags, accumulators)| {
+            let points = grid.timestamps()
+                .zip(accumulators)
+                .filter(|(_, acc)| acc.count() > 0)
+                .map(|(ts, acc)| Point { ts, value: acc.get(function) })
+                .collect();
+            Group { tags, points }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{align, group_by, Grid, Interpolation};
+    use aggregate::Function;
+    use store::{SeriesKey, Store};
+    use Point;
+
+    fn points(values: &[(i64, f64)]) -> Vec<Point> {
+        values.iter().map(|&(ts, value)| Point { ts, value }).collect()
+    }
+
+    #[test]
+    fn test_align() {
+        let points = points(&[(5, 1.0), (25, 3.0)]);
+        let grid = Grid::new(0, 40, 10).with_lookback(20);
+        assert_eq!(vec![None, Some(1.0), None, Some(3.0), None],
+                   align(&points, &grid, Interpolation::None));
+        assert_eq!(vec![None, Some(1.0), Some(1.0), Some(3.0), Some(3.0)],
+                   align(&points, &grid, Interpolation::Previous));
+        assert_eq!(vec![None, Some(1.5), Some(2.5), None, None],
+                   align(&points, &grid, Interpolation::Linear));
+        let grid = grid.with_lookback(10);
+        assert_eq!(vec![None, Some(1.0), None, Some(3.0), None],
+                   align(&points, &grid, Interpolation::Previous));
+    }
+
+    #[test]
+    fn test_group_by() {
+        let mut store = Store::new();
+        let series = [("us", "a", 0, 1.0), ("us", "b", 3, 2.0), ("eu", "c", 7, 4.0)];
+        for &(region, host, offset, value) in &series {
+            let key = SeriesKey::new("requests").tag("region", region).tag("host", host);
+            for i in 0..6 {
+                store.publish_at(&key, value, i * 10 + offset);
+            }
+        }
+        store.publish_at(&SeriesKey::new("other"), 100.0, 0);
+
+        let grid = Grid::new(10, 50, 10);
+        let requests = store.iter().filter(|&(key, _)| key.metric == "requests");
+        let groups = group_by(requests, &["region"], Function::Sum, &grid, Interpolation::Previous);
+        assert_eq!(2, groups.len());
+        assert_eq!(Some(&"eu".to_string()), groups[0].tags.get("region"));
+        assert_eq!(vec![4.0; 5], groups[0].points.iter().map(|p| p.value).collect::<Vec<f64>>());
+        assert_eq!("us", groups[1].tags["region"]);
+        assert_eq!(vec![3.0; 5], groups[1].points.iter().map(|p| p.value).collect::<Vec<f64>>());
+        assert_eq!(vec![10, 20, 30, 40, 50], groups[1].points.iter().map(|p| p.ts).collect::<Vec<i64>>());
+
+        let requests = store.iter().filter(|&(key, _)| key.metric == "requests");
+        let groups = group_by(requests, &[], Function::Count, &grid, Interpolation::Previous);
+        assert_eq!(1, groups.len());
+        assert!(groups[0].tags.is_empty());
+        assert_eq!(3.0, groups[0].points[0].value);
+    }
+}
@@ -0,0 +1,564 @@
+//! Minimal HTTP/1.1 over `std::net`.
+//!
+//! Only what the protocol modules need is supported: plain `http://` URLs,
+//! one request per connection, and bodies delimited by `Content-Length`,
+//! chunked transfer encoding or the end of the connection. A `Server`
+//! answers every request on its own thread by calling a `Handler`.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Largest header section or chunk size line accepted
+const MAX_LINE: usize = 8192;
+
+// Largest body accepted
+const MAX_BODY: usize = 64 << 20;
+
+// Time allowed for reading a request or writing a response
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parsed `http://host[:port][/path]` URL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Path including the query string, always starting with `/`
+    pub path: String,
+}
+
+impl Url {
+    /// Parses `url`, returning `None` if it isn't a valid `http://` URL
+    pub fn parse(url: &str) -> Option<Url> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Returns the `host:port` part of the URL
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Response to a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Creates a response without body
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Creates a response holding `body` of type `content_type`
+    pub fn with_body<B: Into<Vec<u8>>>(status: u16, content_type: &str, body: B) -> Response {
+        Response::new(status).header_value("Content-Type", content_type).body(body)
+    }
+
+    /// Creates a plain text response, e.g. to describe an error
+    pub fn text<S: Into<String>>(status: u16, text: S) -> Response {
+        let mut text = text.into();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        Response::with_body(status, "text/plain; charset=utf-8", text)
+    }
+
+    /// Adds a header to the response
+    pub fn header_value(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Replaces the body of the response
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Returns the value of the first header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+/// Sends a GET request for `url` with extra `headers`. `timeout` applies
+/// to connecting and to every read and write
+pub fn get(url: &Url, headers: &[(&str, &str)], timeout: Duration) -> io::Result<Response> {
+    send("GET", url, headers, None, timeout)
+}
+
+/// Sends a POST request of `body` to `url`, like `get`
+pub fn post(url: &Url, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> io::Result<Response> {
+    send("POST", url, headers, Some(body), timeout)
+}
+
+fn send(method: &str, url: &Url, headers: &[(&str, &str)], body: Option<&[u8]>, timeout: Duration)
+        -> io::Result<Response> {
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid("host not found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, url.path, url.authority());
+    for &(name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(body) = body {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    if let Some(body) = body {
+        stream.write_all(body)?;
+    }
+
+    let mut reader = BufReader::new(stream);
+    let status_line = read_line(&mut reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            status.parse().map_err(|_| invalid("invalid status"))?
+        }
+        _ => return Err(invalid("invalid status line")),
+    };
+    let headers = read_headers(&mut reader)?;
+    let body = read_body(&mut reader, &headers)?;
+    Ok(Response { status, headers, body })
+}
+
+/// Returns the value of the first header of `headers` named `name`,
+/// ignoring case
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Request received by a `Server`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    /// Query string without the leading `?`
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Returns the value of the first header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Returns the decoded values of the query parameter `name`, in order
+    pub fn params(&self, name: &str) -> Vec<String> {
+        parse_query(&self.query)
+            .into_iter()
+            .filter(|(param, _)| param == name)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Returns the decoded value of the first query parameter `name`
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.params(name).into_iter().next()
+    }
+
+    fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+        let request_line = read_line(reader)?;
+        let mut parts = request_line.split(' ');
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method, target),
+            _ => return Err(invalid("invalid request line")),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let headers = read_headers(reader)?;
+        // requests without a length have no body
+        let delimited = ["Content-Length", "Transfer-Encoding"].iter().any(|name| find_header(&headers, name).is_some());
+        let body = if delimited { read_body(reader, &headers)? } else { Vec::new() };
+        Ok(Request {
+            method: method.to_string(),
+            path: percent_decode(path, false),
+            query: query.to_string(),
+            headers,
+            body,
+        })
+    }
+}
+
+/// Answers the requests of a `Server`
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F: Fn(&Request) -> Response + Send + Sync + 'static> Handler for F {
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// HTTP server answering each connection on its own thread
+pub struct Server {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Starts listening on `addr` for requests to pass to `handler`
+    pub fn bind<A: ToSocketAddrs, H: Handler>(addr: A, handler: H) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handler = Arc::new(handler);
+        let handle = thread::spawn(move || {
+            let mut connections: Vec<JoinHandle<()>> = Vec::new();
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let handler = handler.clone();
+                connections.retain(|handle| !handle.is_finished());
+                connections.push(thread::spawn(move || {
+                    let _ = serve(stream, &*handler);
+                }));
+            }
+            for handle in connections {
+                let _ = handle.join();
+            }
+        });
+        Ok(Server {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Returns the address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve<H: Handler + ?Sized>(stream: TcpStream, handler: &H) -> io::Result<()> {
+    stream.set_read_timeout(Some(SERVER_TIMEOUT))?;
+    stream.set_write_timeout(Some(SERVER_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let response = match Request::read_from(&mut reader) {
+        Ok(request) => handler.handle(&request),
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidData => Response::text(400, err.to_string()),
+        Err(err) => return Err(err),
+    };
+    response.write_to(reader.get_mut())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Splits a query string into its decoded name and value pairs
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// Encodes `s` for use within a query string
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Decodes `%XX` escapes, and `+` as a space within query strings
+fn percent_decode(s: &str, query: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() => {
+                let hex = |b: u8| (b as char).to_digit(16).unwrap_or(0) as u8;
+                decoded.push(hex(bytes[i + 1]) << 4 | hex(bytes[i + 2]));
+                i += 3;
+                continue;
+            }
+            b'+' if query => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Reads a CRLF or LF terminated line, without its terminator
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(invalid("unterminated line"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| invalid("line isn't valid UTF-8"))
+}
+
+// Reads header lines up to the empty line ending them
+fn read_headers<R: BufRead>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    let mut size = 0;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        size += line.len();
+        if size > MAX_LINE {
+            return Err(invalid("headers too large"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// Reads a body delimited according to `headers`. Without a length, the
+// body extends to the end of the connection
+fn read_body<R: BufRead>(reader: &mut R, headers: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    if find_header(headers, "Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let line = read_line(reader)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+            if size == 0 {
+                // trailers
+                read_headers(reader)?;
+                return Ok(body);
+            }
+            let start = body.len();
+            if start + size > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            if !read_line(reader)?.is_empty() {
+                return Err(invalid("invalid chunk"));
+            }
+        }
+    }
+    match find_header(headers, "Content-Length") {
+        Some(len) => {
+            let len: usize = len.parse().map_err(|_| invalid("invalid content length"))?;
+            if len > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+            body.resize(len, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None => {
+            reader.take(MAX_BODY as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > MAX_BODY {
+                return Err(invalid("body too large"));
+            }
+        }
+    }
+    Ok(body)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{get, parse_query, percent_encode, post, Request, Response, Server, Url};
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(Some(Url { host: "localhost".to_string(), port: 9100, path: "/metrics?x=1".to_string() }),
+                   Url::parse("http://localhost:9100/metrics?x=1"));
+        assert_eq!(Some(Url { host: "example.com".to_string(), port: 80, path: "/".to_string() }),
+                   Url::parse("http://example.com"));
+        assert_eq!(None, Url::parse("https://example.com"));
+        assert_eq!(None, Url::parse("http://:80/"));
+        assert_eq!(None, Url::parse("http://host:port/"));
+    }
+
+    // Serves a single connection with `response`, returning the request line
+    fn serve_once(response: &'static [u8]) -> (Url, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/metrics", listener.local_addr().unwrap())).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            reader.get_mut().write_all(response).unwrap();
+            request_line
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_get() {
+        let timeout = Duration::from_secs(5);
+        let (url, handle) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-A: b\r\n\r\nhello");
+        let response = get(&url, &[("Accept", "text/plain")], timeout).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("b"), response.header("x-a"));
+        assert_eq!(b"hello".to_vec(), response.body);
+        assert_eq!("GET /metrics HTTP/1.1\r\n", handle.join().unwrap());
+
+        let (url, _) = serve_once(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                    3\r\nhel\r\n2;x=y\r\nlo\r\n0\r\n\r\n");
+        assert_eq!(b"hello".to_vec(), get(&url, &[], timeout).unwrap().body);
+
+        let (url, _) = serve_once(b"HTTP/1.0 404 Not Found\r\n\r\nmissing");
+        let response = get(&url, &[], timeout).unwrap();
+        assert_eq!(404, response.status);
+        assert_eq!(b"missing".to_vec(), response.body);
+
+        let (url, _) = serve_once(b"SSH-2.0\r\n\r\n");
+        assert!(get(&url, &[], timeout).is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        assert_eq!(vec![("q".to_string(), "a b+c/é".to_string()), ("x".to_string(), "".to_string())],
+                   parse_query("q=a+b%2Bc%2f%C3%A9&x&"));
+        assert_eq!(vec![("a".to_string(), "%zz%".to_string())], parse_query("a=%zz%"));
+        assert_eq!("a%20b%2Bc%7B%7D~", percent_encode("a b+c{}~"));
+    }
+
+    #[test]
+    fn test_server() {
+        let server = Server::bind("127.0.0.1:0", |request: &Request| {
+            if request.path != "/echo" {
+                return Response::text(404, "not found");
+            }
+            let body = format!("{} {:?} {}", request.method, request.param("q"), String::from_utf8_lossy(&request.body));
+            Response::with_body(200, "text/plain", body).header_value("X-A", "b")
+        }).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let url = Url::parse(&format!("http://{}/echo?q=a%20b", server.local_addr())).unwrap();
+        let response = get(&url, &[], timeout).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("b"), response.header("X-A"));
+        assert_eq!(b"GET Some(\"a b\") ".to_vec(), response.body);
+
+        let response = post(&url, &[("Content-Type", "text/plain")], b"body", timeout).unwrap();
+        assert_eq!(b"POST Some(\"a b\") body".to_vec(), response.body);
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nPOST None body"));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"garbage\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let url = Url::parse(&format!("http://{}/other", server.local_addr())).unwrap();
+        assert_eq!(404, get(&url, &[], timeout).unwrap().status);
+    }
+}
@@ -0,0 +1,569 @@
+//! InfluxDB line protocol.
+//!
+//! Each line holds a measurement, optional tags, one or more fields and an
+//! optional timestamp:
+//!
+//! ```text
+//! cpu,host=a,region=us usage=0.5,procs=12i 1690000000000000000
+//! ```
+//!
+//! Every field becomes its own series, named `<measurement>_<field>` and
+//! carrying the tags of the line, so the line above publishes to
+//! `cpu_usage{host="a",region="us"}` and `cpu_procs{host="a",region="us"}`.
+//! Integer (`i`), unsigned (`u`) and boolean fields are published as
+//! integers. String fields can't be stored and are skipped. Timestamps are
+//! truncated to seconds, and lines without one are stamped with the time
+//! they were received.
+//!
+//! A `Listener` accepts batches of lines over HTTP as the InfluxDB 1.x
+//! API does:
+//!
+//! ```text
+//! POST /write?db=...&precision=s    publishes a batch of lines
+//! GET  /ping                        answers 204 while the server is up
+//! ```
+//!
+//! Timestamps are in nanoseconds unless `precision` says otherwise, and
+//! `db` is ignored. A batch holding a malformed line is rejected as a
+//! whole, and so is a batch holding a point the validator of the store
+//! rejects, see `validate`.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use time;
+
+use http::{Request, Response, Server};
+use ingest::{Closed, Handle, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use json::Json;
+use store::{SeriesKey, Store};
+use validate::Verdict;
+
+/// Path of the write endpoint
+pub const WRITE_PATH: &str = "/write";
+
+/// Path of the endpoint clients check the server with
+pub const PING_PATH: &str = "/ping";
+
+/// Unit of the timestamps of a batch of lines
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    /// Returns the precision named `name` as in the `precision` parameter
+    /// of the InfluxDB write API, e.g. "ns" or "s"
+    pub fn from_name(name: &str) -> Option<Precision> {
+        match name {
+            "ns" | "n" => Some(Precision::Nanoseconds),
+            "us" | "u" => Some(Precision::Microseconds),
+            "ms" => Some(Precision::Milliseconds),
+            "s" => Some(Precision::Seconds),
+            _ => None,
+        }
+    }
+
+    /// Converts a timestamp of this precision to seconds from the epoch
+    pub fn to_seconds(self, ts: i64) -> i64 {
+        let per_second = match self {
+            Precision::Nanoseconds => 1_000_000_000,
+            Precision::Microseconds => 1_000_000,
+            Precision::Milliseconds => 1_000,
+            Precision::Seconds => 1,
+        };
+        ts.div_euclid(per_second)
+    }
+}
+
+/// Reasons a line can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line doesn't have 2 or 3 space separated sections
+    SectionCount(usize),
+    EmptyMeasurement,
+    InvalidTag(String),
+    InvalidField(String),
+    InvalidValue(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::SectionCount(n) => write!(f, "expected 2 or 3 sections, found {}", n),
+            ParseError::EmptyMeasurement => write!(f, "empty measurement"),
+            ParseError::InvalidTag(ref tag) => write!(f, "invalid tag {:?}", tag),
+            ParseError::InvalidField(ref field) => write!(f, "invalid field {:?}", field),
+            ParseError::InvalidValue(ref value) => write!(f, "invalid field value {:?}", value),
+            ParseError::InvalidTimestamp(ref ts) => write!(f, "invalid timestamp {:?}", ts),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Error of a line within a batch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineError {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub error: ParseError,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl error::Error for LineError {}
+
+/// Parses a batch of lines received at `now` seconds from the epoch.
+/// Blank lines and comments starting with `#` are skipped. Fails on the
+/// first malformed line
+pub fn parse(body: &str, precision: Precision, now: i64) -> Result<Vec<Sample>, LineError> {
+    let mut samples = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = parse_line(line, precision, now).map_err(|error| LineError { line: i + 1, error })?;
+        samples.extend(parsed);
+    }
+    Ok(samples)
+}
+
+/// Parses a line received at `now` seconds from the epoch into one sample
+/// per numeric field
+pub fn parse_line(line: &str, precision: Precision, now: i64) -> Result<Vec<Sample>, ParseError> {
+    let sections = split(line, ' ', true);
+    if sections.len() != 2 && sections.len() != 3 {
+        return Err(ParseError::SectionCount(sections.len()));
+    }
+
+    let mut series = split(sections[0], ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or(""));
+    if measurement.is_empty() {
+        return Err(ParseError::EmptyMeasurement);
+    }
+    let mut tags = Vec::new();
+    for tag in series {
+        match split_pair(tag) {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => {
+                tags.push((unescape(name), unescape(value)));
+            }
+            _ => return Err(ParseError::InvalidTag(tag.to_string())),
+        }
+    }
+
+    let ts = match sections.get(2) {
+        Some(ts) => {
+            let ts = ts.parse().map_err(|_| ParseError::InvalidTimestamp(ts.to_string()))?;
+            precision.to_seconds(ts)
+        }
+        None => now,
+    };
+
+    let mut samples = Vec::new();
+    for field in split(sections[1], ',', true) {
+        let (name, value) = match split_pair(field) {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => (name, value),
+            _ => return Err(ParseError::InvalidField(field.to_string())),
+        };
+        let value = match parse_value(value)? {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut key = SeriesKey::new(format!("{}_{}", measurement, unescape(name)));
+        for (tag, tag_value) in &tags {
+            key = key.tag(tag.as_str(), tag_value.as_str());
+        }
+        samples.push(Sample { key, value, ts });
+    }
+    Ok(samples)
+}
+
+// Parses a field value, returning `None` for strings
+fn parse_value(value: &str) -> Result<Option<Value>, ParseError> {
+    let invalid = || ParseError::InvalidValue(value.to_string());
+    if value.starts_with('"') {
+        return if value.len() >= 2 && value.ends_with('"') && !value.ends_with("\\\"") {
+            Ok(None)
+        } else {
+            Err(invalid())
+        };
+    }
+    let parsed = match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Value::Int(1),
+        "f" | "F" | "false" | "False" | "FALSE" => Value::Int(0),
+        _ if value.ends_with('i') => Value::Int(value[..value.len() - 1].parse().map_err(|_| invalid())?),
+        _ if value.ends_with('u') => {
+            // unsigned values past the range of i64 can't be stored
+            let unsigned: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+            Value::Int(i64::try_from(unsigned).map_err(|_| invalid())?)
+        }
+        _ => Value::Float(value.parse().map_err(|_| invalid())?),
+    };
+    Ok(Some(parsed))
+}
+
+// Splits `s` on every `sep` that isn't escaped by a backslash, nor within
+// a quoted string if `quotes` is set
+fn split(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && quotes {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Splits `s` on its first unescaped `=`
+fn split_pair(s: &str) -> Option<(&str, &str)> {
+    let mut parts = split(s, '=', true).into_iter();
+    let name = parts.next()?;
+    parts.next()?;
+    Some((name, &s[name.len() + 1..]))
+}
+
+// Removes the backslashes escaping commas, equal signs and spaces
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if next == ',' || next == '=' || next == ' ' || next == '\\' {
+                    result.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Settings of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Samples per batch handed to the writer
+    pub batch_size: usize,
+    /// Batches queued up for the writer before writes wait
+    pub queue: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            batch_size: DEFAULT_BATCH_SIZE,
+            queue: DEFAULT_QUEUE,
+        }
+    }
+}
+
+/// Counters of a listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    writes: AtomicU64,
+    points: AtomicU64,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of write requests received
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of samples handed to the writer
+    pub fn points(&self) -> u64 {
+        self.points.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of write requests rejected
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last error
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn fail(&self, status: u16, message: String) -> Response {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(message.clone());
+        error_response(status, &message)
+    }
+}
+
+// Formats an error as InfluxDB does
+fn error_response(status: u16, message: &str) -> Response {
+    let body = Json::Object(vec![("error".to_string(), Json::from(message))]);
+    Response::with_body(status, "application/json", body.to_string())
+}
+
+/// HTTP server publishing the lines it receives into a store
+pub struct Listener {
+    server: Option<Server>,
+    stats: Arc<Stats>,
+    writer: Option<Writer>,
+}
+
+impl Listener {
+    /// Starts listening on `addr` for lines to publish into `store`
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Listener> {
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store.clone(), config.queue);
+        let endpoint = Endpoint {
+            store,
+            handle: writer.handle(),
+            batch_size: config.batch_size,
+            stats: stats.clone(),
+        };
+        let server = Server::bind(addr, move |request: &Request| endpoint.handle(request))?;
+        Ok(Listener {
+            server: Some(server),
+            stats,
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.as_ref().expect("server running").local_addr()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // requests in flight still hold batchers of the writer
+        self.server.take();
+        self.writer.take();
+    }
+}
+
+struct Endpoint {
+    store: Arc<RwLock<Store>>,
+    handle: Handle,
+    batch_size: usize,
+    stats: Arc<Stats>,
+}
+
+impl Endpoint {
+    fn handle(&self, request: &Request) -> Response {
+        match (request.path.as_str(), request.method.as_str()) {
+            (WRITE_PATH, "POST") => self.write(request),
+            (PING_PATH, "GET") | (PING_PATH, "HEAD") => Response::new(204),
+            (WRITE_PATH, _) | (PING_PATH, _) => error_response(405, "method not allowed"),
+            _ => error_response(404, "not found"),
+        }
+    }
+
+    fn write(&self, request: &Request) -> Response {
+        self.stats.writes.fetch_add(1, Ordering::SeqCst);
+        let precision = match request.param("precision") {
+            Some(name) => match Precision::from_name(&name) {
+                Some(precision) => precision,
+                None => return self.stats.fail(400, format!("invalid precision {:?}", name)),
+            },
+            None => Precision::default(),
+        };
+        let body = match str::from_utf8(&request.body) {
+            Ok(body) => body,
+            Err(_) => return self.stats.fail(400, "body isn't UTF-8".to_string()),
+        };
+        let samples = match parse(body, precision, time::get_time().sec) {
+            Ok(samples) => samples,
+            Err(err) => return self.stats.fail(400, format!("unable to parse points: {}", err)),
+        };
+        if let Some(message) = self.check(&samples) {
+            return self.stats.fail(400, message);
+        }
+        let count = samples.len();
+        let mut batcher = self.handle.batcher(self.batch_size);
+        let sent = samples.into_iter().try_for_each(|sample| batcher.push(sample)).and_then(|_| batcher.flush());
+        if let Err(Closed) = sent {
+            return error_response(503, "shutting down");
+        }
+        self.stats.points.fetch_add(count as u64, Ordering::SeqCst);
+        Response::new(204)
+    }
+
+    // Describes the first point the validator rejects, counting every
+    // rejected point. The writer counts the verdicts of the others
+    fn check(&self, samples: &[Sample]) -> Option<String> {
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut rejected = None;
+        for sample in samples {
+            if let verdict @ Verdict::Reject(reason) = store.validator().check(sample, now) {
+                store.validator().record(verdict);
+                rejected = rejected.or_else(|| {
+                    Some(format!("point of {} at {} rejected: {}", sample.key, sample.ts, reason.name()))
+                });
+            }
+        }
+        rejected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{parse, parse_line, Config, LineError, Listener, ParseError, Precision, PING_PATH, WRITE_PATH};
+    use http::{self, Url};
+    use ingest::Value;
+    use store::{SeriesKey, Store};
+    use time;
+    use validate::{Reason, Validation};
+
+    #[test]
+    fn test_parse_line() {
+        let line = "cpu,host=a,region=us usage=1.0,other=2i 1690000000000000000";
+        let samples = parse_line(line, Precision::Nanoseconds, 0).unwrap();
+        assert_eq!(2, samples.len());
+        assert_eq!(SeriesKey::new("cpu_usage").tag("host", "a").tag("region", "us"), samples[0].key);
+        assert_eq!(Value::Float(1.0), samples[0].value);
+        assert_eq!(1690000000, samples[0].ts);
+        assert_eq!(SeriesKey::new("cpu_other").tag("host", "a").tag("region", "us"), samples[1].key);
+        assert_eq!(Value::Int(2), samples[1].value);
+
+        let samples = parse_line(r#"disk\ io,path=C:\\,dev=a\,b free=5u,up=t,label="a, b=c" 1500"#,
+                                 Precision::Milliseconds, 0).unwrap();
+        assert_eq!(SeriesKey::new("disk io_free").tag("path", "C:\\").tag("dev", "a,b"), samples[0].key);
+        assert_eq!(Value::Int(5), samples[0].value);
+        assert_eq!(Value::Int(1), samples[1].value);
+        assert_eq!(1, samples[0].ts);
+        // string fields are skipped
+        assert_eq!(2, samples.len());
+
+        assert_eq!(42, parse_line("cpu value=-1e3", Precision::Nanoseconds, 42).unwrap()[0].ts);
+        assert_eq!(-1, parse_line("cpu value=1 -1", Precision::Seconds, 0).unwrap()[0].ts);
+        assert_eq!(-1, parse_line("cpu value=1 -1", Precision::Nanoseconds, 0).unwrap()[0].ts);
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        let parse = |line| parse_line(line, Precision::Nanoseconds, 0);
+        assert_eq!(Err(ParseError::SectionCount(1)), parse("cpu"));
+        assert_eq!(Err(ParseError::SectionCount(4)), parse("cpu value=1 1 1"));
+        assert_eq!(Err(ParseError::EmptyMeasurement), parse(",host=a value=1"));
+        assert_eq!(Err(ParseError::InvalidTag("host".to_string())), parse("cpu,host value=1"));
+        assert_eq!(Err(ParseError::InvalidField("value".to_string())), parse("cpu value"));
+        assert_eq!(Err(ParseError::InvalidValue("1.5i".to_string())), parse("cpu value=1.5i"));
+        assert_eq!(Err(ParseError::InvalidValue("18446744073709551615u".to_string())),
+                   parse("cpu value=18446744073709551615u"));
+        assert_eq!(Err(ParseError::InvalidValue("\"open".to_string())), parse("cpu value=\"open"));
+        assert_eq!(Err(ParseError::InvalidTimestamp("x".to_string())), parse("cpu value=1 x"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let body = "# comment\ncpu value=1 1000000000\n\n  mem value=2i 2000000000\n";
+        let samples = parse(body, Precision::Nanoseconds, 0).unwrap();
+        assert_eq!(vec![1, 2], samples.iter().map(|s| s.ts).collect::<Vec<i64>>());
+        let err = parse("cpu value=1\ncpu value=x", Precision::Seconds, 0).unwrap_err();
+        assert_eq!(LineError { line: 2, error: ParseError::InvalidValue("x".to_string()) }, err);
+    }
+
+    #[test]
+    fn test_precision() {
+        assert_eq!(Some(Precision::Microseconds), Precision::from_name("us"));
+        assert_eq!(None, Precision::from_name("h"));
+        assert_eq!(1, Precision::Microseconds.to_seconds(1_999_999));
+        assert_eq!(-2, Precision::Milliseconds.to_seconds(-1001));
+    }
+
+    #[test]
+    fn test_listener() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = |path: &str| Url::parse(&format!("http://{}{}", listener.local_addr(), path)).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(204, http::get(&url(PING_PATH), &[], timeout).unwrap().status);
+        let body = b"cpu,host=a usage=0.5,procs=12i 1690000000\ncpu,host=a usage=0.7 1690000010\n";
+        let write = format!("{}?db=metrics&precision=s", WRITE_PATH);
+        assert_eq!(204, http::post(&url(&write), &[], body, timeout).unwrap().status);
+
+        let response = http::post(&url(WRITE_PATH), &[], b"cpu usage=1\ncpu usage=x", timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(r#"{"error":"unable to parse points: line 2: invalid field value \"x\""}"#,
+                   String::from_utf8_lossy(&response.body));
+        let response = http::post(&url(&format!("{}?precision=h", WRITE_PATH)), &[], body, timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(405, http::get(&url(WRITE_PATH), &[], timeout).unwrap().status);
+
+        let deadline = Instant::now() + timeout;
+        while listener.written() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(3, listener.written());
+        let store = store.read().unwrap();
+        let usage = store.get(&SeriesKey::new("cpu_usage").tag("host", "a")).unwrap();
+        assert_eq!(vec![(1690000000, 0.5), (1690000010, 0.7)],
+                   usage.points(0, i64::MAX).iter().map(|p| (p.ts, p.value)).collect::<Vec<_>>());
+        let procs = store.get_int(&SeriesKey::new("cpu_procs").tag("host", "a")).unwrap();
+        assert_eq!(12, procs.points(0, i64::MAX)[0].value);
+        let stats = listener.stats();
+        assert_eq!((3, 3, 2), (stats.writes(), stats.points(), stats.failures()));
+    }
+
+    #[test]
+    fn test_validation() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().set_validation(Validation { max_past_age: Some(3600), ..Validation::default() });
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = Url::parse(&format!("http://{}{}?precision=s", listener.local_addr(), WRITE_PATH)).unwrap();
+        let now = time::get_time().sec;
+        let body = format!("cpu usage=1 {}\ncpu usage=2 {}\n", now, now - 7200);
+        let response = http::post(&url, &[], body.as_bytes(), Duration::from_secs(5)).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(format!(r#"{{"error":"point of cpu_usage at {} rejected: too_old"}}"#, now - 7200),
+                   String::from_utf8_lossy(&response.body));
+        assert_eq!(1, store.read().unwrap().validator().rejected(Reason::TooOld));
+        assert_eq!((0, 1), (listener.stats().points(), listener.stats().failures()));
+        drop(listener);
+        assert!(store.read().unwrap().is_empty());
+    }
+}
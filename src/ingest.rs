@@ -0,0 +1,240 @@
+//! Batched ingestion of samples into a shared `Store`.
+//!
+//! Protocol listeners parse samples on their own threads and hand them to a
+//! single `Writer` thread in batches, so the write lock of the store is
+//! taken once per batch rather than once per sample. Batches travel over a
+//! bounded queue: when the writer falls behind, `Batcher::push` blocks,
+//! listeners stop reading from their sockets and the back-pressure reaches
+//! the clients through the transport.
+//!
+//! Samples are checked by the validator of the store before they're
+//! published, see `validate`.
+
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+
+use store::{SeriesKey, Store};
+use time;
+
+/// Default number of samples per batch
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Default number of batches queued up for the writer
+pub const DEFAULT_QUEUE: usize = 16;
+
+/// A value of a series at a point in time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub key: SeriesKey,
+    pub value: Value,
+    pub ts: i64,
+}
+
+/// Value of a sample. Integers are published to integer series so that
+/// they keep their precision
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Float(f64),
+    Int(i64),
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Int(value)
+    }
+}
+
+/// Error raised when the writer has stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Closed;
+
+/// Thread applying batches of samples to a store
+pub struct Writer {
+    sender: Option<SyncSender<Vec<Sample>>>,
+    handle: Option<JoinHandle<()>>,
+    written: Arc<AtomicU64>,
+}
+
+impl Writer {
+    /// Spawns a writer into `store` accepting up to `queue` pending batches
+    pub fn spawn(store: Arc<RwLock<Store>>, queue: usize) -> Writer {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let handle = thread::spawn(move || write(&store, &receiver, &counter));
+        Writer {
+            sender: Some(sender),
+            handle: Some(handle),
+            written,
+        }
+    }
+
+    /// Returns a handle creating batchers for the writer from any thread
+    pub fn handle(&self) -> Handle {
+        Handle { sender: self.sender.clone().expect("the writer is running") }
+    }
+
+    /// Creates a batcher sending batches of `batch_size` samples
+    pub fn batcher(&self, batch_size: usize) -> Batcher {
+        self.handle().batcher(batch_size)
+    }
+
+    /// Returns the number of samples written to the store so far, leaving
+    /// out those discarded by its validator
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // the thread stops once every batcher is gone too
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn write(store: &RwLock<Store>, receiver: &Receiver<Vec<Sample>>, written: &AtomicU64) {
+    for batch in receiver {
+        let published = apply(&mut store.write().unwrap_or_else(|err| err.into_inner()), &batch);
+        written.fetch_add(published as u64, Ordering::SeqCst);
+    }
+}
+
+/// Publishes the `samples` admitted by the validator of `store` in order,
+/// returning how many were published
+pub fn apply(store: &mut Store, samples: &[Sample]) -> usize {
+    let now = time::get_time().sec;
+    let mut published = 0;
+    for sample in samples {
+        let ts = match store.validator().admit(sample, now) {
+            Some(ts) => ts,
+            None => continue,
+        };
+        match sample.value {
+            Value::Float(value) => store.publish_at(&sample.key, value, ts),
+            Value::Int(value) => store.publish_int_at(&sample.key, value, ts),
+        }
+        published += 1;
+    }
+    published
+}
+
+/// Handle to a `Writer` that can be sent to other threads
+#[derive(Clone)]
+pub struct Handle {
+    sender: SyncSender<Vec<Sample>>,
+}
+
+impl Handle {
+    /// Creates a batcher sending batches of `batch_size` samples
+    pub fn batcher(&self, batch_size: usize) -> Batcher {
+        Batcher {
+            sender: self.sender.clone(),
+            batch: Vec::with_capacity(batch_size),
+            batch_size,
+        }
+    }
+}
+
+/// Collects samples into batches for a `Writer`. Pending samples are sent
+/// when the batcher is dropped
+pub struct Batcher {
+    sender: SyncSender<Vec<Sample>>,
+    batch: Vec<Sample>,
+    batch_size: usize,
+}
+
+impl Batcher {
+    /// Adds a sample to the current batch, sending the batch once full.
+    /// Blocks while the queue of the writer is full
+    pub fn push(&mut self, sample: Sample) -> Result<(), Closed> {
+        self.batch.push(sample);
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the current batch if it holds any sample
+    pub fn flush(&mut self) -> Result<(), Closed> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        self.sender.send(batch).map_err(|_| Closed)
+    }
+
+    /// Returns the number of samples waiting to be sent
+    pub fn pending(&self) -> usize {
+        self.batch.len()
+    }
+}
+
+impl Drop for Batcher {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+
+    use super::{Sample, Value, Writer};
+    use store::{SeriesKey, Store};
+    use validate::{NonFinite, Reason, Validation};
+
+    #[test]
+    fn test_writer() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let key = SeriesKey::new("cpu");
+        let writer = Writer::spawn(store.clone(), 1);
+        {
+            let mut batcher = writer.batcher(10);
+            for ts in 0..25 {
+                batcher.push(Sample { key: key.clone(), value: Value::Float(1.0), ts }).unwrap();
+            }
+            assert_eq!(5, batcher.pending());
+            batcher.push(Sample { key: key.clone(), value: Value::Int(7), ts: 0 }).unwrap();
+        }
+        drop(writer);
+        let store = store.read().unwrap();
+        // the integer is published to the float series of the same key
+        let points = store.get(&key).unwrap().points(0, 100);
+        assert_eq!(26, points.len());
+        assert!(points.iter().any(|p| p.ts == 0 && p.value == 7.0));
+        assert!(store.get_int(&key).is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut store = Store::new();
+        store.set_validation(Validation { non_finite: NonFinite::Drop, ..Validation::default() });
+        let store = Arc::new(RwLock::new(store));
+        let key = SeriesKey::new("cpu");
+        let writer = Writer::spawn(store.clone(), 1);
+        {
+            let mut batcher = writer.batcher(10);
+            for (ts, &value) in [1.0, f64::NAN, 2.0, f64::INFINITY].iter().enumerate() {
+                batcher.push(Sample { key: key.clone(), value: Value::Float(value), ts: ts as i64 }).unwrap();
+            }
+        }
+        drop(writer);
+        let store = store.read().unwrap();
+        assert_eq!(2, store.validator().dropped(Reason::NonFinite));
+        let points = store.get(&key).unwrap().points(0, 100);
+        assert_eq!(vec![(0, 1.0), (2, 2.0)], points.iter().map(|p| (p.ts, p.value)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,272 @@
+//! Time series block of integer values.
+//!
+//! `TSBlock` stores values as XORed floats, which suits gauges but turns
+//! integer counters into mostly meaningful bits and loses precision past
+//! 2^53. An `IntBlock` shares the timestamp encoding of `TSBlock` and
+//! encodes values as the delta of their delta, so a counter increasing at
+//! a steady rate takes a single bit per value.
+
+use bit_vec::{AppendOnlyBitVec, BitReader, BitSlice};
+use {read_delta_delta, CompressedBlock, Point, TSBlock};
+
+/// A single decompressed integer data point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntPoint {
+    /// Seconds since the epoch
+    pub ts: i64,
+    pub value: i64,
+}
+
+impl IntPoint {
+    /// Converts the point to a float point, rounding values past 2^53
+    pub fn to_point(self) -> Point {
+        Point {
+            ts: self.ts,
+            value: self.value as f64,
+        }
+    }
+}
+
+/// Time series data block of integer values
+#[derive(Clone)]
+pub struct IntBlock {
+    last: Option<Last>,
+    data: AppendOnlyBitVec,
+}
+
+impl IntBlock {
+    /// Creates a new IntBlock starting at `ts` seconds from the epoch
+    pub fn at(ts: i64) -> IntBlock {
+        let mut data = AppendOnlyBitVec::with_capacity(1024);
+        data.append(64, ts as u64);
+        IntBlock {
+            last: None,
+            data,
+        }
+    }
+
+    /// Recreates a block from its compressed contents, restoring the
+    /// encoder state like `TSBlock::from_slice`. Returns `None` if the
+    /// slice does not hold a valid block
+    pub fn from_slice(slice: BitSlice) -> Option<IntBlock> {
+        let mut iter = IntBlockIter::new(slice);
+        iter.header?;
+        while iter.next().is_some() {}
+        iter.header?;
+        Some(IntBlock {
+            last: iter.last,
+            data: AppendOnlyBitVec::from_slice(slice),
+        })
+    }
+
+    /// Retrieves the timestamp header for the block
+    pub fn header(&self) -> u64 {
+        self.data.get_block(0)
+    }
+
+    /// Returns the timestamp of the last published point, if any
+    pub fn last_ts(&self) -> Option<i64> {
+        self.last.as_ref().map(|last| last.ts)
+    }
+
+    /// Returns true if no points have been published to the block
+    pub fn is_empty(&self) -> bool {
+        self.last.is_none()
+    }
+
+    /// Returns a borrowed view over the compressed contents of the block
+    pub fn as_slice(&self) -> BitSlice<'_> {
+        self.data.as_slice()
+    }
+
+    /// Returns an iterator that decompresses the points in the block
+    pub fn iter(&self) -> IntBlockIter<'_> {
+        IntBlockIter::new(self.as_slice())
+    }
+
+    /// Publish a value to the block at the given `ts` seconds from the epoch
+    pub fn publish_at(&mut self, value: i64, ts: i64) {
+        if let Some(ref mut last) = self.last {
+            let delta = ts - last.ts;
+            let CompressedBlock { bits, block } = TSBlock::compressed_time_block(delta - last.delta);
+            self.data.append(bits, block);
+            last.ts = ts;
+            last.delta = delta;
+
+            // deltas wrap around so that any pair of values can be encoded
+            let value_delta = value.wrapping_sub(last.value);
+            append_value(&mut self.data, value_delta.wrapping_sub(last.value_delta));
+            last.value = value;
+            last.value_delta = value_delta;
+        } else {
+            // first value is uncompressed
+            let last = Last {
+                ts,
+                delta: ts - self.header() as i64,
+                value,
+                value_delta: 0,
+            };
+            self.data.append(14, last.delta as u64);
+            self.data.append(64, value as u64);
+            self.last = Some(last);
+        }
+    }
+}
+
+// Appends the delta of delta of a value, using the same prefix scheme as
+// timestamps with wider buckets
+fn append_value(data: &mut AppendOnlyBitVec, dd: i64) {
+    let (prefix, prefix_bits, bits) = match dd {
+        0 => return data.append(1, 0),
+        -63..=64 => (0b10, 2, 7),
+        -32767..=32768 => (0b110, 3, 16),
+        -2147483647..=2147483648 => (0b1110, 4, 32),
+        _ => {
+            // the prefix and the value don't fit in a single block
+            data.append(4, 0b1111);
+            return data.append(64, dd as u64);
+        }
+    };
+    let masked_value = dd as u64 & (!0 >> (64 - bits));
+    data.append(prefix_bits + bits, (prefix << bits) | masked_value);
+}
+
+// Mirrors append_value
+fn read_value_delta_delta(reader: &mut BitReader) -> Option<i64> {
+    if !reader.read_bit()? {
+        return Some(0);
+    }
+    // (value bits, largest positive value)
+    let encodings = [(7, 64), (16, 32768), (32, 2147483648)];
+    for &(bits, max) in &encodings {
+        if !reader.read_bit()? {
+            let raw = reader.read(bits)? as i64;
+            return Some(if raw > max { raw - (1 << bits) } else { raw });
+        }
+    }
+    reader.read(64).map(|raw| raw as i64)
+}
+
+/// Decompresses the points of an `IntBlock` from a borrowed `BitSlice`
+pub struct IntBlockIter<'a> {
+    reader: BitReader<'a>,
+    header: Option<i64>,
+    last: Option<Last>,
+}
+
+impl<'a> IntBlockIter<'a> {
+    /// Creates an iterator over the compressed block in `slice`.
+    /// The slice must start with the 64-bit block header
+    pub fn new(slice: BitSlice<'a>) -> IntBlockIter<'a> {
+        let mut reader = slice.reader();
+        let header = reader.read(64).map(|h| h as i64);
+        IntBlockIter {
+            reader,
+            header,
+            last: None,
+        }
+    }
+
+    fn read_first(&mut self, header: i64) -> Option<Last> {
+        let delta = self.reader.read(14)? as i64;
+        let value = self.reader.read(64)? as i64;
+        Some(Last {
+            ts: header + delta,
+            delta,
+            value,
+            value_delta: 0,
+        })
+    }
+
+    fn read_next(&mut self, last: &Last) -> Option<Last> {
+        let delta = last.delta + read_delta_delta(&mut self.reader)?;
+        let value_delta = last.value_delta.wrapping_add(read_value_delta_delta(&mut self.reader)?);
+        Some(Last {
+            ts: last.ts + delta,
+            delta,
+            value: last.value.wrapping_add(value_delta),
+            value_delta,
+        })
+    }
+}
+
+impl<'a> Iterator for IntBlockIter<'a> {
+    type Item = IntPoint;
+
+    fn next(&mut self) -> Option<IntPoint> {
+        let header = self.header?;
+        if self.reader.remaining() == 0 {
+            return None;
+        }
+        let next = match self.last.take() {
+            Some(last) => self.read_next(&last),
+            None => self.read_first(header),
+        };
+        match next {
+            Some(next) => {
+                let point = IntPoint {
+                    ts: next.ts,
+                    value: next.value,
+                };
+                self.last = Some(next);
+                Some(point)
+            }
+            None => {
+                // truncated point, stop decoding
+                self.header = None;
+                None
+            }
+        }
+    }
+}
+
+// Encoder state after the last published point
+#[derive(Clone)]
+struct Last {
+    ts: i64,
+    delta: i64,
+    value: i64,
+    value_delta: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IntBlock, IntPoint};
+
+    #[test]
+    fn test_round_trip() {
+        let mut block = IntBlock::at(1000);
+        let mut expected = Vec::new();
+        let mut ts = 1000;
+        let values = [0, 10, 20, 30, 31, -5000, i64::MAX, i64::MIN, 1 << 40, 7, 7, 7];
+        for (i, &value) in values.iter().enumerate() {
+            ts += 1 + (i as i64 % 3) * 100;
+            block.publish_at(value, ts);
+            expected.push(IntPoint { ts, value });
+        }
+        assert_eq!(expected, block.iter().collect::<Vec<IntPoint>>());
+        assert_eq!(Some(ts), block.last_ts());
+    }
+
+    #[test]
+    fn test_steady_counter_is_compact() {
+        let mut block = IntBlock::at(0);
+        for i in 0..100 {
+            block.publish_at(1_000_000 + i * 3, i * 10);
+        }
+        // header, first point, a second delta then a bit per field
+        assert_eq!(64 + 14 + 64 + 9 + 9 + 98 * 2, block.as_slice().len());
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let mut block = IntBlock::at(0);
+        block.publish_at(5, 10);
+        block.publish_at(8, 20);
+        let mut restored = IntBlock::from_slice(block.as_slice()).unwrap();
+        restored.publish_at(11, 30);
+        block.publish_at(11, 30);
+        assert_eq!(block.iter().collect::<Vec<IntPoint>>(), restored.iter().collect::<Vec<IntPoint>>());
+        assert_eq!(Some(30), restored.last_ts());
+    }
+}
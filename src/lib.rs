@@ -1,17 +1,58 @@
 extern crate time;
 extern crate fnv;
 
+pub mod aggregate;
+pub mod api;
+pub mod binary;
 pub mod bit_vec;
+pub mod block_file;
+pub mod cache;
+pub mod compact;
+pub mod eval;
+pub mod fill;
+pub mod graphite;
+pub mod group;
+pub mod http;
+pub mod influx;
+pub mod ingest;
+pub mod int_block;
+pub mod json;
+pub mod limits;
+pub mod merge;
+pub mod opentsdb;
+pub mod prometheus;
+pub mod protobuf;
+pub mod quantile;
+pub mod query;
+pub mod rate;
+pub mod regex;
+pub mod remote;
+pub mod retention;
+pub mod rollup;
+pub mod series;
+pub mod snappy;
+pub mod snapshot;
+pub mod statsd;
+pub mod store;
+pub mod validate;
+pub mod wire;
 
-use std::mem;
-use bit_vec::AppendOnlyBitVec;
+use std::cmp;
+use bit_vec::{AppendOnlyBitVec, BitReader, BitSlice};
 
 /// Time Series data block (holds 2 hours of data with second precision)
+#[derive(Clone)]
 pub struct TSBlock {
     last: Option<Last>,
     data: AppendOnlyBitVec,
 }
 
+impl Default for TSBlock {
+    fn default() -> TSBlock {
+        TSBlock::new()
+    }
+}
+
 impl TSBlock {
     /// Creates a new TSBlock starting at the current time
     pub fn new() -> TSBlock {
@@ -25,16 +66,52 @@ impl TSBlock {
         data.append(64, ts as u64);
         TSBlock {
             last: None,
-            data: data,
+            data,
         }
     }
 
+    /// Recreates a block from its compressed contents, e.g. as read back
+    /// from a block file. The slice is fully decoded to restore the
+    /// encoder state so that further values can be published to the block.
+    /// Returns `None` if the slice does not hold a valid block
+    pub fn from_slice(slice: BitSlice) -> Option<TSBlock> {
+        let mut iter = TSBlockIter::new(slice);
+        iter.header?;
+        while iter.next().is_some() {}
+        // the header is cleared if decoding stopped at a truncated point
+        iter.header?;
+        Some(TSBlock {
+            last: iter.last,
+            data: AppendOnlyBitVec::from_slice(slice),
+        })
+    }
+
     /// Retrieves the timestamp header for the block
     /// (64-bit integer representing seconds since epoch)
     pub fn header(&self) -> u64 {
         self.data.get_block(0)
     }
 
+    /// Returns the timestamp of the last published point, if any
+    pub fn last_ts(&self) -> Option<i64> {
+        self.last.as_ref().map(|last| last.ts)
+    }
+
+    /// Returns true if no points have been published to the block
+    pub fn is_empty(&self) -> bool {
+        self.last.is_none()
+    }
+
+    /// Returns a borrowed view over the compressed contents of the block
+    pub fn as_slice(&self) -> BitSlice<'_> {
+        self.data.as_slice()
+    }
+
+    /// Returns an iterator that decompresses the points in the block
+    pub fn iter(&self) -> TSBlockIter<'_> {
+        TSBlockIter::new(self.as_slice())
+    }
+
     /// Publish a value to the time block at the current time
     pub fn publish(&mut self, value: f64) {
         let ts = time::get_time().sec;
@@ -53,26 +130,29 @@ impl TSBlock {
             self.data.append(bits, block);
 
             // value compression
-            let v_u64 = unsafe { mem::transmute::<f64, u64>(value) };
+            let v_u64 = value.to_bits();
             let xor = v_u64 ^ last.val;
             let leading = xor.leading_zeros();
             let trailing = xor.trailing_zeros();
             let zinfo = ZeroInfo {
-                leading: leading,
-                trailing: trailing,
+                leading,
+                trailing,
                 last_leading: last.leading,
                 last_trailing: last.trailing,
             };
-            let CompressedBlock { bits, block } = TSBlock::compressed_value_block(xor, zinfo);
-            self.data.append(bits, block);
+            let CompressedValue { control, meaningful } = TSBlock::compressed_value_block(xor, zinfo);
+            self.data.append(control.bits, control.block);
+            if let Some(CompressedBlock { bits, block }) = meaningful {
+                self.data.append(bits, block);
+            }
             last.val = v_u64;
             last.leading = leading;
             last.trailing = trailing;
         } else {
             // first value is uncompressed
-            let v_u64 = unsafe { mem::transmute::<f64, u64>(value) };
+            let v_u64 = value.to_bits();
             let last = Last {
-                ts: ts,
+                ts,
                 delta: ts - self.header() as i64,
                 val: v_u64,
                 leading: v_u64.leading_zeros(),
@@ -87,7 +167,7 @@ impl TSBlock {
     fn calculate_deltas(last: &Last, ts: i64) -> Deltas {
         let delta = ts - last.ts;
         Deltas {
-            delta: delta,
+            delta,
             delta_delta: delta - last.delta,
         }
     }
@@ -101,7 +181,7 @@ impl TSBlock {
                     block: 0,
                 }
             }
-            -63...64 => {
+            -63..=64 => {
                 // block construction:
                 // 2 bits for header (left shift 7)
                 // 7 bits for value
@@ -111,7 +191,7 @@ impl TSBlock {
                     block: (0b10 << 7) | masked_value,
                 }
             }
-            -255...256 => {
+            -255..=256 => {
                 // block construction:
                 // 3 bits for header (left shift 9)
                 // 9 bits for value
@@ -121,7 +201,7 @@ impl TSBlock {
                     block: (0b110 << 9) | masked_value,
                 }
             }
-            -2047...2048 => {
+            -2047..=2048 => {
                 // block construction:
                 // 4 bits for header (left shift 12)
                 // 12 bits for value
@@ -144,46 +224,200 @@ impl TSBlock {
         }
     }
 
-    fn compressed_value_block(xor: u64, zinfo: ZeroInfo) -> CompressedBlock {
+    fn compressed_value_block(xor: u64, zinfo: ZeroInfo) -> CompressedValue {
         if xor == 0 {
             // 1 bit if xor is 0
-            return CompressedBlock {
-                bits: 1,
-                block: 0,
+            return CompressedValue {
+                control: CompressedBlock {
+                    bits: 1,
+                    block: 0,
+                },
+                meaningful: None,
             };
         }
         if zinfo.last_leading <= zinfo.leading && zinfo.last_trailing <= zinfo.trailing {
             // block construction:
-            // 2 controls bits (left shift n)
+            // 2 controls bits
             // n bits of meaningful section
             let len = 64 - zinfo.last_leading - zinfo.last_trailing;
-            let control = 0b10 << len; // control bits
-            let meaningful = xor >> zinfo.last_trailing; // meaningful section
-            CompressedBlock {
-                bits: 2 + len as usize,
-                block: control | meaningful,
+            CompressedValue {
+                control: CompressedBlock {
+                    bits: 2,
+                    block: 0b10,
+                },
+                meaningful: Some(CompressedBlock {
+                    bits: len as usize,
+                    block: xor >> zinfo.last_trailing,
+                }),
             }
         } else {
+            // The control bits used to be a single 1, which made this case
+            // impossible to tell apart from the one above whenever fewer
+            // than 16 leading zeros were stored, and the whole value was
+            // appended at once although it can take up to 77 bits. Blocks
+            // were only kept in memory back then, so no block file holds
+            // the old layout.
+            //
             // block construction:
-            // 1 control bit (left shift 5 + 6 + n)
-            // 5 bits for # of leading zeros (left shift 6 + n)
-            // 6 bits for length of meaningful section  (left shift n)
+            // 2 control bits (left shift 5 + 6)
+            // 5 bits for # of leading zeros (left shift 6)
+            // 6 bits for length of meaningful section (64 is stored as 0)
             // n bits of meaningful section
-            let len = 64 - zinfo.leading - zinfo.trailing;
-            let control = 1u64 << (11 + len); // control bit
-            let leading = (zinfo.leading as u64) << (6 + len); // # leading zeros
-            let n_meaningful = (len as u64) << len; // length of meaninful section
-            let meaningful = xor >> zinfo.trailing as usize; // meaningful section
-            CompressedBlock {
-                bits: 12 + len as usize,
-                block: control | leading | n_meaningful | meaningful,
+            //
+            // leading zeros are capped at 31 so that they fit in 5 bits
+            let leading = cmp::min(zinfo.leading, 31);
+            let len = 64 - leading - zinfo.trailing;
+            let control = 0b11 << 11; // control bits
+            let n_leading = (leading as u64) << 6; // # leading zeros
+            let n_meaningful = len as u64 & 0b111111; // length of meaningful section
+            CompressedValue {
+                control: CompressedBlock {
+                    bits: 13,
+                    block: control | n_leading | n_meaningful,
+                },
+                meaningful: Some(CompressedBlock {
+                    bits: len as usize,
+                    block: xor >> zinfo.trailing,
+                }),
             }
         }
     }
 }
 
+/// A single decompressed data point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    /// Seconds since the epoch
+    pub ts: i64,
+    pub value: f64,
+}
+
+/// Decompresses the points of a block from a borrowed `BitSlice`.
+///
+/// Since the iterator only borrows the compressed bits, it can decode
+/// blocks in place, e.g. straight out of a memory-mapped file. Iteration
+/// ends when the slice is exhausted or a truncated point is encountered
+pub struct TSBlockIter<'a> {
+    reader: BitReader<'a>,
+    header: Option<i64>,
+    last: Option<Last>,
+}
+
+impl<'a> TSBlockIter<'a> {
+    /// Creates an iterator over the compressed block in `slice`.
+    /// The slice must start with the 64-bit block header
+    pub fn new(slice: BitSlice<'a>) -> TSBlockIter<'a> {
+        let mut reader = slice.reader();
+        let header = reader.read(64).map(|h| h as i64);
+        TSBlockIter {
+            reader,
+            header,
+            last: None,
+        }
+    }
+
+    /// Retrieves the timestamp header of the block being decoded
+    pub fn header(&self) -> Option<u64> {
+        self.header.map(|h| h as u64)
+    }
+
+    fn read_first(&mut self, header: i64) -> Option<Last> {
+        let delta = self.reader.read(14)? as i64;
+        let val = self.reader.read(64)?;
+        Some(Last {
+            ts: header + delta,
+            delta,
+            val,
+            leading: val.leading_zeros(),
+            trailing: val.trailing_zeros(),
+        })
+    }
+
+    fn read_next(&mut self, last: &Last) -> Option<Last> {
+        let delta = last.delta + read_delta_delta(&mut self.reader)?;
+        let xor = self.read_xor(last)?;
+        Some(Last {
+            ts: last.ts + delta,
+            delta,
+            val: last.val ^ xor,
+            leading: xor.leading_zeros(),
+            trailing: xor.trailing_zeros(),
+        })
+    }
+
+    // Mirrors TSBlock::compressed_value_block
+    fn read_xor(&mut self, last: &Last) -> Option<u64> {
+        if !self.reader.read_bit()? {
+            return Some(0);
+        }
+        if !self.reader.read_bit()? {
+            let len = 64 - last.leading - last.trailing;
+            let meaningful = self.reader.read(len as usize)?;
+            return Some(meaningful << last.trailing);
+        }
+        let leading = self.reader.read(5)? as u32;
+        let len = match self.reader.read(6)? as u32 {
+            0 => 64,
+            len => len,
+        };
+        if leading + len > 64 {
+            return None;
+        }
+        let meaningful = self.reader.read(len as usize)?;
+        Some(meaningful << (64 - leading - len))
+    }
+}
+
+impl<'a> Iterator for TSBlockIter<'a> {
+    type Item = Point;
+
+    fn next(&mut self) -> Option<Point> {
+        let header = self.header?;
+        if self.reader.remaining() == 0 {
+            return None;
+        }
+        let next = match self.last.take() {
+            Some(last) => self.read_next(&last),
+            None => self.read_first(header),
+        };
+        match next {
+            Some(next) => {
+                let point = Point {
+                    ts: next.ts,
+                    value: f64::from_bits(next.val),
+                };
+                self.last = Some(next);
+                Some(point)
+            }
+            None => {
+                // truncated point, stop decoding
+                self.header = None;
+                None
+            }
+        }
+    }
+}
+
+// Mirrors TSBlock::compressed_time_block
+fn read_delta_delta(reader: &mut BitReader) -> Option<i64> {
+    if !reader.read_bit()? {
+        return Some(0);
+    }
+    // (value bits, largest positive value)
+    let encodings = [(7, 64), (9, 256), (12, 2048)];
+    for &(bits, max) in &encodings {
+        if !reader.read_bit()? {
+            let raw = reader.read(bits)? as i64;
+            return Some(if raw > max { raw - (1 << bits) } else { raw });
+        }
+    }
+    let raw = reader.read(32)?;
+    Some(raw as u32 as i32 as i64)
+}
+
 // contains data point information from the last
 // data point that was published
+#[derive(Clone)]
 struct Last {
     delta: i64,
     ts: i64,
@@ -204,6 +438,13 @@ struct CompressedBlock {
     block: u64,
 }
 
+// Contains a compressed value. The meaningful section is kept
+// separate from the control bits since together they may exceed 64 bits
+struct CompressedValue {
+    control: CompressedBlock,
+    meaningful: Option<CompressedBlock>,
+}
+
 // Contains info on leading and trailing zeros.
 // Used to calculate the compressed value block
 struct ZeroInfo {
@@ -213,9 +454,32 @@ struct ZeroInfo {
     last_trailing: u32,
 }
 
+// Builds a block starting at `header` out of `points`, for the tests of
+// every module handling blocks
+#[cfg(test)]
+fn test_block(header: i64, points: &[(i64, f64)]) -> TSBlock {
+    let mut block = TSBlock::at(header);
+    for &(ts, value) in points {
+        block.publish_at(value, ts);
+    }
+    block
+}
+
+// Copies `bytes` to an 8-byte aligned offset of `buf`, where a memory
+// mapped file would place them, and returns the copy
+#[cfg(test)]
+fn aligned<'a>(bytes: &[u8], buf: &'a mut Vec<u8>) -> &'a [u8] {
+    buf.clear();
+    buf.resize(bytes.len() + 8, 0);
+    let offset = buf.as_ptr().align_offset(8);
+    buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    &buf[offset..offset + bytes.len()]
+}
+
 #[cfg(test)]
 mod test {
-    use super::TSBlock;
+    use super::{aligned, Point, TSBlock, TSBlockIter};
+    use bit_vec::BitSlice;
 
     #[test]
     fn test_publish() {
@@ -226,8 +490,84 @@ mod test {
         ts.publish_at(2.0, 25);
         assert_eq!([0u64,
                     0b0000000000010101000000000000000000000000000000000000000000000000,
-                    0b0000000000000001010110000011100000101010111101110101100000110000,
+                    0b0000000000000001101011000001110000010101011110111101011000001100,
                     0],
                    ts.data.data());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_iter() {
+        let mut ts = TSBlock::at(0);
+        assert_eq!(None, ts.iter().next());
+        ts.publish_at(2.0, 5);
+        ts.publish_at(4.0, 10);
+        ts.publish_at(4.0, 20);
+        ts.publish_at(2.0, 25);
+        let points: Vec<Point> = ts.iter().collect();
+        assert_eq!(vec![Point { ts: 5, value: 2.0 },
+                        Point { ts: 10, value: 4.0 },
+                        Point { ts: 20, value: 4.0 },
+                        Point { ts: 25, value: 2.0 }],
+                   points);
+    }
+
+    #[test]
+    fn test_iter_round_trip() {
+        let start = 1_500_000_000;
+        let mut expected = Vec::new();
+        let mut ts = TSBlock::at(start);
+        let mut t = start;
+        for i in 0..1000i64 {
+            // exercise every delta of delta encoding, including negative ones
+            t += match i % 6 {
+                0 => 60,
+                1 => 1,
+                2 => 200,
+                3 => 2000,
+                4 => 1,
+                _ => 100_000,
+            };
+            let value = match i % 4 {
+                0 => i as f64 * 1.5,
+                1 => -(i as f64) / 7.0,
+                2 => 0.0,
+                _ => f64::MAX / (i as f64),
+            };
+            ts.publish_at(value, t);
+            expected.push(Point { ts: t, value });
+        }
+        let points: Vec<Point> = ts.iter().collect();
+        assert_eq!(expected, points);
+    }
+
+    #[test]
+    fn test_iter_borrowed_bytes() {
+        let mut ts = TSBlock::at(100);
+        ts.publish_at(1.0, 160);
+        ts.publish_at(3.25, 220);
+        let slice = ts.as_slice();
+        let le: Vec<u8> = slice.data().iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut buf = Vec::new();
+        let words = BitSlice::from_bytes(aligned(&le, &mut buf)).unwrap();
+        let iter = TSBlockIter::new(BitSlice::with_len(words.data(), slice.len()));
+        assert_eq!(Some(100), iter.header());
+        assert_eq!(vec![Point { ts: 160, value: 1.0 }, Point { ts: 220, value: 3.25 }],
+                   iter.collect::<Vec<Point>>());
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let mut ts = TSBlock::at(0);
+        ts.publish_at(2.0, 5);
+        ts.publish_at(4.0, 10);
+        let mut restored = TSBlock::from_slice(ts.as_slice()).unwrap();
+        assert_eq!(Some(10), restored.last_ts());
+        restored.publish_at(8.0, 15);
+        ts.publish_at(8.0, 15);
+        assert_eq!(ts.data.data(), restored.data.data());
+
+        let truncated = BitSlice::with_len(ts.as_slice().data(), ts.as_slice().len() - 1);
+        assert!(TSBlock::from_slice(truncated).is_none());
+        assert!(TSBlock::from_slice(BitSlice::new(&[])).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! Cost limits of queries.
+//!
+//! A `Tracker` counts the series a query touches and the points it decodes,
+//! and fails the query as soon as one of its `Limits` is exceeded. Blocks
+//! are decoded one at a time, so a query over too many points stops after
+//! at most one block past the limit rather than after decoding everything.
+
+use std::error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use int_block::IntPoint;
+use series::{IntSeries, Series};
+use Point;
+
+/// Limits of a single query, `None` meaning unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of points decoded
+    pub max_points: Option<u64>,
+    /// Maximum number of series touched
+    pub max_series: Option<u64>,
+    /// Maximum time spent evaluating the query
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Returns limits that never stop a query
+    pub fn none() -> Limits {
+        Limits::default()
+    }
+
+    pub fn max_points(self, max_points: u64) -> Limits {
+        Limits { max_points: Some(max_points), ..self }
+    }
+
+    pub fn max_series(self, max_series: u64) -> Limits {
+        Limits { max_series: Some(max_series), ..self }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Limits {
+        Limits { timeout: Some(timeout), ..self }
+    }
+}
+
+/// Error raised when a query exceeds one of its limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryLimitExceeded {
+    /// More than `limit` points were decoded
+    Points { limit: u64 },
+    /// More than `limit` series were touched
+    Series { limit: u64 },
+    /// The query ran for longer than `timeout`
+    Deadline { timeout: Duration },
+}
+
+impl fmt::Display for QueryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryLimitExceeded::Points { limit } => write!(f, "query decoded more than {} points", limit),
+            QueryLimitExceeded::Series { limit } => write!(f, "query touched more than {} series", limit),
+            QueryLimitExceeded::Deadline { timeout } => write!(f, "query ran for longer than {:?}", timeout),
+        }
+    }
+}
+
+impl error::Error for QueryLimitExceeded {}
+
+/// Running cost of a query
+#[derive(Debug)]
+pub struct Tracker {
+    limits: Limits,
+    deadline: Option<Instant>,
+    points: u64,
+    series: u64,
+}
+
+impl Tracker {
+    /// Starts tracking a query, the timeout runs from now
+    pub fn new(limits: Limits) -> Tracker {
+        Tracker {
+            limits,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            points: 0,
+            series: 0,
+        }
+    }
+
+    /// Returns the number of points decoded so far
+    pub fn points(&self) -> u64 {
+        self.points
+    }
+
+    /// Returns the number of series touched so far
+    pub fn series(&self) -> u64 {
+        self.series
+    }
+
+    /// Counts a series touched by the query
+    pub fn add_series(&mut self) -> Result<(), QueryLimitExceeded> {
+        self.series += 1;
+        match self.limits.max_series {
+            Some(limit) if self.series > limit => Err(QueryLimitExceeded::Series { limit }),
+            _ => self.check_deadline(),
+        }
+    }
+
+    /// Counts `n` points decoded by the query
+    pub fn add_points(&mut self, n: u64) -> Result<(), QueryLimitExceeded> {
+        self.points += n;
+        match self.limits.max_points {
+            Some(limit) if self.points > limit => Err(QueryLimitExceeded::Points { limit }),
+            _ => self.check_deadline(),
+        }
+    }
+
+    /// Fails if the deadline of the query has passed
+    pub fn check_deadline(&self) -> Result<(), QueryLimitExceeded> {
+        match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+                Err(QueryLimitExceeded::Deadline { timeout })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes the points of `series` within [`start`, `end`) like
+    /// `Series::points`, counting the series and every point decoded,
+    /// including points of the blocks outside of the range
+    pub fn decode(&mut self, series: &Series, start: i64, end: i64) -> Result<Vec<Point>, QueryLimitExceeded> {
+        let blocks = series.blocks().map(|block| (block.header(), block.last_ts(), block.iter()));
+        self.decode_blocks(blocks, start, end)
+    }
+
+    /// Decodes the points of the integer series `series` as floats, see
+    /// `decode`
+    pub fn decode_int(&mut self, series: &IntSeries, start: i64, end: i64) -> Result<Vec<Point>, QueryLimitExceeded> {
+        let blocks = series.blocks()
+            .map(|block| (block.header(), block.last_ts(), block.iter().map(IntPoint::to_point)));
+        self.decode_blocks(blocks, start, end)
+    }
+
+    // Takes the header, last timestamp and points of every block
+    fn decode_blocks<B, P>(&mut self, blocks: B, start: i64, end: i64) -> Result<Vec<Point>, QueryLimitExceeded>
+        where B: Iterator<Item = (u64, Option<i64>, P)>,
+              P: Iterator<Item = Point>
+    {
+        self.add_series()?;
+        let mut points = Vec::new();
+        for (header, last_ts, iter) in blocks {
+            // blocks entirely outside of the range needn't be decoded
+            if last_ts.is_none_or(|last| last < start) || header as i64 >= end {
+                continue;
+            }
+            let mut decoded = 0;
+            for point in iter {
+                decoded += 1;
+                if point.ts >= start && point.ts < end {
+                    points.push(point);
+                }
+            }
+            self.add_points(decoded)?;
+        }
+        // stable so that points of later blocks stay last
+        points.sort_by_key(|p| p.ts);
+        Ok(points)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Limits, QueryLimitExceeded, Tracker};
+    use series::Series;
+
+    #[test]
+    fn test_tracker() {
+        let mut series = Series::with_block_size(100);
+        for ts in 0..1000 {
+            series.publish_at(1.0, ts);
+        }
+        let mut tracker = Tracker::new(Limits::none());
+        assert_eq!(150, tracker.decode(&series, 250, 400).unwrap().len());
+        assert_eq!(200, tracker.points());
+        assert_eq!(1, tracker.series());
+
+        let mut tracker = Tracker::new(Limits::none().max_points(250));
+        assert!(tracker.decode(&series, 0, 200).is_ok());
+        assert_eq!(Err(QueryLimitExceeded::Points { limit: 250 }), tracker.decode(&series, 200, 300));
+
+        let mut tracker = Tracker::new(Limits::none().max_series(1));
+        assert!(tracker.add_series().is_ok());
+        assert_eq!(Err(QueryLimitExceeded::Series { limit: 1 }), tracker.add_series());
+
+        let timeout = Duration::from_millis(0);
+        let tracker = Tracker::new(Limits::none().timeout(timeout));
+        assert_eq!(Err(QueryLimitExceeded::Deadline { timeout }), tracker.check_deadline());
+    }
+}
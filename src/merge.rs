@@ -0,0 +1,194 @@
+//! Streaming k-way merge of time ordered point iterators.
+//!
+//! A series may be spread over overlapping blocks, after late writes or
+//! before compaction, or be held by several replicas. `Merge` combines the
+//! decoders of such blocks into a single time ordered stream, holding only
+//! one pending point per input.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::error;
+use std::fmt;
+
+use {Point, TSBlock, TSBlockIter};
+
+/// What to do with points sharing a timestamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedup {
+    /// Keep the point of the first input, or the first point of an input
+    KeepFirst,
+    /// Keep the point of the last input, or the last point of an input
+    KeepLast,
+    /// Yield an error instead of the points
+    Error,
+}
+
+/// Error yielded for points sharing a timestamp with `Dedup::Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DuplicateTimestamp {
+    pub ts: i64,
+    /// Number of points sharing the timestamp
+    pub count: usize,
+}
+
+impl fmt::Display for DuplicateTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} points share the timestamp {}", self.count, self.ts)
+    }
+}
+
+impl error::Error for DuplicateTimestamp {}
+
+// Pending point of an input, ordered by timestamp, then input, then
+// position within the input
+struct Head {
+    point: Point,
+    input: usize,
+    seq: u64,
+}
+
+impl Head {
+    fn key(&self) -> (i64, usize, u64) {
+        (self.point.ts, self.input, self.seq)
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Merges time ordered inputs into a single time ordered stream, resolving
+/// points sharing a timestamp according to a `Dedup` policy
+pub struct Merge<I> {
+    inputs: Vec<I>,
+    heads: BinaryHeap<Reverse<Head>>,
+    seq: u64,
+    dedup: Dedup,
+}
+
+impl<'a> Merge<TSBlockIter<'a>> {
+    /// Merges the points of `blocks`, in the order given
+    pub fn blocks<B: IntoIterator<Item = &'a TSBlock>>(blocks: B, dedup: Dedup) -> Merge<TSBlockIter<'a>> {
+        Merge::new(blocks.into_iter().map(TSBlock::iter).collect(), dedup)
+    }
+}
+
+impl<I: Iterator<Item = Point>> Merge<I> {
+    /// Creates a merge of `inputs`, each of which must be time ordered.
+    /// The order of the inputs decides which point comes first when
+    /// several share a timestamp
+    pub fn new(inputs: Vec<I>, dedup: Dedup) -> Merge<I> {
+        let mut merge = Merge {
+            heads: BinaryHeap::with_capacity(inputs.len()),
+            inputs,
+            seq: 0,
+            dedup,
+        };
+        for input in 0..merge.inputs.len() {
+            merge.advance(input);
+        }
+        merge
+    }
+
+    // Reads the next point of `input` into the heap
+    fn advance(&mut self, input: usize) {
+        if let Some(point) = self.inputs[input].next() {
+            self.seq += 1;
+            self.heads.push(Reverse(Head { point, input, seq: self.seq }));
+        }
+    }
+
+    fn pop(&mut self) -> Option<Point> {
+        let Reverse(head) = self.heads.pop()?;
+        self.advance(head.input);
+        Some(head.point)
+    }
+}
+
+impl<I: Iterator<Item = Point>> Iterator for Merge<I> {
+    type Item = Result<Point, DuplicateTimestamp>;
+
+    fn next(&mut self) -> Option<Result<Point, DuplicateTimestamp>> {
+        let first = self.pop()?;
+        let mut last = first;
+        let mut count = 1;
+        while self.heads.peek().is_some_and(|head| head.0.point.ts == first.ts) {
+            last = self.pop().expect("the heap isn't empty");
+            count += 1;
+        }
+        Some(match self.dedup {
+            Dedup::KeepFirst => Ok(first),
+            Dedup::KeepLast => Ok(last),
+            Dedup::Error if count > 1 => Err(DuplicateTimestamp { ts: first.ts, count }),
+            Dedup::Error => Ok(first),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // duplicates may collapse every pending point into one
+        let pending = self.heads.len();
+        let mut upper = Some(pending);
+        for input in &self.inputs {
+            upper = upper.and_then(|n| input.size_hint().1.and_then(|m| n.checked_add(m)));
+        }
+        (pending.min(1), upper)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dedup, DuplicateTimestamp, Merge};
+    use {test_block, Point, TSBlock};
+
+    fn blocks() -> Vec<TSBlock> {
+        vec![test_block(0, &[(0, 1.0), (20, 1.0), (40, 1.0)]),
+             test_block(0, &[(10, 2.0), (20, 2.0), (30, 2.0)]),
+             test_block(0, &[(20, 3.0), (50, 3.0)])]
+    }
+
+    fn merge(dedup: Dedup) -> Vec<Result<Point, DuplicateTimestamp>> {
+        Merge::blocks(&blocks(), dedup).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let points: Vec<Point> = merge(Dedup::KeepFirst).into_iter().map(Result::unwrap).collect();
+        assert_eq!(vec![0, 10, 20, 30, 40, 50], points.iter().map(|p| p.ts).collect::<Vec<i64>>());
+        assert_eq!(1.0, points[2].value);
+        let points: Vec<Point> = merge(Dedup::KeepLast).into_iter().map(Result::unwrap).collect();
+        assert_eq!(3.0, points[2].value);
+        assert_eq!(6, points.len());
+    }
+
+    #[test]
+    fn test_merge_errors_on_duplicates() {
+        let results = merge(Dedup::Error);
+        assert_eq!(Err(DuplicateTimestamp { ts: 20, count: 3 }), results[2]);
+        assert_eq!(Ok(Point { ts: 30, value: 2.0 }), results[3]);
+        assert_eq!(6, results.len());
+    }
+
+    #[test]
+    fn test_merge_within_input() {
+        let blocks = [test_block(0, &[(0, 1.0), (0, 2.0)]), test_block(0, &[])];
+        let points: Vec<_> = Merge::blocks(&blocks, Dedup::KeepLast).collect();
+        assert_eq!(vec![Ok(Point { ts: 0, value: 2.0 })], points);
+        assert_eq!(0, Merge::blocks(&[], Dedup::KeepFirst).count());
+    }
+}
@@ -0,0 +1,1334 @@
+//! OpenTSDB compatible HTTP API.
+//!
+//! Data points are written as JSON to `/api/put`, one object or an array
+//! of them:
+//!
+//! ```text
+//! {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 18, "tags": {"host": "web01"}}
+//! ```
+//!
+//! Timestamps of more than 10 digits are in milliseconds and truncated to
+//! seconds. Integer values are stored as integers, others as floats. The
+//! `details` and `summary` parameters ask for a report of the points
+//! stored and rejected, without them the response is empty unless some
+//! point was rejected. Points the validator of the store rejects are
+//! reported like invalid ones, see `validate`.
+//!
+//! `/api/query` answers `GET` requests with `start`, `end` and `m`
+//! parameters, and `POST` requests holding the same query as JSON. A
+//! sub-query `m` reads:
+//!
+//! ```text
+//! sum:rate{counter,,1000}:1m-avg-zero:sys.cpu.nice{host=web*}{dc=lga|sjc}
+//! ```
+//!
+//! that is an aggregator, optional rate and downsampling specs, a metric
+//! and tag filters. Filters within the first braces group the results by
+//! tag value, those within the second braces only filter. Each series is
+//! downsampled, then turned into a rate, then aggregated with the other
+//! series of its group. Like OpenTSDB, every aggregator but `zimsum`,
+//! `mimmin` and `mimmax` interpolates series linearly at the timestamps of
+//! the others. Absolute dates are read in UTC. Queries exceeding the
+//! `Limits` of the listener fail with a 400 response.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error;
+use std::f64;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use time;
+
+use aggregate::{Accumulator, Function, Window};
+use fill::{Fill, GapFill};
+use http::{Request, Response, Server};
+use ingest::{Closed, Handle, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use json::{self, Json};
+use limits::{Limits, QueryLimitExceeded, Tracker};
+use store::{SeriesKey, Store};
+use validate::{Reason, Verdict};
+use Point;
+
+/// Path of the write endpoint
+pub const PUT_PATH: &str = "/api/put";
+
+/// Path of the query endpoint
+pub const QUERY_PATH: &str = "/api/query";
+
+// Timestamps above this have more than 10 digits and are in milliseconds
+const MAX_SECONDS: i64 = 9_999_999_999;
+
+// Formats of absolute dates, in UTC
+const DATE_FORMATS: [&str; 4] = ["%Y/%m/%d-%H:%M:%S", "%Y/%m/%d %H:%M:%S", "%Y/%m/%d-%H:%M", "%Y/%m/%d"];
+
+/// Reasons a data point can't be stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PutError {
+    NotAnObject,
+    MissingMetric,
+    InvalidMetric(String),
+    MissingTimestamp,
+    InvalidTimestamp,
+    MissingValue,
+    InvalidValue,
+    MissingTags,
+    InvalidTag(String),
+    /// The validator of the store rejected the point
+    Rejected(Reason),
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PutError::NotAnObject => write!(f, "Data point is not an object"),
+            PutError::MissingMetric => write!(f, "Metric name was empty"),
+            PutError::InvalidMetric(ref metric) => write!(f, "Invalid metric name {:?}", metric),
+            PutError::MissingTimestamp => write!(f, "Missing timestamp"),
+            PutError::InvalidTimestamp => write!(f, "Invalid timestamp"),
+            PutError::MissingValue => write!(f, "Missing value"),
+            PutError::InvalidValue => write!(f, "Unable to parse value to a number"),
+            PutError::MissingTags => write!(f, "Missing tags"),
+            PutError::InvalidTag(ref tag) => write!(f, "Invalid tag {:?}", tag),
+            PutError::Rejected(reason) => write!(f, "Rejected by validation: {}", reason.name()),
+        }
+    }
+}
+
+impl error::Error for PutError {}
+
+/// A data point rejected by `parse_put`, with the reason
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejected {
+    pub datapoint: Json,
+    pub error: PutError,
+}
+
+/// Parses the body of a put request, a single data point or an array of
+/// them, returning the samples of the valid points and the invalid ones
+pub fn parse_put(body: &Json) -> (Vec<Sample>, Vec<Rejected>) {
+    let mut samples = Vec::new();
+    let mut rejected = Vec::new();
+    for datapoint in datapoints(body) {
+        match parse_datapoint(datapoint) {
+            Ok(sample) => samples.push(sample),
+            Err(error) => rejected.push(Rejected { datapoint: datapoint.clone(), error }),
+        }
+    }
+    (samples, rejected)
+}
+
+// Returns the data points of a put request body
+fn datapoints(body: &Json) -> &[Json] {
+    match *body {
+        Json::Array(ref datapoints) => datapoints,
+        _ => std::slice::from_ref(body),
+    }
+}
+
+/// Parses a single data point
+pub fn parse_datapoint(datapoint: &Json) -> Result<Sample, PutError> {
+    if datapoint.as_object().is_none() {
+        return Err(PutError::NotAnObject);
+    }
+    let metric = match datapoint.get("metric").and_then(Json::as_str) {
+        Some("") | None => return Err(PutError::MissingMetric),
+        Some(metric) if !valid_name(metric) => return Err(PutError::InvalidMetric(metric.to_string())),
+        Some(metric) => metric,
+    };
+
+    let ts = match datapoint.get("timestamp") {
+        Some(&Json::Int(ts)) => ts,
+        Some(Json::String(ts)) => ts.parse().map_err(|_| PutError::InvalidTimestamp)?,
+        Some(_) => return Err(PutError::InvalidTimestamp),
+        None => return Err(PutError::MissingTimestamp),
+    };
+    if ts <= 0 {
+        return Err(PutError::InvalidTimestamp);
+    }
+    let ts = if ts > MAX_SECONDS { ts / 1000 } else { ts };
+
+    let value = match datapoint.get("value") {
+        Some(&Json::Int(value)) => Value::Int(value),
+        Some(&Json::Float(value)) => Value::Float(value),
+        Some(Json::String(value)) => match value.parse() {
+            Ok(value) => Value::Int(value),
+            Err(_) => Value::Float(value.parse().map_err(|_| PutError::InvalidValue)?),
+        },
+        Some(_) => return Err(PutError::InvalidValue),
+        None => return Err(PutError::MissingValue),
+    };
+
+    let tags = datapoint.get("tags").and_then(Json::as_object).unwrap_or(&[]);
+    if tags.is_empty() {
+        return Err(PutError::MissingTags);
+    }
+    let mut key = SeriesKey::new(metric);
+    for (name, value) in tags {
+        match value.as_str() {
+            Some(value) if valid_name(name) && valid_name(value) => key = key.tag(name.as_str(), value),
+            _ => return Err(PutError::InvalidTag(format!("{}={}", name, value))),
+        }
+    }
+    Ok(Sample { key, value, ts })
+}
+
+// Metric names, tag names and tag values are made of letters, digits and
+// the characters `-_./`
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || "-_./".contains(c))
+}
+
+/// Aggregators combining series at each timestamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregator {
+    Sum,
+    Min,
+    Max,
+    Avg,
+    /// Population standard deviation
+    Dev,
+    Count,
+    Last,
+    /// Sum without interpolation, missing values count as zero
+    Zimsum,
+    /// Minimum without interpolation
+    Mimmin,
+    /// Maximum without interpolation
+    Mimmax,
+    /// Every series is returned on its own
+    None,
+}
+
+impl Aggregator {
+    /// Returns the aggregator called `name` by OpenTSDB
+    pub fn from_name(name: &str) -> Option<Aggregator> {
+        let aggregator = match name {
+            "sum" => Aggregator::Sum,
+            "min" => Aggregator::Min,
+            "max" => Aggregator::Max,
+            "avg" => Aggregator::Avg,
+            "dev" => Aggregator::Dev,
+            "count" => Aggregator::Count,
+            "last" => Aggregator::Last,
+            "zimsum" => Aggregator::Zimsum,
+            "mimmin" => Aggregator::Mimmin,
+            "mimmax" => Aggregator::Mimmax,
+            "none" => Aggregator::None,
+            _ => return None,
+        };
+        Some(aggregator)
+    }
+
+    /// Returns the function applied across series, `None` for `None`
+    pub fn function(self) -> Option<Function> {
+        let function = match self {
+            Aggregator::Sum | Aggregator::Zimsum => Function::Sum,
+            Aggregator::Min | Aggregator::Mimmin => Function::Min,
+            Aggregator::Max | Aggregator::Mimmax => Function::Max,
+            Aggregator::Avg => Function::Avg,
+            Aggregator::Dev => Function::Stddev,
+            Aggregator::Count => Function::Count,
+            Aggregator::Last => Function::Last,
+            Aggregator::None => return None,
+        };
+        Some(function)
+    }
+
+    /// Returns whether series are interpolated at the timestamps of others
+    pub fn interpolates(self) -> bool {
+        !matches!(self, Aggregator::Zimsum | Aggregator::Mimmin | Aggregator::Mimmax | Aggregator::None)
+    }
+}
+
+/// How empty downsampling buckets are filled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillPolicy {
+    /// Empty buckets are skipped, and may be interpolated
+    None,
+    /// Empty buckets are reported as `null` and not aggregated, `nan` is
+    /// the same as JSON has no NaN
+    Null,
+    Zero,
+}
+
+/// Downsampling spec such as `1m-avg-zero`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Downsample {
+    /// Bucket width in seconds
+    pub interval: i64,
+    pub function: Function,
+    pub fill: FillPolicy,
+}
+
+impl Downsample {
+    /// Parses a `<interval>-<aggregator>[-<fill policy>]` spec
+    pub fn parse(spec: &str) -> Result<Downsample, QueryError> {
+        let invalid = || QueryError::InvalidDownsample(spec.to_string());
+        let mut parts = spec.split('-');
+        let interval = parts.next().and_then(parse_duration).filter(|&interval| interval > 0).ok_or_else(invalid)?;
+        let function = parts.next().and_then(Aggregator::from_name).and_then(Aggregator::function).ok_or_else(invalid)?;
+        let fill = match parts.next() {
+            None | Some("none") => FillPolicy::None,
+            Some("null") | Some("nan") => FillPolicy::Null,
+            Some("zero") => FillPolicy::Zero,
+            Some(_) => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Downsample { interval, function, fill })
+    }
+}
+
+/// Options of a rate over counters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateOptions {
+    /// Whether the series are counters, which only decrease when they
+    /// wrap around or reset
+    pub counter: bool,
+    /// Value at which counters wrap around. A counter decreasing without
+    /// one is assumed to have reset to zero
+    pub counter_max: Option<f64>,
+    /// Rates above this are reported as zero, if positive
+    pub reset_value: f64,
+}
+
+impl RateOptions {
+    /// Parses the `{counter[,<counter max>[,<reset value>]]}` options of a
+    /// `rate` spec, without the braces
+    pub fn parse(options: &str) -> Result<RateOptions, QueryError> {
+        let invalid = || QueryError::InvalidRate(options.to_string());
+        let mut parts = options.split(',');
+        let mut rate = RateOptions::default();
+        match parts.next() {
+            Some("counter") => rate.counter = true,
+            Some("") | None => {}
+            Some(_) => return Err(invalid()),
+        }
+        match parts.next() {
+            Some("") | None => {}
+            Some(max) => rate.counter_max = Some(max.parse().map_err(|_| invalid())?),
+        }
+        match parts.next() {
+            Some("") | None => {}
+            Some(reset) => rate.reset_value = reset.parse().map_err(|_| invalid())?,
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(rate)
+    }
+}
+
+/// Values a tag filter accepts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    /// One of the values, exactly
+    LiteralOr(Vec<String>),
+    /// None of the values
+    NotLiteralOr(Vec<String>),
+    /// Values matching the pattern, where `*` stands for any characters
+    Wildcard(String),
+}
+
+/// Restricts the series read to those with tag `tag` accepted by `kind`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub tag: String,
+    pub kind: FilterKind,
+    /// Whether results are split by the value of the tag
+    pub group_by: bool,
+}
+
+impl Filter {
+    /// Parses the filter of `tag` from its text in a sub-query, either
+    /// `literal_or(a|b)`, `not_literal_or(a|b)` or `wildcard(a*)`, or the
+    /// shorthands `a|b` and `a*`
+    pub fn parse(tag: &str, filter: &str, group_by: bool) -> Result<Filter, QueryError> {
+        let invalid = || QueryError::InvalidFilter(format!("{}={}", tag, filter));
+        if !valid_name(tag) || filter.is_empty() {
+            return Err(invalid());
+        }
+        let literals = |values: &str| values.split('|').map(str::to_string).collect();
+        let kind = match filter.split_once('(') {
+            Some((kind, rest)) => {
+                let arg = rest.strip_suffix(')').filter(|arg| !arg.is_empty()).ok_or_else(invalid)?;
+                match kind {
+                    "literal_or" => FilterKind::LiteralOr(literals(arg)),
+                    "not_literal_or" => FilterKind::NotLiteralOr(literals(arg)),
+                    "wildcard" => FilterKind::Wildcard(arg.to_string()),
+                    _ => return Err(invalid()),
+                }
+            }
+            None if filter.contains('*') => FilterKind::Wildcard(filter.to_string()),
+            None => FilterKind::LiteralOr(literals(filter)),
+        };
+        Ok(Filter { tag: tag.to_string(), kind, group_by })
+    }
+
+    /// Returns whether the filter accepts the series `key`, which must
+    /// have the tag
+    pub fn matches(&self, key: &SeriesKey) -> bool {
+        let value = match key.tags.get(&self.tag) {
+            Some(value) => value,
+            None => return false,
+        };
+        match self.kind {
+            FilterKind::LiteralOr(ref values) => values.iter().any(|v| v == value),
+            FilterKind::NotLiteralOr(ref values) => values.iter().all(|v| v != value),
+            FilterKind::Wildcard(ref pattern) => wildcard(pattern, value),
+        }
+    }
+}
+
+// Matches `value` against `pattern` where `*` stands for any characters
+fn wildcard(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no `*` at all
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// One metric to read within a query
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubQuery {
+    pub aggregator: Aggregator,
+    pub metric: String,
+    pub rate: Option<RateOptions>,
+    pub downsample: Option<Downsample>,
+    pub filters: Vec<Filter>,
+}
+
+impl SubQuery {
+    /// Parses the `m` parameter of a query
+    pub fn parse(m: &str) -> Result<SubQuery, QueryError> {
+        let invalid = || QueryError::InvalidSubQuery(m.to_string());
+        // filters may hold colons, rate options hold braces
+        let (specs, braces) = m.split_at(filters_start(m));
+        let mut parts: Vec<&str> = specs.split(':').collect();
+        let metric = parts.pop().filter(|metric| valid_name(metric)).ok_or_else(invalid)?;
+        if parts.is_empty() {
+            return Err(invalid());
+        }
+        let aggregator = Aggregator::from_name(parts[0]).ok_or_else(|| QueryError::UnknownAggregator(parts[0].to_string()))?;
+        let mut query = SubQuery {
+            aggregator,
+            metric: metric.to_string(),
+            rate: None,
+            downsample: None,
+            filters: Vec::new(),
+        };
+        for &spec in &parts[1..] {
+            if spec == "rate" {
+                query.rate = Some(RateOptions::default());
+            } else if let Some(options) = spec.strip_prefix("rate{") {
+                // as in rate{counter,,1000}
+                let options = options.strip_suffix('}').ok_or_else(invalid)?;
+                query.rate = Some(RateOptions::parse(options)?);
+            } else {
+                query.downsample = Some(Downsample::parse(spec)?);
+            }
+        }
+
+        let mut rest = braces;
+        for group_by in [true, false] {
+            if rest.is_empty() {
+                break;
+            }
+            let end = rest.find('}').filter(|_| rest.starts_with('{')).ok_or_else(invalid)?;
+            for filter in rest[1..end].split(',').filter(|filter| !filter.is_empty()) {
+                let (tag, value) = filter.split_once('=').ok_or_else(|| QueryError::InvalidFilter(filter.to_string()))?;
+                query.filters.push(Filter::parse(tag, value, group_by)?);
+            }
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        Ok(query)
+    }
+
+    fn from_json(json: &Json) -> Result<SubQuery, QueryError> {
+        let invalid = |field: &str| QueryError::InvalidBody(format!("invalid {} of sub-query", field));
+        let aggregator = json.get("aggregator").and_then(Json::as_str).ok_or_else(|| invalid("aggregator"))?;
+        let metric = json.get("metric").and_then(Json::as_str).filter(|metric| valid_name(metric));
+        let mut query = SubQuery {
+            aggregator: Aggregator::from_name(aggregator).ok_or_else(|| QueryError::UnknownAggregator(aggregator.to_string()))?,
+            metric: metric.ok_or_else(|| invalid("metric"))?.to_string(),
+            rate: None,
+            downsample: None,
+            filters: Vec::new(),
+        };
+        if json.get("rate").and_then(Json::as_bool).unwrap_or(false) {
+            let options = json.get("rateOptions");
+            let number = |name: &str| options.and_then(|options| options.get(name)).and_then(Json::as_f64);
+            query.rate = Some(RateOptions {
+                counter: options.and_then(|options| options.get("counter")).and_then(Json::as_bool).unwrap_or(false),
+                counter_max: number("counterMax"),
+                reset_value: number("resetValue").unwrap_or(0.0),
+            });
+        }
+        if let Some(downsample) = json.get("downsample") {
+            query.downsample = Some(Downsample::parse(downsample.as_str().ok_or_else(|| invalid("downsample"))?)?);
+        }
+        for (tag, filter) in json.get("tags").and_then(Json::as_object).unwrap_or(&[]) {
+            query.filters.push(Filter::parse(tag, filter.as_str().ok_or_else(|| invalid("tags"))?, true)?);
+        }
+        for filter in json.get("filters").and_then(Json::as_array).unwrap_or(&[]) {
+            let field = |name: &str| filter.get(name).and_then(Json::as_str).ok_or_else(|| invalid("filters"));
+            let group_by = filter.get("groupBy").and_then(Json::as_bool).unwrap_or(false);
+            let text = format!("{}({})", field("type")?, field("filter")?);
+            query.filters.push(Filter::parse(field("tagk")?, &text, group_by)?);
+        }
+        Ok(query)
+    }
+}
+
+// Returns the offset of the filters of a sub-query, past any `rate{...}`
+fn filters_start(m: &str) -> usize {
+    let mut from = 0;
+    while let Some(i) = m[from..].find('{').map(|i| from + i) {
+        let rate = m[..i].ends_with("rate") && m[..i - 4].ends_with(':');
+        match m[i..].find('}') {
+            Some(end) if rate => from = i + end + 1,
+            _ => return i,
+        }
+    }
+    m.len()
+}
+
+/// Reasons a query can't be answered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryError {
+    MissingStart,
+    InvalidTime(String),
+    /// The end of the range is before its start
+    InvalidRange,
+    MissingQueries,
+    InvalidSubQuery(String),
+    UnknownAggregator(String),
+    InvalidDownsample(String),
+    InvalidRate(String),
+    InvalidFilter(String),
+    InvalidJson(json::Error),
+    InvalidBody(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueryError::MissingStart => write!(f, "Missing start time"),
+            QueryError::InvalidTime(ref time) => write!(f, "Invalid time {:?}", time),
+            QueryError::InvalidRange => write!(f, "End time must be greater than the start time"),
+            QueryError::MissingQueries => write!(f, "Missing sub queries"),
+            QueryError::InvalidSubQuery(ref m) => write!(f, "Invalid sub query {:?}", m),
+            QueryError::UnknownAggregator(ref name) => write!(f, "No such aggregator {:?}", name),
+            QueryError::InvalidDownsample(ref spec) => write!(f, "Invalid downsampling specifier {:?}", spec),
+            QueryError::InvalidRate(ref spec) => write!(f, "Invalid rate options {:?}", spec),
+            QueryError::InvalidFilter(ref filter) => write!(f, "Invalid tag filter {:?}", filter),
+            QueryError::InvalidJson(ref err) => write!(f, "Invalid JSON: {}", err),
+            QueryError::InvalidBody(ref message) => write!(f, "Invalid query: {}", message),
+        }
+    }
+}
+
+impl error::Error for QueryError {}
+
+/// A query over the range [`start`, `end`] in seconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub start: i64,
+    pub end: i64,
+    pub queries: Vec<SubQuery>,
+    /// Whether timestamps are reported in milliseconds
+    pub ms_resolution: bool,
+}
+
+impl Query {
+    /// Reads a query from the parameters of a `GET` request received at
+    /// `now` seconds from the epoch
+    pub fn from_params(request: &Request, now: i64) -> Result<Query, QueryError> {
+        let start = request.param("start").ok_or(QueryError::MissingStart)?;
+        let end = request.param("end");
+        let queries = request.params("m").iter().map(|m| SubQuery::parse(m)).collect::<Result<_, _>>()?;
+        let ms_resolution = request.param("ms").is_some() || request.param("msResolution").is_some_and(|ms| ms != "false");
+        Query::new(&start, end.as_deref(), queries, ms_resolution, now)
+    }
+
+    /// Reads a query from the body of a `POST` request received at `now`
+    /// seconds from the epoch
+    pub fn from_json(json: &Json, now: i64) -> Result<Query, QueryError> {
+        let time = |name: &str| -> Result<Option<String>, QueryError> {
+            match json.get(name) {
+                Some(Json::String(time)) => Ok(Some(time.clone())),
+                Some(&Json::Int(time)) => Ok(Some(time.to_string())),
+                Some(_) => Err(QueryError::InvalidBody(format!("invalid {}", name))),
+                None => Ok(None),
+            }
+        };
+        let start = time("start")?.ok_or(QueryError::MissingStart)?;
+        let end = time("end")?;
+        let queries = json.get("queries").and_then(Json::as_array).unwrap_or(&[]);
+        let queries = queries.iter().map(SubQuery::from_json).collect::<Result<_, _>>()?;
+        let ms_resolution = json.get("msResolution").and_then(Json::as_bool).unwrap_or(false);
+        Query::new(&start, end.as_deref(), queries, ms_resolution, now)
+    }
+
+    fn new(start: &str, end: Option<&str>, queries: Vec<SubQuery>, ms_resolution: bool, now: i64) -> Result<Query, QueryError> {
+        let start = parse_time(start, now)?;
+        let end = match end {
+            Some(end) => parse_time(end, now)?,
+            None => now,
+        };
+        if end < start {
+            return Err(QueryError::InvalidRange);
+        }
+        if queries.is_empty() {
+            return Err(QueryError::MissingQueries);
+        }
+        Ok(Query { start, end, queries, ms_resolution })
+    }
+}
+
+/// Parses a time of a query received at `now` seconds from the epoch,
+/// either relative as in `1h-ago`, in seconds or milliseconds from the
+/// epoch, or a UTC date as in `2013/01/01-12:00:00`
+pub fn parse_time(time: &str, now: i64) -> Result<i64, QueryError> {
+    let invalid = || QueryError::InvalidTime(time.to_string());
+    if let Some(ago) = time.strip_suffix("-ago") {
+        return parse_duration(ago).map(|ago| now - ago).ok_or_else(invalid);
+    }
+    if let Ok(ts) = time.parse::<i64>() {
+        return Ok(if ts > MAX_SECONDS { ts / 1000 } else { ts });
+    }
+    DATE_FORMATS.iter()
+        .filter_map(|format| time::strptime(time, format).ok())
+        .map(|tm| tm.to_timespec().sec)
+        .next()
+        .ok_or_else(invalid)
+}
+
+// Parses a duration such as `15m` into seconds
+fn parse_duration(duration: &str) -> Option<i64> {
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = duration.split_at(split);
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "n" => 30 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return None,
+    };
+    count.parse::<i64>().ok()?.checked_mul(unit)
+}
+
+/// Aggregated series answering a sub-query
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryResult {
+    pub metric: String,
+    /// Tags with the same value in every series aggregated
+    pub tags: BTreeMap<String, String>,
+    /// Tags whose value differs between the series aggregated
+    pub aggregate_tags: Vec<String>,
+    /// Points ordered by timestamp, NaN where a bucket was filled with null
+    pub dps: Vec<Point>,
+}
+
+impl QueryResult {
+    /// Returns the result as OpenTSDB writes it
+    pub fn to_json(&self, ms_resolution: bool) -> Json {
+        let tags = self.tags.iter().map(|(name, value)| (name.clone(), Json::from(value.as_str()))).collect();
+        let aggregate_tags = self.aggregate_tags.iter().map(|tag| Json::from(tag.as_str())).collect();
+        let dps = self.dps.iter()
+            .map(|p| {
+                let ts = if ms_resolution { p.ts * 1000 } else { p.ts };
+                (ts.to_string(), Json::Float(p.value))
+            })
+            .collect();
+        Json::Object(vec![
+            ("metric".to_string(), Json::from(self.metric.as_str())),
+            ("tags".to_string(), Json::Object(tags)),
+            ("aggregateTags".to_string(), Json::Array(aggregate_tags)),
+            ("dps".to_string(), Json::Object(dps)),
+        ])
+    }
+}
+
+/// Answers `query` from `store` without any limits. The results of each
+/// sub-query are ordered by the values of their grouping tags
+pub fn run(store: &Store, query: &Query) -> Vec<QueryResult> {
+    run_with_limits(store, query, Limits::none()).expect("queries without limits can't exceed them")
+}
+
+/// Answers `query` from `store` within `limits`, counted over every
+/// sub-query
+pub fn run_with_limits(store: &Store, query: &Query, limits: Limits) -> Result<Vec<QueryResult>, QueryLimitExceeded> {
+    let mut tracker = Tracker::new(limits);
+    let mut results = Vec::new();
+    for sub in &query.queries {
+        results.extend(run_sub_query(store, query, sub, &mut tracker)?);
+    }
+    Ok(results)
+}
+
+fn run_sub_query(store: &Store, query: &Query, sub: &SubQuery, tracker: &mut Tracker)
+                 -> Result<Vec<QueryResult>, QueryLimitExceeded> {
+    let selected = |key: &SeriesKey| key.metric == sub.metric && sub.filters.iter().all(|f| f.matches(key));
+    let mut series: BTreeMap<&SeriesKey, Vec<Point>> = BTreeMap::new();
+    for (key, float) in store.iter().filter(|(key, _)| selected(key)) {
+        series.entry(key).or_default().extend(tracker.decode(float, query.start, query.end + 1)?);
+    }
+    for (key, int) in store.iter_ints().filter(|(key, _)| selected(key)) {
+        series.entry(key).or_default().extend(tracker.decode_int(int, query.start, query.end + 1)?);
+    }
+
+    let group_by: Vec<&str> = sub.filters.iter().filter(|f| f.group_by).map(|f| f.tag.as_str()).collect();
+    let mut groups: BTreeMap<Group, Vec<(&SeriesKey, Vec<Point>)>> = BTreeMap::new();
+    for (key, mut points) in series {
+        // stable, the last point published at a timestamp wins
+        points.sort_by_key(|p| p.ts);
+        points.reverse();
+        points.dedup_by_key(|p| p.ts);
+        points.reverse();
+        if let Some(downsample) = sub.downsample {
+            points = downsampled(points, downsample, query);
+        }
+        if let Some(options) = sub.rate {
+            points = rate(&points, options);
+        }
+        let group = match sub.aggregator {
+            Aggregator::None => Group::Series(key),
+            _ => Group::Tags(group_by.iter().map(|&tag| key.tags.get(tag).map_or("", |v| v.as_str())).collect()),
+        };
+        groups.entry(group).or_default().push((key, points));
+    }
+
+    let results = groups.into_values()
+        .map(|members| {
+            let dps = match (sub.aggregator.function(), members.as_slice()) {
+                (Some(function), _) => aggregate(&members, function, sub.aggregator.interpolates()),
+                (None, [(_, points)]) => points.clone(),
+                (None, _) => unreachable!("series aren't grouped without an aggregator"),
+            };
+            let (tags, aggregate_tags) = common_tags(members.iter().map(|&(key, _)| key));
+            QueryResult { metric: sub.metric.clone(), tags, aggregate_tags, dps }
+        })
+        .filter(|result| !result.dps.is_empty())
+        .collect();
+    Ok(results)
+}
+
+// Series aggregated together, ordered by key without an aggregator and by
+// the values of the grouping tags otherwise
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Group<'a> {
+    Series(&'a SeriesKey),
+    Tags(Vec<&'a str>),
+}
+
+fn downsampled(points: Vec<Point>, downsample: Downsample, query: &Query) -> Vec<Point> {
+    let window = Window::new(points.into_iter(), downsample.function, downsample.interval);
+    let value = match downsample.fill {
+        FillPolicy::None => return window.collect(),
+        FillPolicy::Null => f64::NAN,
+        FillPolicy::Zero => 0.0,
+    };
+    let start = query.start - query.start.rem_euclid(downsample.interval);
+    GapFill::new(window, start, query.end, downsample.interval, Fill::Constant(value))
+        .map(|(ts, value)| Point { ts, value: value.unwrap_or(f64::NAN) })
+        .collect()
+}
+
+// Returns the rate of change per second between consecutive points,
+// timestamped with the later one
+fn rate(points: &[Point], options: RateOptions) -> Vec<Point> {
+    points.windows(2)
+        .map(|pair| {
+            let (prev, next) = (pair[0], pair[1]);
+            let mut delta = next.value - prev.value;
+            if options.counter && delta < 0.0 {
+                delta = match options.counter_max {
+                    Some(max) => max - prev.value + next.value,
+                    None => next.value,
+                };
+            }
+            let mut rate = delta / (next.ts - prev.ts) as f64;
+            if options.reset_value > 0.0 && rate > options.reset_value {
+                rate = 0.0;
+            }
+            Point { ts: next.ts, value: rate }
+        })
+        .collect()
+}
+
+// Aggregates time ordered series at every timestamp of any of them
+fn aggregate(members: &[(&SeriesKey, Vec<Point>)], function: Function, interpolate: bool) -> Vec<Point> {
+    let timestamps: BTreeSet<i64> = members.iter().flat_map(|(_, points)| points.iter().map(|p| p.ts)).collect();
+    timestamps.into_iter()
+        .map(|ts| {
+            let mut acc = Accumulator::new();
+            let mut filled = false;
+            for (_, points) in members {
+                match value_at(points, ts, interpolate) {
+                    Some(value) if value.is_nan() => filled = true,
+                    Some(value) => acc.push(value),
+                    None => {}
+                }
+            }
+            let value = if acc.count() == 0 && filled { f64::NAN } else { acc.get(function) };
+            Point { ts, value }
+        })
+        .collect()
+}
+
+// Returns the value of time ordered `points` at `ts`, interpolated
+// linearly between the points around it if `interpolate` is set
+fn value_at(points: &[Point], ts: i64, interpolate: bool) -> Option<f64> {
+    match points.binary_search_by_key(&ts, |p| p.ts) {
+        Ok(i) => Some(points[i].value),
+        Err(i) if interpolate && i > 0 && i < points.len() => {
+            let (prev, next) = (points[i - 1], points[i]);
+            let weight = (ts - prev.ts) as f64 / (next.ts - prev.ts) as f64;
+            Some(prev.value + (next.value - prev.value) * weight)
+        }
+        Err(_) => None,
+    }
+}
+
+// Splits the tags of `keys` into those with a single value and the names
+// of the others
+fn common_tags<'a, I: Iterator<Item = &'a SeriesKey>>(keys: I) -> (BTreeMap<String, String>, Vec<String>) {
+    // series carrying each tag, and its values
+    let mut values: BTreeMap<&str, (usize, BTreeSet<&str>)> = BTreeMap::new();
+    let mut count = 0;
+    for key in keys {
+        count += 1;
+        for (name, value) in &key.tags {
+            let entry = values.entry(name).or_default();
+            entry.0 += 1;
+            entry.1.insert(value);
+        }
+    }
+    let mut tags = BTreeMap::new();
+    let mut aggregate_tags = Vec::new();
+    for (name, (present, values)) in values {
+        // a tag some series lack isn't common either
+        match values.iter().next() {
+            Some(value) if values.len() == 1 && present == count => {
+                tags.insert(name.to_string(), value.to_string());
+            }
+            _ => aggregate_tags.push(name.to_string()),
+        }
+    }
+    (tags, aggregate_tags)
+}
+
+/// Settings of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Batches queued up for the writer before puts wait
+    pub queue: usize,
+    /// Limits of every query
+    pub limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            queue: DEFAULT_QUEUE,
+            limits: Limits::none(),
+        }
+    }
+}
+
+/// Counters of a listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    puts: AtomicU64,
+    points: AtomicU64,
+    rejected: AtomicU64,
+    queries: AtomicU64,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of put requests received
+    pub fn puts(&self) -> u64 {
+        self.puts.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of data points stored
+    pub fn points(&self) -> u64 {
+        self.points.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of data points rejected
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of query requests received
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests failed
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last error
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn record_error(&self, error: String) {
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(error);
+    }
+
+    fn fail(&self, status: u16, message: String, details: Option<&str>) -> Response {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        self.record_error(message.clone());
+        error_response(status, &message, details)
+    }
+}
+
+// Formats an error as OpenTSDB does
+fn error_response(status: u16, message: &str, details: Option<&str>) -> Response {
+    let mut error = vec![("code".to_string(), Json::Int(i64::from(status))), ("message".to_string(), Json::from(message))];
+    if let Some(details) = details {
+        error.push(("details".to_string(), Json::from(details)));
+    }
+    json_response(status, &Json::Object(vec![("error".to_string(), Json::Object(error))]))
+}
+
+fn json_response(status: u16, json: &Json) -> Response {
+    Response::with_body(status, "application/json", json.to_string())
+}
+
+/// HTTP server answering the OpenTSDB put and query endpoints
+pub struct Listener {
+    server: Option<Server>,
+    stats: Arc<Stats>,
+    writer: Option<Writer>,
+}
+
+impl Listener {
+    /// Starts listening on `addr` for data points to write into `store`
+    /// and queries to answer from it
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Listener> {
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store.clone(), config.queue);
+        let endpoint = Endpoint {
+            store,
+            handle: writer.handle(),
+            limits: config.limits,
+            stats: stats.clone(),
+        };
+        let server = Server::bind(addr, move |request: &Request| endpoint.handle(request))?;
+        Ok(Listener {
+            server: Some(server),
+            stats,
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.as_ref().expect("server running").local_addr()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // requests in flight still hold batchers of the writer
+        self.server.take();
+        self.writer.take();
+    }
+}
+
+struct Endpoint {
+    store: Arc<RwLock<Store>>,
+    handle: Handle,
+    limits: Limits,
+    stats: Arc<Stats>,
+}
+
+impl Endpoint {
+    fn handle(&self, request: &Request) -> Response {
+        match (request.path.trim_end_matches('/'), request.method.as_str()) {
+            (PUT_PATH, "POST") => self.put(request),
+            (QUERY_PATH, "GET") | (QUERY_PATH, "POST") => self.query(request),
+            (PUT_PATH, _) => error_response(405, "Method not allowed", None),
+            (QUERY_PATH, _) => error_response(405, "Method not allowed", None),
+            _ => error_response(404, "Endpoint not found", None),
+        }
+    }
+
+    fn put(&self, request: &Request) -> Response {
+        self.stats.puts.fetch_add(1, Ordering::SeqCst);
+        let body = match parse_body(request) {
+            Ok(body) => body,
+            Err(err) => return self.stats.fail(400, err.to_string(), None),
+        };
+        let (samples, rejected) = self.check(&body);
+        let success = samples.len();
+        let mut batcher = self.handle.batcher(DEFAULT_BATCH_SIZE);
+        let sent = samples.into_iter().try_for_each(|sample| batcher.push(sample)).and_then(|_| batcher.flush());
+        if let Err(Closed) = sent {
+            return error_response(503, "Shutting down", None);
+        }
+        self.stats.points.fetch_add(success as u64, Ordering::SeqCst);
+        self.stats.rejected.fetch_add(rejected.len() as u64, Ordering::SeqCst);
+        if let Some(first) = rejected.first() {
+            self.stats.record_error(first.error.to_string());
+        }
+
+        let status = if rejected.is_empty() { 200 } else { 400 };
+        let details = request.param("details").is_some();
+        if details || request.param("summary").is_some() {
+            let mut summary = vec![("success".to_string(), Json::Int(success as i64)),
+                                   ("failed".to_string(), Json::Int(rejected.len() as i64))];
+            if details {
+                let errors = rejected.into_iter()
+                    .map(|rejected| Json::Object(vec![("datapoint".to_string(), rejected.datapoint),
+                                                     ("error".to_string(), Json::from(rejected.error.to_string()))]))
+                    .collect();
+                summary.push(("errors".to_string(), Json::Array(errors)));
+            }
+            json_response(status, &Json::Object(summary))
+        } else if rejected.is_empty() {
+            Response::new(204)
+        } else {
+            error_response(400, "One or more data points had errors",
+                           Some("Please see the TSD logs or append \"details\" to the put request"))
+        }
+    }
+
+    // Parses the data points of a put request, rejecting those the
+    // validator rejects as well as invalid ones. The writer counts the
+    // verdicts of the others
+    fn check(&self, body: &Json) -> (Vec<Sample>, Vec<Rejected>) {
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut samples = Vec::new();
+        let mut rejected = Vec::new();
+        for datapoint in datapoints(body) {
+            let checked = parse_datapoint(datapoint).and_then(|sample| match store.validator().check(&sample, now) {
+                verdict @ Verdict::Reject(reason) => {
+                    store.validator().record(verdict);
+                    Err(PutError::Rejected(reason))
+                }
+                _ => Ok(sample),
+            });
+            match checked {
+                Ok(sample) => samples.push(sample),
+                Err(error) => rejected.push(Rejected { datapoint: datapoint.clone(), error }),
+            }
+        }
+        (samples, rejected)
+    }
+
+    fn query(&self, request: &Request) -> Response {
+        self.stats.queries.fetch_add(1, Ordering::SeqCst);
+        let now = time::get_time().sec;
+        let query = if request.method == "GET" {
+            Query::from_params(request, now)
+        } else {
+            parse_body(request).and_then(|body| Query::from_json(&body, now))
+        };
+        let query = match query {
+            Ok(query) => query,
+            Err(err) => return self.stats.fail(400, err.to_string(), None),
+        };
+        let results = {
+            let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+            run_with_limits(&store, &query, self.limits)
+        };
+        let results = match results {
+            Ok(results) => results,
+            Err(err) => return self.stats.fail(400, format!("Query exceeded its limits: {}", err), None),
+        };
+        let json = results.iter().map(|result| result.to_json(query.ms_resolution)).collect();
+        json_response(200, &Json::Array(json))
+    }
+}
+
+fn parse_body(request: &Request) -> Result<Json, QueryError> {
+    let body = std::str::from_utf8(&request.body).map_err(|_| QueryError::InvalidBody("body isn't UTF-8".to_string()))?;
+    Json::parse(body).map_err(QueryError::InvalidJson)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{parse_put, parse_time, Aggregator, Config, Downsample, FillPolicy, Filter, FilterKind, Listener,
+                PutError, Query, QueryError, RateOptions, SubQuery, PUT_PATH, QUERY_PATH};
+    use aggregate::Function;
+    use http::{self, Url};
+    use ingest::Value;
+    use json::Json;
+    use limits::Limits;
+    use store::{SeriesKey, Store};
+    use time;
+    use validate::{Reason, Validation};
+
+    #[test]
+    fn test_parse_put() {
+        let body = Json::parse(r#"[
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 18, "tags": {"host": "web01"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400500, "value": "9.5", "tags": {"host": "web02"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 1},
+            {"metric": "sys cpu", "timestamp": 1346846400, "value": 1, "tags": {"host": "a"}},
+            {"metric": "sys.cpu.nice", "value": 1, "tags": {"host": "a"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1, "value": "x", "tags": {"host": "a"}},
+            {"metric": "sys.cpu.nice", "timestamp": 1, "value": 1, "tags": {"host": 1}},
+            3
+        ]"#).unwrap();
+        let (samples, rejected) = parse_put(&body);
+        assert_eq!(2, samples.len());
+        assert_eq!(SeriesKey::new("sys.cpu.nice").tag("host", "web01"), samples[0].key);
+        assert_eq!((Value::Int(18), 1346846400), (samples[0].value, samples[0].ts));
+        assert_eq!((Value::Float(9.5), 1346846400), (samples[1].value, samples[1].ts));
+        let errors: Vec<PutError> = rejected.iter().map(|r| r.error.clone()).collect();
+        assert_eq!(vec![PutError::MissingTags, PutError::InvalidMetric("sys cpu".to_string()),
+                        PutError::MissingTimestamp, PutError::InvalidValue, PutError::InvalidTag("host=1".to_string()),
+                        PutError::NotAnObject],
+                   errors);
+        assert_eq!(Json::Int(3), rejected[5].datapoint);
+
+        let (samples, rejected) = parse_put(&body.as_array().unwrap()[0]);
+        assert_eq!((1, 0), (samples.len(), rejected.len()));
+    }
+
+    #[test]
+    fn test_parse_sub_query() {
+        let query = SubQuery::parse("sum:rate{counter,,1000}:1m-avg-zero:sys.cpu.nice{host=web*,dc=lga|sjc}{env=prod}").unwrap();
+        assert_eq!(Aggregator::Sum, query.aggregator);
+        assert_eq!("sys.cpu.nice", query.metric);
+        assert_eq!(Some(RateOptions { counter: true, counter_max: None, reset_value: 1000.0 }), query.rate);
+        assert_eq!(Some(Downsample { interval: 60, function: Function::Avg, fill: FillPolicy::Zero }), query.downsample);
+        assert_eq!(vec![Filter { tag: "host".to_string(), kind: FilterKind::Wildcard("web*".to_string()), group_by: true },
+                        Filter { tag: "dc".to_string(), kind: FilterKind::LiteralOr(vec!["lga".to_string(), "sjc".to_string()]),
+                                 group_by: true },
+                        Filter { tag: "env".to_string(), kind: FilterKind::LiteralOr(vec!["prod".to_string()]), group_by: false }],
+                   query.filters);
+
+        let query = SubQuery::parse("avg:1h-max:m{instance=not_literal_or(db:9090)}").unwrap();
+        assert_eq!(None, query.rate);
+        assert_eq!(FilterKind::NotLiteralOr(vec!["db:9090".to_string()]), query.filters[0].kind);
+
+        assert_eq!(Err(QueryError::UnknownAggregator("p99".to_string())), SubQuery::parse("p99:m"));
+        assert_eq!(Err(QueryError::InvalidSubQuery("m".to_string())), SubQuery::parse("m"));
+        assert_eq!(Err(QueryError::InvalidDownsample("1x-avg".to_string())), SubQuery::parse("sum:1x-avg:m"));
+        assert_eq!(Err(QueryError::InvalidFilter("host".to_string())), SubQuery::parse("sum:m{host}"));
+        assert_eq!(Err(QueryError::InvalidSubQuery("sum:m{a=b}x".to_string())), SubQuery::parse("sum:m{a=b}x"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(Ok(1000 - 3600), parse_time("1h-ago", 1000));
+        assert_eq!(Ok(1356998400), parse_time("1356998400", 0));
+        assert_eq!(Ok(1356998400), parse_time("1356998400123", 0));
+        assert_eq!(Ok(1356998400 + 3723), parse_time("2013/01/01-01:02:03", 0));
+        assert_eq!(Ok(1356998400), parse_time("2013/01/01", 0));
+        assert_eq!(Err(QueryError::InvalidTime("yesterday".to_string())), parse_time("yesterday", 0));
+    }
+
+    fn store() -> Store {
+        let mut store = Store::new();
+        let web01 = SeriesKey::new("requests").tag("host", "web01").tag("dc", "lga");
+        let web02 = SeriesKey::new("requests").tag("host", "web02").tag("dc", "lga");
+        let db = SeriesKey::new("requests").tag("host", "db01").tag("dc", "sjc");
+        for i in 0..6 {
+            store.publish_at(&web01, (i * 10) as f64, 1000 + i * 20);
+            store.publish_int_at(&web02, i * 100, 1010 + i * 20);
+        }
+        store.publish_at(&db, 5.0, 1000);
+        store
+    }
+
+    fn query(m: &str, start: i64, end: i64) -> Query {
+        let queries = vec![SubQuery::parse(m).unwrap()];
+        Query { start, end, queries, ms_resolution: false }
+    }
+
+    fn dps(results: &[super::QueryResult], i: usize) -> Vec<(i64, f64)> {
+        results[i].dps.iter().map(|p| (p.ts, p.value)).collect()
+    }
+
+    #[test]
+    fn test_run() {
+        let store = store();
+        // web01 is interpolated at the timestamps of web02 and the other
+        // way around, within the range of each
+        let results = super::run(&store, &query("sum:requests{}{host=web*}", 1000, 1050));
+        assert_eq!(1, results.len());
+        assert_eq!(vec![(1000, 0.0), (1010, 5.0), (1020, 60.0), (1030, 115.0), (1040, 170.0), (1050, 200.0)],
+                   dps(&results, 0));
+        assert_eq!(Some(&"lga".to_string()), results[0].tags.get("dc"));
+        assert_eq!(vec!["host".to_string()], results[0].aggregate_tags);
+
+        let results = super::run(&store, &query("zimsum:requests{}{host=web*}", 1000, 1030));
+        assert_eq!(vec![(1000, 0.0), (1010, 0.0), (1020, 10.0), (1030, 100.0)], dps(&results, 0));
+
+        // grouped by dc, filtered on host without grouping
+        let results = super::run(&store, &query("max:1m-max:requests{dc=*}{host=not_literal_or(web02)}", 0, 2000));
+        assert_eq!(2, results.len());
+        assert_eq!(vec![(960, 0.0), (1020, 30.0), (1080, 50.0)], dps(&results, 0));
+        assert_eq!(vec![(960, 5.0)], dps(&results, 1));
+        assert_eq!(Some(&"sjc".to_string()), results[1].tags.get("dc"));
+
+        let results = super::run(&store, &query("none:rate:requests{host=web01|db01}", 0, 2000));
+        assert_eq!(1, results.len());
+        assert_eq!(vec![(1020, 0.5), (1040, 0.5), (1060, 0.5), (1080, 0.5), (1100, 0.5)], dps(&results, 0));
+
+        // series whose tag values are the same under other names aren't merged
+        let mut store = store;
+        store.publish_at(&SeriesKey::new("requests").tag("zone", "web01").tag("dc", "lga"), 1.0, 1000);
+        let results = super::run(&store, &query("none:requests{dc=lga}", 1000, 1010));
+        assert_eq!(vec![Some("web01"), Some("web02"), None],
+                   results.iter().map(|r| r.tags.get("host").map(|h| h.as_str())).collect::<Vec<_>>());
+        assert_eq!(vec![(1000, 1.0)], dps(&results, 2));
+
+        let results = super::run(&store, &query("sum:1m-count-null:requests{host=db01}", 900, 1100));
+        let values: Vec<String> = results[0].dps.iter().map(|p| format!("{}", p.value)).collect();
+        assert_eq!(vec!["NaN", "1", "NaN", "NaN"], values);
+        assert_eq!(r#"{"metric":"requests","tags":{"dc":"sjc","host":"db01"},"aggregateTags":[],"dps":{"900":null,"960":1,"1020":null,"1080":null}}"#,
+                   results[0].to_json(false).to_string());
+    }
+
+    #[test]
+    fn test_rate_counter() {
+        let mut store = Store::new();
+        let key = SeriesKey::new("bytes").tag("host", "a");
+        for &(ts, value) in &[(0, 90), (10, 110), (20, 10), (30, 250)] {
+            store.publish_int_at(&key, value, ts);
+        }
+        let results = super::run(&store, &query("sum:rate{counter,120}:bytes{host=a}", 0, 100));
+        assert_eq!(vec![(10, 2.0), (20, 2.0), (30, 24.0)], dps(&results, 0));
+        let results = super::run(&store, &query("sum:rate{counter,,20}:bytes{host=a}", 0, 100));
+        assert_eq!(vec![(10, 2.0), (20, 1.0), (30, 0.0)], dps(&results, 0));
+    }
+
+    #[test]
+    fn test_listener() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = |path: &str| Url::parse(&format!("http://{}{}", listener.local_addr(), path)).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let body = br#"[{"metric": "sys.cpu.nice", "timestamp": 1346846400, "value": 18, "tags": {"host": "web01"}},
+                        {"metric": "sys.cpu.nice", "timestamp": 1346846460, "value": 20.5, "tags": {"host": "web02"}}]"#;
+        assert_eq!(204, http::post(&url(PUT_PATH), &[], body, timeout).unwrap().status);
+        let response = http::post(&url("/api/put?details"), &[], br#"{"metric": "sys.cpu.nice", "value": 1}"#, timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(r#"{"success":0,"failed":1,"errors":[{"datapoint":{"metric":"sys.cpu.nice","value":1},"error":"Missing timestamp"}]}"#,
+                   String::from_utf8_lossy(&response.body));
+        let response = http::post(&url("/api/put?summary"), &[], b"{}", timeout).unwrap();
+        assert_eq!(r#"{"success":0,"failed":1}"#, String::from_utf8_lossy(&response.body));
+        let response = http::post(&url(PUT_PATH), &[], b"[1]", timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert!(String::from_utf8_lossy(&response.body).contains("\"details\":\"Please see the TSD logs"));
+
+        let deadline = Instant::now() + timeout;
+        while listener.written() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(2, listener.written());
+
+        let response = http::get(&url("/api/query?start=1346846400&end=1346846460&m=sum:sys.cpu.nice"), &[], timeout).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(r#"[{"metric":"sys.cpu.nice","tags":{},"aggregateTags":["host"],"dps":{"1346846400":18,"1346846460":20.5}}]"#,
+                   String::from_utf8_lossy(&response.body));
+
+        let body = br#"{"start": 1346846400, "end": "1346846460000", "msResolution": true,
+                        "queries": [{"aggregator": "sum", "metric": "sys.cpu.nice", "tags": {"host": "web01"}}]}"#;
+        let response = http::post(&url(QUERY_PATH), &[], body, timeout).unwrap();
+        assert_eq!(r#"[{"metric":"sys.cpu.nice","tags":{"host":"web01"},"aggregateTags":[],"dps":{"1346846400000":18}}]"#,
+                   String::from_utf8_lossy(&response.body));
+
+        let response = http::get(&url("/api/query?m=sum:sys.cpu.nice"), &[], timeout).unwrap();
+        assert_eq!(400, response.status);
+        assert_eq!(r#"{"error":{"code":400,"message":"Missing start time"}}"#, String::from_utf8_lossy(&response.body));
+        assert_eq!(404, http::get(&url("/api/unknown"), &[], timeout).unwrap().status);
+        assert_eq!((4, 2, 3), (listener.stats().puts(), listener.stats().points(), listener.stats().rejected()));
+    }
+
+    #[test]
+    fn test_put_validation() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().set_validation(Validation { max_past_age: Some(3600), ..Validation::default() });
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = Url::parse(&format!("http://{}/api/put?details", listener.local_addr())).unwrap();
+        let now = time::get_time().sec;
+        let body = format!(r#"[{{"metric": "cpu", "timestamp": {}, "value": 1, "tags": {{"host": "a"}}}},
+                               {{"metric": "cpu", "timestamp": 1346846400, "value": 2, "tags": {{"host": "a"}}}}]"#,
+                           now);
+        let response = http::post(&url, &[], body.as_bytes(), Duration::from_secs(5)).unwrap();
+        assert_eq!(400, response.status);
+        let datapoint = r#"{"metric":"cpu","timestamp":1346846400,"value":2,"tags":{"host":"a"}}"#;
+        assert_eq!(format!(r#"{{"success":1,"failed":1,"errors":[{{"datapoint":{},"error":"Rejected by validation: too_old"}}]}}"#,
+                           datapoint),
+                   String::from_utf8_lossy(&response.body));
+        assert_eq!(1, store.read().unwrap().validator().rejected(Reason::TooOld));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.written() < 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let key = SeriesKey::new("cpu").tag("host", "a");
+        assert_eq!(vec![now], store.read().unwrap().get_int(&key).unwrap().points(0, i64::MAX).iter().map(|p| p.ts).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let store = Arc::new(RwLock::new(store()));
+        let config = Config { limits: Limits::none().max_series(2), ..Config::default() };
+        let listener = Listener::bind("127.0.0.1:0", store, config).unwrap();
+        let get = |path: &str| {
+            let url = Url::parse(&format!("http://{}{}", listener.local_addr(), path)).unwrap();
+            let response = http::get(&url, &[], Duration::from_secs(5)).unwrap();
+            (response.status, String::from_utf8_lossy(&response.body).into_owned())
+        };
+        assert_eq!(200, get("/api/query?start=1000&end=1100&m=sum:requests{host=web*}").0);
+        assert_eq!((400, r#"{"error":{"code":400,"message":"Query exceeded its limits: query touched more than 2 series"}}"#
+                        .to_string()),
+                   get("/api/query?start=1000&end=1100&m=sum:requests"));
+        assert_eq!(1, listener.stats().failures());
+    }
+}
@@ -0,0 +1,688 @@
+//! Prometheus text exposition format and scraping.
+//!
+//! Targets expose their metrics over HTTP as text:
+//!
+//! ```text
+//! # HELP http_requests_total Requests handled.
+//! # TYPE http_requests_total counter
+//! http_requests_total{method="get",code="200"} 1027 1395066363000
+//! # TYPE latency_seconds histogram
+//! latency_seconds_bucket{le="0.1"} 12
+//! latency_seconds_bucket{le="+Inf"} 15
+//! latency_seconds_sum 1.7
+//! latency_seconds_count 15
+//! ```
+//!
+//! Samples are grouped into the families announced by `TYPE` and `HELP`
+//! lines, the `_bucket`, `_sum` and `_count` samples of histograms and
+//! summaries belonging to their base name. Every sample is stored under
+//! its own name and labels, as Prometheus does, with its millisecond
+//! timestamp truncated to seconds. A `Scraper` polls targets periodically
+//! and labels their samples with the `job` and `instance` they came from.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use time;
+
+use http::{self, Url};
+use ingest::{Batcher, Closed, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use store::{SeriesKey, Store};
+
+/// Default time between two scrapes of a target
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default time allowed for a scrape
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Type of a metric family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Summary,
+    Untyped,
+}
+
+impl MetricType {
+    fn from_name(name: &str) -> Option<MetricType> {
+        match name {
+            "counter" => Some(MetricType::Counter),
+            "gauge" => Some(MetricType::Gauge),
+            "histogram" => Some(MetricType::Histogram),
+            "summary" => Some(MetricType::Summary),
+            "untyped" => Some(MetricType::Untyped),
+            _ => None,
+        }
+    }
+
+    // Suffixes of the samples belonging to a family of this type
+    fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            MetricType::Histogram => &["_bucket", "_sum", "_count"],
+            MetricType::Summary => &["_sum", "_count"],
+            _ => &[],
+        }
+    }
+}
+
+/// Samples sharing a metric name, or a base name for histograms and
+/// summaries
+#[derive(Clone, Debug, PartialEq)]
+pub struct Family {
+    pub name: String,
+    pub help: Option<String>,
+    pub kind: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+/// Reasons a line can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A `HELP` or `TYPE` line without a metric name
+    InvalidMetadata,
+    UnknownType(String),
+    /// A second `HELP` or `TYPE` line for the same family
+    DuplicateMetadata(String),
+    InvalidName(String),
+    InvalidLabels(String),
+    /// A histogram bucket without a valid `le` label
+    InvalidBucket(String),
+    InvalidValue(String),
+    InvalidTimestamp(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidMetadata => write!(f, "metadata line without metric name"),
+            ParseError::UnknownType(ref kind) => write!(f, "unknown metric type {:?}", kind),
+            ParseError::DuplicateMetadata(ref name) => write!(f, "duplicate metadata for {:?}", name),
+            ParseError::InvalidName(ref name) => write!(f, "invalid metric name {:?}", name),
+            ParseError::InvalidLabels(ref labels) => write!(f, "invalid labels {:?}", labels),
+            ParseError::InvalidBucket(ref name) => write!(f, "bucket of {:?} without a valid le label", name),
+            ParseError::InvalidValue(ref value) => write!(f, "invalid value {:?}", value),
+            ParseError::InvalidTimestamp(ref ts) => write!(f, "invalid timestamp {:?}", ts),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Error of a line of an exposition
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineError {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub error: ParseError,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl error::Error for LineError {}
+
+/// Parses an exposition scraped at `now` seconds from the epoch into its
+/// families, in the order they first appear
+pub fn parse(text: &str, now: i64) -> Result<Vec<Family>, LineError> {
+    let mut parser = Parser::default();
+    for (i, line) in text.lines().enumerate() {
+        parser.line(line.trim(), now).map_err(|error| LineError { line: i + 1, error })?;
+    }
+    Ok(parser.families)
+}
+
+#[derive(Default)]
+struct Parser {
+    families: Vec<Family>,
+    index: FnvHashMap<String, usize>,
+    // families whose type was declared
+    typed: Vec<bool>,
+}
+
+impl Parser {
+    fn line(&mut self, line: &str, now: i64) -> Result<(), ParseError> {
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            return self.comment(comment.trim_start());
+        }
+        let (name, sample) = parse_sample(line, now)?;
+        let family = self.family_of(&name);
+        self.families[family].samples.push(sample);
+        Ok(())
+    }
+
+    fn comment(&mut self, comment: &str) -> Result<(), ParseError> {
+        let (keyword, rest) = comment.split_once([' ', '\t']).unwrap_or((comment, ""));
+        if keyword != "HELP" && keyword != "TYPE" {
+            // other comments are free form
+            return Ok(());
+        }
+        let rest = rest.trim_start();
+        let (name, text) = rest.split_once([' ', '\t']).unwrap_or((rest, ""));
+        if name.is_empty() {
+            return Err(ParseError::InvalidMetadata);
+        }
+        if !is_metric_name(name) {
+            return Err(ParseError::InvalidName(name.to_string()));
+        }
+        let family = self.family(name);
+        if keyword == "HELP" {
+            if self.families[family].help.is_some() {
+                return Err(ParseError::DuplicateMetadata(name.to_string()));
+            }
+            self.families[family].help = Some(unescape_help(text.trim_start()));
+        } else {
+            let text = text.trim();
+            let kind = MetricType::from_name(text).ok_or_else(|| ParseError::UnknownType(text.to_string()))?;
+            if self.typed[family] {
+                return Err(ParseError::DuplicateMetadata(name.to_string()));
+            }
+            self.typed[family] = true;
+            self.families[family].kind = kind;
+        }
+        Ok(())
+    }
+
+    // Returns the family named `name`, creating it if needed
+    fn family(&mut self, name: &str) -> usize {
+        if let Some(&family) = self.index.get(name) {
+            return family;
+        }
+        self.families.push(Family {
+            name: name.to_string(),
+            help: None,
+            kind: MetricType::Untyped,
+            samples: Vec::new(),
+        });
+        self.typed.push(false);
+        self.index.insert(name.to_string(), self.families.len() - 1);
+        self.families.len() - 1
+    }
+
+    // Returns the family a sample named `name` belongs to
+    fn family_of(&mut self, name: &str) -> usize {
+        if let Some(&family) = self.index.get(name) {
+            return family;
+        }
+        for suffix in &["_bucket", "_sum", "_count"] {
+            if let Some(base) = name.strip_suffix(suffix) {
+                if let Some(&family) = self.index.get(base) {
+                    if self.families[family].kind.suffixes().contains(suffix) {
+                        return family;
+                    }
+                }
+            }
+        }
+        self.family(name)
+    }
+}
+
+// Parses `name{labels} value [timestamp]`, returning the metric name
+// along with the sample
+fn parse_sample(line: &str, now: i64) -> Result<(String, Sample), ParseError> {
+    let end = line.find(['{', ' ', '\t']).unwrap_or(line.len());
+    let name = &line[..end];
+    if !is_metric_name(name) {
+        return Err(ParseError::InvalidName(name.to_string()));
+    }
+    let mut key = SeriesKey::new(name);
+    let mut rest = &line[end..];
+    if rest.starts_with('{') {
+        let (labels, after) = parse_labels(rest).ok_or_else(|| ParseError::InvalidLabels(rest.to_string()))?;
+        for (label, value) in labels {
+            key = key.tag(label, value);
+        }
+        rest = after;
+    }
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next().unwrap_or("");
+    let value = match value {
+        "+Inf" | "Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        "NaN" => f64::NAN,
+        _ => value.parse().map_err(|_| ParseError::InvalidValue(value.to_string()))?,
+    };
+    let ts = match fields.next() {
+        Some(ts) => {
+            let ms: i64 = ts.parse().map_err(|_| ParseError::InvalidTimestamp(ts.to_string()))?;
+            ms.div_euclid(1000)
+        }
+        None => now,
+    };
+    if let Some(extra) = fields.next() {
+        return Err(ParseError::InvalidTimestamp(extra.to_string()));
+    }
+    if name.ends_with("_bucket") && key.tags.get("le").is_none_or(|le| parse_bound(le).is_none()) {
+        return Err(ParseError::InvalidBucket(name.to_string()));
+    }
+    Ok((name.to_string(), Sample { key, value: Value::Float(value), ts }))
+}
+
+// Parses `{name="value",...}`, returning the labels and what follows them
+fn parse_labels(s: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut labels = Vec::new();
+    let mut rest = s.strip_prefix('{')?;
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('}') {
+            return Some((labels, after));
+        }
+        let end = rest.find(|c: char| c == '=' || c.is_whitespace())?;
+        let name = &rest[..end];
+        if !is_label_name(name) {
+            return None;
+        }
+        rest = rest[end..].trim_start().strip_prefix('=')?.trim_start().strip_prefix('"')?;
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        let close = loop {
+            match chars.next()? {
+                (i, '"') => break i,
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                (_, c) => value.push(c),
+            }
+        };
+        labels.push((name.to_string(), value));
+        rest = rest[close + 1..].trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after;
+        } else if !rest.starts_with('}') {
+            return None;
+        }
+    }
+}
+
+// Parses the `le` bound of a bucket
+fn parse_bound(le: &str) -> Option<f64> {
+    match le {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        _ => le.parse().ok().filter(|le: &f64| !le.is_nan()),
+    }
+}
+
+fn unescape_help(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Endpoint exposing metrics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    /// Value of the `job` label of the samples of the target
+    pub job: String,
+    pub url: Url,
+}
+
+impl Target {
+    /// Returns the value of the `instance` label of the samples of the target
+    pub fn instance(&self) -> String {
+        self.url.authority()
+    }
+}
+
+/// Reasons a scrape can fail
+#[derive(Debug)]
+pub enum ScrapeError {
+    Io(io::Error),
+    /// The target answered with a status other than 200
+    Status(u16),
+    Parse(LineError),
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScrapeError::Io(ref err) => write!(f, "{}", err),
+            ScrapeError::Status(status) => write!(f, "unexpected status {}", status),
+            ScrapeError::Parse(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ScrapeError {}
+
+impl From<io::Error> for ScrapeError {
+    fn from(err: io::Error) -> ScrapeError {
+        ScrapeError::Io(err)
+    }
+}
+
+impl From<LineError> for ScrapeError {
+    fn from(err: LineError) -> ScrapeError {
+        ScrapeError::Parse(err)
+    }
+}
+
+/// Scrapes `target` at `now` seconds from the epoch, returning its samples
+/// labelled with the job and instance of the target. Labels of the target
+/// that clash with those of the samples are renamed `exported_job` and
+/// `exported_instance`
+pub fn scrape(target: &Target, timeout: Duration, now: i64) -> Result<Vec<Sample>, ScrapeError> {
+    let accept = [("Accept", "text/plain;version=0.0.4")];
+    let response = http::get(&target.url, &accept, timeout)?;
+    if response.status != 200 {
+        return Err(ScrapeError::Status(response.status));
+    }
+    let text = String::from_utf8_lossy(&response.body);
+    let instance = target.instance();
+    let mut samples = Vec::new();
+    for family in parse(&text, now)? {
+        for mut sample in family.samples {
+            for &(label, value) in &[("job", target.job.as_str()), ("instance", instance.as_str())] {
+                if let Some(exported) = sample.key.tags.remove(label) {
+                    sample.key.tags.insert(format!("exported_{}", label), exported);
+                }
+                sample.key.tags.insert(label.to_string(), value.to_string());
+            }
+            samples.push(sample);
+        }
+    }
+    Ok(samples)
+}
+
+/// Settings of a scraper
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub targets: Vec<Target>,
+    /// Time between two scrapes of every target
+    pub interval: Duration,
+    /// Time allowed for the scrape of a target
+    pub timeout: Duration,
+    /// Batches queued up for the writer before scrapes wait
+    pub queue: usize,
+}
+
+impl Config {
+    /// Creates settings scraping `targets` with the default interval and timeout
+    pub fn new(targets: Vec<Target>) -> Config {
+        Config {
+            targets,
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            queue: DEFAULT_QUEUE,
+        }
+    }
+}
+
+/// Counters of a scraper
+#[derive(Debug, Default)]
+pub struct Stats {
+    scrapes: AtomicU64,
+    failures: AtomicU64,
+    samples: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of scrapes attempted
+    pub fn scrapes(&self) -> u64 {
+        self.scrapes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of failed scrapes
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of samples scraped
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last failed scrape
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+}
+
+/// Thread scraping targets periodically into a store. Every scrape also
+/// publishes an `up` sample, 1 if it succeeded and 0 otherwise
+pub struct Scraper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    stats: Arc<Stats>,
+    writer: Option<Writer>,
+}
+
+impl Scraper {
+    /// Spawns a thread scraping the targets of `config` into `store`,
+    /// starting right away
+    pub fn spawn(store: Arc<RwLock<Store>>, config: Config) -> Scraper {
+        let (stop, stopped) = mpsc::channel();
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store, config.queue);
+        let mut batcher = writer.batcher(DEFAULT_BATCH_SIZE);
+        let counters = stats.clone();
+        let handle = thread::spawn(move || {
+            loop {
+                for target in &config.targets {
+                    if scrape_into(target, config.timeout, &counters, &mut batcher).is_err() {
+                        return;
+                    }
+                }
+                match stopped.recv_timeout(config.interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        Scraper {
+            stop: Some(stop),
+            handle: Some(handle),
+            stats,
+            writer: Some(writer),
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Scraper {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.writer.take();
+    }
+}
+
+fn scrape_into(target: &Target, timeout: Duration, stats: &Stats, batcher: &mut Batcher) -> Result<(), Closed> {
+    let now = time::get_time().sec;
+    stats.scrapes.fetch_add(1, Ordering::SeqCst);
+    let up = match scrape(target, timeout, now) {
+        Ok(samples) => {
+            stats.samples.fetch_add(samples.len() as u64, Ordering::SeqCst);
+            for sample in samples {
+                batcher.push(sample)?;
+            }
+            1.0
+        }
+        Err(err) => {
+            stats.failures.fetch_add(1, Ordering::SeqCst);
+            let message = format!("{}: {}", target.url.authority(), err);
+            *stats.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(message);
+            0.0
+        }
+    };
+    let key = SeriesKey::new("up").tag("job", target.job.as_str()).tag("instance", target.instance());
+    batcher.push(Sample { key, value: Value::Float(up), ts: now })?;
+    batcher.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse, Config, LineError, MetricType, ParseError, Scraper, Target};
+    use http::Url;
+    use ingest::Value;
+    use store::{SeriesKey, Store};
+
+    const EXPOSITION: &str = "\
+# HELP http_requests_total Requests\\nhandled.
+# TYPE http_requests_total counter
+http_requests_total{method=\"get\",code=\"200\"} 1027 1395066363000
+http_requests_total{method=\"post\", path=\"a\\\"b\\\\c\",} 3 1395066363000
+
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 12
+latency_seconds_bucket{le=\"+Inf\"} 15
+latency_seconds_sum 1.7
+latency_seconds_count 15
+# a comment
+temperature -Inf
+";
+
+    #[test]
+    fn test_parse() {
+        let families = parse(EXPOSITION, 42).unwrap();
+        assert_eq!(3, families.len());
+
+        let requests = &families[0];
+        assert_eq!("http_requests_total", requests.name);
+        assert_eq!(Some("Requests\nhandled."), requests.help.as_deref());
+        assert_eq!(MetricType::Counter, requests.kind);
+        assert_eq!(SeriesKey::new("http_requests_total").tag("method", "get").tag("code", "200"),
+                   requests.samples[0].key);
+        assert_eq!(Value::Float(1027.0), requests.samples[0].value);
+        assert_eq!(1395066363, requests.samples[0].ts);
+        assert_eq!(Some("a\"b\\c"), requests.samples[1].key.tags.get("path").map(String::as_str));
+
+        let latency = &families[1];
+        assert_eq!(MetricType::Histogram, latency.kind);
+        let names: Vec<String> = latency.samples.iter().map(|s| s.key.to_string()).collect();
+        assert_eq!(vec!["latency_seconds_bucket{le=\"0.1\"}",
+                        "latency_seconds_bucket{le=\"+Inf\"}",
+                        "latency_seconds_sum",
+                        "latency_seconds_count"],
+                   names);
+        assert_eq!(42, latency.samples[0].ts);
+
+        assert_eq!(MetricType::Untyped, families[2].kind);
+        assert_eq!(Value::Float(f64::NEG_INFINITY), families[2].samples[0].value);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| parse(text, 0).unwrap_err();
+        assert_eq!(LineError { line: 2, error: ParseError::UnknownType("meter".to_string()) },
+                   error("# TYPE a counter\n# TYPE b meter"));
+        assert_eq!(ParseError::DuplicateMetadata("a".to_string()), error("# TYPE a counter\n# TYPE a gauge").error);
+        assert_eq!(ParseError::InvalidMetadata, error("# HELP").error);
+        assert_eq!(ParseError::InvalidName("1a".to_string()), error("1a 1").error);
+        assert_eq!(ParseError::InvalidLabels("{a=b} 1".to_string()), error("a{a=b} 1").error);
+        assert_eq!(ParseError::InvalidLabels("{a=\"b\" c=\"d\"} 1".to_string()), error("a{a=\"b\" c=\"d\"} 1").error);
+        assert_eq!(ParseError::InvalidValue("x".to_string()), error("a x").error);
+        assert_eq!(ParseError::InvalidValue("".to_string()), error("a").error);
+        assert_eq!(ParseError::InvalidTimestamp("1.5".to_string()), error("a 1 1.5").error);
+        assert_eq!(ParseError::InvalidBucket("a_bucket".to_string()),
+                   error("# TYPE a histogram\na_bucket{le=\"x\"} 1").error);
+    }
+
+    #[test]
+    fn test_scraper() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/metrics", listener.local_addr().unwrap())).unwrap();
+        let instance = url.authority();
+        // stub target answering every scrape with the same exposition
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let body = "# TYPE jobs gauge\njobs{job=\"batch\"} 3\n";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        let missing = Url::parse(&format!("http://{}/missing", "127.0.0.1:1")).unwrap();
+
+        let store = Arc::new(RwLock::new(Store::new()));
+        let mut config = Config::new(vec![Target { job: "node".to_string(), url },
+                                          Target { job: "down".to_string(), url: missing }]);
+        config.interval = Duration::from_millis(10);
+        config.timeout = Duration::from_secs(1);
+        let scraper = Scraper::spawn(store.clone(), config);
+        for _ in 0..1000 {
+            if scraper.stats().scrapes() >= 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(scraper);
+
+        let store = store.read().unwrap();
+        let key = SeriesKey::new("jobs")
+            .tag("exported_job", "batch")
+            .tag("job", "node")
+            .tag("instance", instance.as_str());
+        let points = store.get(&key).unwrap().points(0, i64::MAX);
+        assert!(points.len() >= 2);
+        assert!(points.iter().all(|p| p.value == 3.0));
+        let up = SeriesKey::new("up").tag("job", "node").tag("instance", instance.as_str());
+        assert_eq!(1.0, store.get(&up).unwrap().points(0, i64::MAX)[0].value);
+        let down = SeriesKey::new("up").tag("job", "down").tag("instance", "127.0.0.1:1");
+        assert_eq!(0.0, store.get(&down).unwrap().points(0, i64::MAX)[0].value);
+    }
+}
@@ -0,0 +1,253 @@
+//! Protocol buffers wire format, enough to read and write the messages of
+//! the Prometheus remote storage protocol without generated code.
+//!
+//! A message is a sequence of fields, each made of a key holding the field
+//! number and wire type, followed by the value:
+//!
+//! |-------------------------------|-------|
+//! | key: field << 3 | wire type   | value |
+//! |-------------------------------|-------|
+//!
+//! Only the varint, 64-bit and length-delimited wire types are read, the
+//! 32-bit one is skipped. Groups are deprecated and rejected.
+//! Reference: https://protobuf.dev/programming-guides/encoding/
+
+use std::error;
+use std::fmt;
+use std::str;
+
+/// Wire type of `int32`, `int64`, `uint64`, `bool` and enums
+pub const VARINT: u8 = 0;
+/// Wire type of `fixed64` and `double`
+pub const FIXED64: u8 = 1;
+/// Wire type of strings, bytes, messages and packed repeated fields
+pub const LENGTH_DELIMITED: u8 = 2;
+/// Wire type of `fixed32` and `float`
+pub const FIXED32: u8 = 5;
+
+/// Reasons a message can't be decoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The message ends within a field
+    Truncated,
+    /// A varint is longer than 10 bytes
+    InvalidVarint,
+    UnsupportedWireType(u8),
+    /// A field doesn't have the wire type of its declaration
+    UnexpectedWireType { field: u32, wire_type: u8 },
+    InvalidUtf8,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated => write!(f, "truncated message"),
+            Error::InvalidVarint => write!(f, "invalid varint"),
+            Error::UnsupportedWireType(wire_type) => write!(f, "unsupported wire type {}", wire_type),
+            Error::UnexpectedWireType { field, wire_type } => {
+                write!(f, "unexpected wire type {} for field {}", wire_type, field)
+            }
+            Error::InvalidUtf8 => write!(f, "string isn't valid UTF-8"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Reads the fields of an encoded message in order
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Creates a reader over the encoded message `buf`
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    /// Returns whether every field has been read
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Reads the key of the next field, returning its number and wire
+    /// type, or `None` at the end of the message
+    pub fn next_field(&mut self) -> Result<Option<(u32, u8)>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        Ok(Some(((key >> 3) as u32, (key & 0b111) as u8)))
+    }
+
+    /// Reads a varint value
+    pub fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..70).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or(Error::Truncated)?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f).checked_shl(shift).unwrap_or(0);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::InvalidVarint)
+    }
+
+    /// Reads a little endian 64-bit value
+    pub fn fixed64(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?;
+        let mut word = [0; 8];
+        word.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    /// Reads a `double` value
+    pub fn double(&mut self) -> Result<f64, Error> {
+        self.fixed64().map(f64::from_bits)
+    }
+
+    /// Reads a length-delimited value
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.varint()?;
+        if len > (self.buf.len() - self.pos) as u64 {
+            return Err(Error::Truncated);
+        }
+        self.take(len as usize)
+    }
+
+    /// Reads a `string` value
+    pub fn string(&mut self) -> Result<&'a str, Error> {
+        str::from_utf8(self.bytes()?).map_err(|_| Error::InvalidUtf8)
+    }
+
+    /// Skips the value of a field of an unknown number
+    pub fn skip(&mut self, wire_type: u8) -> Result<(), Error> {
+        match wire_type {
+            VARINT => self.varint().map(|_| ()),
+            FIXED64 => self.take(8).map(|_| ()),
+            LENGTH_DELIMITED => self.bytes().map(|_| ()),
+            FIXED32 => self.take(4).map(|_| ()),
+            _ => Err(Error::UnsupportedWireType(wire_type)),
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or(Error::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+}
+
+/// Checks that `field` has the wire type `expected`
+pub fn expect(field: u32, wire_type: u8, expected: u8) -> Result<(), Error> {
+    if wire_type == expected {
+        Ok(())
+    } else {
+        Err(Error::UnexpectedWireType { field, wire_type })
+    }
+}
+
+/// Encodes a message field by field
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Creates a writer for an empty message
+    pub fn new() -> Writer {
+        Writer::default()
+    }
+
+    /// Writes a varint field, negative `int64` values are written as their
+    /// two's complement
+    pub fn varint(&mut self, field: u32, value: u64) {
+        self.key(field, VARINT);
+        self.raw_varint(value);
+    }
+
+    /// Writes a `double` field
+    pub fn double(&mut self, field: u32, value: f64) {
+        self.key(field, FIXED64);
+        self.buf.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+
+    /// Writes a length-delimited field
+    pub fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, LENGTH_DELIMITED);
+        self.raw_varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    /// Writes a `string` field
+    pub fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    /// Writes an embedded message field
+    pub fn message(&mut self, field: u32, message: &Writer) {
+        self.bytes(field, &message.buf);
+    }
+
+    /// Returns the encoded message
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.raw_varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Reader, Writer, FIXED32, FIXED64, LENGTH_DELIMITED, VARINT};
+
+    #[test]
+    fn test_round_trip() {
+        let mut inner = Writer::new();
+        inner.string(1, "job");
+        let mut writer = Writer::new();
+        writer.varint(1, 150);
+        writer.varint(2, -2i64 as u64);
+        writer.double(3, 0.5);
+        writer.message(4, &inner);
+        let buf = writer.into_vec();
+        // the example of the encoding guide
+        assert_eq!(&[0x08, 0x96, 0x01], &buf[..3]);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(Ok(Some((1, VARINT))), reader.next_field());
+        assert_eq!(Ok(150), reader.varint());
+        assert_eq!(Ok(Some((2, VARINT))), reader.next_field());
+        assert_eq!(Ok(-2), reader.varint().map(|v| v as i64));
+        assert_eq!(Ok(Some((3, FIXED64))), reader.next_field());
+        assert_eq!(Ok(0.5), reader.double());
+        assert_eq!(Ok(Some((4, LENGTH_DELIMITED))), reader.next_field());
+        let mut inner = Reader::new(reader.bytes().unwrap());
+        assert_eq!(Ok(Some((1, LENGTH_DELIMITED))), inner.next_field());
+        assert_eq!(Ok("job"), inner.string());
+        assert_eq!(Ok(None), inner.next_field());
+        assert_eq!(Ok(None), reader.next_field());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(Err(Error::Truncated), Reader::new(&[0x96]).varint());
+        assert_eq!(Err(Error::InvalidVarint), Reader::new(&[0xff; 11]).varint());
+        assert_eq!(Err(Error::Truncated), Reader::new(&[5, b'a']).bytes());
+        assert_eq!(Err(Error::InvalidUtf8), Reader::new(&[1, 0xff]).string());
+        assert_eq!(Err(Error::Truncated), Reader::new(&[1, 2]).skip(FIXED32));
+        assert_eq!(Err(Error::UnsupportedWireType(3)), Reader::new(&[]).skip(3));
+    }
+}
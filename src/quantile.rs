@@ -0,0 +1,727 @@
+//! Quantile estimation over windows of points.
+//!
+//! Small windows are answered exactly from their values. Once a window
+//! holds too many values to keep around, they are folded into a
+//! `DDSketch`, which bounds the relative error of every quantile and can
+//! be merged with other sketches, e.g. those of other series or windows.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::f64;
+use std::fmt;
+
+use series::Series;
+use Point;
+
+/// Default relative accuracy of sketches (1%)
+pub const DEFAULT_ALPHA: f64 = 0.01;
+
+/// Default number of values kept before switching to a sketch
+pub const DEFAULT_EXACT_LIMIT: usize = 1024;
+
+// Magnitudes below this are counted as zero
+const MIN_INDEXABLE: f64 = 1e-9;
+
+/// Returns the `q` quantile of `values` (0 <= `q` <= 1), interpolating
+/// linearly between the closest ranks. NaN values are ignored. Returns
+/// `None` if there are no values
+pub fn quantile(values: &[f64], q: f64) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.iter().cloned().filter(|v| !v.is_nan()).collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    if q < 0.0 {
+        return Some(f64::NEG_INFINITY);
+    }
+    if q > 1.0 {
+        return Some(f64::INFINITY);
+    }
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let rank = q * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    let weight = rank - lower as f64;
+    Some(sorted[lower] * (1.0 - weight) + sorted[upper] * weight)
+}
+
+/// Error raised when merging sketches of different relative accuracy,
+/// whose buckets don't line up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccuracyMismatch {
+    /// Accuracy of the sketch merged into
+    pub expected: f64,
+    /// Accuracy of the sketch merged
+    pub actual: f64,
+}
+
+impl fmt::Display for AccuracyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't merge a sketch of accuracy {} into one of accuracy {}", self.actual, self.expected)
+    }
+}
+
+impl error::Error for AccuracyMismatch {}
+
+/// A mergeable quantile sketch with relative error guarantees
+/// (DDSketch, Masson et al. 2019).
+///
+/// Values are counted in logarithmically sized buckets so that every
+/// estimated quantile is within `alpha` relative error of the true one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DDSketch {
+    alpha: f64,
+    gamma_ln: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for DDSketch {
+    fn default() -> DDSketch {
+        DDSketch::new(DEFAULT_ALPHA)
+    }
+}
+
+impl DDSketch {
+    /// Creates an empty sketch with relative accuracy `alpha` (0 < `alpha` < 1)
+    pub fn new(alpha: f64) -> DDSketch {
+        assert!(alpha > 0.0 && alpha < 1.0, "sketch accuracy must be within (0, 1)");
+        let gamma = (1.0 + alpha) / (1.0 - alpha);
+        DDSketch {
+            alpha,
+            gamma_ln: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Returns the relative accuracy of the sketch
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    /// Returns the number of values added to the sketch
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Adds a value to the sketch. NaN values are ignored
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        if value > MIN_INDEXABLE {
+            *self.positive.entry(self.index(value)).or_insert(0) += 1;
+        } else if value < -MIN_INDEXABLE {
+            *self.negative.entry(self.index(-value)).or_insert(0) += 1;
+        } else {
+            self.zero += 1;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Adds every value counted by `other` to the sketch. Fails, leaving
+    /// the sketch unchanged, if the sketches don't share the same accuracy
+    pub fn merge(&mut self, other: &DDSketch) -> Result<(), AccuracyMismatch> {
+        self.check_alpha(other)?;
+        for (&index, &count) in &other.positive {
+            *self.positive.entry(index).or_insert(0) += count;
+        }
+        for (&index, &count) in &other.negative {
+            *self.negative.entry(index).or_insert(0) += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        Ok(())
+    }
+
+    fn check_alpha(&self, other: &DDSketch) -> Result<(), AccuracyMismatch> {
+        if self.alpha == other.alpha {
+            Ok(())
+        } else {
+            Err(AccuracyMismatch { expected: self.alpha, actual: other.alpha })
+        }
+    }
+
+    /// Returns the estimated `q` quantile (0 <= `q` <= 1) of the values
+    /// added to the sketch, or `None` if the sketch is empty
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        if q < 0.0 {
+            return Some(f64::NEG_INFINITY);
+        }
+        if q > 1.0 {
+            return Some(f64::INFINITY);
+        }
+        let rank = (q * (self.count - 1) as f64).floor() as u64;
+        if rank == 0 {
+            return Some(self.min);
+        }
+        if rank == self.count - 1 {
+            return Some(self.max);
+        }
+        let mut seen = 0;
+        // from the most negative value to the most positive one
+        for (&index, &count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(self.clamp(-self.value(index)));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(self.clamp(0.0));
+        }
+        for (&index, &count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return Some(self.clamp(self.value(index)));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Serializes the sketch so it can be stored alongside rollups
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.alpha.to_le_bytes());
+        buf.extend_from_slice(&self.zero.to_le_bytes());
+        buf.extend_from_slice(&self.min.to_le_bytes());
+        buf.extend_from_slice(&self.max.to_le_bytes());
+        for bins in &[&self.positive, &self.negative] {
+            buf.extend_from_slice(&(bins.len() as u32).to_le_bytes());
+            for (&index, &count) in bins.iter() {
+                buf.extend_from_slice(&index.to_le_bytes());
+                buf.extend_from_slice(&count.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Deserializes a sketch produced by `encode`
+    pub fn decode(bytes: &[u8]) -> Option<DDSketch> {
+        let mut reader = ByteReader { bytes };
+        let alpha = f64::from_le_bytes(reader.take()?);
+        if !(alpha > 0.0 && alpha < 1.0) {
+            return None;
+        }
+        let mut sketch = DDSketch::new(alpha);
+        sketch.zero = u64::from_le_bytes(reader.take()?);
+        sketch.min = f64::from_le_bytes(reader.take()?);
+        sketch.max = f64::from_le_bytes(reader.take()?);
+        sketch.count = sketch.zero;
+        for negative in &[false, true] {
+            let len = u32::from_le_bytes(reader.take()?);
+            for _ in 0..len {
+                let index = i32::from_le_bytes(reader.take()?);
+                let count = u64::from_le_bytes(reader.take()?);
+                sketch.count += count;
+                let bins = if *negative { &mut sketch.negative } else { &mut sketch.positive };
+                bins.insert(index, count);
+            }
+        }
+        if reader.bytes.is_empty() { Some(sketch) } else { None }
+    }
+
+    fn index(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma_ln).ceil() as i32
+    }
+
+    // Returns the value representing the bucket at `index`, which is
+    // within alpha of every value counted by the bucket
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.gamma_ln.exp();
+        2.0 * (self.gamma_ln * index as f64).exp() / (1.0 + gamma)
+    }
+
+    fn clamp(&self, value: f64) -> f64 {
+        value.max(self.min).min(self.max)
+    }
+}
+
+// Reads fixed size fields out of a byte slice
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take<T: Default + AsMut<[u8]>>(&mut self) -> Option<T> {
+        let mut field = T::default();
+        let len = field.as_mut().len();
+        if self.bytes.len() < len {
+            return None;
+        }
+        field.as_mut().copy_from_slice(&self.bytes[..len]);
+        self.bytes = &self.bytes[len..];
+        Some(field)
+    }
+
+    fn take_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(field)
+    }
+}
+
+/// Quantiles of a window of values, exact while the window is small and
+/// estimated with a `DDSketch` once it outgrows its limit
+#[derive(Clone, Debug)]
+pub struct Quantiles {
+    exact: Vec<f64>,
+    sketch: Option<DDSketch>,
+    limit: usize,
+    alpha: f64,
+}
+
+impl Default for Quantiles {
+    fn default() -> Quantiles {
+        Quantiles::new(DEFAULT_EXACT_LIMIT, DEFAULT_ALPHA)
+    }
+}
+
+impl Quantiles {
+    /// Creates an empty window keeping up to `limit` values before
+    /// switching to a sketch of relative accuracy `alpha`
+    pub fn new(limit: usize, alpha: f64) -> Quantiles {
+        Quantiles {
+            exact: Vec::new(),
+            sketch: None,
+            limit,
+            alpha,
+        }
+    }
+
+    /// Adds a value to the window
+    pub fn push(&mut self, value: f64) {
+        match self.sketch {
+            Some(ref mut sketch) => sketch.add(value),
+            None => {
+                self.exact.push(value);
+                if self.exact.len() > self.limit {
+                    self.sketch = Some(self.to_sketch());
+                    self.exact = Vec::new();
+                }
+            }
+        }
+    }
+
+    /// Returns true if quantiles are exact rather than estimated
+    pub fn is_exact(&self) -> bool {
+        self.sketch.is_none()
+    }
+
+    /// Returns the `q` quantile of the window, or `None` if it is empty
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        match self.sketch {
+            Some(ref sketch) => sketch.quantile(q),
+            None => quantile(&self.exact, q),
+        }
+    }
+
+    /// Adds every value of `other` to the window. Fails, leaving the
+    /// window unchanged, if `other` holds a sketch whose accuracy differs
+    /// from the one of the window
+    pub fn merge(&mut self, other: &Quantiles) -> Result<(), AccuracyMismatch> {
+        match other.sketch {
+            None => {
+                for &value in &other.exact {
+                    self.push(value);
+                }
+            }
+            Some(ref other_sketch) => {
+                let mut sketch = self.to_sketch();
+                sketch.merge(other_sketch)?;
+                self.sketch = Some(sketch);
+                self.exact = Vec::new();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a sketch of the values in the window
+    pub fn to_sketch(&self) -> DDSketch {
+        match self.sketch {
+            Some(ref sketch) => sketch.clone(),
+            None => {
+                let mut sketch = DDSketch::new(self.alpha);
+                for &value in &self.exact {
+                    sketch.add(value);
+                }
+                sketch
+            }
+        }
+    }
+}
+
+/// Computes the quantiles of a time ordered stream of points over `step`
+/// second windows aligned to the epoch, yielding the start of every
+/// non-empty window along with its quantiles
+pub struct QuantileWindows<I> {
+    points: I,
+    step: i64,
+    limit: usize,
+    alpha: f64,
+    current: Option<(i64, Quantiles)>,
+}
+
+impl<I: Iterator<Item = Point>> QuantileWindows<I> {
+    /// Creates windows with the default exact limit and sketch accuracy
+    pub fn new(points: I, step: i64) -> QuantileWindows<I> {
+        QuantileWindows::with_limit(points, step, DEFAULT_EXACT_LIMIT, DEFAULT_ALPHA)
+    }
+
+    /// Creates windows keeping up to `limit` exact values per window
+    /// before switching to sketches of relative accuracy `alpha`
+    pub fn with_limit(points: I, step: i64, limit: usize, alpha: f64) -> QuantileWindows<I> {
+        assert!(step > 0, "window step must be positive");
+        QuantileWindows {
+            points,
+            step,
+            limit,
+            alpha,
+            current: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Point>> Iterator for QuantileWindows<I> {
+    type Item = (i64, Quantiles);
+
+    fn next(&mut self) -> Option<(i64, Quantiles)> {
+        for point in self.points.by_ref() {
+            let start = point.ts - point.ts.rem_euclid(self.step);
+            match self.current {
+                Some((current, ref mut quantiles)) if current == start => quantiles.push(point.value),
+                _ => {
+                    let mut quantiles = Quantiles::new(self.limit, self.alpha);
+                    quantiles.push(point.value);
+                    if let Some(done) = self.current.replace((start, quantiles)) {
+                        return Some(done);
+                    }
+                }
+            }
+        }
+        self.current.take()
+    }
+}
+
+/// Sketches of a raw series kept per rollup window, so that quantiles can
+/// later be computed over any range of windows and across series
+#[derive(Clone, Debug)]
+pub struct SketchRollup {
+    resolution: i64,
+    alpha: f64,
+    watermark: Option<i64>,
+    sketches: BTreeMap<i64, DDSketch>,
+}
+
+impl SketchRollup {
+    /// Creates empty sketch rollups of `resolution` second windows
+    pub fn new(resolution: i64, alpha: f64) -> SketchRollup {
+        assert!(resolution > 0, "rollup resolution must be positive");
+        SketchRollup {
+            resolution,
+            alpha,
+            watermark: None,
+            sketches: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the length in seconds of a rollup window
+    pub fn resolution(&self) -> i64 {
+        self.resolution
+    }
+
+    /// Returns the relative accuracy of the sketches
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    /// Returns the end of the last window rolled up, if any
+    pub fn watermark(&self) -> Option<i64> {
+        self.watermark
+    }
+
+    /// Sketches the windows of `raw` that end at or before `upto` and
+    /// haven't been sketched yet
+    pub fn update(&mut self, raw: &Series, upto: i64) {
+        let upto = upto - upto.rem_euclid(self.resolution);
+        let from = self.watermark.unwrap_or(i64::MIN);
+        if upto <= from {
+            return;
+        }
+        for point in raw.points(from, upto) {
+            let start = point.ts - point.ts.rem_euclid(self.resolution);
+            let alpha = self.alpha;
+            self.sketches.entry(start).or_insert_with(|| DDSketch::new(alpha)).add(point.value);
+        }
+        self.watermark = Some(upto);
+    }
+
+    /// Sketches the windows starting at or after `start` again on the next
+    /// update, dropping their sketches, if they were sketched already
+    pub fn rewind(&mut self, start: i64) {
+        if self.watermark.is_some_and(|watermark| start < watermark) {
+            self.sketches.split_off(&start);
+            self.watermark = Some(start);
+        }
+    }
+
+    /// Drops the sketches of the windows ending at or before `cutoff`,
+    /// returning the number of sketches dropped
+    pub fn expire(&mut self, cutoff: i64) -> usize {
+        let before = self.sketches.len();
+        let resolution = self.resolution;
+        self.sketches.retain(|&start, _| start + resolution > cutoff);
+        before - self.sketches.len()
+    }
+
+    /// Returns true if no window holds a sketch
+    pub fn is_empty(&self) -> bool {
+        self.sketches.is_empty()
+    }
+
+    /// Stores the sketch of the window starting at `start`, e.g. when
+    /// loading previously encoded sketches. Fails if the accuracy of
+    /// `sketch` isn't the one of the rollup
+    pub fn insert(&mut self, start: i64, sketch: DDSketch) -> Result<(), AccuracyMismatch> {
+        if sketch.alpha != self.alpha {
+            return Err(AccuracyMismatch { expected: self.alpha, actual: sketch.alpha });
+        }
+        self.sketches.insert(start, sketch);
+        Ok(())
+    }
+
+    /// Returns an iterator over the start of every window and its sketch
+    pub fn iter(&self) -> impl Iterator<Item = (i64, &DDSketch)> {
+        self.sketches.iter().map(|(&start, sketch)| (start, sketch))
+    }
+
+    /// Merges the sketches of every window starting within [`start`, `end`)
+    pub fn merged(&self, start: i64, end: i64) -> Option<DDSketch> {
+        // every sketch of a rollup has its accuracy, see `insert`
+        merge_rollups(Some(self), start, end).ok().flatten()
+    }
+
+    /// Returns the estimated `q` quantile of the windows starting within
+    /// [`start`, `end`)
+    pub fn quantile(&self, start: i64, end: i64, q: f64) -> Option<f64> {
+        self.merged(start, end)?.quantile(q)
+    }
+
+    /// Serializes the sketches and watermark of the rollup
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.alpha.to_le_bytes());
+        buf.push(self.watermark.is_some() as u8);
+        buf.extend_from_slice(&self.watermark.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(self.sketches.len() as u32).to_le_bytes());
+        for (&start, sketch) in &self.sketches {
+            let sketch = sketch.encode();
+            buf.extend_from_slice(&start.to_le_bytes());
+            buf.extend_from_slice(&(sketch.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&sketch);
+        }
+        buf
+    }
+
+    /// Deserializes the sketch rollup of `resolution` second windows
+    /// produced by `encode`
+    pub fn decode(resolution: i64, bytes: &[u8]) -> Option<SketchRollup> {
+        let mut reader = ByteReader { bytes };
+        let alpha = f64::from_le_bytes(reader.take()?);
+        if resolution <= 0 || !(alpha > 0.0 && alpha < 1.0) {
+            return None;
+        }
+        let mut rollup = SketchRollup::new(resolution, alpha);
+        let [has_watermark] = reader.take::<[u8; 1]>()?;
+        let watermark = i64::from_le_bytes(reader.take()?);
+        rollup.watermark = if has_watermark != 0 { Some(watermark) } else { None };
+        for _ in 0..u32::from_le_bytes(reader.take()?) {
+            let start = i64::from_le_bytes(reader.take()?);
+            let len = u32::from_le_bytes(reader.take()?) as usize;
+            let sketch = DDSketch::decode(reader.take_bytes(len)?)?;
+            rollup.insert(start, sketch).ok()?;
+        }
+        if reader.bytes.is_empty() { Some(rollup) } else { None }
+    }
+}
+
+/// Merges the sketches of every window starting within [`start`, `end`)
+/// across several sketch rollups, e.g. those of every series of a service.
+/// Fails if the rollups don't share the same accuracy
+pub fn merge_rollups<'a, I>(rollups: I, start: i64, end: i64) -> Result<Option<DDSketch>, AccuracyMismatch>
+    where I: IntoIterator<Item = &'a SketchRollup>
+{
+    let mut merged: Option<DDSketch> = None;
+    for rollup in rollups {
+        for (_, sketch) in rollup.sketches.range(start..end) {
+            match merged {
+                Some(ref mut merged) => merged.merge(sketch)?,
+                None => merged = Some(sketch.clone()),
+            }
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge_rollups, quantile, AccuracyMismatch, DDSketch, QuantileWindows, Quantiles, SketchRollup};
+    use series::Series;
+    use TSBlock;
+
+    fn assert_close(expected: f64, actual: f64, alpha: f64) {
+        assert!((expected - actual).abs() <= expected.abs() * alpha + 1e-9,
+                "expected {} got {}",
+                expected,
+                actual);
+    }
+
+    #[test]
+    fn test_exact_quantile() {
+        let values = [5.0, 1.0, 4.0, 2.0, 3.0];
+        assert_eq!(Some(1.0), quantile(&values, 0.0));
+        assert_eq!(Some(3.0), quantile(&values, 0.5));
+        assert_eq!(Some(4.6), quantile(&values, 0.9));
+        assert_eq!(Some(5.0), quantile(&values, 1.0));
+        assert_eq!(None, quantile(&[], 0.5));
+    }
+
+    #[test]
+    fn test_sketch_accuracy() {
+        let mut sketch = DDSketch::new(0.01);
+        let values: Vec<f64> = (1..10_001).map(|v| v as f64).collect();
+        for &v in &values {
+            sketch.add(v);
+        }
+        for &q in &[0.5, 0.95, 0.99] {
+            assert_close(quantile(&values, q).unwrap(), sketch.quantile(q).unwrap(), 0.02);
+        }
+        assert_eq!(Some(1.0), sketch.quantile(0.0));
+        assert_eq!(Some(10_000.0), sketch.quantile(1.0));
+    }
+
+    #[test]
+    fn test_sketch_negative_values_and_merge() {
+        let mut a = DDSketch::default();
+        let mut b = DDSketch::default();
+        for v in -500..0 {
+            a.add(v as f64);
+        }
+        for v in 0..500 {
+            b.add(v as f64);
+        }
+        a.merge(&b).unwrap();
+        assert_eq!(1000, a.count());
+        assert_close(-250.0, a.quantile(0.25).unwrap(), 0.02);
+        assert_close(-1.0, a.quantile(0.5).unwrap(), 0.02);
+        assert_close(249.0, a.quantile(0.75).unwrap(), 0.02);
+    }
+
+    #[test]
+    fn test_sketch_encode_decode() {
+        let mut sketch = DDSketch::default();
+        for v in -10..100 {
+            sketch.add(v as f64 * 1.5);
+        }
+        let bytes = sketch.encode();
+        assert_eq!(Some(sketch), DDSketch::decode(&bytes));
+        assert_eq!(None, DDSketch::decode(&bytes[..bytes.len() - 1]));
+    }
+
+    #[test]
+    fn test_quantiles_switch_to_sketch() {
+        let mut quantiles = Quantiles::new(10, 0.01);
+        for v in 0..10 {
+            quantiles.push(v as f64);
+        }
+        assert!(quantiles.is_exact());
+        assert_eq!(Some(4.5), quantiles.quantile(0.5));
+        let mut other = Quantiles::new(10, 0.01);
+        for v in 10..100 {
+            other.push(v as f64);
+        }
+        assert!(!other.is_exact());
+        quantiles.merge(&other).unwrap();
+        assert!(!quantiles.is_exact());
+        assert_close(49.0, quantiles.quantile(0.5).unwrap(), 0.02);
+    }
+
+    #[test]
+    fn test_merge_accuracy_mismatch() {
+        let mut coarse = Quantiles::new(1, 0.05);
+        let mut fine = Quantiles::new(1, 0.01);
+        for v in 0..10 {
+            coarse.push(v as f64);
+            fine.push(v as f64);
+        }
+        let mismatch = AccuracyMismatch { expected: 0.05, actual: 0.01 };
+        assert_eq!(Err(mismatch), coarse.merge(&fine));
+        assert_eq!(10, coarse.to_sketch().count());
+        // exact values can be merged whatever the accuracy
+        let mut exact = Quantiles::new(100, 0.01);
+        exact.push(42.0);
+        assert_eq!(Ok(()), coarse.merge(&exact));
+        assert_eq!(11, coarse.to_sketch().count());
+
+        let mut rollup = SketchRollup::new(60, 0.05);
+        assert_eq!(Err(mismatch), rollup.insert(0, fine.to_sketch()));
+        rollup.insert(0, coarse.to_sketch()).unwrap();
+        let mut other = SketchRollup::new(60, 0.01);
+        other.insert(0, fine.to_sketch()).unwrap();
+        assert_eq!(Err(mismatch), merge_rollups(vec![&rollup, &other], 0, 60));
+    }
+
+    #[test]
+    fn test_quantile_windows() {
+        let mut block = TSBlock::at(0);
+        for i in 0..120 {
+            block.publish_at((i % 60) as f64, i);
+        }
+        let windows: Vec<(i64, Option<f64>)> = QuantileWindows::new(block.iter(), 60)
+            .map(|(ts, q)| (ts, q.quantile(0.5)))
+            .collect();
+        assert_eq!(vec![(0, Some(29.5)), (60, Some(29.5))], windows);
+    }
+
+    #[test]
+    fn test_sketch_rollup_merges_across_series() {
+        let mut a = Series::new();
+        let mut b = Series::new();
+        for i in 0..600 {
+            a.publish_at(i as f64, i);
+            b.publish_at((i + 600) as f64, i);
+        }
+        let mut rollup_a = SketchRollup::new(300, 0.01);
+        let mut rollup_b = SketchRollup::new(300, 0.01);
+        rollup_a.update(&a, 600);
+        rollup_b.update(&b, 600);
+        assert_eq!(2, rollup_a.iter().count());
+        assert_close(299.0, rollup_a.quantile(0, 600, 0.5).unwrap(), 0.02);
+        let merged = merge_rollups(vec![&rollup_a, &rollup_b], 0, 600).unwrap().unwrap();
+        assert_eq!(1200, merged.count());
+        assert_close(1188.0, merged.quantile(0.99).unwrap(), 0.02);
+
+        let decoded = SketchRollup::decode(300, &rollup_a.encode()).unwrap();
+        assert_eq!(Some(600), decoded.watermark());
+        assert_eq!(rollup_a.iter().collect::<Vec<_>>(), decoded.iter().collect::<Vec<_>>());
+        assert!(SketchRollup::decode(300, &rollup_a.encode()[1..]).is_none());
+    }
+}
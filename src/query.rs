@@ -0,0 +1,869 @@
+//! A small PromQL-like query language.
+//!
+//! Queries are parsed into an `Expr` tree that `eval` evaluates against a
+//! `Store`. The supported subset is:
+//!
+//! ```text
+//! cpu{host="a", region!="eu"}       instant vector selector
+//! cpu{host=~"web.*", dc!~"eu|us"}    regex matchers, see `regex`
+//! requests[5m]                      range vector selector
+//! requests offset 1h                selector shifted back in time
+//! rate(requests[5m])                rate, irate, increase
+//! avg_over_time(cpu[10m])           sum, avg, min, max, count, stddev
+//!                                   and last over time
+//! sum by (region) (cpu)             sum, avg, min, max, count, stddev
+//! sum without (host) (cpu)          by or without tags
+//! errors / requests * 100           + - * / % between series and numbers
+//! cpu > bool 0.5                    == != > < >= <= with optional bool
+//! used / on (host) group_left total vector matching, see `binary`
+//! ```
+//!
+//! Durations are made of a number followed by one of the units `s`, `m`,
+//! `h`, `d`, `w` or `y`, and may be combined as in `1h30m`.
+
+use std::error;
+use std::fmt;
+
+use aggregate::Function;
+use binary::{BinaryOp, MatchTags, Matching};
+use regex::{self, Regex};
+use store::SeriesKey;
+
+/// Name of the pseudo tag matching the metric name of a series
+pub const METRIC_NAME: &str = "__name__";
+
+/// A position in the query text, both line and column start at 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Error raised by a query that could not be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub position: Position,
+    pub message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(position: Position, message: S) -> Error {
+        Error {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.position.line, self.position.column, self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+/// Comparison of a tag with a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    /// The value is a regular expression matching the whole tag
+    Regex,
+    NotRegex,
+}
+
+/// Restricts the series selected to those whose tag `name` compares to
+/// `value`. A missing tag compares like an empty value
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    // compiled from `value` for the regex operators
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    /// Creates a matcher, compiling `value` for the regex operators
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, op: MatchOp, value: V) -> Result<Matcher, regex::Error> {
+        let value = value.into();
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(Regex::new(&value)?),
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+        Ok(Matcher { name: name.into(), op, value, regex })
+    }
+
+    /// Returns whether the matcher accepts `value` for its tag
+    pub fn matches(&self, value: &str) -> bool {
+        match (self.op, self.regex.as_ref()) {
+            (MatchOp::Equal, _) => self.value == value,
+            (MatchOp::NotEqual, _) => self.value != value,
+            (MatchOp::Regex, Some(regex)) => regex.is_match(value),
+            (MatchOp::NotRegex, Some(regex)) => !regex.is_match(value),
+            (_, None) => unreachable!("regex matchers are compiled on creation"),
+        }
+    }
+
+    /// Returns whether the matcher accepts the series `key`, whose metric
+    /// is the value of `METRIC_NAME`
+    pub fn matches_key(&self, key: &SeriesKey) -> bool {
+        if self.name == METRIC_NAME {
+            self.matches(&key.metric)
+        } else {
+            self.matches(key.tags.get(&self.name).map_or("", |v| v.as_str()))
+        }
+    }
+}
+
+// the regex is derived from the value
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Matcher) -> bool {
+        (&self.name, self.op, &self.value) == (&other.name, other.op, &other.value)
+    }
+}
+
+impl Eq for Matcher {}
+
+/// Selects series by their metric name and tags
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selector {
+    /// Matchers every selected series satisfies, a metric name written
+    /// before the braces is a matcher on `METRIC_NAME`
+    pub matchers: Vec<Matcher>,
+    /// Length in seconds of the window of a range vector selector
+    pub range: Option<i64>,
+    /// Seconds by which the selector is shifted back in time
+    pub offset: i64,
+}
+
+impl Selector {
+    /// Returns whether the series `key` satisfies every matcher
+    pub fn matches(&self, key: &SeriesKey) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches_key(key))
+    }
+}
+
+/// Functions over range vectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Call {
+    Rate,
+    Irate,
+    Increase,
+    /// `<function>_over_time`
+    OverTime(Function),
+}
+
+/// How an aggregation groups series
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    /// Groups by the given tags only
+    By(Vec<String>),
+    /// Groups by every tag but the given ones
+    Without(Vec<String>),
+}
+
+/// A parsed query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Call(Call, Box<Expr>),
+    Aggregate(Function, Grouping, Box<Expr>),
+    /// The matching only applies between two instant vectors
+    Binary(BinaryOp, Matching, Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+}
+
+// The kind of value an expression evaluates to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Scalar,
+    Instant,
+    Range,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Scalar => "scalar",
+            Kind::Instant => "instant vector",
+            Kind::Range => "range vector",
+        }
+    }
+}
+
+/// Parses `query`, which must evaluate to a number or an instant vector
+pub fn parse(query: &str) -> Result<Expr, Error> {
+    let mut parser = Parser::new(query)?;
+    let (expr, kind, position) = parser.expr()?;
+    if parser.token != Token::Eof {
+        return Err(parser.unexpected());
+    }
+    if kind == Kind::Range {
+        return Err(Error::new(position, "query must not be a range vector"));
+    }
+    Ok(expr)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Duration(i64),
+    Str(String),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Assign,
+    Equal,
+    NotEqual,
+    RegexMatch,
+    NotRegexMatch,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Ident(ref ident) => write!(f, "identifier \"{}\"", ident),
+            Token::Number(number) => write!(f, "number {}", number),
+            Token::Duration(_) => write!(f, "duration"),
+            Token::Str(_) => write!(f, "string"),
+            Token::LParen => write!(f, "\"(\""),
+            Token::RParen => write!(f, "\")\""),
+            Token::LBrace => write!(f, "\"{{\""),
+            Token::RBrace => write!(f, "\"}}\""),
+            Token::LBracket => write!(f, "\"[\""),
+            Token::RBracket => write!(f, "\"]\""),
+            Token::Comma => write!(f, "\",\""),
+            Token::Assign => write!(f, "\"=\""),
+            Token::Equal => write!(f, "\"==\""),
+            Token::NotEqual => write!(f, "\"!=\""),
+            Token::RegexMatch => write!(f, "\"=~\""),
+            Token::NotRegexMatch => write!(f, "\"!~\""),
+            Token::Greater => write!(f, "\">\""),
+            Token::Less => write!(f, "\"<\""),
+            Token::GreaterEqual => write!(f, "\">=\""),
+            Token::LessEqual => write!(f, "\"<=\""),
+            Token::Plus => write!(f, "\"+\""),
+            Token::Minus => write!(f, "\"-\""),
+            Token::Star => write!(f, "\"*\""),
+            Token::Slash => write!(f, "\"/\""),
+            Token::Percent => write!(f, "\"%\""),
+            Token::Eof => write!(f, "end of query"),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    chars: ::std::iter::Peekable<::std::str::Chars<'a>>,
+    position: Position,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(query: &'a str) -> Lexer<'a> {
+        Lexer {
+            chars: query.chars().peekable(),
+            position: Position { line: 1, column: 1 },
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(c)
+    }
+
+    // Returns the next token along with the position it starts at
+    fn next(&mut self) -> Result<(Token, Position), Error> {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+        let start = self.position;
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Ok((Token::Eof, start)),
+        };
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '=' if self.peek() == Some('~') => {
+                self.bump();
+                Token::RegexMatch
+            }
+            '=' => self.followed_by('=', Token::Equal, Token::Assign),
+            '>' => self.followed_by('=', Token::GreaterEqual, Token::Greater),
+            '<' => self.followed_by('=', Token::LessEqual, Token::Less),
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '!' if self.peek() == Some('=') => {
+                self.bump();
+                Token::NotEqual
+            }
+            '!' if self.peek() == Some('~') => {
+                self.bump();
+                Token::NotRegexMatch
+            }
+            '"' | '\'' => self.string(c, start)?,
+            '0'..='9' | '.' => self.number(c, start)?,
+            c if c.is_ascii_alphabetic() || c == '_' || c == ':' => {
+                let mut ident = c.to_string();
+                while let Some(c) = self.peek().filter(|&c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+                    ident.push(c);
+                    self.bump();
+                }
+                Token::Ident(ident)
+            }
+            c => return Err(Error::new(start, format!("unexpected character {:?}", c))),
+        };
+        Ok((token, start))
+    }
+
+    // Returns `then` and skips `next` if it is the next character
+    fn followed_by(&mut self, next: char, then: Token, otherwise: Token) -> Token {
+        if self.peek() == Some(next) {
+            self.bump();
+            then
+        } else {
+            otherwise
+        }
+    }
+
+    fn string(&mut self, quote: char, start: Position) -> Result<Token, Error> {
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => return Ok(Token::Str(value)),
+                Some('\\') => {
+                    match self.bump() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some(c) => value.push(c),
+                        None => break,
+                    }
+                }
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+        Err(Error::new(start, "unterminated string"))
+    }
+
+    // Lexes a number, or a duration if the digits are followed by a unit
+    fn number(&mut self, first: char, start: Position) -> Result<Token, Error> {
+        let mut text = first.to_string();
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '+' || c == '-') && text.ends_with(['e', 'E']);
+            if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                // a unit-less `e` can't start a duration, so it's an exponent
+                text.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if self.peek().is_some_and(|c| unit(c).is_some()) && text.bytes().all(|b| b.is_ascii_digit()) {
+            return self.duration(text, start);
+        }
+        text.parse()
+            .map(Token::Number)
+            .map_err(|_| Error::new(start, format!("invalid number {:?}", text)))
+    }
+
+    fn duration(&mut self, mut digits: String, start: Position) -> Result<Token, Error> {
+        let mut seconds: i64 = 0;
+        loop {
+            let unit = self.peek().and_then(unit).ok_or_else(|| Error::new(start, "invalid duration"))?;
+            self.bump();
+            let value: i64 = digits.parse().map_err(|_| Error::new(start, "invalid duration"))?;
+            seconds = value.checked_mul(unit)
+                .and_then(|s| seconds.checked_add(s))
+                .ok_or_else(|| Error::new(start, "duration out of range"))?;
+            digits.clear();
+            while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+                digits.push(c);
+                self.bump();
+            }
+            if digits.is_empty() {
+                return Ok(Token::Duration(seconds));
+            }
+        }
+    }
+}
+
+// Returns the number of seconds in a duration unit
+fn unit(c: char) -> Option<i64> {
+    match c {
+        's' => Some(1),
+        'm' => Some(60),
+        'h' => Some(60 * 60),
+        'd' => Some(24 * 60 * 60),
+        'w' => Some(7 * 24 * 60 * 60),
+        'y' => Some(365 * 24 * 60 * 60),
+        _ => None,
+    }
+}
+
+fn aggregation(name: &str) -> Option<Function> {
+    match name {
+        "sum" => Some(Function::Sum),
+        "avg" => Some(Function::Avg),
+        "min" => Some(Function::Min),
+        "max" => Some(Function::Max),
+        "count" => Some(Function::Count),
+        "stddev" => Some(Function::Stddev),
+        _ => None,
+    }
+}
+
+fn call(name: &str) -> Option<Call> {
+    match name {
+        "rate" => Some(Call::Rate),
+        "irate" => Some(Call::Irate),
+        "increase" => Some(Call::Increase),
+        "sum_over_time" => Some(Call::OverTime(Function::Sum)),
+        "avg_over_time" => Some(Call::OverTime(Function::Avg)),
+        "min_over_time" => Some(Call::OverTime(Function::Min)),
+        "max_over_time" => Some(Call::OverTime(Function::Max)),
+        "count_over_time" => Some(Call::OverTime(Function::Count)),
+        "stddev_over_time" => Some(Call::OverTime(Function::Stddev)),
+        "last_over_time" => Some(Call::OverTime(Function::Last)),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    token: Token,
+    position: Position,
+}
+
+impl<'a> Parser<'a> {
+    fn new(query: &'a str) -> Result<Parser<'a>, Error> {
+        let mut lexer = Lexer::new(query);
+        let (token, position) = lexer.next()?;
+        Ok(Parser {
+            lexer,
+            token,
+            position,
+        })
+    }
+
+    fn advance(&mut self) -> Result<Token, Error> {
+        let (token, position) = self.lexer.next()?;
+        self.position = position;
+        Ok(::std::mem::replace(&mut self.token, token))
+    }
+
+    fn unexpected(&self) -> Error {
+        Error::new(self.position, format!("unexpected {}", self.token))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), Error> {
+        if self.token != token {
+            return Err(Error::new(self.position, format!("expected {}, found {}", token, self.token)));
+        }
+        self.advance().map(|_| ())
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.advance()? {
+            Token::Ident(ident) => Ok(ident),
+            token => Err(Error::new(self.position, format!("expected identifier, found {}", token))),
+        }
+    }
+
+    fn duration(&mut self) -> Result<i64, Error> {
+        let position = self.position;
+        match self.advance()? {
+            Token::Duration(seconds) => Ok(seconds),
+            token => Err(Error::new(position, format!("expected duration, found {}", token))),
+        }
+    }
+
+    fn binary_op(&self, operators: &[(Token, BinaryOp)]) -> Option<BinaryOp> {
+        operators.iter().find(|(token, _)| *token == self.token).map(|&(_, op)| op)
+    }
+
+    fn expr(&mut self) -> Result<(Expr, Kind, Position), Error> {
+        let operators = [(Token::Equal, BinaryOp::Equal),
+                         (Token::NotEqual, BinaryOp::NotEqual),
+                         (Token::Greater, BinaryOp::Greater),
+                         (Token::Less, BinaryOp::Less),
+                         (Token::GreaterEqual, BinaryOp::GreaterEqual),
+                         (Token::LessEqual, BinaryOp::LessEqual)];
+        self.binary(&operators, Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<(Expr, Kind, Position), Error> {
+        self.binary(&[(Token::Plus, BinaryOp::Add), (Token::Minus, BinaryOp::Sub)], Parser::term)
+    }
+
+    fn term(&mut self) -> Result<(Expr, Kind, Position), Error> {
+        let operators = [(Token::Star, BinaryOp::Mul), (Token::Slash, BinaryOp::Div), (Token::Percent, BinaryOp::Mod)];
+        self.binary(&operators, Parser::unary)
+    }
+
+    // Parses a left associative chain of `operators` between operands
+    fn binary<F>(&mut self, operators: &[(Token, BinaryOp)], operand: F) -> Result<(Expr, Kind, Position), Error>
+        where F: Fn(&mut Parser<'a>) -> Result<(Expr, Kind, Position), Error>
+    {
+        let (mut lhs, mut kind, position) = operand(self)?;
+        while let Some(op) = self.binary_op(operators) {
+            let op_position = self.position;
+            self.advance()?;
+            let (matching, explicit) = self.matching(op)?;
+            let (rhs, rhs_kind, rhs_position) = operand(self)?;
+            for &(kind, position) in &[(kind, position), (rhs_kind, rhs_position)] {
+                if kind == Kind::Range {
+                    return Err(Error::new(position, "binary operators do not accept range vectors"));
+                }
+            }
+            let vectors = kind == Kind::Instant && rhs_kind == Kind::Instant;
+            if explicit && !vectors {
+                return Err(Error::new(op_position, "vector matching only applies between instant vectors"));
+            }
+            if kind == Kind::Scalar && rhs_kind == Kind::Scalar && op.is_comparison() && !matching.bool {
+                return Err(Error::new(op_position, "comparisons between scalars must use bool"));
+            }
+            if rhs_kind == Kind::Instant {
+                kind = Kind::Instant;
+            }
+            lhs = Expr::Binary(op, matching, Box::new(lhs), Box::new(rhs));
+        }
+        Ok((lhs, kind, position))
+    }
+
+    // Parses the modifiers following `op`, returning whether `on`,
+    // `ignoring` or `group_left` were given
+    fn matching(&mut self, op: BinaryOp) -> Result<(Matching, bool), Error> {
+        let mut matching = Matching::default();
+        if self.is_keyword("bool") {
+            if !op.is_comparison() {
+                return Err(Error::new(self.position, "bool only applies to comparisons"));
+            }
+            self.advance()?;
+            matching.bool = true;
+        }
+        let mut explicit = false;
+        if self.is_keyword("on") || self.is_keyword("ignoring") {
+            let on = self.ident()? == "on";
+            let tags = self.tags()?;
+            matching.tags = if on { MatchTags::On(tags) } else { MatchTags::Ignoring(tags) };
+            explicit = true;
+        }
+        if self.is_keyword("group_left") {
+            self.advance()?;
+            let include = if self.token == Token::LParen { self.tags()? } else { Vec::new() };
+            matching.group_left = Some(include);
+            explicit = true;
+        }
+        Ok((matching, explicit))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match self.token {
+            Token::Ident(ref ident) => ident == keyword,
+            _ => false,
+        }
+    }
+
+    fn unary(&mut self) -> Result<(Expr, Kind, Position), Error> {
+        let position = self.position;
+        match self.token {
+            Token::Minus => {
+                self.advance()?;
+                let (expr, kind, _) = self.unary()?;
+                if kind == Kind::Range {
+                    return Err(Error::new(position, "unary minus does not accept range vectors"));
+                }
+                let expr = match expr {
+                    Expr::Number(n) => Expr::Number(-n),
+                    expr => Expr::Negate(Box::new(expr)),
+                };
+                Ok((expr, kind, position))
+            }
+            Token::Plus => {
+                self.advance()?;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<(Expr, Kind, Position), Error> {
+        let position = self.position;
+        match self.token.clone() {
+            Token::Number(n) => {
+                self.advance()?;
+                Ok((Expr::Number(n), Kind::Scalar, position))
+            }
+            Token::LParen => {
+                self.advance()?;
+                let (expr, kind, _) = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok((expr, kind, position))
+            }
+            Token::LBrace => self.selector(None, position),
+            Token::Ident(ident) => {
+                self.advance()?;
+                if let Some(function) = aggregation(&ident) {
+                    if self.token == Token::LParen || self.is_grouping() {
+                        return self.aggregate(function, position);
+                    }
+                }
+                if self.token == Token::LParen {
+                    return self.call(&ident, position);
+                }
+                self.selector(Some(ident), position)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn is_grouping(&self) -> bool {
+        self.is_keyword("by") || self.is_keyword("without")
+    }
+
+    fn aggregate(&mut self, function: Function, position: Position) -> Result<(Expr, Kind, Position), Error> {
+        let mut grouping = if self.is_grouping() {
+            Some(self.grouping()?)
+        } else {
+            None
+        };
+        self.expect(Token::LParen)?;
+        let (expr, kind, expr_position) = self.expr()?;
+        self.expect(Token::RParen)?;
+        if kind != Kind::Instant {
+            return Err(Error::new(expr_position, format!("expected instant vector, found {}", kind.name())));
+        }
+        if grouping.is_none() && self.is_grouping() {
+            grouping = Some(self.grouping()?);
+        }
+        let grouping = grouping.unwrap_or_else(|| Grouping::By(Vec::new()));
+        Ok((Expr::Aggregate(function, grouping, Box::new(expr)), Kind::Instant, position))
+    }
+
+    fn grouping(&mut self) -> Result<Grouping, Error> {
+        let by = self.ident()? == "by";
+        let tags = self.tags()?;
+        Ok(if by { Grouping::By(tags) } else { Grouping::Without(tags) })
+    }
+
+    // Parses a parenthesized list of tag names
+    fn tags(&mut self) -> Result<Vec<String>, Error> {
+        self.expect(Token::LParen)?;
+        let mut tags = Vec::new();
+        while self.token != Token::RParen {
+            tags.push(self.ident()?);
+            if self.token != Token::Comma {
+                break;
+            }
+            self.advance()?;
+        }
+        self.expect(Token::RParen)?;
+        Ok(tags)
+    }
+
+    fn call(&mut self, name: &str, position: Position) -> Result<(Expr, Kind, Position), Error> {
+        let call = call(name).ok_or_else(|| Error::new(position, format!("unknown function {:?}", name)))?;
+        self.expect(Token::LParen)?;
+        let (expr, kind, expr_position) = self.expr()?;
+        self.expect(Token::RParen)?;
+        if kind != Kind::Range {
+            return Err(Error::new(expr_position,
+                                  format!("{} expects a range vector, found {}", name, kind.name())));
+        }
+        Ok((Expr::Call(call, Box::new(expr)), Kind::Instant, position))
+    }
+
+    fn selector(&mut self, metric: Option<String>, position: Position) -> Result<(Expr, Kind, Position), Error> {
+        let mut matchers = Vec::new();
+        if let Some(metric) = metric {
+            matchers.push(Matcher::new(METRIC_NAME, MatchOp::Equal, metric).expect("equality matchers are valid"));
+        }
+        if self.token == Token::LBrace {
+            self.advance()?;
+            while self.token != Token::RBrace {
+                matchers.push(self.matcher()?);
+                if self.token != Token::Comma {
+                    break;
+                }
+                self.advance()?;
+            }
+            self.expect(Token::RBrace)?;
+        }
+        if matchers.iter().all(|m| m.matches("")) {
+            return Err(Error::new(position, "selector must contain a matcher not matching empty values"));
+        }
+
+        let mut range = None;
+        if self.token == Token::LBracket {
+            self.advance()?;
+            let position = self.position;
+            let seconds = self.duration()?;
+            if seconds <= 0 {
+                return Err(Error::new(position, "range must be positive"));
+            }
+            range = Some(seconds);
+            self.expect(Token::RBracket)?;
+        }
+        let mut offset = 0;
+        if self.is_keyword("offset") {
+            self.advance()?;
+            offset = self.duration()?;
+        }
+        let kind = if range.is_some() { Kind::Range } else { Kind::Instant };
+        Ok((Expr::Selector(Selector { matchers, range, offset }), kind, position))
+    }
+
+    fn matcher(&mut self) -> Result<Matcher, Error> {
+        let name = self.ident()?;
+        let op = match self.advance()? {
+            Token::Assign => MatchOp::Equal,
+            Token::NotEqual => MatchOp::NotEqual,
+            Token::RegexMatch => MatchOp::Regex,
+            Token::NotRegexMatch => MatchOp::NotRegex,
+            token => {
+                let message = format!("expected \"=\", \"!=\", \"=~\" or \"!~\", found {}", token);
+                return Err(Error::new(self.position, message));
+            }
+        };
+        let position = self.position;
+        match self.advance()? {
+            Token::Str(value) => {
+                Matcher::new(name, op, value).map_err(|err| Error::new(position, format!("invalid regex: {}", err)))
+            }
+            token => Err(Error::new(position, format!("expected string, found {}", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Call, Expr, Grouping, MatchOp, Matcher, Position, Selector, METRIC_NAME};
+    use aggregate::Function;
+    use binary::{BinaryOp, MatchTags, Matching};
+
+    fn selector(metric: &str, range: Option<i64>, offset: i64) -> Expr {
+        Expr::Selector(Selector {
+            matchers: vec![Matcher::new(METRIC_NAME, MatchOp::Equal, metric).unwrap()],
+            range,
+            offset,
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        let expr = parse("sum by (region) (rate(requests[5m] offset 1h30m))").unwrap();
+        let rate = Expr::Call(Call::Rate, Box::new(selector("requests", Some(300), 5400)));
+        assert_eq!(Expr::Aggregate(Function::Sum, Grouping::By(vec!["region".to_string()]), Box::new(rate)),
+                   expr);
+
+        let expr = parse("a - b * 2 + -1").unwrap();
+        let binary = |op, lhs, rhs| Expr::Binary(op, Matching::default(), Box::new(lhs), Box::new(rhs));
+        let product = binary(BinaryOp::Mul, selector("b", None, 0), Expr::Number(2.0));
+        let difference = binary(BinaryOp::Sub, selector("a", None, 0), product);
+        assert_eq!(binary(BinaryOp::Add, difference, Expr::Number(-1.0)), expr);
+
+        match parse("avg(cpu{host=\"a\", region!='eu'}) without (host)").unwrap() {
+            Expr::Aggregate(Function::Avg, Grouping::Without(ref tags), ref inner) => {
+                assert_eq!(vec!["host".to_string()], *tags);
+                match **inner {
+                    Expr::Selector(ref selector) => {
+                        assert_eq!(3, selector.matchers.len());
+                        assert_eq!(MatchOp::NotEqual, selector.matchers[2].op);
+                        assert_eq!("eu", selector.matchers[2].value);
+                    }
+                    _ => panic!("expected a selector"),
+                }
+            }
+            expr => panic!("unexpected {:?}", expr),
+        }
+        assert_eq!(Expr::Number(1.5e3), parse("1.5e3").unwrap());
+    }
+
+    #[test]
+    fn test_parse_matching() {
+        let expr = parse("used / on (host) group_left (zone) total > bool 0.5").unwrap();
+        let matching = Matching {
+            tags: MatchTags::On(vec!["host".to_string()]),
+            group_left: Some(vec!["zone".to_string()]),
+            bool: false,
+        };
+        let ratio = Expr::Binary(BinaryOp::Div,
+                                 matching,
+                                 Box::new(selector("used", None, 0)),
+                                 Box::new(selector("total", None, 0)));
+        let matching = Matching { bool: true, ..Matching::default() };
+        assert_eq!(Expr::Binary(BinaryOp::Greater, matching, Box::new(ratio), Box::new(Expr::Number(0.5))),
+                   expr);
+
+        assert!(parse("a <= ignoring (host) group_left b").is_ok());
+        let position = |query| parse(query).unwrap_err().position;
+        assert_eq!(Position { line: 1, column: 3 }, position("1 > 2"));
+        assert_eq!(Position { line: 1, column: 5 }, position("cpu + on (host) 1"));
+        assert_eq!(Position { line: 1, column: 7 }, position("cpu + bool 1"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let position = |query| parse(query).unwrap_err().position;
+        assert_eq!(Position { line: 1, column: 6 }, position("rate(cpu)"));
+        assert_eq!(Position { line: 2, column: 3 }, position("sum(\n  cpu[5m])"));
+        assert_eq!(Position { line: 1, column: 5 }, position("cpu[5x]"));
+        assert_eq!(Position { line: 1, column: 6 }, position("cpu +"));
+        assert_eq!(Position { line: 1, column: 1 }, position("cpu[5m]"));
+        assert_eq!(Position { line: 1, column: 1 }, position("foo(cpu[5m])"));
+        assert_eq!(Position { line: 1, column: 1 }, position("{host=\"\"}"));
+        assert_eq!(Position { line: 1, column: 1 }, position("{host=~\".*\"}"));
+        let err = parse("cpu{host=\"a}").unwrap_err();
+        assert_eq!("1:10: unterminated string", err.to_string());
+        let err = parse("cpu{host=~\"(a\"}").unwrap_err();
+        assert_eq!("1:11: invalid regex: missing ) at offset 2", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_regex() {
+        let matchers = match parse("cpu{host=~\"web.*\", dc!~'eu|us'}").unwrap() {
+            Expr::Selector(selector) => selector.matchers,
+            expr => panic!("unexpected {:?}", expr),
+        };
+        assert_eq!(vec![(MatchOp::Regex, "web.*"), (MatchOp::NotRegex, "eu|us")],
+                   matchers[1..].iter().map(|m| (m.op, m.value.as_str())).collect::<Vec<_>>());
+        assert!(matchers[1].matches("web01") && !matchers[1].matches("db01"));
+        assert!(matchers[2].matches("ap") && !matchers[2].matches("eu"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Functions over monotonic counters.
+//!
+//! A counter only ever increases, except when the process publishing it
+//! restarts and the counter resets to zero. A value lower than the one
+//! before it is therefore treated as a reset, and the value before the
+//! reset is added back so that increases are never lost.
+
+use Point;
+
+/// Returns the increase of the counter over the range (`start`, `end`].
+///
+/// Like Prometheus, the increase observed between the first and last point
+/// in the range is extrapolated towards the edges of the range, so the
+/// result may be fractional even for integer counters. Returns `None` if
+/// the range holds fewer than 2 points
+pub fn increase<I: IntoIterator<Item = Point>>(points: I, start: i64, end: i64) -> Option<f64> {
+    extrapolated(points, start, end, false)
+}
+
+/// Returns the per-second rate of increase of the counter over the range
+/// (`start`, `end`], extrapolated like `increase`. Returns `None` if the
+/// range holds fewer than 2 points
+pub fn rate<I: IntoIterator<Item = Point>>(points: I, start: i64, end: i64) -> Option<f64> {
+    extrapolated(points, start, end, true)
+}
+
+/// Returns the per-second rate of increase between the last two points
+/// within the range (`start`, `end`]. Returns `None` if the range holds
+/// fewer than 2 points
+pub fn irate<I: IntoIterator<Item = Point>>(points: I, start: i64, end: i64) -> Option<f64> {
+    let mut last = None;
+    let mut prev = None;
+    for point in points.into_iter().filter(|p| p.ts > start && p.ts <= end) {
+        prev = last.replace(point);
+    }
+    let (prev, last): (Point, Point) = (prev?, last?);
+    let elapsed = last.ts - prev.ts;
+    if elapsed <= 0 {
+        return None;
+    }
+    let delta = if last.value < prev.value {
+        // counter reset, the counter increased from 0 to its last value
+        last.value
+    } else {
+        last.value - prev.value
+    };
+    Some(delta / elapsed as f64)
+}
+
+// Single pass summary of the points of a range
+struct Summary {
+    first: Point,
+    last: Point,
+    count: u64,
+    // sum of the values seen right before each reset
+    resets: f64,
+}
+
+// Mirrors extrapolatedRate of Prometheus
+fn extrapolated<I: IntoIterator<Item = Point>>(points: I, start: i64, end: i64, per_second: bool) -> Option<f64> {
+    let mut summary: Option<Summary> = None;
+    for point in points.into_iter().filter(|p| p.ts > start && p.ts <= end) {
+        match summary {
+            Some(ref mut summary) => {
+                if point.value < summary.last.value {
+                    summary.resets += summary.last.value;
+                }
+                summary.last = point;
+                summary.count += 1;
+            }
+            None => {
+                summary = Some(Summary {
+                    first: point,
+                    last: point,
+                    count: 1,
+                    resets: 0.0,
+                })
+            }
+        }
+    }
+    let summary = summary?;
+    if summary.count < 2 {
+        return None;
+    }
+
+    let result = summary.last.value - summary.first.value + summary.resets;
+    let sampled = (summary.last.ts - summary.first.ts) as f64;
+    if sampled <= 0.0 {
+        return None;
+    }
+    let average = sampled / (summary.count - 1) as f64;
+    // only extrapolate all the way to an edge if the points reach close
+    // enough to it, otherwise assume the series starts or ends half an
+    // average interval beyond the outermost points
+    let threshold = average * 1.1;
+    let mut to_start = (summary.first.ts - start) as f64;
+    if to_start >= threshold {
+        to_start = average / 2.0;
+    }
+    let mut to_end = (end - summary.last.ts) as f64;
+    if to_end >= threshold {
+        to_end = average / 2.0;
+    }
+    if result > 0.0 && summary.first.value >= 0.0 {
+        // a counter can't go below zero, so don't extrapolate past the
+        // point where it would have been zero
+        let to_zero = sampled * (summary.first.value / result);
+        if to_zero < to_start {
+            to_start = to_zero;
+        }
+    }
+    let interval = sampled + to_start + to_end;
+
+    let mut factor = interval / sampled;
+    if per_second {
+        factor /= (end - start) as f64;
+    }
+    Some(result * factor)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{increase, irate, rate};
+    use test_block;
+
+    #[test]
+    fn test_increase() {
+        let block = test_block(0, &[(10, 100.0), (20, 110.0), (30, 120.0), (40, 130.0), (50, 140.0)]);
+        // the first point is within an interval of the start of the range
+        // so the increase is extrapolated all the way to it
+        assert_eq!(Some(50.0), increase(block.iter(), 0, 50));
+        assert_eq!(Some(1.0), rate(block.iter(), 0, 50));
+        // the range ends more than an interval after the last point so the
+        // increase is only extrapolated by half an interval
+        assert_eq!(Some(45.0), increase(block.iter(), 10, 80));
+        assert_eq!(None, increase(block.iter(), 45, 55));
+    }
+
+    #[test]
+    fn test_increase_with_reset() {
+        let block = test_block(0, &[(10, 10.0), (20, 20.0), (30, 5.0), (40, 15.0)]);
+        // 10 -> 20 -> reset -> 5 -> 15 is an increase of 10 + 5 + 10
+        // extrapolated to both edges of the range
+        let increase = increase(block.iter(), 0, 50).unwrap();
+        assert!((increase - 25.0 * 50.0 / 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_extrapolation_stops_at_zero() {
+        let block = test_block(0, &[(100, 1.0), (110, 11.0), (120, 21.0)]);
+        // the counter would have been 0 at 99, so it isn't extrapolated to 90
+        assert_eq!(Some(20.0 * 21.0 / 20.0), increase(block.iter(), 90, 120));
+    }
+
+    #[test]
+    fn test_zero_clamp_after_threshold() {
+        let block = test_block(0, &[(100, 7.0), (110, 17.0), (120, 27.0)]);
+        // the start of the range is beyond the threshold so the increase is
+        // extrapolated by half an interval, which is less than the 7
+        // seconds back to zero
+        assert_eq!(Some(20.0 * 25.0 / 20.0), increase(block.iter(), 80, 120));
+    }
+
+    #[test]
+    fn test_irate() {
+        let block = test_block(0, &[(10, 10.0), (20, 20.0), (30, 50.0)]);
+        assert_eq!(Some(3.0), irate(block.iter(), 0, 30));
+        assert_eq!(Some(1.0), irate(block.iter(), 0, 29));
+        let block = test_block(0, &[(10, 10.0), (20, 20.0), (30, 5.0)]);
+        assert_eq!(Some(0.5), irate(block.iter(), 0, 30));
+        assert_eq!(None, irate(block.iter(), 25, 30));
+    }
+}
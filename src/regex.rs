@@ -0,0 +1,489 @@
+//! Regular expressions for label matchers.
+//!
+//! Like in Prometheus, a pattern matches a whole value rather than a part
+//! of it, so `a.*` matches "abc" but not "cab". The supported syntax is a
+//! subset of RE2:
+//!
+//! ```text
+//! abc          literals, metacharacters are escaped with a backslash
+//! .            any character
+//! [a-z_] [^0]  character classes, with ranges and negation
+//! \d \w \s     digits, word characters and whitespace, \D \W \S negated
+//! x* x+ x?     repetitions, greedy
+//! x{2} x{2,}   counted repetitions
+//! x{2,5}
+//! a|b (ab)     alternation and groups, `(?:ab)` is accepted too
+//! ```
+//!
+//! Patterns are compiled to a Thompson NFA whose states are all followed
+//! at once, so matching takes time proportional to the length of the value
+//! times the size of the compiled pattern, whatever the pattern. Counted
+//! repetitions are expanded when compiling, which is why their size is
+//! limited.
+
+use std::error;
+use std::fmt;
+
+// Counted repetitions beyond this are rejected to bound the pattern size
+const MAX_REPEAT: usize = 1000;
+// Patterns compiling to more instructions than this are rejected, as nested
+// counted repetitions multiply
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+/// Error raised by a pattern that could not be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    /// Offset in characters into the pattern
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.position)
+    }
+}
+
+impl error::Error for Error {}
+
+/// A compiled pattern
+#[derive(Clone, Debug)]
+pub struct Regex {
+    pattern: String,
+    program: Vec<Inst>,
+}
+
+// Instructions of the NFA, the program starting at 0
+#[derive(Clone, Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    // continues at both instructions
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Class),
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+#[derive(Clone, Debug)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn new(ranges: Vec<(char, char)>, negated: bool) -> Class {
+        Class { ranges, negated }
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+impl Regex {
+    /// Compiles `pattern`
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0 };
+        let root = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        let mut compiler = Compiler { program: Vec::new() };
+        compiler.alternation(&root)?;
+        compiler.push(Inst::Match)?;
+        Ok(Regex { pattern: pattern.to_string(), program: compiler.program })
+    }
+
+    /// Returns the pattern the regex was compiled from
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns whether the pattern matches the whole of `value`
+    pub fn is_match(&self, value: &str) -> bool {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        self.add(&mut current, 0);
+        for c in value.chars() {
+            if current.list.is_empty() {
+                return false;
+            }
+            next.clear();
+            for &pc in &current.list {
+                let matched = match self.program[pc] {
+                    Inst::Char(expected) => c == expected,
+                    Inst::Any => true,
+                    Inst::Class(ref class) => class.contains(c),
+                    _ => false,
+                };
+                if matched {
+                    self.add(&mut next, pc + 1);
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        current.list.iter().any(|&pc| matches!(self.program[pc], Inst::Match))
+    }
+
+    // Adds the thread at `pc` to `threads`, following splits and jumps to
+    // the instructions that consume a character or match
+    fn add(&self, threads: &mut Threads, pc: usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if !threads.mark(pc) {
+                continue;
+            }
+            match self.program[pc] {
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Jump(to) => stack.push(to),
+                _ => threads.list.push(pc),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+// Instructions reached at one position of the value
+struct Threads {
+    list: Vec<usize>,
+    // instructions already reached hold the current generation
+    seen: Vec<usize>,
+    generation: usize,
+}
+
+impl Threads {
+    fn new(len: usize) -> Threads {
+        Threads { list: Vec::new(), seen: vec![0; len], generation: 1 }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.generation += 1;
+    }
+
+    // Returns false if `pc` was already reached
+    fn mark(&mut self, pc: usize) -> bool {
+        let first = self.seen[pc] != self.generation;
+        self.seen[pc] = self.generation;
+        first
+    }
+}
+
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, Error> {
+        if self.program.len() >= MAX_INSTRUCTIONS {
+            return Err(error_at(0, "pattern too large"));
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn alternation(&mut self, alternatives: &[Vec<Node>]) -> Result<(), Error> {
+        let mut jumps = Vec::new();
+        for (i, alternative) in alternatives.iter().enumerate() {
+            if i + 1 == alternatives.len() {
+                self.sequence(alternative)?;
+                break;
+            }
+            let split = self.push(Inst::Split(0, 0))?;
+            self.sequence(alternative)?;
+            jumps.push(self.push(Inst::Jump(0))?);
+            self.program[split] = Inst::Split(split + 1, self.program.len());
+        }
+        let end = self.program.len();
+        for jump in jumps {
+            self.program[jump] = Inst::Jump(end);
+        }
+        Ok(())
+    }
+
+    fn sequence(&mut self, nodes: &[Node]) -> Result<(), Error> {
+        nodes.iter().try_for_each(|node| self.node(node))
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), Error> {
+        match *node {
+            Node::Char(c) => self.push(Inst::Char(c)).map(drop),
+            Node::Any => self.push(Inst::Any).map(drop),
+            Node::Class(ref class) => self.push(Inst::Class(class.clone())).map(drop),
+            Node::Group(ref alternatives) => self.alternation(alternatives),
+            Node::Repeat(ref inner, min, max) => {
+                for _ in 0..min {
+                    self.node(inner)?;
+                }
+                let mut splits = Vec::new();
+                match max {
+                    Some(max) => {
+                        for _ in min..max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.node(inner)?;
+                        }
+                    }
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        splits.push(split);
+                        self.node(inner)?;
+                        self.push(Inst::Jump(split))?;
+                    }
+                }
+                let end = self.program.len();
+                for split in splits {
+                    self.program[split] = Inst::Split(split + 1, end);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> Error {
+        error_at(self.pos, message)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, Error> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, Error> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repetition(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, Error> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '(' => {
+                if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                }
+                let group = self.alternation()?;
+                if self.peek() != Some(')') {
+                    return Err(self.error("missing )"));
+                }
+                self.pos += 1;
+                Ok(Node::Group(group))
+            }
+            '[' => self.class().map(Node::Class),
+            '\\' => self.escape(),
+            '*' | '+' | '?' | '{' => Err(error_at(self.pos - 1, "nothing to repeat")),
+            '^' | '$' => Err(error_at(self.pos - 1, "anchors aren't supported")),
+            _ => Ok(Node::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, Error> {
+        let c = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+        self.pos += 1;
+        if let Some(class) = shorthand(c) {
+            return Ok(Node::Class(class));
+        }
+        match c {
+            'n' => Ok(Node::Char('\n')),
+            't' => Ok(Node::Char('\t')),
+            _ if c.is_ascii_alphanumeric() => Err(error_at(self.pos - 2, &format!("unsupported escape \\{}", c))),
+            _ => Ok(Node::Char(c)),
+        }
+    }
+
+    fn class(&mut self) -> Result<Class, Error> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("missing ]"))?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.error("trailing backslash"))?;
+                    self.pos += 1;
+                    if let Some(class) = shorthand(escaped) {
+                        if class.negated {
+                            return Err(self.error("negated shorthand within a class"));
+                        }
+                        ranges.extend(class.ranges);
+                        continue;
+                    }
+                    escaped
+                }
+                _ => c,
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&next| next != ']') {
+                let hi = self.chars[self.pos + 1];
+                self.pos += 2;
+                if hi < lo {
+                    return Err(self.error("invalid class range"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Class::new(ranges, negated))
+    }
+
+    fn repetition(&mut self, atom: Node) -> Result<Node, Error> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn counted(&mut self, atom: Node) -> Result<Node, Error> {
+        self.pos += 1;
+        let min = self.number()?;
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            if self.peek() == Some('}') { None } else { Some(self.number()?) }
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some('}') {
+            return Err(self.error("missing }"));
+        }
+        self.pos += 1;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("invalid repetition range"));
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn number(&mut self) -> Result<usize, Error> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(n) if n <= MAX_REPEAT => Ok(n),
+            _ => Err(error_at(start, "invalid repetition count")),
+        }
+    }
+}
+
+fn error_at(position: usize, message: &str) -> Error {
+    Error { position, message: message.to_string() }
+}
+
+// Returns the class of a `\d`, `\w` or `\s` escape and their negations
+fn shorthand(c: char) -> Option<Class> {
+    let ranges = match c.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        's' => vec![('\t', '\n'), ('\x0c', '\r'), (' ', ' ')],
+        _ => return None,
+    };
+    Some(Class::new(ranges, c.is_ascii_uppercase()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Regex;
+
+    #[test]
+    fn test_match() {
+        let cases = [
+            ("abc", "abc", true),
+            ("abc", "abcd", false),
+            ("a.*", "abc", true),
+            ("a.*", "cab", false),
+            ("api|web", "web", true),
+            ("api|web", "webs", false),
+            ("(ab)+c?", "ababc", true),
+            ("(?:ab)+", "aba", false),
+            ("[a-c_]+", "a_cb", true),
+            ("[^0-9]*", "abc1", false),
+            ("\\d{2,3}", "123", true),
+            ("\\d{2,3}", "1234", false),
+            ("x{2,}", "xxxx", true),
+            ("\\w+\\.\\w+", "host.local", true),
+            ("\\S+", "a b", false),
+            ("(a*)*b", "aaab", true),
+            ("(a|ab)(c|bcd)", "abcd", true),
+            ("a{2}(b|c){0,2}", "aacb", true),
+            ("a{2}(b|c){0,2}", "aacbb", false),
+            ("a|", "", true),
+            ("[]a]", "]", true),
+            ("[a-]", "-", true),
+        ];
+        for &(pattern, value, expected) in cases.iter() {
+            let regex = Regex::new(pattern).unwrap();
+            assert_eq!(expected, regex.is_match(value), "{} ~ {:?}", pattern, value);
+        }
+    }
+
+    #[test]
+    fn test_pathological() {
+        // patterns that take exponential time to backtrack
+        let value = "a".repeat(100);
+        assert!(!Regex::new("(a|a)*b").unwrap().is_match(&value));
+        assert!(!Regex::new("(a*)*c").unwrap().is_match(&value));
+        assert!(Regex::new("(a|a)*").unwrap().is_match(&value));
+    }
+
+    #[test]
+    fn test_errors() {
+        for &(pattern, position) in [("(ab", 3), ("ab)", 2), ("*a", 0), ("[a", 2), ("a{3,1}", 6), ("\\q", 0),
+                                     ("^a", 0), ("a{2000}", 2), ("(a{100}){200}", 0)].iter() {
+            let err = Regex::new(pattern).unwrap_err();
+            assert_eq!(position, err.position, "{}: {}", pattern, err);
+        }
+    }
+}
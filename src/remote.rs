@@ -0,0 +1,697 @@
+//! Prometheus remote storage protocol.
+//!
+//! A `Receiver` lets Prometheus use the store as long-term storage, with
+//! the following in its configuration:
+//!
+//! ```text
+//! remote_write:
+//!   - url: http://localhost:9201/api/v1/write
+//! remote_read:
+//!   - url: http://localhost:9201/api/v1/read
+//! ```
+//!
+//! Both endpoints take snappy compressed protobuf messages. Written samples
+//! are stored under their `__name__` label and other labels, with their
+//! millisecond timestamp truncated to seconds. Stale markers, which
+//! Prometheus writes once a series disappears, aren't stored. A write
+//! holding a sample the validator of the store rejects fails as a whole,
+//! see `validate`. Reads select
+//! float and integer series with the label matchers of each query and
+//! answer with their samples, only the `SAMPLES` response type is
+//! supported.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use http::{Request, Response, Server};
+use ingest::{Closed, Handle, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use protobuf::{self, Reader, LENGTH_DELIMITED, VARINT};
+use query::{MatchOp, Matcher, METRIC_NAME};
+use regex;
+use snappy;
+use store::{SeriesKey, Store};
+use time;
+use validate::{Reason, Verdict};
+
+/// Path of the remote write endpoint
+pub const WRITE_PATH: &str = "/api/v1/write";
+
+/// Path of the remote read endpoint
+pub const READ_PATH: &str = "/api/v1/read";
+
+/// Default limit of the size of a decompressed request
+pub const DEFAULT_MAX_BODY: usize = 32 << 20;
+
+// Value Prometheus writes to mark a series as stale
+const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+// ReadRequest.ResponseType.SAMPLES
+const SAMPLES: u64 = 0;
+
+/// Reasons a request can't be answered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Snappy(snappy::Error),
+    Protobuf(protobuf::Error),
+    /// A written series has no `__name__` label
+    MissingName,
+    /// A matcher type other than `EQ`, `NEQ`, `RE` and `NRE`
+    UnknownMatcherType(u64),
+    InvalidRegex(String, regex::Error),
+    /// The client accepts none of the response types supported
+    UnsupportedResponseTypes(Vec<u64>),
+    /// The validator rejected the sample of a series at a timestamp
+    Rejected(SeriesKey, i64, Reason),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Snappy(ref err) => write!(f, "{}", err),
+            Error::Protobuf(ref err) => write!(f, "invalid message: {}", err),
+            Error::MissingName => write!(f, "series without a {} label", METRIC_NAME),
+            Error::UnknownMatcherType(kind) => write!(f, "unknown matcher type {}", kind),
+            Error::InvalidRegex(ref pattern, ref err) => write!(f, "invalid regex {:?}: {}", pattern, err),
+            Error::UnsupportedResponseTypes(ref types) => {
+                write!(f, "unsupported response types {:?}, only SAMPLES is supported", types)
+            }
+            Error::Rejected(ref key, ts, reason) => write!(f, "sample of {} at {} rejected: {}", key, ts, reason.name()),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<snappy::Error> for Error {
+    fn from(err: snappy::Error) -> Error {
+        Error::Snappy(err)
+    }
+}
+
+impl From<protobuf::Error> for Error {
+    fn from(err: protobuf::Error) -> Error {
+        Error::Protobuf(err)
+    }
+}
+
+/// Decodes the samples of an uncompressed `WriteRequest`
+pub fn decode_write(message: &[u8]) -> Result<Vec<Sample>, Error> {
+    let mut samples = Vec::new();
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            // repeated TimeSeries timeseries
+            1 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                decode_timeseries(reader.bytes()?, &mut samples)?;
+            }
+            // metadata and anything newer
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(samples)
+}
+
+fn decode_timeseries(message: &[u8], samples: &mut Vec<Sample>) -> Result<(), Error> {
+    let mut labels = Vec::new();
+    let mut points = Vec::new();
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            // repeated Label labels
+            1 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                labels.push(decode_label(reader.bytes()?)?);
+            }
+            // repeated Sample samples
+            2 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                points.push(decode_sample(reader.bytes()?)?);
+            }
+            // exemplars and native histograms
+            _ => reader.skip(wire_type)?,
+        }
+    }
+
+    let mut metric = None;
+    let mut tags = BTreeMap::new();
+    for (name, value) in labels {
+        if name == METRIC_NAME {
+            metric = Some(value);
+        } else if !value.is_empty() {
+            tags.insert(name, value);
+        }
+    }
+    let key = SeriesKey { metric: metric.filter(|m| !m.is_empty()).ok_or(Error::MissingName)?, tags };
+    for (value, ts) in points {
+        if value.to_bits() == STALE_NAN {
+            continue;
+        }
+        samples.push(Sample { key: key.clone(), value: Value::Float(value), ts: ts.div_euclid(1000) });
+    }
+    Ok(())
+}
+
+fn decode_label(message: &[u8]) -> Result<(String, String), Error> {
+    let (mut name, mut value) = (String::new(), String::new());
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            1 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                name = reader.string()?.to_string();
+            }
+            2 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                value = reader.string()?.to_string();
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok((name, value))
+}
+
+// Returns the value and millisecond timestamp of a sample
+fn decode_sample(message: &[u8]) -> Result<(f64, i64), Error> {
+    let (mut value, mut ts) = (0.0, 0);
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            1 => {
+                protobuf::expect(field, wire_type, protobuf::FIXED64)?;
+                value = reader.double()?;
+            }
+            2 => {
+                protobuf::expect(field, wire_type, VARINT)?;
+                ts = reader.varint()? as i64;
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok((value, ts))
+}
+
+/// Query of a `ReadRequest`
+#[derive(Clone, Debug)]
+pub struct Query {
+    /// Start of the range in milliseconds, inclusive
+    pub start_ms: i64,
+    /// End of the range in milliseconds, inclusive
+    pub end_ms: i64,
+    pub matchers: Vec<Matcher>,
+}
+
+/// Decodes the queries of an uncompressed `ReadRequest`
+pub fn decode_read(message: &[u8]) -> Result<Vec<Query>, Error> {
+    let mut queries = Vec::new();
+    let mut response_types = Vec::new();
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            // repeated Query queries
+            1 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                queries.push(decode_query(reader.bytes()?)?);
+            }
+            // repeated ResponseType accepted_response_types, usually packed
+            2 if wire_type == LENGTH_DELIMITED => {
+                let mut packed = Reader::new(reader.bytes()?);
+                while !packed.is_empty() {
+                    response_types.push(packed.varint()?);
+                }
+            }
+            2 => {
+                protobuf::expect(field, wire_type, VARINT)?;
+                response_types.push(reader.varint()?);
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    // no types at all means SAMPLES
+    if !response_types.is_empty() && !response_types.contains(&SAMPLES) {
+        return Err(Error::UnsupportedResponseTypes(response_types));
+    }
+    Ok(queries)
+}
+
+fn decode_query(message: &[u8]) -> Result<Query, Error> {
+    let mut query = Query { start_ms: 0, end_ms: 0, matchers: Vec::new() };
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            1 => {
+                protobuf::expect(field, wire_type, VARINT)?;
+                query.start_ms = reader.varint()? as i64;
+            }
+            2 => {
+                protobuf::expect(field, wire_type, VARINT)?;
+                query.end_ms = reader.varint()? as i64;
+            }
+            3 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                query.matchers.push(decode_matcher(reader.bytes()?)?);
+            }
+            // ReadHints
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(query)
+}
+
+fn decode_matcher(message: &[u8]) -> Result<Matcher, Error> {
+    let (mut kind, mut name, mut value) = (0, String::new(), String::new());
+    let mut reader = Reader::new(message);
+    while let Some((field, wire_type)) = reader.next_field()? {
+        match field {
+            1 => {
+                protobuf::expect(field, wire_type, VARINT)?;
+                kind = reader.varint()?;
+            }
+            2 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                name = reader.string()?.to_string();
+            }
+            3 => {
+                protobuf::expect(field, wire_type, LENGTH_DELIMITED)?;
+                value = reader.string()?.to_string();
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    let op = match kind {
+        0 => MatchOp::Equal,
+        1 => MatchOp::NotEqual,
+        2 => MatchOp::Regex,
+        3 => MatchOp::NotRegex,
+        _ => return Err(Error::UnknownMatcherType(kind)),
+    };
+    Matcher::new(name, op, value.as_str()).map_err(|err| Error::InvalidRegex(value, err))
+}
+
+/// Answers `queries` from `store` with an uncompressed `ReadResponse`
+/// holding one `QueryResult` per query. Series are ordered by key and
+/// their labels by name
+pub fn encode_read(store: &Store, queries: &[Query]) -> Vec<u8> {
+    let mut response = protobuf::Writer::new();
+    for query in queries {
+        let mut result = protobuf::Writer::new();
+        for (key, points) in select(store, query) {
+            result.message(1, &encode_timeseries(&key, &points));
+        }
+        response.message(1, &result);
+    }
+    response.into_vec()
+}
+
+// Returns the points of the series matching `query`, with millisecond
+// timestamps
+fn select(store: &Store, query: &Query) -> BTreeMap<SeriesKey, Vec<(i64, f64)>> {
+    let matches = |key: &SeriesKey| query.matchers.iter().all(|matcher| matcher.matches_key(key));
+    // points are stored by the second
+    let (start, end) = (query.start_ms.div_euclid(1000), query.end_ms.div_euclid(1000) + 1);
+    let within = |ts: i64| ts * 1000 >= query.start_ms && ts * 1000 <= query.end_ms;
+    let mut selected: BTreeMap<SeriesKey, Vec<(i64, f64)>> = BTreeMap::new();
+    for (key, series) in store.iter().filter(|(key, _)| matches(key)) {
+        let points = series.points(start, end).into_iter().filter(|p| within(p.ts)).map(|p| (p.ts * 1000, p.value));
+        selected.entry(key.clone()).or_default().extend(points);
+    }
+    for (key, series) in store.iter_ints().filter(|(key, _)| matches(key)) {
+        let points = series.points(start, end).into_iter().filter(|p| within(p.ts));
+        selected.entry(key.clone()).or_default().extend(points.map(|p| (p.ts * 1000, p.value as f64)));
+    }
+    selected.retain(|_, points| !points.is_empty());
+    for points in selected.values_mut() {
+        // stable so that float points stay first at equal timestamps
+        points.sort_by_key(|&(ts, _)| ts);
+    }
+    selected
+}
+
+fn encode_timeseries(key: &SeriesKey, points: &[(i64, f64)]) -> protobuf::Writer {
+    let mut labels: Vec<(&str, &str)> = key.tags.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+    labels.push((METRIC_NAME, &key.metric));
+    labels.sort();
+    let mut timeseries = protobuf::Writer::new();
+    for (name, value) in labels {
+        let mut label = protobuf::Writer::new();
+        label.string(1, name);
+        label.string(2, value);
+        timeseries.message(1, &label);
+    }
+    for &(ts, value) in points {
+        let mut sample = protobuf::Writer::new();
+        sample.double(1, value);
+        sample.varint(2, ts as u64);
+        timeseries.message(2, &sample);
+    }
+    timeseries
+}
+
+/// Settings of a receiver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Largest decompressed request accepted
+    pub max_body: usize,
+    /// Batches queued up for the writer before writes wait
+    pub queue: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_body: DEFAULT_MAX_BODY,
+            queue: DEFAULT_QUEUE,
+        }
+    }
+}
+
+/// Counters of a receiver
+#[derive(Debug, Default)]
+pub struct Stats {
+    writes: AtomicU64,
+    samples: AtomicU64,
+    reads: AtomicU64,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of write requests received
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of samples written
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of read requests received
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of requests rejected
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last rejected request
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn fail(&self, err: &Error) -> Response {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(err.to_string());
+        Response::text(400, err.to_string())
+    }
+}
+
+/// HTTP server answering remote write and read requests from Prometheus
+pub struct Receiver {
+    server: Option<Server>,
+    stats: Arc<Stats>,
+    writer: Option<Writer>,
+}
+
+impl Receiver {
+    /// Starts listening on `addr` for samples to write into `store` and
+    /// queries to answer from it
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Receiver> {
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store.clone(), config.queue);
+        let endpoint = Endpoint {
+            store,
+            handle: writer.handle(),
+            stats: stats.clone(),
+            max_body: config.max_body,
+        };
+        let server = Server::bind(addr, move |request: &Request| endpoint.handle(request))?;
+        Ok(Receiver {
+            server: Some(server),
+            stats,
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the receiver is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.as_ref().expect("server running").local_addr()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        // requests in flight still hold batchers of the writer
+        self.server.take();
+        self.writer.take();
+    }
+}
+
+struct Endpoint {
+    store: Arc<RwLock<Store>>,
+    handle: Handle,
+    stats: Arc<Stats>,
+    max_body: usize,
+}
+
+impl Endpoint {
+    fn handle(&self, request: &Request) -> Response {
+        if request.path != WRITE_PATH && request.path != READ_PATH {
+            return Response::text(404, "not found");
+        }
+        if request.method != "POST" {
+            return Response::text(405, "method not allowed").header_value("Allow", "POST");
+        }
+        if request.header("Content-Encoding").is_some_and(|encoding| !encoding.eq_ignore_ascii_case("snappy")) {
+            return Response::text(415, "only snappy content encoding is supported");
+        }
+        let counter = if request.path == WRITE_PATH { &self.stats.writes } else { &self.stats.reads };
+        counter.fetch_add(1, Ordering::SeqCst);
+        let result = snappy::decompress(&request.body, self.max_body).map_err(Error::from).and_then(|message| {
+            if request.path == WRITE_PATH {
+                self.write(&message)
+            } else {
+                self.read(&message)
+            }
+        });
+        result.unwrap_or_else(|err| self.stats.fail(&err))
+    }
+
+    fn write(&self, message: &[u8]) -> Result<Response, Error> {
+        let samples = decode_write(message)?;
+        self.check(&samples)?;
+        let count = samples.len() as u64;
+        let mut batcher = self.handle.batcher(DEFAULT_BATCH_SIZE);
+        let sent = samples.into_iter().try_for_each(|sample| batcher.push(sample)).and_then(|_| batcher.flush());
+        if let Err(Closed) = sent {
+            return Ok(Response::text(503, "shutting down"));
+        }
+        self.stats.samples.fetch_add(count, Ordering::SeqCst);
+        Ok(Response::new(204))
+    }
+
+    // Fails on the first sample the validator rejects, counting every
+    // rejected sample. The writer counts the verdicts of the others
+    fn check(&self, samples: &[Sample]) -> Result<(), Error> {
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut rejected = None;
+        for sample in samples {
+            if let verdict @ Verdict::Reject(reason) = store.validator().check(sample, now) {
+                store.validator().record(verdict);
+                rejected = rejected.or_else(|| Some(Error::Rejected(sample.key.clone(), sample.ts, reason)));
+            }
+        }
+        rejected.map_or(Ok(()), Err)
+    }
+
+    fn read(&self, message: &[u8]) -> Result<Response, Error> {
+        let queries = decode_read(message)?;
+        let encoded = {
+            let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+            encode_read(&store, &queries)
+        };
+        Ok(Response::with_body(200, "application/x-protobuf", snappy::compress(&encoded))
+            .header_value("Content-Encoding", "snappy"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{decode_read, decode_write, encode_read, Config, Error, Receiver, READ_PATH, WRITE_PATH};
+    use http::{self, Url};
+    use ingest::Value;
+    use protobuf::Writer;
+    use query::MatchOp;
+    use snappy;
+    use store::{SeriesKey, Store};
+    use validate::{Reason, Validation};
+
+    // Payloads generated by fixtures/remote/generate.py, compressed with the
+    // snappy encoder Prometheus uses so that they hold copies as well
+    const WRITE_REQUEST: &[u8] = include_bytes!("../fixtures/remote/write_request.bin");
+    const READ_REQUEST: &[u8] = include_bytes!("../fixtures/remote/read_request.bin");
+    const READ_RESPONSE: &[u8] = include_bytes!("../fixtures/remote/read_response.pb");
+
+    fn api_key(code: &str) -> SeriesKey {
+        SeriesKey::new("http_requests_total").tag("code", code).tag("instance", "10.0.0.1:9090").tag("job", "api")
+    }
+
+    #[test]
+    fn test_decode_write() {
+        let samples = decode_write(&snappy::decompress(WRITE_REQUEST, 1 << 20).unwrap()).unwrap();
+        // 8 samples, less the stale marker
+        assert_eq!(7, samples.len());
+        assert_eq!(api_key("200"), samples[0].key);
+        assert_eq!(Value::Float(1027.0), samples[0].value);
+        assert_eq!(1690000000, samples[0].ts);
+        // milliseconds are truncated
+        assert_eq!(1690000015, samples[1].ts);
+        let up = SeriesKey::new("up").tag("instance", "10.0.0.1:9090").tag("job", "api");
+        assert_eq!(vec![(up, 1690000000)],
+                   samples[6..].iter().map(|s| (s.key.clone(), s.ts)).collect::<Vec<_>>());
+
+        let mut label = Writer::new();
+        label.string(1, "job");
+        label.string(2, "api");
+        let mut timeseries = Writer::new();
+        timeseries.message(1, &label);
+        let mut request = Writer::new();
+        request.message(1, &timeseries);
+        assert_eq!(Err(Error::MissingName), decode_write(&request.into_vec()));
+    }
+
+    #[test]
+    fn test_decode_read() {
+        let queries = decode_read(&snappy::decompress(READ_REQUEST, 1 << 20).unwrap()).unwrap();
+        assert_eq!(2, queries.len());
+        assert_eq!((1690000000000, 1690000030000), (queries[0].start_ms, queries[0].end_ms));
+        assert_eq!(3, queries[0].matchers.len());
+        assert_eq!((MatchOp::Regex, "2.."), (queries[0].matchers[1].op, queries[0].matchers[1].value.as_str()));
+        assert!(queries[0].matchers[2].matches_key(&api_key("200")));
+
+        // STREAMED_XOR_CHUNKS only
+        let mut request = Writer::new();
+        request.bytes(2, &[1]);
+        assert_eq!(Err(Error::UnsupportedResponseTypes(vec![1])), decode_read(&request.into_vec()).map(|_| ()));
+
+        let mut matcher = Writer::new();
+        matcher.varint(1, 2);
+        matcher.string(2, "job");
+        matcher.string(3, "(api");
+        let mut query = Writer::new();
+        query.message(3, &matcher);
+        let mut request = Writer::new();
+        request.message(1, &query);
+        match decode_read(&request.into_vec()) {
+            Err(Error::InvalidRegex(ref pattern, _)) => assert_eq!("(api", pattern),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_encode_read() {
+        let mut store = Store::new();
+        for sample in decode_write(&snappy::decompress(WRITE_REQUEST, 1 << 20).unwrap()).unwrap() {
+            match sample.value {
+                Value::Float(value) => store.publish_at(&sample.key, value, sample.ts),
+                Value::Int(value) => store.publish_int_at(&sample.key, value, sample.ts),
+            }
+        }
+        let queries = decode_read(&snappy::decompress(READ_REQUEST, 1 << 20).unwrap()).unwrap();
+        assert_eq!(READ_RESPONSE, &encode_read(&store, &queries)[..]);
+
+        // integer series are read as floats
+        let mut store = Store::new();
+        store.publish_int_at(&SeriesKey::new("up"), 1, 1690000000);
+        let mut request = Writer::new();
+        let mut query = Writer::new();
+        query.varint(1, 1690000000000);
+        query.varint(2, 1690000000000);
+        request.message(1, &query);
+        let queries = decode_read(&request.into_vec()).unwrap();
+        let mut expected = Writer::new();
+        let mut result = Writer::new();
+        let mut timeseries = Writer::new();
+        let mut label = Writer::new();
+        label.string(1, "__name__");
+        label.string(2, "up");
+        timeseries.message(1, &label);
+        let mut sample = Writer::new();
+        sample.double(1, 1.0);
+        sample.varint(2, 1690000000000);
+        timeseries.message(2, &sample);
+        result.message(1, &timeseries);
+        expected.message(1, &result);
+        assert_eq!(expected.into_vec(), encode_read(&store, &queries));
+    }
+
+    #[test]
+    fn test_receiver() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let receiver = Receiver::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = |path: &str| Url::parse(&format!("http://{}{}", receiver.local_addr(), path)).unwrap();
+        let timeout = Duration::from_secs(5);
+        let headers = [("Content-Encoding", "snappy"), ("Content-Type", "application/x-protobuf"),
+                       ("X-Prometheus-Remote-Write-Version", "0.1.0")];
+
+        let response = http::post(&url(WRITE_PATH), &headers, WRITE_REQUEST, timeout).unwrap();
+        assert_eq!(204, response.status);
+        let deadline = Instant::now() + timeout;
+        while receiver.written() < 7 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(7, receiver.written());
+        assert_eq!(3, store.read().unwrap().get(&api_key("200")).unwrap().points(0, i64::MAX).len());
+
+        let response = http::post(&url(READ_PATH), &headers, READ_REQUEST, timeout).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("snappy"), response.header("Content-Encoding"));
+        assert_eq!(Ok(READ_RESPONSE.to_vec()), snappy::decompress(&response.body, 1 << 20));
+
+        assert_eq!(400, http::post(&url(WRITE_PATH), &headers, b"\x05garbage", timeout).unwrap().status);
+        assert_eq!(Some("invalid copy offset in snappy block".to_string()), receiver.stats().last_error());
+        assert_eq!(415, http::post(&url(WRITE_PATH), &[("Content-Encoding", "gzip")], b"", timeout).unwrap().status);
+        assert_eq!(405, http::get(&url(READ_PATH), &[], timeout).unwrap().status);
+        assert_eq!(404, http::get(&url("/metrics"), &[], timeout).unwrap().status);
+        assert_eq!((2, 1, 1), (receiver.stats().writes(), receiver.stats().reads(), receiver.stats().failures()));
+    }
+
+    #[test]
+    fn test_validation() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().set_validation(Validation { max_past_age: Some(3600), ..Validation::default() });
+        let receiver = Receiver::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let url = Url::parse(&format!("http://{}{}", receiver.local_addr(), WRITE_PATH)).unwrap();
+
+        // every sample of the fixture is years old
+        let response = http::post(&url, &[("Content-Encoding", "snappy")], WRITE_REQUEST, Duration::from_secs(5)).unwrap();
+        assert_eq!(400, response.status);
+        let error = format!("sample of {} at 1690000000 rejected: too_old\n", api_key("200"));
+        assert_eq!(error, String::from_utf8_lossy(&response.body));
+        assert_eq!(7, store.read().unwrap().validator().rejected(Reason::TooOld));
+        assert_eq!((0, 1), (receiver.stats().samples(), receiver.stats().failures()));
+        drop(receiver);
+        assert!(store.read().unwrap().is_empty());
+    }
+}
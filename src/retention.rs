@@ -0,0 +1,165 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use time;
+
+use store::Store;
+
+/// Per-metric retention settings.
+///
+/// A metric without its own setting falls back to the default retention,
+/// and data is kept forever if neither is set. These settings apply to
+/// raw points, rollups have their own per resolution and are kept forever
+/// unless it is set.
+#[derive(Clone, Default)]
+pub struct Retention {
+    default: Option<i64>,
+    metrics: FnvHashMap<String, i64>,
+    rollups: FnvHashMap<i64, i64>,
+}
+
+impl Retention {
+    /// Creates retention settings that keep all data forever
+    pub fn new() -> Retention {
+        Retention::default()
+    }
+
+    /// Sets the retention in seconds used by metrics without their own setting
+    pub fn set_default(&mut self, retention: Option<i64>) {
+        self.default = retention;
+    }
+
+    /// Returns the retention in seconds used by metrics without their own setting
+    pub fn default_retention(&self) -> Option<i64> {
+        self.default
+    }
+
+    /// Returns an iterator over every metric with its own retention setting
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i64)> {
+        self.metrics.iter().map(|(metric, &retention)| (metric.as_str(), retention))
+    }
+
+    /// Keeps the data of `metric` for `retention` seconds
+    pub fn set<S: Into<String>>(&mut self, metric: S, retention: i64) {
+        self.metrics.insert(metric.into(), retention);
+    }
+
+    /// Removes the retention setting of `metric`
+    pub fn unset(&mut self, metric: &str) {
+        self.metrics.remove(metric);
+    }
+
+    /// Returns the retention in seconds of `metric`, if any
+    pub fn get(&self, metric: &str) -> Option<i64> {
+        self.metrics.get(metric).cloned().or(self.default)
+    }
+
+    /// Returns the timestamp before which data of `metric` has expired as of
+    /// `now` seconds from the epoch, if any
+    pub fn cutoff(&self, metric: &str, now: i64) -> Option<i64> {
+        self.get(metric).map(|retention| now - retention)
+    }
+
+    /// Keeps the rollups of `resolution` second windows for `retention`
+    /// seconds
+    pub fn set_rollup(&mut self, resolution: i64, retention: i64) {
+        self.rollups.insert(resolution, retention);
+    }
+
+    /// Removes the retention setting of the rollups of `resolution` second
+    /// windows
+    pub fn unset_rollup(&mut self, resolution: i64) {
+        self.rollups.remove(&resolution);
+    }
+
+    /// Returns an iterator over every rollup resolution with a retention
+    /// setting
+    pub fn iter_rollups(&self) -> impl Iterator<Item = (i64, i64)> + '_ {
+        self.rollups.iter().map(|(&resolution, &retention)| (resolution, retention))
+    }
+
+    /// Returns the timestamp before which the rollups of `resolution`
+    /// second windows have expired as of `now`, if any
+    pub fn rollup_cutoff(&self, resolution: i64, now: i64) -> Option<i64> {
+        self.rollups.get(&resolution).map(|retention| now - retention)
+    }
+}
+
+/// Periodically sweeps expired blocks out of a shared store on a
+/// background thread. The thread is stopped when the sweeper is dropped
+pub struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    /// Spawns a thread sweeping `store` every `interval`
+    pub fn spawn(store: Arc<RwLock<Store>>, interval: Duration) -> Sweeper {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let now = time::get_time().sec;
+                if let Ok(mut store) = store.write() {
+                    store.sweep(now);
+                }
+            }
+        });
+        Sweeper {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Retention, Sweeper};
+    use store::{SeriesKey, Store};
+
+    #[test]
+    fn test_retention() {
+        let mut retention = Retention::new();
+        assert_eq!(None, retention.cutoff("cpu", 100));
+        retention.set_default(Some(50));
+        retention.set("cpu", 10);
+        assert_eq!(Some(90), retention.cutoff("cpu", 100));
+        assert_eq!(Some(50), retention.cutoff("mem", 100));
+        retention.unset("cpu");
+        assert_eq!(Some(50), retention.get("cpu"));
+    }
+
+    #[test]
+    fn test_sweeper() {
+        let key = SeriesKey::new("cpu");
+        let mut store = Store::new();
+        store.retention_mut().set("cpu", 60);
+        store.publish_at(&key, 1.0, 0);
+        let store = Arc::new(RwLock::new(store));
+        let sweeper = Sweeper::spawn(store.clone(), Duration::from_millis(1));
+        for _ in 0..1000 {
+            if store.read().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(sweeper);
+        assert!(store.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+use std::cmp;
+
+use compact::Compactor;
+use quantile::{self, SketchRollup};
+use retention::Retention;
+use series::Series;
+use Point;
+
+/// Resolution of 5 minute rollups
+pub const FIVE_MINUTES: i64 = 5 * 60;
+
+/// Resolution of 1 hour rollups
+pub const ONE_HOUR: i64 = 60 * 60;
+
+// Rollup series hold few points per window so use 4 hour blocks, the
+// largest whole number of hours within the 2^14 second maximum block size
+// of a series, and rely on compaction to merge them further
+const ROLLUP_BLOCK_SIZE: i64 = 4 * 60 * 60;
+
+/// Aggregates kept for every rollup window
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Aggregate {
+    Min,
+    Max,
+    Sum,
+    Count,
+    Last,
+}
+
+impl Aggregate {
+    /// Every aggregate, in the order their rollup series are stored
+    pub const ALL: [Aggregate; 5] = [Aggregate::Min,
+                                     Aggregate::Max,
+                                     Aggregate::Sum,
+                                     Aggregate::Count,
+                                     Aggregate::Last];
+
+    // Returns the value a raw point contributes to the aggregate
+    fn raw_value(self, value: f64) -> f64 {
+        match self {
+            Aggregate::Count => 1.0,
+            _ => value,
+        }
+    }
+
+    // Combines an aggregated value with the next value of the window.
+    // Counts of different windows are added together
+    fn combine(self, acc: f64, value: f64) -> f64 {
+        match self {
+            Aggregate::Min => acc.min(value),
+            Aggregate::Max => acc.max(value),
+            Aggregate::Sum | Aggregate::Count => acc + value,
+            Aggregate::Last => value,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Lower resolution copies of a raw series.
+///
+/// Every complete window of `resolution` seconds is summarized into one
+/// point per `Aggregate`, each aggregate being stored as its own
+/// compressed series so rollups benefit from the same encoding as raw data.
+/// The values of every window are also kept as a quantile sketch.
+#[derive(Clone)]
+pub struct Rollup {
+    resolution: i64,
+    watermark: Option<i64>,
+    series: Vec<Series>,
+    sketches: SketchRollup,
+}
+
+impl Rollup {
+    /// Creates an empty rollup summarizing `resolution` second windows
+    pub fn new(resolution: i64) -> Rollup {
+        assert!(resolution > 0, "rollup resolution must be positive");
+        Rollup {
+            resolution,
+            watermark: None,
+            series: Aggregate::ALL.iter().map(|_| Series::with_block_size(ROLLUP_BLOCK_SIZE)).collect(),
+            sketches: SketchRollup::new(resolution, quantile::DEFAULT_ALPHA),
+        }
+    }
+
+    /// Recreates a rollup from its aggregate series, ordered as
+    /// `Aggregate::ALL`, its watermark and its sketches
+    pub fn from_series(resolution: i64,
+                       watermark: Option<i64>,
+                       series: Vec<Series>,
+                       sketches: SketchRollup)
+                       -> Rollup {
+        assert_eq!(Aggregate::ALL.len(), series.len());
+        assert_eq!(resolution, sketches.resolution());
+        Rollup {
+            resolution,
+            watermark,
+            series,
+            sketches,
+        }
+    }
+
+    /// Returns the length in seconds of a rollup window
+    pub fn resolution(&self) -> i64 {
+        self.resolution
+    }
+
+    /// Returns the end of the last window rolled up, if any. It moves back
+    /// when raw points are added to windows already rolled up, see `rewind`
+    pub fn watermark(&self) -> Option<i64> {
+        self.watermark
+    }
+
+    /// Returns the series holding `aggregate` for every rolled up window.
+    /// Points are timestamped with the start of their window, and those at
+    /// or after the watermark are stale until the next update
+    pub fn series(&self, aggregate: Aggregate) -> &Series {
+        &self.series[aggregate.index()]
+    }
+
+    /// Returns every aggregate series, ordered as `Aggregate::ALL`
+    pub fn all_series(&self) -> &[Series] {
+        &self.series
+    }
+
+    /// Returns the quantile sketches of every rolled up window
+    pub fn sketches(&self) -> &SketchRollup {
+        &self.sketches
+    }
+
+    /// Rolls up the windows of `raw` that end at or before `upto` and
+    /// haven't been rolled up yet
+    pub fn update(&mut self, raw: &Series, upto: i64) {
+        self.sketches.update(raw, upto);
+        let upto = upto - upto.rem_euclid(self.resolution);
+        let from = self.watermark.unwrap_or(i64::MIN);
+        if upto <= from {
+            return;
+        }
+        for series in &mut self.series {
+            truncate(series, from);
+        }
+        let points = raw.points(from, upto);
+        let mut points = points.iter().peekable();
+        while let Some(first) = points.next() {
+            let start = first.ts - first.ts.rem_euclid(self.resolution);
+            let mut values = [0.0; 5];
+            for aggregate in &Aggregate::ALL {
+                values[aggregate.index()] = aggregate.raw_value(first.value);
+            }
+            while let Some(point) = points.next_if(|p| p.ts < start + self.resolution) {
+                for aggregate in &Aggregate::ALL {
+                    let acc = values[aggregate.index()];
+                    values[aggregate.index()] = aggregate.combine(acc, aggregate.raw_value(point.value));
+                }
+            }
+            for aggregate in &Aggregate::ALL {
+                self.series[aggregate.index()].publish_at(values[aggregate.index()], start);
+            }
+        }
+        self.watermark = Some(upto);
+    }
+
+    /// Rolls up the windows from the one holding `from` again on the next
+    /// update, after raw points were added to them. Windows starting before
+    /// `expired_before` are left alone since their raw points may be gone
+    pub fn rewind(&mut self, from: i64, expired_before: i64) {
+        let mut start = from - from.rem_euclid(self.resolution);
+        if start < expired_before {
+            start = expired_before + (self.resolution - expired_before.rem_euclid(self.resolution)) % self.resolution;
+        }
+        if self.watermark.is_some_and(|watermark| start < watermark) {
+            self.watermark = Some(start);
+        }
+        self.sketches.rewind(start);
+    }
+
+    /// Drops the blocks of every aggregate series whose last window starts
+    /// before `cutoff` and the sketches of the windows ending by then,
+    /// returning the number of blocks dropped
+    pub fn expire(&mut self, cutoff: i64) -> usize {
+        self.sketches.expire(cutoff);
+        self.series.iter_mut().map(|series| series.expire(cutoff)).sum()
+    }
+
+    /// Returns true if no window is held by the rollup
+    pub fn is_empty(&self) -> bool {
+        self.series.iter().all(Series::is_empty) && self.sketches.is_empty()
+    }
+
+    /// Compacts the sealed blocks of every aggregate series
+    pub fn compact(&mut self, compactor: &Compactor) {
+        for series in &mut self.series {
+            compactor.compact_series(series);
+        }
+    }
+}
+
+// Drops the points of `series` at or after `end`, left by windows that were
+// rolled up before being rewound
+fn truncate(series: &mut Series, end: i64) {
+    if series.blocks().all(|block| block.last_ts().is_none_or(|ts| ts < end)) {
+        return;
+    }
+    let mut kept = Series::with_block_size(series.block_size());
+    for point in series.points(i64::MIN, end) {
+        kept.publish_at(point.value, point.ts);
+    }
+    *series = kept;
+}
+
+/// The set of rollups kept for a single raw series
+#[derive(Clone)]
+pub struct Rollups {
+    // sorted by increasing resolution
+    rollups: Vec<Rollup>,
+}
+
+impl Default for Rollups {
+    fn default() -> Rollups {
+        Rollups::new(&[FIVE_MINUTES, ONE_HOUR])
+    }
+}
+
+impl Rollups {
+    /// Creates empty rollups for each of `resolutions`
+    pub fn new(resolutions: &[i64]) -> Rollups {
+        Rollups::from_rollups(resolutions.iter().map(|&r| Rollup::new(r)).collect())
+    }
+
+    /// Creates rollups from existing rollups
+    pub fn from_rollups(mut rollups: Vec<Rollup>) -> Rollups {
+        rollups.sort_by_key(|rollup| rollup.resolution);
+        Rollups { rollups }
+    }
+
+    /// Returns the rollups ordered by increasing resolution
+    pub fn rollups(&self) -> &[Rollup] {
+        &self.rollups
+    }
+
+    /// Retrieves the rollup with the given resolution
+    pub fn get(&self, resolution: i64) -> Option<&Rollup> {
+        self.rollups.iter().find(|rollup| rollup.resolution == resolution)
+    }
+
+    /// Rolls up the complete windows of `raw` ending at or before `upto`
+    pub fn update(&mut self, raw: &Series, upto: i64) {
+        for rollup in &mut self.rollups {
+            rollup.update(raw, upto);
+        }
+    }
+
+    /// Rolls up the windows from the one holding `from` again, see
+    /// `Rollup::rewind`
+    pub fn rewind(&mut self, from: i64, expired_before: i64) {
+        for rollup in &mut self.rollups {
+            rollup.rewind(from, expired_before);
+        }
+    }
+
+    /// Expires the windows of every rollup according to the rollup
+    /// retention settings as of `now`, returning the number of blocks
+    /// dropped
+    pub fn expire(&mut self, retention: &Retention, now: i64) -> usize {
+        self.rollups
+            .iter_mut()
+            .filter_map(|rollup| Some((retention.rollup_cutoff(rollup.resolution, now)?, rollup)))
+            .map(|(cutoff, rollup)| rollup.expire(cutoff))
+            .sum()
+    }
+
+    /// Returns true if none of the rollups holds a window
+    pub fn is_empty(&self) -> bool {
+        self.rollups.iter().all(Rollup::is_empty)
+    }
+
+    /// Compacts every rollup
+    pub fn compact(&mut self, compactor: &Compactor) {
+        for rollup in &mut self.rollups {
+            rollup.compact(compactor);
+        }
+    }
+
+    /// Aggregates the points of [`start`, `end`) into `step` second windows
+    /// aligned to the epoch, returning one point per non-empty window.
+    ///
+    /// The coarsest rollup whose resolution evenly divides `step` answers
+    /// the part of the range it has rolled up. The rest of the range, and
+    /// ranges no rollup fits, are answered from the raw series.
+    pub fn query(&self, raw: &Series, aggregate: Aggregate, start: i64, end: i64, step: i64) -> Vec<Point> {
+        assert!(step > 0, "query step must be positive");
+        let rollup = self.rollups
+            .iter()
+            .rev()
+            .find(|r| r.resolution <= step && step % r.resolution == 0 && r.watermark.is_some());
+
+        let mut points = Vec::new();
+        let mut raw_start = start;
+        if let Some(rollup) = rollup {
+            // rollup windows must not straddle the start of the range
+            let to = cmp::min(end, rollup.watermark.unwrap());
+            if start.rem_euclid(rollup.resolution) == 0 && to > start {
+                points.extend(rollup.series(aggregate).points(start, to));
+                raw_start = to;
+            }
+        }
+        let raw_points = raw.points(raw_start, end);
+        let raw_points = raw_points.into_iter().map(|p| {
+            Point {
+                ts: p.ts,
+                value: aggregate.raw_value(p.value),
+            }
+        });
+        points.extend(raw_points);
+        downsample(&points, aggregate, step)
+    }
+}
+
+// Combines time ordered points into `step` second windows
+fn downsample(points: &[Point], aggregate: Aggregate, step: i64) -> Vec<Point> {
+    let mut result: Vec<Point> = Vec::new();
+    for point in points {
+        let ts = point.ts - point.ts.rem_euclid(step);
+        match result.last_mut() {
+            Some(last) if last.ts == ts => last.value = aggregate.combine(last.value, point.value),
+            _ => result.push(Point { ts, value: point.value }),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aggregate, Rollups, FIVE_MINUTES, ONE_HOUR};
+    use retention::Retention;
+    use series::Series;
+    use Point;
+
+    fn raw() -> Series {
+        let mut series = Series::new();
+        // one point every 10 seconds for 2 hours and 5 minutes
+        for i in 0..750 {
+            series.publish_at(i as f64, i * 10);
+        }
+        series
+    }
+
+    #[test]
+    fn test_update() {
+        let raw = raw();
+        let mut rollups = Rollups::default();
+        rollups.update(&raw, 3 * 60 * 60);
+        let five = rollups.get(FIVE_MINUTES).unwrap();
+        assert_eq!(Some(3 * 60 * 60), five.watermark());
+        assert_eq!(Some(3 * 60 * 60), five.sketches().watermark());
+        assert_eq!(Some(29.0), five.sketches().quantile(0, 300, 1.0));
+        let max: Vec<Point> = five.series(Aggregate::Max).blocks().flat_map(|b| b.iter()).collect();
+        assert_eq!(25, max.len());
+        assert_eq!(Point { ts: 300, value: 59.0 }, max[1]);
+        let count: Vec<f64> = five.series(Aggregate::Count).blocks().flat_map(|b| b.iter()).map(|p| p.value).collect();
+        assert!(count.iter().all(|&c| c == 30.0));
+
+        let hour = rollups.get(ONE_HOUR).unwrap();
+        let sum: Vec<f64> = hour.series(Aggregate::Sum).blocks().flat_map(|b| b.iter()).map(|p| p.value).collect();
+        assert_eq!(vec![(0..360).sum::<i64>() as f64,
+                        (360..720).sum::<i64>() as f64,
+                        (720..750).sum::<i64>() as f64],
+                   sum);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut raw = raw();
+        let mut rollups = Rollups::default();
+        rollups.update(&raw, ONE_HOUR);
+        raw.publish_at(1000.0, 650);
+        rollups.rewind(650, i64::MIN);
+        let five = rollups.get(FIVE_MINUTES).unwrap();
+        assert_eq!(Some(600), five.watermark());
+        assert_eq!(Some(600), five.sketches().watermark());
+        assert_eq!(Some(0), rollups.get(ONE_HOUR).unwrap().watermark());
+
+        rollups.update(&raw, ONE_HOUR);
+        let five = rollups.get(FIVE_MINUTES).unwrap();
+        let max: Vec<Point> = five.series(Aggregate::Max).points(0, ONE_HOUR);
+        assert_eq!(12, max.len());
+        assert_eq!(Point { ts: 600, value: 1000.0 }, max[2]);
+        assert_eq!(Some(1000.0), five.sketches().quantile(600, 900, 1.0));
+        let count = rollups.get(ONE_HOUR).unwrap().series(Aggregate::Count).points(0, ONE_HOUR);
+        assert_eq!(vec![Point { ts: 0, value: 361.0 }], count);
+
+        // windows whose raw points may have expired are kept
+        rollups.rewind(650, 601);
+        assert_eq!(Some(900), rollups.get(FIVE_MINUTES).unwrap().watermark());
+        assert_eq!(Some(ONE_HOUR), rollups.get(ONE_HOUR).unwrap().watermark());
+    }
+
+    #[test]
+    fn test_expire() {
+        let raw = raw();
+        let mut rollups = Rollups::default();
+        rollups.update(&raw, 3 * ONE_HOUR);
+        let mut retention = Retention::new();
+        retention.set_rollup(FIVE_MINUTES, ONE_HOUR);
+        assert_eq!(0, rollups.expire(&retention, ONE_HOUR));
+        assert_eq!(Aggregate::ALL.len(), rollups.expire(&retention, 5 * ONE_HOUR));
+        assert!(rollups.get(FIVE_MINUTES).unwrap().is_empty());
+        assert!(!rollups.get(ONE_HOUR).unwrap().is_empty());
+        assert!(!rollups.is_empty());
+    }
+
+    #[test]
+    fn test_query_matches_raw() {
+        let raw = raw();
+        let mut rollups = Rollups::default();
+        let expected: Vec<Vec<Point>> = Aggregate::ALL.iter()
+            .map(|&a| Rollups::new(&[]).query(&raw, a, 0, 7500, 1800))
+            .collect();
+        // only roll up the first hour so queries combine rollups and raw data
+        rollups.update(&raw, ONE_HOUR);
+        for (&aggregate, expected) in Aggregate::ALL.iter().zip(expected) {
+            assert_eq!(expected, rollups.query(&raw, aggregate, 0, 7500, 1800));
+        }
+        let last = rollups.query(&raw, Aggregate::Last, 0, 7500, 1800);
+        assert_eq!(Point { ts: 1800, value: 359.0 }, last[1]);
+    }
+}
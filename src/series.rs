@@ -0,0 +1,268 @@
+use std::mem;
+
+use block_file::Block;
+use compact;
+use int_block::IntBlock;
+use time;
+use TSBlock;
+
+/// Default amount of time covered by a single block (2 hours)
+pub const DEFAULT_BLOCK_SIZE: i64 = 2 * 60 * 60;
+
+// The first timestamp of a block is stored as a 14 bit delta
+// from the block header so blocks can't cover more time than this
+const MAX_BLOCK_SIZE: i64 = 1 << 14;
+
+/// How `Series::append_block` added a block to a series
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Appended {
+    /// The block overlapped no other block and was added as is
+    AsIs,
+    /// The block was merged with this many overlapping blocks
+    Merged(usize),
+    /// The block held no points and was dropped
+    Empty,
+}
+
+/// A chain of time series blocks holding the data of a single series.
+///
+/// Values are published to an open block which is sealed once a value
+/// falls outside of its window. Values published out of order also seal
+/// the open block and start a new one, so sealed blocks may overlap.
+/// Series of floats chain `TSBlock`s, see `IntSeries` for integers.
+#[derive(Clone)]
+pub struct Series<B = TSBlock> {
+    block_size: i64,
+    sealed: Vec<B>,
+    open: Option<B>,
+}
+
+impl Default for Series {
+    fn default() -> Series {
+        Series::new()
+    }
+}
+
+impl Series {
+    /// Creates a new empty series with 2 hour blocks
+    pub fn new() -> Series {
+        Series::with_block_size(DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Publish a value to the series at the current time
+    pub fn publish(&mut self, value: f64) {
+        let ts = time::get_time().sec;
+        self.publish_at(value, ts);
+    }
+
+    /// Adds `block`, whose points must be ordered by timestamp, to the
+    /// sealed blocks of the series. A block overlapping none of the blocks
+    /// of the series is added as is. Otherwise it is merged with the blocks
+    /// it overlaps, its points replacing theirs at shared timestamps, and
+    /// the open block is sealed first if it is one of them
+    pub fn append_block(&mut self, block: TSBlock) -> Appended {
+        let (first, last) = match time_range(&block) {
+            Some(range) => range,
+            None => return Appended::Empty,
+        };
+        let overlaps = |other: &TSBlock| time_range(other).is_some_and(|(start, end)| start <= last && first <= end);
+        if self.open.as_ref().is_some_and(overlaps) {
+            self.seal();
+        }
+        let (mut merged, kept): (Vec<TSBlock>, Vec<TSBlock>) = mem::take(&mut self.sealed).into_iter().partition(overlaps);
+        self.sealed = kept;
+        if merged.is_empty() {
+            self.sealed.push(block);
+            return Appended::AsIs;
+        }
+        let count = merged.len();
+        merged.push(block);
+        self.sealed.push(compact::merge(&merged));
+        Appended::Merged(count)
+    }
+}
+
+impl<B: Block> Series<B> {
+    /// Creates a new empty series whose blocks each cover `block_size`
+    /// seconds. Panics if `block_size` is not within (0, 2^14]
+    pub fn with_block_size(block_size: i64) -> Series<B> {
+        assert!(block_size > 0 && block_size <= MAX_BLOCK_SIZE,
+                "block size must be within (0, 2^14] seconds");
+        Series {
+            block_size,
+            sealed: Vec::new(),
+            open: None,
+        }
+    }
+
+    /// Recreates a series from previously sealed blocks and an optional
+    /// open block
+    pub fn from_blocks(block_size: i64, sealed: Vec<B>, open: Option<B>) -> Series<B> {
+        let mut series = Series::with_block_size(block_size);
+        series.sealed = sealed;
+        series.open = open;
+        series
+    }
+
+    /// Returns the amount of time in seconds covered by a single block
+    pub fn block_size(&self) -> i64 {
+        self.block_size
+    }
+
+    /// Publish a value to the series at the given `ts` seconds from the epoch
+    pub fn publish_at(&mut self, value: B::Value, ts: i64) {
+        let seal = match self.open {
+            Some(ref open) => {
+                let end = open.header() as i64 + self.block_size;
+                ts >= end || open.last_ts().is_some_and(|last| ts < last)
+            }
+            None => false,
+        };
+        if seal {
+            self.seal();
+        }
+        let block_size = self.block_size;
+        self.open
+            .get_or_insert_with(|| B::at(ts - ts.rem_euclid(block_size)))
+            .publish_at(value, ts);
+    }
+
+    /// Seals the open block, if any. The next published value starts a new block
+    pub fn seal(&mut self) {
+        if let Some(open) = self.open.take() {
+            if !open.is_empty() {
+                self.sealed.push(open);
+            }
+        }
+    }
+
+    /// Drops every block whose last point was published before `cutoff`,
+    /// returning the number of blocks dropped
+    pub fn expire(&mut self, cutoff: i64) -> usize {
+        let before = self.sealed.len();
+        self.sealed.retain(|block| block.last_ts().is_some_and(|ts| ts >= cutoff));
+        let mut dropped = before - self.sealed.len();
+        if self.open.as_ref().is_some_and(|open| open.last_ts().is_none_or(|ts| ts < cutoff)) {
+            self.open = None;
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Returns true if the series holds no blocks
+    pub fn is_empty(&self) -> bool {
+        self.sealed.is_empty() && self.open.is_none()
+    }
+
+    /// Returns the sealed blocks of the series in the order they were sealed
+    pub fn sealed(&self) -> &[B] {
+        &self.sealed
+    }
+
+    /// Returns the sealed blocks of the series for modification. Results
+    /// cached from these blocks by a `Store` must be invalidated, which
+    /// `Store::series_mut` does
+    pub fn sealed_mut(&mut self) -> &mut Vec<B> {
+        &mut self.sealed
+    }
+
+    /// Returns the block currently being published to, if any
+    pub fn open(&self) -> Option<&B> {
+        self.open.as_ref()
+    }
+
+    /// Returns every block of the series, sealed blocks first
+    pub fn blocks(&self) -> impl Iterator<Item = &B> {
+        self.sealed.iter().chain(self.open.iter())
+    }
+
+    /// Returns the points of the series within [`start`, `end`) ordered by
+    /// timestamp. Points sharing a timestamp are ordered by block
+    pub fn points(&self, start: i64, end: i64) -> Vec<B::Point> {
+        let mut points: Vec<B::Point> = self.blocks()
+            .flat_map(|block| block.iter())
+            .filter(|p| B::timestamp(p) >= start && B::timestamp(p) < end)
+            .collect();
+        // stable so that points of later blocks stay last
+        points.sort_by_key(B::timestamp);
+        points
+    }
+}
+
+// Returns the timestamps of the first and last points of a time ordered
+// block, unless it is empty
+fn time_range<B: Block>(block: &B) -> Option<(i64, i64)> {
+    Some((B::timestamp(&block.iter().next()?), block.last_ts()?))
+}
+
+/// A chain of integer blocks holding the data of a single integer series
+pub type IntSeries = Series<IntBlock>;
+
+#[cfg(test)]
+mod test {
+    use super::{Appended, IntSeries, Series};
+    use {test_block, TSBlock};
+
+    #[test]
+    fn test_publish_seals_blocks() {
+        let mut series: Series = Series::with_block_size(100);
+        series.publish_at(1.0, 150);
+        series.publish_at(2.0, 199);
+        assert_eq!(0, series.sealed().len());
+        assert_eq!(100, series.open().unwrap().header());
+        series.publish_at(3.0, 200);
+        assert_eq!(1, series.sealed().len());
+        assert_eq!(200, series.open().unwrap().header());
+
+        // out of order values start a new overlapping block
+        series.publish_at(4.0, 120);
+        assert_eq!(2, series.sealed().len());
+        assert_eq!(100, series.open().unwrap().header());
+
+        let values: Vec<f64> = series.blocks().flat_map(|b| b.iter()).map(|p| p.value).collect();
+        assert_eq!(vec![1.0, 2.0, 3.0, 4.0], values);
+    }
+
+    #[test]
+    fn test_int_series() {
+        let mut series = IntSeries::with_block_size(100);
+        series.publish_at(1, 150);
+        series.publish_at(2, 200);
+        series.publish_at(3, 120);
+        assert_eq!(2, series.sealed().len());
+        assert_eq!(100, series.open().unwrap().header());
+        let values: Vec<(i64, i64)> = series.points(0, 300).iter().map(|p| (p.ts, p.value)).collect();
+        assert_eq!(vec![(120, 3), (150, 1), (200, 2)], values);
+    }
+
+    #[test]
+    fn test_append_block() {
+        let mut series: Series = Series::with_block_size(100);
+        series.publish_at(1.0, 10);
+        series.publish_at(2.0, 20);
+        series.publish_at(3.0, 150);
+
+        assert_eq!(Appended::AsIs, series.append_block(test_block(30, &[(30, 4.0), (40, 5.0)])));
+        assert_eq!(2, series.sealed().len());
+        assert_eq!(Appended::Empty, series.append_block(TSBlock::at(0)));
+
+        // overlaps the first two sealed blocks and the open one
+        assert_eq!(Appended::Merged(3), series.append_block(test_block(20, &[(20, 6.0), (35, 7.0), (160, 8.0)])));
+        assert!(series.open().is_none());
+        assert_eq!(1, series.sealed().len());
+        let points: Vec<(i64, f64)> = series.points(0, 200).iter().map(|p| (p.ts, p.value)).collect();
+        assert_eq!(vec![(10, 1.0), (20, 6.0), (30, 4.0), (35, 7.0), (40, 5.0), (150, 3.0), (160, 8.0)], points);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut series: Series = Series::with_block_size(100);
+        series.publish_at(1.0, 10);
+        series.publish_at(2.0, 150);
+        series.publish_at(3.0, 250);
+        assert_eq!(1, series.expire(100));
+        assert_eq!(1, series.sealed().len());
+        assert_eq!(2, series.expire(300));
+        assert!(series.is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+//! Snappy block format, as used by the Prometheus remote storage protocol.
+//!
+//! A compressed block starts with the uncompressed length as a varint,
+//! followed by literals and back references into the output:
+//!
+//! |-----------||---------|---------|-----|
+//! | length    || element | element | ... |
+//! |-----------||---------|---------|-----|
+//!
+//! The low 2 bits of the tag byte of an element give its kind: a literal,
+//! or a copy with a 1, 2 or 4 byte offset.
+//! Reference: https://github.com/google/snappy/blob/main/format_description.txt
+
+use std::error;
+use std::fmt;
+
+const LITERAL: u8 = 0b00;
+const COPY_1: u8 = 0b01;
+const COPY_2: u8 = 0b10;
+const COPY_4: u8 = 0b11;
+
+// Inputs are matched 4 bytes at a time through a table of this many entries
+const HASH_BITS: u32 = 14;
+
+/// Reasons a block can't be decompressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The block ends within an element or its length
+    Truncated,
+    /// The uncompressed length exceeds the limit given
+    TooLarge(usize),
+    /// A copy refers to data before the start of the output
+    InvalidOffset,
+    /// The elements don't add up to the uncompressed length
+    LengthMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated => write!(f, "truncated snappy block"),
+            Error::TooLarge(len) => write!(f, "snappy block of {} bytes is too large", len),
+            Error::InvalidOffset => write!(f, "invalid copy offset in snappy block"),
+            Error::LengthMismatch => write!(f, "snappy block doesn't match its length"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Decompresses `input`, failing if it holds more than `max_len` bytes
+pub fn decompress(input: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    let mut pos = 0;
+    let len = read_varint(input, &mut pos)? as usize;
+    if len > max_len {
+        return Err(Error::TooLarge(len));
+    }
+    let mut output = Vec::with_capacity(len);
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        let (offset, copy_len) = match tag & 0b11 {
+            LITERAL => {
+                let literal_len = match tag >> 2 {
+                    n @ 0..=59 => n as usize + 1,
+                    n => read_le(input, &mut pos, n as usize - 59)? + 1,
+                };
+                let end = pos.checked_add(literal_len).ok_or(Error::Truncated)?;
+                let literal = input.get(pos..end).ok_or(Error::Truncated)?;
+                if output.len() + literal.len() > len {
+                    return Err(Error::LengthMismatch);
+                }
+                output.extend_from_slice(literal);
+                pos = end;
+                continue;
+            }
+            COPY_1 => {
+                let low = read_le(input, &mut pos, 1)?;
+                ((tag as usize >> 5) << 8 | low, 4 + (tag as usize >> 2 & 0b111))
+            }
+            COPY_2 => (read_le(input, &mut pos, 2)?, (tag as usize >> 2) + 1),
+            _ => (read_le(input, &mut pos, 4)?, (tag as usize >> 2) + 1),
+        };
+        if offset == 0 || offset > output.len() {
+            return Err(Error::InvalidOffset);
+        }
+        if output.len() + copy_len > len {
+            return Err(Error::LengthMismatch);
+        }
+        // copies may overlap the bytes they produce
+        let start = output.len() - offset;
+        for i in 0..copy_len {
+            let byte = output[start + i];
+            output.push(byte);
+        }
+    }
+    if output.len() != len {
+        return Err(Error::LengthMismatch);
+    }
+    Ok(output)
+}
+
+/// Compresses `input` into a single block
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    write_varint(&mut output, input.len() as u64);
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    // start of the bytes not covered by an element yet
+    let mut literal = 0;
+    let mut pos = 0;
+    while pos + 4 <= input.len() {
+        let hash = hash(&input[pos..pos + 4]);
+        // entries are stored off by one so that 0 means empty
+        let candidate = table[hash];
+        table[hash] = pos + 1;
+        if candidate == 0 || input[candidate - 1..candidate + 3] != input[pos..pos + 4] {
+            pos += 1;
+            continue;
+        }
+        let candidate = candidate - 1;
+        let mut len = 4;
+        while pos + len < input.len() && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+        emit_literal(&mut output, &input[literal..pos]);
+        emit_copy(&mut output, pos - candidate, len);
+        pos += len;
+        literal = pos;
+    }
+    emit_literal(&mut output, &input[literal..]);
+    output
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize
+}
+
+fn emit_literal(output: &mut Vec<u8>, literal: &[u8]) {
+    if literal.is_empty() {
+        return;
+    }
+    let n = literal.len() - 1;
+    if n < 60 {
+        output.push((n as u8) << 2 | LITERAL);
+    } else {
+        let bytes = (n as u32).to_le_bytes();
+        let count = 4 - (n as u32).leading_zeros() as usize / 8;
+        output.push((59 + count as u8) << 2 | LITERAL);
+        output.extend_from_slice(&bytes[..count]);
+    }
+    output.extend_from_slice(literal);
+}
+
+fn emit_copy(output: &mut Vec<u8>, offset: usize, mut len: usize) {
+    while len > 0 {
+        // a copy covers at most 64 bytes, and must leave at least 4 behind
+        let n = if len > 64 && len < 68 { 60 } else { len.min(64) };
+        if offset < 1 << 11 && (4..=11).contains(&n) {
+            output.push(((offset >> 8) as u8) << 5 | ((n - 4) as u8) << 2 | COPY_1);
+            output.push(offset as u8);
+        } else if offset < 1 << 16 {
+            output.push(((n - 1) as u8) << 2 | COPY_2);
+            output.extend_from_slice(&(offset as u16).to_le_bytes());
+        } else {
+            output.push(((n - 1) as u8) << 2 | COPY_4);
+            output.extend_from_slice(&(offset as u32).to_le_bytes());
+        }
+        len -= n;
+    }
+}
+
+// Reads a little endian integer of `n` bytes
+fn read_le(input: &[u8], pos: &mut usize, n: usize) -> Result<usize, Error> {
+    let bytes = input.get(*pos..*pos + n).ok_or(Error::Truncated)?;
+    *pos += n;
+    Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize))
+}
+
+fn read_varint(input: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *input.get(*pos).ok_or(Error::Truncated)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::Truncated)
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress, Error};
+
+    #[test]
+    fn test_decompress() {
+        // "abcd" as a literal, then copies with 1, 2 and 4 byte offsets
+        let elements = [0b11 << 2, b'a', b'b', b'c', b'd', 0b01, 4, 0b110, 8, 0, 0b111, 1, 0, 0, 0];
+        let mut block = vec![12];
+        block.extend_from_slice(&elements);
+        assert_eq!(Ok(b"abcdabcdabbb".to_vec()), decompress(&block, 100));
+        block[0] = 14;
+        assert_eq!(Err(Error::LengthMismatch), decompress(&block, 100));
+        // overlapping copy of a single byte
+        assert_eq!(Ok(b"aaaaaa".to_vec()), decompress(&[6, 0, b'a', 0b101, 1], 100));
+
+        assert_eq!(Err(Error::Truncated), decompress(&[], 100));
+        assert_eq!(Err(Error::Truncated), decompress(&[4, 0b11 << 2, b'a'], 100));
+        assert_eq!(Err(Error::InvalidOffset), decompress(&[4, 0b01, 1], 100));
+        assert_eq!(Err(Error::TooLarge(200)), decompress(&[200, 1], 100));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut input = Vec::new();
+        for i in 0..10_000u32 {
+            input.extend_from_slice(format!("sample {} value {}\n", i % 97, i % 13).as_bytes());
+        }
+        input.extend((0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        let compressed = compress(&input);
+        assert!(compressed.len() < input.len() * 3 / 4);
+        assert_eq!(Ok(input.clone()), decompress(&compressed, input.len()));
+
+        for input in &[&b""[..], b"a", b"abcdabcdabcd", &[7; 1000]] {
+            assert_eq!(Ok(input.to_vec()), decompress(&compress(input), 1000));
+        }
+    }
+}
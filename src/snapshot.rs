@@ -0,0 +1,363 @@
+//! Point-in-time snapshots of an entire `Store`.
+//!
+//! A snapshot is a directory holding a `MANIFEST` and one sub-directory per
+//! series and per rollup aggregate series:
+//!
+//! ```text
+//! snapshot/
+//!   MANIFEST
+//!   s0/00000000.block sealed blocks of the first series
+//!   s0/open.block     open block of the first series
+//!   i0/...            blocks of the first integer series
+//!   r0/0/...          blocks of the first aggregate of the first rollup
+//!   r0/sketches       quantile sketches of the first rollup
+//! ```
+//!
+//! The manifest is a line based text file whose fields are tab separated.
+//! Blocks are stored with `block_file`, and since a block can be fully
+//! decoded the encoder state of open blocks is restored along with them.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use block_file::{self, Block};
+use quantile::SketchRollup;
+use rollup::{Aggregate, Rollup, Rollups};
+use series::{IntSeries, Series};
+use store::{SeriesKey, Store};
+
+const VERSION: &str = "counter-snapshot 1";
+const MANIFEST: &str = "MANIFEST";
+const OPEN_BLOCK: &str = "open.block";
+const SKETCHES: &str = "sketches";
+
+/// Writes a snapshot of `store` to the directory `path`, which must not
+/// exist yet. The snapshot is first written to a temporary directory that
+/// is renamed to `path` once complete, so `path` never holds a partial
+/// snapshot
+pub fn write<P: AsRef<Path>>(store: &Store, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "snapshot already exists"));
+    }
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    fs::create_dir_all(&tmp)?;
+    if let Err(err) = write_dir(store, &tmp).and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_dir_all(&tmp);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Restores the store saved to the snapshot directory `path`
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Store> {
+    let path = path.as_ref();
+    let manifest = BufReader::new(File::open(path.join(MANIFEST))?);
+    let mut lines = manifest.lines();
+    match lines.next() {
+        Some(Ok(ref line)) if line == VERSION => {}
+        _ => return Err(invalid("unsupported snapshot version")),
+    }
+
+    let mut store = Store::new();
+    let mut rollups: Vec<(SeriesKey, Rollup)> = Vec::new();
+    for line in lines {
+        let line = line?;
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+        let (kind, args) = fields.split_first().ok_or_else(|| invalid("empty manifest line"))?;
+        match (kind.as_str(), args) {
+            ("block_size", [size]) => {
+                let mut restored = Store::with_block_size(parse(size)?);
+                restored.set_rollup_resolutions(store.rollup_resolutions().to_vec());
+                *restored.retention_mut() = store.retention().clone();
+                if let Some(now) = store.expired_at() {
+                    restored.expire_at(now);
+                }
+                store = restored;
+            }
+            ("rollup_resolutions", resolutions) => {
+                let resolutions = resolutions.iter().map(|r| parse(r)).collect::<io::Result<_>>()?;
+                store.set_rollup_resolutions(resolutions);
+            }
+            ("retention_default", [retention]) => {
+                store.retention_mut().set_default(parse_opt(retention)?);
+            }
+            ("retention", [metric, retention]) => {
+                store.retention_mut().set(metric.as_str(), parse(retention)?);
+            }
+            ("rollup_retention", [resolution, retention]) => {
+                store.retention_mut().set_rollup(parse(resolution)?, parse(retention)?);
+            }
+            ("expired_at", [now]) => {
+                if let Some(now) = parse_opt(now)? {
+                    store.expire_at(now);
+                }
+            }
+            ("series", [dir, block_size, key @ ..]) => {
+                let series = read_series(&path.join(dir), parse(block_size)?)?;
+                store.insert(parse_key(key)?, series);
+            }
+            ("int_series", [dir, block_size, key @ ..]) => {
+                let (sealed, open) = read_blocks(&path.join(dir))?;
+                store.insert_int(parse_key(key)?, IntSeries::from_blocks(parse(block_size)?, sealed, open));
+            }
+            ("rollup", [dir, resolution, watermark, block_size, key @ ..]) => {
+                let dir = path.join(dir);
+                let block_size = parse(block_size)?;
+                let series = (0..Aggregate::ALL.len())
+                    .map(|i| read_series(&dir.join(i.to_string()), block_size))
+                    .collect::<io::Result<Vec<Series>>>()?;
+                let resolution = parse(resolution)?;
+                let sketches = read_sketches(&dir.join(SKETCHES), resolution)?;
+                let rollup = Rollup::from_series(resolution, parse_opt(watermark)?, series, sketches);
+                rollups.push((parse_key(key)?, rollup));
+            }
+            _ => return Err(invalid("unknown manifest line")),
+        }
+    }
+
+    rollups.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut rollups = rollups.into_iter().peekable();
+    while let Some((key, rollup)) = rollups.next() {
+        let mut group = vec![rollup];
+        while let Some((_, rollup)) = rollups.next_if(|(k, _)| *k == key) {
+            group.push(rollup);
+        }
+        store.insert_rollups(key, Rollups::from_rollups(group));
+    }
+    Ok(store)
+}
+
+fn write_dir(store: &Store, dir: &Path) -> io::Result<()> {
+    let mut manifest = BufWriter::new(File::create(dir.join(MANIFEST))?);
+    writeln!(manifest, "{}", VERSION)?;
+    writeln!(manifest, "block_size\t{}", store.block_size())?;
+    let resolutions: Vec<String> = store.rollup_resolutions().iter().map(|r| r.to_string()).collect();
+    writeln!(manifest, "{}", line("rollup_resolutions", &resolutions))?;
+    writeln!(manifest, "retention_default\t{}", format_opt(store.retention().default_retention()))?;
+    for (metric, retention) in store.retention().iter() {
+        writeln!(manifest, "{}", line("retention", &[metric.to_string(), retention.to_string()]))?;
+    }
+    for (resolution, retention) in store.retention().iter_rollups() {
+        writeln!(manifest, "{}", line("rollup_retention", &[resolution.to_string(), retention.to_string()]))?;
+    }
+    writeln!(manifest, "expired_at\t{}", format_opt(store.expired_at()))?;
+
+    for (i, (key, series)) in store.iter().enumerate() {
+        let name = format!("s{}", i);
+        write_series(&dir.join(&name), series)?;
+        let mut fields = vec![name, series.block_size().to_string()];
+        fields.extend(key_fields(key));
+        writeln!(manifest, "{}", line("series", &fields))?;
+    }
+
+    for (i, (key, series)) in store.iter_ints().enumerate() {
+        let name = format!("i{}", i);
+        write_blocks(&dir.join(&name), series.sealed(), series.open())?;
+        let mut fields = vec![name, series.block_size().to_string()];
+        fields.extend(key_fields(key));
+        writeln!(manifest, "{}", line("int_series", &fields))?;
+    }
+
+    let mut n = 0;
+    for (key, rollups) in store.iter_rollups() {
+        for rollup in rollups.rollups() {
+            let name = format!("r{}", n);
+            n += 1;
+            for (i, series) in rollup.all_series().iter().enumerate() {
+                write_series(&dir.join(&name).join(i.to_string()), series)?;
+            }
+            fs::write(dir.join(&name).join(SKETCHES), rollup.sketches().encode())?;
+            let block_size = rollup.all_series()[0].block_size();
+            let mut fields = vec![name,
+                                  rollup.resolution().to_string(),
+                                  format_opt(rollup.watermark()),
+                                  block_size.to_string()];
+            fields.extend(key_fields(key));
+            writeln!(manifest, "{}", line("rollup", &fields))?;
+        }
+    }
+
+    let manifest = manifest.into_inner().map_err(|err| err.into_error())?;
+    manifest.sync_all()
+}
+
+fn write_series(dir: &Path, series: &Series) -> io::Result<()> {
+    write_blocks(dir, series.sealed(), series.open())
+}
+
+fn write_blocks<B: Block>(dir: &Path, sealed: &[B], open: Option<&B>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (i, block) in sealed.iter().enumerate() {
+        block_file::write(dir.join(format!("{:08}.{}", i, block_file::EXTENSION)), block)?;
+    }
+    if let Some(open) = open {
+        block_file::write(dir.join(OPEN_BLOCK), open)?;
+    }
+    Ok(())
+}
+
+fn read_series(dir: &Path, block_size: i64) -> io::Result<Series> {
+    let (sealed, open) = read_blocks(dir)?;
+    Ok(Series::from_blocks(block_size, sealed, open))
+}
+
+fn read_sketches(path: &Path, resolution: i64) -> io::Result<SketchRollup> {
+    SketchRollup::decode(resolution, &fs::read(path)?).ok_or_else(|| invalid("invalid rollup sketches"))
+}
+
+fn read_blocks<B: Block>(dir: &Path) -> io::Result<(Vec<B>, Option<B>)> {
+    let mut sealed = Vec::new();
+    for path in block_file::list(dir)? {
+        if path.file_name().is_some_and(|name| name != OPEN_BLOCK) {
+            sealed.push(block_file::read(&path)?);
+        }
+    }
+    let open_path = dir.join(OPEN_BLOCK);
+    let open = if open_path.exists() {
+        Some(block_file::read(&open_path)?)
+    } else {
+        None
+    };
+    Ok((sealed, open))
+}
+
+fn key_fields(key: &SeriesKey) -> Vec<String> {
+    let mut fields = vec![key.metric.clone()];
+    for (name, value) in &key.tags {
+        fields.push(name.clone());
+        fields.push(value.clone());
+    }
+    fields
+}
+
+fn parse_key(fields: &[String]) -> io::Result<SeriesKey> {
+    let (metric, tags) = fields.split_first().ok_or_else(|| invalid("missing series key"))?;
+    if tags.len() % 2 != 0 {
+        return Err(invalid("unpaired series tag"));
+    }
+    Ok(tags.chunks(2).fold(SeriesKey::new(metric.as_str()), |key, tag| key.tag(tag[0].as_str(), tag[1].as_str())))
+}
+
+fn line(kind: &str, fields: &[String]) -> String {
+    let mut line = kind.to_string();
+    for field in fields {
+        line.push('\t');
+        line.push_str(&escape(field));
+    }
+    line
+}
+
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn format_opt(value: Option<i64>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+fn parse(field: &str) -> io::Result<i64> {
+    field.parse().map_err(|_| invalid("invalid number"))
+}
+
+fn parse_opt(field: &str) -> io::Result<Option<i64>> {
+    match field {
+        "-" => Ok(None),
+        _ => parse(field).map(Some),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use rollup::Aggregate;
+    use store::{SeriesKey, Store};
+
+    #[test]
+    fn test_snapshot_restore() {
+        let dir = env::temp_dir().join(format!("counter-snapshot-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("snap");
+
+        let cpu = SeriesKey::new("cpu").tag("host", "a\tb").tag("region", "us");
+        let mem = SeriesKey::new("mem");
+        let requests = SeriesKey::new("requests");
+        let mut store = Store::with_block_size(600);
+        store.retention_mut().set("cpu", 86400);
+        store.retention_mut().set_default(Some(3600));
+        store.retention_mut().set_rollup(3600, 86400 * 365);
+        store.expire_at(60);
+        for i in 0..100 {
+            store.publish_at(&cpu, i as f64, i * 60);
+            store.publish_at(&mem, 0.5, i * 60);
+            store.publish_int_at(&requests, i * 1000, i * 60);
+        }
+        store.rollup(6000);
+        store.snapshot(&path).unwrap();
+        assert!(store.snapshot(&path).is_err());
+
+        let mut restored = Store::restore(&path).unwrap();
+        assert_eq!(600, restored.block_size());
+        assert_eq!(Some(86400), restored.retention().get("cpu"));
+        assert_eq!(Some(3600), restored.retention().get("mem"));
+        assert_eq!(vec![(3600, 86400 * 365)], restored.retention().iter_rollups().collect::<Vec<_>>());
+        assert_eq!(Some(60), restored.expired_at());
+        for key in &[&cpu, &mem] {
+            let expected: Vec<_> = store.get(key).unwrap().blocks().flat_map(|b| b.iter()).collect();
+            let actual: Vec<_> = restored.get(key).unwrap().blocks().flat_map(|b| b.iter()).collect();
+            assert_eq!(expected, actual);
+            assert_eq!(store.query(key, Aggregate::Sum, 0, 6000, 3600),
+                       restored.query(key, Aggregate::Sum, 0, 6000, 3600));
+        }
+        assert_eq!(store.get_int(&requests).unwrap().points(0, 6000),
+                   restored.get_int(&requests).unwrap().points(0, 6000));
+        assert_eq!(Some(6000), restored.rollups(&cpu).unwrap().get(300).unwrap().watermark());
+        let sketches = |store: &Store| {
+            let sketches = store.rollups(&cpu).unwrap().get(300).unwrap().sketches();
+            (sketches.watermark(), sketches.iter().map(|(start, sketch)| (start, sketch.clone())).collect::<Vec<_>>())
+        };
+        assert_eq!(20, sketches(&restored).1.len());
+        assert_eq!(sketches(&store), sketches(&restored));
+
+        // open blocks keep accepting values after a restore
+        store.publish_at(&cpu, 100.0, 6000);
+        restored.publish_at(&cpu, 100.0, 6000);
+        assert_eq!(store.get(&cpu).unwrap().open().unwrap().as_slice().data(),
+                   restored.get(&cpu).unwrap().open().unwrap().as_slice().data());
+
+        // every rollup comes with its sketches
+        fs::remove_file(path.join("r0").join("sketches")).unwrap();
+        assert!(Store::restore(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,547 @@
+//! StatsD protocol.
+//!
+//! Clients send metrics over UDP, one per line and possibly several per
+//! datagram:
+//!
+//! ```text
+//! requests:1|c|@0.1
+//! queue.depth:12|g
+//! queue.depth:-2|g
+//! latency:320|ms|#route:home
+//! users:alice|s
+//! ```
+//!
+//! Metrics are aggregated over a flush interval rather than stored one by
+//! one. At every flush, an `Aggregator` publishes for each metric updated
+//! during the interval:
+//!
+//! - the sum of a counter, scaled up by its sample rate
+//! - the last value of a gauge, signed values being relative to it
+//! - the `quantile` tagged percentiles of a timer or histogram, along with
+//!   its `_count` and `_sum`
+//! - the number of distinct values of a set
+//!
+//! Tags use the `#name:value` extension of DogStatsD.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use fnv::{FnvHashMap, FnvHashSet};
+use time;
+
+use ingest::{Batcher, Closed, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use quantile::Quantiles;
+use store::{SeriesKey, Store};
+
+/// Default time between two flushes (10 seconds)
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default percentiles of timers
+pub const DEFAULT_PERCENTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+// How often the listener checks whether it is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Largest datagram accepted
+const MAX_DATAGRAM: usize = 65_536;
+
+/// Value of a metric along with its type
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    /// Value added to a counter (`c`)
+    Counter(f64),
+    /// Value of a gauge (`g`)
+    Gauge(f64),
+    /// Signed value added to a gauge (`g` with `+` or `-`)
+    GaugeDelta(f64),
+    /// Measurement of a timer (`ms`) or histogram (`h`)
+    Timer(f64),
+    /// Member of a set (`s`)
+    Set(String),
+}
+
+/// A single metric received from a client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric {
+    pub key: SeriesKey,
+    pub value: MetricValue,
+    /// Fraction of the events the client sent, within (0, 1]
+    pub sample_rate: f64,
+}
+
+/// Reasons a line can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line isn't of the form `name:value|type`
+    InvalidFormat,
+    EmptyName,
+    InvalidValue(String),
+    UnknownType(String),
+    InvalidSampleRate(String),
+    InvalidTag(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::InvalidFormat => write!(f, "expected name:value|type"),
+            ParseError::EmptyName => write!(f, "empty metric name"),
+            ParseError::InvalidValue(ref value) => write!(f, "invalid value {:?}", value),
+            ParseError::UnknownType(ref kind) => write!(f, "unknown metric type {:?}", kind),
+            ParseError::InvalidSampleRate(ref rate) => write!(f, "invalid sample rate {:?}", rate),
+            ParseError::InvalidTag(ref tag) => write!(f, "invalid tag {:?}", tag),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a line of the form `name:value|type[|@rate][|#tags]`
+pub fn parse_line(line: &str) -> Result<Metric, ParseError> {
+    let mut parts = line.split('|');
+    let (name, value) = parts.next()
+        .and_then(|metric| metric.rsplit_once(':'))
+        .ok_or(ParseError::InvalidFormat)?;
+    if name.is_empty() {
+        return Err(ParseError::EmptyName);
+    }
+    let kind = parts.next().ok_or(ParseError::InvalidFormat)?;
+    let mut key = SeriesKey::new(name);
+    let mut sample_rate = 1.0;
+    for part in parts {
+        if let Some(rate) = part.strip_prefix('@') {
+            sample_rate = match rate.parse() {
+                Ok(rate) if rate > 0.0 && rate <= 1.0 => rate,
+                _ => return Err(ParseError::InvalidSampleRate(rate.to_string())),
+            };
+        } else if let Some(tags) = part.strip_prefix('#') {
+            for tag in tags.split(',') {
+                match tag.split_once(':') {
+                    Some((name, value)) if !name.is_empty() && !value.is_empty() => key = key.tag(name, value),
+                    _ => return Err(ParseError::InvalidTag(tag.to_string())),
+                }
+            }
+        } else {
+            return Err(ParseError::InvalidFormat);
+        }
+    }
+
+    let number = || {
+        value.parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| ParseError::InvalidValue(value.to_string()))
+    };
+    let value = match kind {
+        "c" => MetricValue::Counter(number()?),
+        "g" if value.starts_with('+') || value.starts_with('-') => MetricValue::GaugeDelta(number()?),
+        "g" => MetricValue::Gauge(number()?),
+        "ms" | "h" => MetricValue::Timer(number()?),
+        "s" if !value.is_empty() => MetricValue::Set(value.to_string()),
+        "s" => return Err(ParseError::InvalidValue(value.to_string())),
+        _ => return Err(ParseError::UnknownType(kind.to_string())),
+    };
+    Ok(Metric { key, value, sample_rate })
+}
+
+// Measurements of a timer over the current interval
+struct Timer {
+    count: f64,
+    sum: f64,
+    quantiles: Quantiles,
+}
+
+/// Aggregates metrics over flush intervals
+pub struct Aggregator {
+    percentiles: Vec<f64>,
+    counters: FnvHashMap<SeriesKey, f64>,
+    // gauges outlive intervals so that deltas apply to their last value
+    gauges: FnvHashMap<SeriesKey, f64>,
+    updated_gauges: FnvHashSet<SeriesKey>,
+    timers: FnvHashMap<SeriesKey, Timer>,
+    sets: FnvHashMap<SeriesKey, FnvHashSet<String>>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Aggregator {
+        Aggregator::new(DEFAULT_PERCENTILES.to_vec())
+    }
+}
+
+impl Aggregator {
+    /// Creates an aggregator publishing the `percentiles` of timers,
+    /// each within [0, 1]
+    pub fn new(percentiles: Vec<f64>) -> Aggregator {
+        Aggregator {
+            percentiles,
+            counters: FnvHashMap::default(),
+            gauges: FnvHashMap::default(),
+            updated_gauges: FnvHashSet::default(),
+            timers: FnvHashMap::default(),
+            sets: FnvHashMap::default(),
+        }
+    }
+
+    /// Adds a metric to the current interval
+    pub fn add(&mut self, metric: Metric) {
+        let Metric { key, value, sample_rate } = metric;
+        match value {
+            MetricValue::Counter(value) => {
+                *self.counters.entry(key).or_insert(0.0) += value / sample_rate;
+            }
+            MetricValue::Gauge(value) => {
+                self.gauges.insert(key.clone(), value);
+                self.updated_gauges.insert(key);
+            }
+            MetricValue::GaugeDelta(delta) => {
+                *self.gauges.entry(key.clone()).or_insert(0.0) += delta;
+                self.updated_gauges.insert(key);
+            }
+            MetricValue::Timer(value) => {
+                let timer = self.timers.entry(key).or_insert_with(|| Timer {
+                    count: 0.0,
+                    sum: 0.0,
+                    quantiles: Quantiles::default(),
+                });
+                timer.count += 1.0 / sample_rate;
+                timer.sum += value / sample_rate;
+                timer.quantiles.push(value);
+            }
+            MetricValue::Set(member) => {
+                self.sets.entry(key).or_default().insert(member);
+            }
+        }
+    }
+
+    /// Returns true if no metric was added since the last flush
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.updated_gauges.is_empty() && self.timers.is_empty() && self.sets.is_empty()
+    }
+
+    /// Ends the current interval at `ts` seconds from the epoch, returning
+    /// the samples to publish for it
+    pub fn flush(&mut self, ts: i64) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut push = |key: SeriesKey, value: f64| samples.push(Sample { key, value: Value::Float(value), ts });
+        for (key, sum) in self.counters.drain() {
+            push(key, sum);
+        }
+        for key in self.updated_gauges.drain() {
+            let value = self.gauges[&key];
+            push(key, value);
+        }
+        for (key, timer) in self.timers.drain() {
+            for &q in &self.percentiles {
+                if let Some(value) = timer.quantiles.quantile(q) {
+                    push(key.clone().tag("quantile", q.to_string()), value);
+                }
+            }
+            push(suffixed(&key, "_count"), timer.count);
+            push(suffixed(&key, "_sum"), timer.sum);
+        }
+        for (key, members) in self.sets.drain() {
+            push(key, members.len() as f64);
+        }
+        samples
+    }
+}
+
+fn suffixed(key: &SeriesKey, suffix: &str) -> SeriesKey {
+    SeriesKey {
+        metric: format!("{}{}", key.metric, suffix),
+        tags: key.tags.clone(),
+    }
+}
+
+/// Settings of a listener
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Time between two flushes
+    pub interval: Duration,
+    /// Percentiles published for timers, each within [0, 1]
+    pub percentiles: Vec<f64>,
+    /// Batches queued up for the writer before the listener stops reading
+    pub queue: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            interval: DEFAULT_INTERVAL,
+            percentiles: DEFAULT_PERCENTILES.to_vec(),
+            queue: DEFAULT_QUEUE,
+        }
+    }
+}
+
+/// Counters of a listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    packets: AtomicU64,
+    metrics: AtomicU64,
+    malformed: AtomicU64,
+    flushes: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of datagrams received
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of metrics aggregated
+    pub fn metrics(&self) -> u64 {
+        self.metrics.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of malformed lines skipped
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of intervals flushed
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last malformed line
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn report(&self, line: &str, err: &ParseError) {
+        self.malformed.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(format!("{}: {:?}", err, line));
+    }
+}
+
+/// UDP listener aggregating the metrics it receives and publishing them
+/// into a store at every flush. The current interval is flushed when the
+/// listener is dropped
+pub struct Listener {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    handle: Option<JoinHandle<()>>,
+    writer: Option<Writer>,
+}
+
+impl Listener {
+    /// Starts listening on `addr` for metrics to publish into `store`
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Listener> {
+        let socket = UdpSocket::bind(addr)?;
+        let addr = socket.local_addr()?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store, config.queue);
+        let receive = Receive {
+            socket,
+            stop: stop.clone(),
+            stats: stats.clone(),
+            aggregator: Aggregator::new(config.percentiles),
+            batcher: writer.batcher(DEFAULT_BATCH_SIZE),
+            interval: config.interval,
+        };
+        let handle = thread::spawn(move || receive.run());
+        Ok(Listener {
+            addr,
+            stop,
+            stats,
+            handle: Some(handle),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.writer.take();
+    }
+}
+
+struct Receive {
+    socket: UdpSocket,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    aggregator: Aggregator,
+    batcher: Batcher,
+    interval: Duration,
+}
+
+impl Receive {
+    fn run(mut self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut next_flush = Instant::now() + self.interval;
+        while !self.stop.load(Ordering::SeqCst) {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, _)) => self.handle_packet(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {}
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+            if Instant::now() >= next_flush {
+                next_flush += self.interval;
+                if self.flush().is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = self.flush();
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        let packet = String::from_utf8_lossy(packet);
+        for line in packet.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match parse_line(line) {
+                Ok(metric) => {
+                    self.stats.metrics.fetch_add(1, Ordering::SeqCst);
+                    self.aggregator.add(metric);
+                }
+                Err(err) => self.stats.report(line, &err),
+            }
+        }
+        self.stats.packets.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn flush(&mut self) -> Result<(), Closed> {
+        if self.aggregator.is_empty() {
+            return Ok(());
+        }
+        self.stats.flushes.fetch_add(1, Ordering::SeqCst);
+        let samples = self.aggregator.flush(time::get_time().sec);
+        for sample in samples {
+            self.batcher.push(sample)?;
+        }
+        self.batcher.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse_line, Aggregator, Config, Listener, Metric, MetricValue, ParseError};
+    use ingest::{Sample, Value};
+    use store::{SeriesKey, Store};
+
+    #[test]
+    fn test_parse_line() {
+        let metric = parse_line("requests:2|c|@0.5|#host:a,dc:1").unwrap();
+        assert_eq!(Metric {
+                       key: SeriesKey::new("requests").tag("host", "a").tag("dc", "1"),
+                       value: MetricValue::Counter(2.0),
+                       sample_rate: 0.5,
+                   },
+                   metric);
+        assert_eq!(MetricValue::Gauge(3.5), parse_line("depth:3.5|g").unwrap().value);
+        assert_eq!(MetricValue::GaugeDelta(-2.0), parse_line("depth:-2|g").unwrap().value);
+        assert_eq!(MetricValue::Timer(320.0), parse_line("latency:320|ms").unwrap().value);
+        assert_eq!(MetricValue::Timer(1.5), parse_line("size:1.5|h").unwrap().value);
+        assert_eq!(MetricValue::Set("alice".to_string()), parse_line("users:alice|s").unwrap().value);
+        assert_eq!(SeriesKey::new("a:b"), parse_line("a:b:1|c").unwrap().key);
+
+        assert_eq!(Err(ParseError::InvalidFormat), parse_line("requests"));
+        assert_eq!(Err(ParseError::InvalidFormat), parse_line("requests:1"));
+        assert_eq!(Err(ParseError::EmptyName), parse_line(":1|c"));
+        assert_eq!(Err(ParseError::InvalidValue("x".to_string())), parse_line("requests:x|c"));
+        assert_eq!(Err(ParseError::UnknownType("q".to_string())), parse_line("requests:1|q"));
+        assert_eq!(Err(ParseError::InvalidSampleRate("2".to_string())), parse_line("requests:1|c|@2"));
+        assert_eq!(Err(ParseError::InvalidTag("host".to_string())), parse_line("requests:1|c|#host"));
+    }
+
+    fn flush(aggregator: &mut Aggregator) -> Vec<(String, f64)> {
+        let mut samples: Vec<(String, f64)> = aggregator.flush(100)
+            .into_iter()
+            .map(|Sample { key, value, ts }| {
+                assert_eq!(100, ts);
+                match value {
+                    Value::Float(value) => (key.to_string(), value),
+                    Value::Int(_) => panic!("integer sample"),
+                }
+            })
+            .collect();
+        samples.sort_by(|a, b| a.0.cmp(&b.0));
+        samples
+    }
+
+    #[test]
+    fn test_aggregator() {
+        let mut aggregator = Aggregator::new(vec![0.5, 1.0]);
+        for line in &["requests:1|c", "requests:2|c|@0.5", "depth:10|g", "depth:+5|g", "depth:-3|g",
+                      "latency:10|ms", "latency:20|ms|@0.5", "latency:30|ms", "users:a|s", "users:b|s",
+                      "users:a|s"] {
+            aggregator.add(parse_line(line).unwrap());
+        }
+        assert_eq!(vec![("depth".to_string(), 12.0),
+                        ("latency_count".to_string(), 4.0),
+                        ("latency_sum".to_string(), 80.0),
+                        ("latency{quantile=\"0.5\"}".to_string(), 20.0),
+                        ("latency{quantile=\"1\"}".to_string(), 30.0),
+                        ("requests".to_string(), 5.0),
+                        ("users".to_string(), 2.0)],
+                   flush(&mut aggregator));
+        assert!(aggregator.is_empty());
+        assert!(flush(&mut aggregator).is_empty());
+
+        // gauges keep their value across intervals
+        aggregator.add(parse_line("depth:+1|g").unwrap());
+        assert_eq!(vec![("depth".to_string(), 13.0)], flush(&mut aggregator));
+    }
+
+    #[test]
+    fn test_listener() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let config = Config { interval: Duration::from_secs(3600), ..Config::default() };
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), config).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"requests:1|c|#host:a\nrequests:2|c|#host:a\nbroken", listener.local_addr()).unwrap();
+        socket.send_to(b"depth:7|g", listener.local_addr()).unwrap();
+
+        for _ in 0..1000 {
+            if listener.stats().packets() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(2, listener.stats().packets());
+        assert_eq!(3, listener.stats().metrics());
+        assert_eq!(1, listener.stats().malformed());
+        // the interval is only flushed once the listener stops
+        assert_eq!(0, listener.written());
+        drop(listener);
+
+        let store = store.read().unwrap();
+        let requests = store.get(&SeriesKey::new("requests").tag("host", "a")).unwrap().points(0, i64::MAX);
+        assert_eq!(vec![3.0], requests.iter().map(|p| p.value).collect::<Vec<f64>>());
+        assert_eq!(7.0, store.get(&SeriesKey::new("depth")).unwrap().points(0, i64::MAX)[0].value);
+    }
+}
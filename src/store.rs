@@ -0,0 +1,795 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use fnv::FnvHashMap;
+
+use aggregate::{Function, Window};
+use cache::{self, Cache, CacheKey};
+use compact::Compactor;
+use int_block::IntPoint;
+use retention::Retention;
+use rollup::{Aggregate, Rollups, FIVE_MINUTES, ONE_HOUR};
+use series::{Appended, IntSeries, Series, DEFAULT_BLOCK_SIZE};
+use snapshot;
+use time;
+use validate::{Validation, Validator};
+use {Point, TSBlock};
+
+/// Identifies a series by its metric name and a set of tags
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SeriesKey {
+    pub metric: String,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl SeriesKey {
+    /// Creates a key for `metric` without any tags
+    pub fn new<S: Into<String>>(metric: S) -> SeriesKey {
+        SeriesKey {
+            metric: metric.into(),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a tag to the key
+    pub fn tag<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> SeriesKey {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+}
+
+impl fmt::Display for SeriesKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.metric)?;
+        if self.tags.is_empty() {
+            return Ok(());
+        }
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}=\"{}\"", key, value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// In-memory store holding every series, keyed by `SeriesKey`
+pub struct Store {
+    block_size: i64,
+    series: FnvHashMap<SeriesKey, Series>,
+    // series of integer values, kept apart since they use another codec.
+    // A key is never in both maps, see `publish_int_at`
+    int_series: FnvHashMap<SeriesKey, IntSeries>,
+    retention: Retention,
+    rollup_resolutions: Vec<i64>,
+    rollups: FnvHashMap<SeriesKey, Rollups>,
+    // the latest time raw points were expired as of, by `sweep` or
+    // `compact`. Rollup windows before the cutoff at that time can't be
+    // rolled up again since their raw points may be gone
+    expired_at: Option<i64>,
+    validator: Validator,
+    // results computed from sealed blocks, shared by concurrent readers
+    cache: Mutex<Cache>,
+}
+
+impl Default for Store {
+    fn default() -> Store {
+        Store::new()
+    }
+}
+
+impl Store {
+    /// Creates an empty store using 2 hour blocks, 5 minute and 1 hour
+    /// rollups and no retention limits
+    pub fn new() -> Store {
+        Store::with_block_size(DEFAULT_BLOCK_SIZE)
+    }
+
+    /// Creates an empty store whose series use `block_size` second blocks
+    pub fn with_block_size(block_size: i64) -> Store {
+        Store {
+            block_size,
+            series: FnvHashMap::default(),
+            int_series: FnvHashMap::default(),
+            retention: Retention::new(),
+            rollup_resolutions: vec![FIVE_MINUTES, ONE_HOUR],
+            rollups: FnvHashMap::default(),
+            expired_at: None,
+            validator: Validator::default(),
+            cache: Mutex::new(Cache::new(cache::DEFAULT_BUDGET)),
+        }
+    }
+
+    /// Returns the amount of time in seconds covered by a single block
+    pub fn block_size(&self) -> i64 {
+        self.block_size
+    }
+
+    /// Publish a value to the series identified by `key` at the current time
+    pub fn publish(&mut self, key: &SeriesKey, value: f64) {
+        self.publish_at(key, value, time::get_time().sec);
+    }
+
+    /// Publish a value to the series identified by `key` at the given `ts`
+    /// seconds from the epoch. The series is created if it doesn't exist.
+    /// When the value seals the open block, the windows before the new
+    /// open block are rolled up, after rewinding the rollups if the value
+    /// falls into a window they hold. An integer series identified by
+    /// `key` is converted to a float series first
+    pub fn publish_at(&mut self, key: &SeriesKey, value: f64, ts: i64) {
+        if let Some(int) = self.int_series.remove(key) {
+            self.series.insert(key.clone(), to_floats(&int));
+        }
+        let block_size = self.block_size;
+        let series = self.series.entry(key.clone()).or_insert_with(|| Series::with_block_size(block_size));
+        let sealed = series.sealed().len();
+        series.publish_at(value, ts);
+        if series.sealed().len() > sealed {
+            // results of another block with the same header may be cached
+            let header = series.sealed()[sealed].header();
+            let upto = series.open().map_or(ts, |open| open.header() as i64);
+            self.cache().invalidate_block(key, header);
+            self.rewind_rollups(key, ts);
+            self.update_rollups(key, upto);
+        }
+    }
+
+    /// Retrieves the series identified by `key`
+    pub fn get(&self, key: &SeriesKey) -> Option<&Series> {
+        self.series.get(key)
+    }
+
+    /// Retrieves the series identified by `key`, creating it if needed.
+    /// Since its sealed blocks may be modified, the cached results of the
+    /// series are invalidated
+    pub fn series_mut(&mut self, key: &SeriesKey) -> &mut Series {
+        self.cache().invalidate_series(key);
+        let block_size = self.block_size;
+        self.series.entry(key.clone()).or_insert_with(|| Series::with_block_size(block_size))
+    }
+
+    /// Adds a sealed `block` to the series identified by `key`, merging it
+    /// with the blocks it overlaps. See `Series::append_block`. Rollup
+    /// windows holding points of `block` are rolled up again on the next
+    /// update
+    pub fn append_block(&mut self, key: &SeriesKey, block: TSBlock) -> Appended {
+        let header = block.header();
+        let first = block.iter().next().map(|point| point.ts);
+        let block_size = self.block_size;
+        let cache = self.cache.get_mut().unwrap_or_else(|err| err.into_inner());
+        let series = self.series.entry(key.clone()).or_insert_with(|| Series::with_block_size(block_size));
+        let before = block_ids(series);
+        let appended = series.append_block(block);
+        invalidate_replaced(cache, key, &before, series);
+        // results of another block with the same header may be cached
+        cache.invalidate_block(key, header);
+        if let Some(first) = first {
+            self.rewind_rollups(key, first);
+        }
+        appended
+    }
+
+    /// Inserts `series` under `key`, replacing any existing series of
+    /// either value type
+    pub fn insert(&mut self, key: SeriesKey, series: Series) {
+        self.cache().invalidate_series(&key);
+        self.int_series.remove(&key);
+        self.series.insert(key, series);
+    }
+
+    /// Removes the series identified by `key`, whatever its value type,
+    /// along with its rollups. Returns it if it was a float series, see
+    /// `remove_int` for integer series
+    pub fn remove(&mut self, key: &SeriesKey) -> Option<Series> {
+        self.cache().invalidate_series(key);
+        self.rollups.remove(key);
+        self.int_series.remove(key);
+        self.series.remove(key)
+    }
+
+    /// Returns an iterator over every series in the store
+    pub fn iter(&self) -> impl Iterator<Item = (&SeriesKey, &Series)> {
+        self.series.iter()
+    }
+
+    /// Publish an integer value to the integer series identified by `key`
+    /// at the given `ts` seconds from the epoch. The series is created if
+    /// it doesn't exist. If a float series is identified by `key`, the
+    /// value is published to it as a float instead
+    pub fn publish_int_at(&mut self, key: &SeriesKey, value: i64, ts: i64) {
+        if self.series.contains_key(key) {
+            return self.publish_at(key, value as f64, ts);
+        }
+        if !self.int_series.contains_key(key) {
+            self.int_series.insert(key.clone(), IntSeries::with_block_size(self.block_size));
+        }
+        self.int_series.get_mut(key).unwrap().publish_at(value, ts);
+    }
+
+    /// Retrieves the integer series identified by `key`
+    pub fn get_int(&self, key: &SeriesKey) -> Option<&IntSeries> {
+        self.int_series.get(key)
+    }
+
+    /// Inserts the integer series `series` under `key`, replacing any
+    /// existing series of either value type
+    pub fn insert_int(&mut self, key: SeriesKey, series: IntSeries) {
+        if self.series.remove(&key).is_some() {
+            self.cache().invalidate_series(&key);
+        }
+        self.int_series.insert(key, series);
+    }
+
+    /// Removes the series identified by `key`, whatever its value type,
+    /// along with its rollups. Returns it if it was an integer series
+    pub fn remove_int(&mut self, key: &SeriesKey) -> Option<IntSeries> {
+        self.cache().invalidate_series(key);
+        self.rollups.remove(key);
+        self.series.remove(key);
+        self.int_series.remove(key)
+    }
+
+    /// Returns an iterator over every integer series in the store
+    pub fn iter_ints(&self) -> impl Iterator<Item = (&SeriesKey, &IntSeries)> {
+        self.int_series.iter()
+    }
+
+    /// Returns the number of series in the store, integer series included
+    pub fn len(&self) -> usize {
+        self.series.len() + self.int_series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty() && self.int_series.is_empty()
+    }
+
+    /// Retrieves the retention settings of the store
+    pub fn retention(&self) -> &Retention {
+        &self.retention
+    }
+
+    /// Retrieves the retention settings of the store for modification
+    pub fn retention_mut(&mut self) -> &mut Retention {
+        &mut self.retention
+    }
+
+    /// Retrieves the validator checking samples ingested into the store
+    pub fn validator(&self) -> &Validator {
+        &self.validator
+    }
+
+    /// Replaces the validation of ingested samples, resetting its counters
+    pub fn set_validation(&mut self, validation: Validation) {
+        self.validator = Validator::new(validation);
+    }
+
+    /// Sets the resolutions in seconds at which series are rolled up.
+    /// Existing rollups are discarded
+    pub fn set_rollup_resolutions(&mut self, resolutions: Vec<i64>) {
+        self.rollup_resolutions = resolutions;
+        self.rollups.clear();
+    }
+
+    /// Returns the resolutions in seconds at which series are rolled up
+    pub fn rollup_resolutions(&self) -> &[i64] {
+        &self.rollup_resolutions
+    }
+
+    /// Returns an iterator over the rollups of every series
+    pub fn iter_rollups(&self) -> impl Iterator<Item = (&SeriesKey, &Rollups)> {
+        self.rollups.iter()
+    }
+
+    /// Retrieves the rollups of the series identified by `key`
+    pub fn rollups(&self, key: &SeriesKey) -> Option<&Rollups> {
+        self.rollups.get(key)
+    }
+
+    /// Replaces the rollups of the series identified by `key`
+    pub fn insert_rollups(&mut self, key: SeriesKey, rollups: Rollups) {
+        self.rollups.insert(key, rollups);
+    }
+
+    /// Rolls up every series up to `now` seconds from the epoch. Rollups
+    /// are also updated as blocks seal and on every compaction pass
+    pub fn rollup(&mut self, now: i64) {
+        for (key, series) in &self.series {
+            if !self.rollups.contains_key(key) {
+                self.rollups.insert(key.clone(), Rollups::new(&self.rollup_resolutions));
+            }
+            self.rollups.get_mut(key).unwrap().update(series, now);
+        }
+    }
+
+    // Rewinds the rollups of the series identified by `key` to the window
+    // holding `from`, which raw points were added to
+    fn rewind_rollups(&mut self, key: &SeriesKey, from: i64) {
+        let expired_before = self.expired_at
+            .and_then(|now| self.retention.cutoff(&key.metric, now))
+            .unwrap_or(i64::MIN);
+        if let Some(rollups) = self.rollups.get_mut(key) {
+            rollups.rewind(from, expired_before);
+        }
+    }
+
+    // Rolls up the series identified by `key` up to `upto`
+    fn update_rollups(&mut self, key: &SeriesKey, upto: i64) {
+        if let Some(series) = self.series.get(key) {
+            let resolutions = &self.rollup_resolutions;
+            self.rollups
+                .entry(key.clone())
+                .or_insert_with(|| Rollups::new(resolutions))
+                .update(series, upto);
+        }
+    }
+
+    /// Aggregates the series identified by `key` over [`start`, `end`) into
+    /// `step` second windows, using the rollups best suited to `step`.
+    /// Integer series aren't rolled up, their raw points are aggregated as
+    /// floats
+    pub fn query(&self, key: &SeriesKey, aggregate: Aggregate, start: i64, end: i64, step: i64) -> Vec<Point> {
+        // rollups outlive the raw data of series removed by retention
+        let converted;
+        let series = match (self.series.get(key), self.int_series.get(key)) {
+            (Some(series), _) => series,
+            (None, Some(int)) => {
+                converted = to_floats(int);
+                &converted
+            }
+            (None, None) => {
+                converted = Series::new();
+                &converted
+            }
+        };
+        match self.rollups.get(key) {
+            Some(rollups) => rollups.query(series, aggregate, start, end, step),
+            None => Rollups::new(&[]).query(series, aggregate, start, end, step),
+        }
+    }
+
+    /// Aggregates the raw points of the series identified by `key` into
+    /// `step` second windows aligned to the epoch, returning the windows
+    /// starting within [`start`, `end`).
+    ///
+    /// Results computed from sealed blocks are cached, so repeating a query
+    /// only decodes the open block. Blocks sharing windows with each other
+    /// can't be aggregated separately, their decoded points are cached
+    /// instead. The points of integer series are aggregated as floats
+    /// without caching.
+    pub fn window(&self, key: &SeriesKey, function: Function, start: i64, end: i64, step: i64) -> Vec<Point> {
+        assert!(step > 0, "window step must be positive");
+        let window_of = |ts: i64| ts - ts.rem_euclid(step);
+        let from = window_of(start);
+        let to = window_of(end - 1) + step;
+        let series = match (self.series.get(key), self.int_series.get(key)) {
+            (Some(series), _) => series,
+            (None, Some(int)) => {
+                let points = int.points(from, to).into_iter().map(IntPoint::to_point);
+                return Window::new(points, function, step).filter(|p| p.ts >= start && p.ts < end).collect();
+            }
+            (None, None) => return Vec::new(),
+        };
+        let blocks: Vec<&TSBlock> = series.blocks()
+            .filter(|block| block.last_ts().is_some_and(|last| last >= from) && (block.header() as i64) < to)
+            .collect();
+        let separate = blocks.windows(2)
+            .all(|pair| window_of(pair[0].last_ts().unwrap_or(i64::MIN)) < window_of(pair[1].header() as i64));
+
+        let mut cache = self.cache();
+        let mut points = Vec::new();
+        if separate {
+            for block in &blocks {
+                let cache_key = CacheKey::aggregated(key, block.header(), function, step);
+                let windows = cached(&mut cache, series, block, cache_key, || {
+                    Window::new(block.iter(), function, step).collect()
+                });
+                points.extend(windows.iter().filter(|p| p.ts >= start && p.ts < end));
+            }
+            return points;
+        }
+        for block in &blocks {
+            // overlapping blocks may share a header and can't be told apart
+            let unique = blocks.iter().filter(|b| b.header() == block.header()).count() == 1;
+            if unique {
+                let cache_key = CacheKey::decoded(key, block.header());
+                let decoded = cached(&mut cache, series, block, cache_key, || block.iter().collect());
+                points.extend(decoded.iter().filter(|p| p.ts >= from && p.ts < to));
+            } else {
+                points.extend(block.iter().filter(|p| p.ts >= from && p.ts < to));
+            }
+        }
+        // stable so that points of later blocks stay last
+        points.sort_by_key(|p| p.ts);
+        Window::new(points.into_iter(), function, step)
+            .filter(|p| p.ts >= start && p.ts < end)
+            .collect()
+    }
+
+    /// Sets the memory budget in bytes of the cache of query results,
+    /// 0 disables caching
+    pub fn set_cache_budget(&mut self, budget: usize) {
+        self.cache().set_budget(budget);
+    }
+
+    /// Returns the hit and miss counters of the cache of query results
+    pub fn cache_stats(&self) -> cache::Stats {
+        self.cache().stats()
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        // the cache only holds derived data, so it's fine to keep using it
+        // after a panic while it was locked
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Writes every block of the store, including open blocks and rollups,
+    /// to a new snapshot directory at `path`
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        snapshot::write(self, path)
+    }
+
+    /// Restores a store from the snapshot directory at `path`
+    pub fn restore<P: AsRef<Path>>(path: P) -> io::Result<Store> {
+        snapshot::read(path)
+    }
+
+    /// Drops every block that has expired according to the retention
+    /// settings as of `now` seconds from the epoch, rollup blocks included.
+    /// Series left without blocks are removed, but their rollups are kept
+    /// until they expire too. Returns the number of blocks dropped
+    pub fn sweep(&mut self, now: i64) -> usize {
+        self.expire_at(now);
+        let retention = &self.retention;
+        let cache = self.cache.get_mut().unwrap_or_else(|err| err.into_inner());
+        let mut dropped = 0;
+        self.series.retain(|key, series| {
+            if let Some(cutoff) = retention.cutoff(&key.metric, now) {
+                let before = block_ids(series);
+                dropped += series.expire(cutoff);
+                invalidate_replaced(cache, key, &before, series);
+            }
+            !series.is_empty()
+        });
+        self.int_series.retain(|key, series| {
+            if let Some(cutoff) = retention.cutoff(&key.metric, now) {
+                dropped += series.expire(cutoff);
+            }
+            !series.is_empty()
+        });
+        let series = &self.series;
+        self.rollups.retain(|key, rollups| {
+            dropped += rollups.expire(retention, now);
+            !rollups.is_empty() || series.contains_key(key)
+        });
+        dropped
+    }
+
+    /// Compacts the sealed blocks of every series and rollup into `window`
+    /// second blocks. Series are rolled up to `now` first, then raw points
+    /// that have expired as of `now` are trimmed on the way, while rollups
+    /// are kept
+    pub fn compact(&mut self, window: i64, now: i64) {
+        self.rollup(now);
+        self.expire_at(now);
+        let compactor = Compactor::new(window);
+        let cache = self.cache.get_mut().unwrap_or_else(|err| err.into_inner());
+        for (key, series) in &mut self.series {
+            let before = block_ids(series);
+            match self.retention.cutoff(&key.metric, now) {
+                Some(cutoff) => compactor.expire_before(cutoff).compact_series(series),
+                None => compactor.compact_series(series),
+            }
+            // blocks rewritten by compaction may keep their header and
+            // length, so any change drops every result of the series
+            if block_ids(series) != before {
+                cache.invalidate_series(key);
+            }
+        }
+        for rollups in self.rollups.values_mut() {
+            rollups.compact(&compactor);
+        }
+    }
+
+    /// Returns the latest time raw points were expired as of, by `sweep` or
+    /// `compact`, if any
+    pub fn expired_at(&self) -> Option<i64> {
+        self.expired_at
+    }
+
+    /// Records that raw points were expired as of `now`, e.g. when
+    /// restoring a store. Rollup windows before the retention cutoff of the
+    /// latest such time are never rolled up again
+    pub fn expire_at(&mut self, now: i64) {
+        self.expired_at = Some(self.expired_at.map_or(now, |at| at.max(now)));
+    }
+}
+
+// Returns the result of `block` cached under `cache_key`, computing and
+// caching it if needed. Open blocks are never cached
+fn cached<F>(cache: &mut Cache, series: &Series, block: &TSBlock, cache_key: CacheKey, compute: F) -> Arc<Vec<Point>>
+    where F: FnOnce() -> Vec<Point>
+{
+    let sealed = series.open().is_none_or(|open| !::std::ptr::eq(open, block));
+    if !sealed {
+        return Arc::new(compute());
+    }
+    if let Some(points) = cache.get(&cache_key) {
+        return points;
+    }
+    let points = Arc::new(compute());
+    cache.insert(cache_key, points.clone());
+    points
+}
+
+// Republishes the points of an integer series as floats, block by block,
+// leaving the last block open if it was
+fn to_floats(int: &IntSeries) -> Series {
+    let mut series = Series::with_block_size(int.block_size());
+    for block in int.sealed() {
+        for point in block.iter() {
+            series.publish_at(point.value as f64, point.ts);
+        }
+        series.seal();
+    }
+    for point in int.open().into_iter().flat_map(|open| open.iter()) {
+        series.publish_at(point.value as f64, point.ts);
+    }
+    series
+}
+
+// Identifies the sealed blocks of a series by header and encoded length
+fn block_ids(series: &Series) -> Vec<(u64, usize)> {
+    series.sealed().iter().map(|block| (block.header(), block.as_slice().len())).collect()
+}
+
+// Invalidates the cached results of the blocks in `before` that are no
+// longer part of `series`
+fn invalidate_replaced(cache: &mut Cache, key: &SeriesKey, before: &[(u64, usize)], series: &Series) {
+    let after = block_ids(series);
+    for &(header, len) in before {
+        if !after.contains(&(header, len)) {
+            cache.invalidate_block(key, header);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SeriesKey, Store};
+    use aggregate::Function;
+    use rollup::{Aggregate, FIVE_MINUTES, ONE_HOUR};
+    use {test_block, Point, TSBlock};
+
+    #[test]
+    fn test_series_key_display() {
+        assert_eq!("cpu", SeriesKey::new("cpu").to_string());
+        let key = SeriesKey::new("cpu").tag("region", "us").tag("host", "a");
+        assert_eq!("cpu{host=\"a\",region=\"us\"}", key.to_string());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let cpu = SeriesKey::new("cpu");
+        let mem = SeriesKey::new("mem");
+        let mut store = Store::with_block_size(100);
+        store.retention_mut().set("cpu", 1000);
+        for ts in 0..20 {
+            store.publish_at(&cpu, 1.0, ts * 100);
+            store.publish_at(&mem, 1.0, ts * 100);
+        }
+        assert_eq!(0, store.sweep(1000));
+        assert_eq!(10, store.sweep(2000));
+        assert_eq!(10, store.get(&cpu).unwrap().blocks().count());
+        assert_eq!(20, store.get(&mem).unwrap().blocks().count());
+        assert_eq!(10, store.sweep(10_000));
+        assert!(store.get(&cpu).is_none());
+    }
+
+    #[test]
+    fn test_remove() {
+        let cpu = SeriesKey::new("cpu");
+        let hits = SeriesKey::new("hits");
+        let mut store = Store::with_block_size(3600);
+        for i in 0..400 {
+            store.publish_at(&cpu, 1.0, i * 10);
+        }
+        store.publish_int_at(&hits, 1, 0);
+        assert!(store.rollups(&cpu).is_some());
+        assert_eq!(1, store.window(&cpu, Function::Sum, 0, 3600, 3600).len());
+
+        assert!(store.remove(&cpu).is_some());
+        assert!(store.rollups(&cpu).is_none());
+        assert!(store.window(&cpu, Function::Sum, 0, 3600, 3600).is_empty());
+        // integer series are removed too, though only returned by remove_int
+        assert!(store.remove(&hits).is_none());
+        assert!(store.is_empty());
+
+        store.publish_int_at(&hits, 1, 0);
+        assert!(store.remove_int(&hits).is_some());
+        assert!(store.remove_int(&hits).is_none());
+    }
+
+    #[test]
+    fn test_compact_trims_expired_points() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(100);
+        store.retention_mut().set("cpu", 1000);
+        for ts in 0..20 {
+            store.publish_at(&cpu, ts as f64, ts * 100 + 50);
+        }
+        store.compact(2000, 1500);
+        let series = store.get(&cpu).unwrap();
+        assert_eq!(1, series.sealed().len());
+        let first = series.sealed()[0].iter().next().unwrap();
+        assert_eq!(550, first.ts);
+    }
+
+    #[test]
+    fn test_query_uses_rollups() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::new();
+        store.retention_mut().set("cpu", 3600);
+        for i in 0..720 {
+            store.publish_at(&cpu, 1.0, i * 10);
+        }
+        // raw data of the first hour expires but its rollups remain
+        store.compact(86400, 7200);
+        let counts = store.query(&cpu, Aggregate::Count, 0, 7200, 3600);
+        assert_eq!(vec![Point { ts: 0, value: 360.0 }, Point { ts: 3600, value: 360.0 }], counts);
+    }
+
+    #[test]
+    fn test_rollup_on_seal() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(3600);
+        for i in 0..400 {
+            store.publish_at(&cpu, 1.0, i * 10);
+        }
+        let rollups = store.rollups(&cpu).unwrap();
+        assert_eq!(vec![Some(3600), Some(3600)], rollups.rollups().iter().map(|r| r.watermark()).collect::<Vec<_>>());
+        let counts = rollups.get(ONE_HOUR).unwrap().series(Aggregate::Count).points(0, 3600);
+        assert_eq!(vec![Point { ts: 0, value: 360.0 }], counts);
+    }
+
+    #[test]
+    fn test_late_points_rewind_rollups() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(3600);
+        for i in 0..400 {
+            store.publish_at(&cpu, 1.0, i * 10);
+        }
+        store.publish_at(&cpu, 1.0, 100);
+        assert_eq!(Some(0), store.rollups(&cpu).unwrap().get(ONE_HOUR).unwrap().watermark());
+        assert_eq!(vec![Point { ts: 0, value: 361.0 }], store.query(&cpu, Aggregate::Count, 0, 3600, 3600));
+
+        store.append_block(&cpu, test_block(0, &[(200, 5.0), (3500, 1.0)]));
+        store.rollup(3600);
+        assert_eq!(Some(3600), store.rollups(&cpu).unwrap().get(ONE_HOUR).unwrap().watermark());
+        // the point at 200 is replaced, the one at 100 is kept twice
+        assert_eq!(vec![Point { ts: 0, value: 361.0 }], store.query(&cpu, Aggregate::Count, 0, 3600, 3600));
+        assert_eq!(vec![Point { ts: 0, value: 5.0 }], store.query(&cpu, Aggregate::Max, 0, 3600, 3600));
+
+        // points of windows whose raw points may have expired aren't rolled up again
+        store.retention_mut().set("cpu", 3600);
+        store.sweep(5000);
+        store.append_block(&cpu, test_block(0, &[(300, 9.0)]));
+        store.rollup(7200);
+        assert_eq!(vec![Point { ts: 0, value: 5.0 }], store.query(&cpu, Aggregate::Max, 0, 3600, 3600));
+    }
+
+    #[test]
+    fn test_sweep_rollups() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(3600);
+        store.retention_mut().set("cpu", 3600);
+        store.retention_mut().set_rollup(FIVE_MINUTES, 3600);
+        for i in 0..400 {
+            store.publish_at(&cpu, 1.0, i * 10);
+        }
+        store.rollup(7200);
+        assert!(store.sweep(86400) > 0);
+        assert!(store.get(&cpu).is_none());
+        let rollups = store.rollups(&cpu).unwrap();
+        assert!(rollups.get(FIVE_MINUTES).unwrap().is_empty());
+        assert!(!rollups.get(ONE_HOUR).unwrap().is_empty());
+
+        store.retention_mut().set_rollup(ONE_HOUR, 86400);
+        store.sweep(2 * 86400);
+        assert!(store.rollups(&cpu).is_none());
+    }
+
+    #[test]
+    fn test_window_cache() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(600);
+        for i in 0..180 {
+            store.publish_at(&cpu, i as f64, i * 10);
+        }
+        let sums = store.window(&cpu, Function::Sum, 0, 1800, 300);
+        assert_eq!(6, sums.len());
+        assert_eq!(Point { ts: 300, value: (30..60).sum::<i64>() as f64 }, sums[1]);
+        assert_eq!(0, store.cache_stats().hits);
+        assert_eq!(2, store.cache_stats().misses);
+
+        // only the open block is aggregated again
+        assert_eq!(sums, store.window(&cpu, Function::Sum, 0, 1800, 300));
+        assert_eq!(2, store.cache_stats().hits);
+        assert_eq!(sums[2..4].to_vec(), store.window(&cpu, Function::Sum, 600, 1200, 300));
+
+        // windows spanning blocks fall back to decoded points
+        let sums = store.window(&cpu, Function::Sum, 0, 1800, 900);
+        assert_eq!(vec![(0..90).sum::<i64>() as f64, (90..180).sum::<i64>() as f64],
+                   sums.iter().map(|p| p.value).collect::<Vec<f64>>());
+    }
+
+    #[test]
+    fn test_series_mut_invalidates_cache() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(100);
+        for ts in 0..3 {
+            store.publish_at(&cpu, 1.0, ts * 100);
+        }
+        let sums = |store: &Store| -> Vec<f64> {
+            store.window(&cpu, Function::Sum, 0, 200, 100).iter().map(|p| p.value).collect()
+        };
+        assert_eq!(vec![1.0, 1.0], sums(&store));
+        // a block with the same header and encoded length as the one replaced
+        let mut block = TSBlock::at(0);
+        block.publish_at(5.0, 0);
+        assert_eq!(store.get(&cpu).unwrap().sealed()[0].as_slice().len(), block.as_slice().len());
+        store.series_mut(&cpu).sealed_mut()[0] = block;
+        assert_eq!(vec![5.0, 1.0], sums(&store));
+    }
+
+    #[test]
+    fn test_window_cache_invalidation() {
+        let cpu = SeriesKey::new("cpu");
+        let mut store = Store::with_block_size(100);
+        store.retention_mut().set("cpu", 1000);
+        for ts in 0..20 {
+            store.publish_at(&cpu, 1.0, ts * 100);
+        }
+        assert_eq!(20, store.window(&cpu, Function::Count, 0, 2000, 100).len());
+        // late points replace the first blocks once compacted
+        store.publish_at(&cpu, 1.0, 50);
+        store.publish_at(&cpu, 1.0, 150);
+        store.compact(200, 0);
+        let counts = store.window(&cpu, Function::Count, 0, 400, 100);
+        assert_eq!(vec![2.0, 2.0, 1.0, 1.0], counts.iter().map(|p| p.value).collect::<Vec<f64>>());
+
+        assert_eq!(6, store.window(&cpu, Function::Count, 0, 600, 100).len());
+
+        // the blocks before 400 expire
+        store.sweep(1500);
+        assert_eq!(vec![Point { ts: 400, value: 1.0 }, Point { ts: 500, value: 1.0 }],
+                   store.window(&cpu, Function::Count, 0, 600, 100));
+    }
+
+    #[test]
+    fn test_int_series() {
+        let hits = SeriesKey::new("hits");
+        let mut store = Store::with_block_size(100);
+        for ts in 0..4 {
+            store.publish_int_at(&hits, ts * 10, ts * 50);
+        }
+        assert_eq!(1, store.len());
+        assert_eq!(vec![Point { ts: 0, value: 10.0 }, Point { ts: 100, value: 50.0 }],
+                   store.window(&hits, Function::Sum, 0, 200, 100));
+        assert_eq!(vec![Point { ts: 0, value: 10.0 }, Point { ts: 100, value: 30.0 }],
+                   store.query(&hits, Aggregate::Max, 0, 200, 100));
+
+        // a float converts the series, later integers are published as floats
+        store.publish_at(&hits, 0.5, 200);
+        store.publish_int_at(&hits, 40, 210);
+        assert!(store.get_int(&hits).is_none());
+        assert_eq!(1, store.len());
+        let series = store.get(&hits).unwrap();
+        assert_eq!(vec![0.0, 10.0, 20.0, 30.0, 0.5, 40.0], series.points(0, 300).iter().map(|p| p.value).collect::<Vec<f64>>());
+        assert_eq!(2, series.sealed().len());
+    }
+}
@@ -0,0 +1,270 @@
+//! Validation of ingested samples.
+//!
+//! Every sample going through `ingest`, as well as every point of the
+//! blocks `wire` writes, is checked by the `Validator` of the store before
+//! it is published, so that misbehaving clients can't store NaN values or
+//! timestamps years away from the present. What the validator does with
+//! each sample is counted by reason, and the counters are exposed in the
+//! Prometheus text format:
+//!
+//! ```text
+//! ingest_samples_rejected_total{reason="future"} 3
+//! ```
+//!
+//! Writes answered once their samples are checked fail when a sample is
+//! rejected: those of `api`, `influx`, `remote` and `wire` fail as a
+//! whole, while `opentsdb` reports the rejected points along with the
+//! invalid ones. Listeners that don't answer writes, like `graphite`,
+//! `statsd` and the scrapes of `prometheus`, only count rejected samples.
+//!
+//! Samples published straight to a `Store` or `Series` aren't validated.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ingest::{Sample, Value};
+
+/// What to do with NaN and infinite values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFinite {
+    /// Publish them like any other value
+    #[default]
+    Store,
+    /// Discard them, counting them as dropped
+    Drop,
+    /// Discard them, counting them as rejected. Synchronous writes fail
+    Reject,
+}
+
+/// Settings of a validator. The default accepts every sample
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Validation {
+    pub non_finite: NonFinite,
+    /// Seconds a timestamp may lie ahead of the time it is ingested
+    pub max_future_skew: Option<i64>,
+    /// Whether timestamps beyond `max_future_skew` are brought back to it
+    /// rather than rejected
+    pub clamp_future: bool,
+    /// Seconds a timestamp may lie behind the time it is ingested
+    pub max_past_age: Option<i64>,
+}
+
+/// Why a sample isn't published as is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    NonFinite,
+    /// The timestamp is further ahead than `max_future_skew`
+    Future,
+    /// The timestamp is further behind than `max_past_age`
+    TooOld,
+}
+
+impl Reason {
+    /// Every reason, in the order of their counters
+    pub const ALL: [Reason; 3] = [Reason::NonFinite, Reason::Future, Reason::TooOld];
+
+    /// Returns the name of the reason in metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::NonFinite => "non_finite",
+            Reason::Future => "future",
+            Reason::TooOld => "too_old",
+        }
+    }
+}
+
+/// Outcome of the validation of a sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// The sample is published at this timestamp instead of its own
+    Clamp(i64),
+    Drop(Reason),
+    Reject(Reason),
+}
+
+/// Checks samples against a `Validation`, counting the outcomes
+#[derive(Debug, Default)]
+pub struct Validator {
+    validation: Validation,
+    accepted: AtomicU64,
+    clamped: AtomicU64,
+    dropped: [AtomicU64; 3],
+    rejected: [AtomicU64; 3],
+}
+
+impl Validator {
+    pub fn new(validation: Validation) -> Validator {
+        Validator { validation, ..Validator::default() }
+    }
+
+    pub fn validation(&self) -> &Validation {
+        &self.validation
+    }
+
+    /// Returns what to do with `sample` ingested at `now` seconds from the
+    /// epoch, without counting it
+    pub fn check(&self, sample: &Sample, now: i64) -> Verdict {
+        let validation = &self.validation;
+        let finite = match sample.value {
+            Value::Float(value) => value.is_finite(),
+            Value::Int(_) => true,
+        };
+        if !finite {
+            match validation.non_finite {
+                NonFinite::Store => {}
+                NonFinite::Drop => return Verdict::Drop(Reason::NonFinite),
+                NonFinite::Reject => return Verdict::Reject(Reason::NonFinite),
+            }
+        }
+        if validation.max_past_age.is_some_and(|age| sample.ts < now.saturating_sub(age)) {
+            return Verdict::Reject(Reason::TooOld);
+        }
+        match validation.max_future_skew {
+            Some(skew) if sample.ts > now.saturating_add(skew) => {
+                if validation.clamp_future {
+                    Verdict::Clamp(now.saturating_add(skew))
+                } else {
+                    Verdict::Reject(Reason::Future)
+                }
+            }
+            _ => Verdict::Accept,
+        }
+    }
+
+    /// Counts a verdict returned by `check`
+    pub fn record(&self, verdict: Verdict) {
+        let counter = match verdict {
+            Verdict::Accept => &self.accepted,
+            Verdict::Clamp(_) => &self.clamped,
+            Verdict::Drop(reason) => &self.dropped[reason as usize],
+            Verdict::Reject(reason) => &self.rejected[reason as usize],
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Checks and counts `sample`, returning the timestamp to publish it at
+    /// or `None` if it must be discarded
+    pub fn admit(&self, sample: &Sample, now: i64) -> Option<i64> {
+        let verdict = self.check(sample, now);
+        self.record(verdict);
+        match verdict {
+            Verdict::Accept => Some(sample.ts),
+            Verdict::Clamp(ts) => Some(ts),
+            Verdict::Drop(_) | Verdict::Reject(_) => None,
+        }
+    }
+
+    /// Returns the number of samples accepted as is
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of samples whose timestamp was clamped
+    pub fn clamped(&self) -> u64 {
+        self.clamped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of samples dropped for `reason`
+    pub fn dropped(&self, reason: Reason) -> u64 {
+        self.dropped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of samples rejected for `reason`
+    pub fn rejected(&self, reason: Reason) -> u64 {
+        self.rejected[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters in the Prometheus text exposition format
+    pub fn exposition(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, samples: &[(Option<Reason>, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for &(reason, value) in samples {
+                match reason {
+                    Some(reason) => {
+                        let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", name, reason.name(), value);
+                    }
+                    None => {
+                        let _ = writeln!(out, "{} {}", name, value);
+                    }
+                }
+            }
+        };
+        counter("ingest_samples_accepted_total", "Samples published as received.", &[(None, self.accepted())]);
+        counter("ingest_samples_clamped_total", "Samples published with their timestamp clamped.",
+                &[(None, self.clamped())]);
+        let by_reason = |count: &dyn Fn(Reason) -> u64| -> Vec<(Option<Reason>, u64)> {
+            Reason::ALL.iter().map(|&reason| (Some(reason), count(reason))).collect()
+        };
+        counter("ingest_samples_dropped_total", "Samples discarded by policy.", &by_reason(&|r| self.dropped(r)));
+        counter("ingest_samples_rejected_total", "Samples rejected as invalid.", &by_reason(&|r| self.rejected(r)));
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NonFinite, Reason, Validation, Validator, Verdict};
+    use ingest::{Sample, Value};
+    use prometheus;
+    use store::SeriesKey;
+
+    fn sample(value: Value, ts: i64) -> Sample {
+        Sample { key: SeriesKey::new("cpu"), value, ts }
+    }
+
+    #[test]
+    fn test_check() {
+        let now = 1_000_000;
+        let accept_all = Validator::default();
+        assert_eq!(Verdict::Accept, accept_all.check(&sample(Value::Float(f64::NAN), 0), now));
+
+        let validation = Validation {
+            non_finite: NonFinite::Drop,
+            max_future_skew: Some(60),
+            clamp_future: false,
+            max_past_age: Some(3600),
+        };
+        let validator = Validator::new(validation);
+        let cases = [
+            (Value::Float(1.0), now + 60, Verdict::Accept),
+            (Value::Float(f64::INFINITY), now, Verdict::Drop(Reason::NonFinite)),
+            (Value::Int(i64::MAX), now, Verdict::Accept),
+            (Value::Float(1.0), now + 61, Verdict::Reject(Reason::Future)),
+            (Value::Float(1.0), now - 3601, Verdict::Reject(Reason::TooOld)),
+            (Value::Float(1.0), i64::MAX, Verdict::Reject(Reason::Future)),
+        ];
+        for &(value, ts, expected) in cases.iter() {
+            assert_eq!(expected, validator.check(&sample(value, ts), now), "{:?} at {}", value, ts);
+        }
+
+        let validator = Validator::new(Validation { non_finite: NonFinite::Reject, clamp_future: true, ..validation });
+        assert_eq!(Verdict::Clamp(now + 60), validator.check(&sample(Value::Int(1), now + 86400), now));
+        assert_eq!(Verdict::Reject(Reason::NonFinite), validator.check(&sample(Value::Float(f64::NAN), now), now));
+    }
+
+    #[test]
+    fn test_counters() {
+        let now = 1_000_000;
+        let validation = Validation {
+            non_finite: NonFinite::Reject,
+            max_future_skew: Some(0),
+            clamp_future: true,
+            ..Validation::default()
+        };
+        let validator = Validator::new(validation);
+        assert_eq!(Some(now), validator.admit(&sample(Value::Float(1.0), now), now));
+        assert_eq!(Some(now), validator.admit(&sample(Value::Float(1.0), now + 10), now));
+        assert_eq!(None, validator.admit(&sample(Value::Float(f64::NAN), now), now));
+        assert_eq!(None, validator.admit(&sample(Value::Float(f64::NEG_INFINITY), now), now));
+        assert_eq!((1, 1, 2, 0), (validator.accepted(), validator.clamped(), validator.rejected(Reason::NonFinite),
+                                  validator.dropped(Reason::NonFinite)));
+
+        let families = prometheus::parse(&validator.exposition(), now).unwrap();
+        let rejected = families.iter().find(|f| f.name == "ingest_samples_rejected_total").unwrap();
+        assert_eq!(3, rejected.samples.len());
+        assert!(validator.exposition().contains("ingest_samples_rejected_total{reason=\"non_finite\"} 2\n"));
+    }
+}
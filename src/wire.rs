@@ -0,0 +1,1215 @@
+//! Binary ingestion protocol over TCP.
+//!
+//! Clients send frames made of a big-endian `u32` length followed by that
+//! many bytes of payload. A data frame holds a sequence number, the series
+//! ids the connection hasn't defined yet and batches of points per series
+//! id:
+//!
+//! ```text
+//! u8 DATA | u64 seq
+//! varint definitions | (varint id | string metric | varint tags | (string name | string value)*)*
+//! varint series      | (varint id | varint n | zigzag ts | zigzag delta * (n - 1) | f64 * n)*
+//! ```
+//!
+//! Strings are a varint length followed by UTF-8, timestamps are seconds,
+//! each one but the first a delta from the previous point, and values are
+//! little-endian IEEE 754. Ids are chosen by the client and only last as
+//! long as the connection.
+//!
+//! Agents that compress points themselves send sealed `TSBlock`s instead,
+//! in the format of `block_file`, see `encode_block`:
+//!
+//! ```text
+//! u8 BLOCKS | u64 seq
+//! varint definitions | (varint id | string metric | varint tags | (string name | string value)*)*
+//! varint blocks      | (varint id | varint length | block file)*
+//! ```
+//!
+//! Blocks are validated by decoding them, and their points must be ordered
+//! by timestamp. A block overlapping none of the blocks of its series is
+//! added as is, otherwise it is merged with them, see
+//! `Series::append_block`. Unlike points, blocks are written to the store
+//! by the connection itself.
+//!
+//! Points and the points of blocks are checked by the validator of the
+//! store before the frame is answered. A point the validator rejects fails
+//! the whole frame, while points it drops are left out and points it
+//! clamps are moved, rebuilding the blocks holding them.
+//!
+//! The server answers every frame with an `ACK` carrying its sequence
+//! number once its points are handed to the writer or its blocks written,
+//! or with an `ERROR` and a message if the frame can't be used, in which
+//! case none of it is. A client that doesn't receive the ack resends the
+//! frame, on a new connection and with its definitions, so every point is
+//! written at least once. Frames too large or cut short end the
+//! connection.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use block_file;
+use ingest::{self, Batcher, Sample, Value, Writer, DEFAULT_BATCH_SIZE, DEFAULT_QUEUE};
+use series::Appended;
+use store::{SeriesKey, Store};
+use time;
+use validate::{Reason, Verdict};
+use {Point, TSBlock};
+
+/// Kind of a data frame
+pub const DATA: u8 = 1;
+
+/// Kind of a frame of compressed blocks
+pub const BLOCKS: u8 = 4;
+
+/// Kind of the reply to a frame that was accepted
+pub const ACK: u8 = 2;
+
+/// Kind of the reply to a frame that was rejected
+pub const ERROR: u8 = 3;
+
+/// Default maximum size of a frame payload (16MB)
+pub const DEFAULT_MAX_FRAME: usize = 16 << 20;
+
+// How often connections check whether the listener is shutting down
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The first point of a block is stored as a 14 bit delta from its header
+const MAX_FIRST_DELTA: i64 = 1 << 14;
+
+/// Reasons a frame can't be decoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    Truncated,
+    InvalidVarint,
+    InvalidUtf8,
+    UnknownKind(u8),
+    /// Points refer to a series id the connection hasn't defined
+    UnknownSeries(u32),
+    /// The block of a series id can't be decoded or isn't time ordered
+    InvalidBlock(u32),
+    /// The payload is longer than what it encodes
+    TrailingBytes,
+    TooLarge(usize),
+    /// A point was rejected by the validator of the store
+    Rejected(SeriesKey, i64, Reason),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::Truncated => write!(f, "truncated frame"),
+            FrameError::InvalidVarint => write!(f, "invalid varint"),
+            FrameError::InvalidUtf8 => write!(f, "invalid UTF-8 string"),
+            FrameError::UnknownKind(kind) => write!(f, "unknown frame kind {}", kind),
+            FrameError::UnknownSeries(id) => write!(f, "undefined series id {}", id),
+            FrameError::InvalidBlock(id) => write!(f, "invalid block for series id {}", id),
+            FrameError::TrailingBytes => write!(f, "trailing bytes after frame"),
+            FrameError::TooLarge(len) => write!(f, "frame of {} bytes is too large", len),
+            FrameError::Rejected(ref key, ts, reason) => {
+                write!(f, "point of {} at {} rejected: {}", key, ts, reason.name())
+            }
+        }
+    }
+}
+
+impl error::Error for FrameError {}
+
+/// A batch of points sent by a client
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    pub seq: u64,
+    /// Series ids first used by this frame on its connection
+    pub definitions: Vec<(u32, SeriesKey)>,
+    /// Points as `(ts, value)` by series id
+    pub points: Vec<(u32, Vec<(i64, f64)>)>,
+}
+
+impl Frame {
+    /// Encodes the frame with its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        buf.push(DATA);
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        put_definitions(&mut buf, &self.definitions);
+        put_varint(&mut buf, self.points.len() as u64);
+        for (id, points) in &self.points {
+            put_varint(&mut buf, u64::from(*id));
+            put_varint(&mut buf, points.len() as u64);
+            let mut prev = 0;
+            for &(ts, _) in points {
+                put_varint(&mut buf, zigzag(ts.wrapping_sub(prev)));
+                prev = ts;
+            }
+            for &(_, value) in points {
+                buf.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+        }
+        with_length(buf)
+    }
+
+    /// Decodes a data frame from its payload, without the length prefix
+    pub fn decode(payload: &[u8]) -> Result<Frame, FrameError> {
+        let mut reader = Reader { buf: payload, pos: 0 };
+        match reader.byte()? {
+            DATA => {}
+            kind => return Err(FrameError::UnknownKind(kind)),
+        }
+        let mut frame = Frame { seq: reader.u64()?, ..Frame::default() };
+        frame.definitions = reader.definitions()?;
+        for _ in 0..reader.count()? {
+            let id = reader.id()?;
+            let n = reader.count()?;
+            let mut timestamps = Vec::with_capacity(n);
+            let mut ts = 0i64;
+            for _ in 0..n {
+                ts = ts.wrapping_add(unzigzag(reader.varint()?));
+                timestamps.push(ts);
+            }
+            let mut points = Vec::with_capacity(n);
+            for ts in timestamps {
+                points.push((ts, f64::from_bits(reader.u64_le()?)));
+            }
+            frame.points.push((id, points));
+        }
+        if reader.pos < payload.len() {
+            return Err(FrameError::TrailingBytes);
+        }
+        Ok(frame)
+    }
+
+    /// Returns the samples of the frame, resolving series ids with
+    /// `dictionary` and the definitions of the frame. The definitions are
+    /// added to `dictionary` only if the frame is valid
+    pub fn samples(&self, dictionary: &mut HashMap<u32, SeriesKey>) -> Result<Vec<Sample>, FrameError> {
+        let mut samples = Vec::new();
+        for (id, points) in &self.points {
+            let key = resolve(*id, &self.definitions, dictionary)?;
+            samples.extend(points.iter().map(|&(ts, value)| Sample { key: key.clone(), value: Value::Float(value), ts }));
+        }
+        dictionary.extend(self.definitions.iter().cloned());
+        Ok(samples)
+    }
+}
+
+/// A batch of compressed blocks sent by a client
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockFrame {
+    pub seq: u64,
+    /// Series ids first used by this frame on its connection
+    pub definitions: Vec<(u32, SeriesKey)>,
+    /// Blocks in the block file format by series id
+    pub blocks: Vec<(u32, Vec<u8>)>,
+}
+
+impl BlockFrame {
+    /// Encodes the frame with its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        buf.push(BLOCKS);
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        put_definitions(&mut buf, &self.definitions);
+        put_varint(&mut buf, self.blocks.len() as u64);
+        for (id, block) in &self.blocks {
+            put_varint(&mut buf, u64::from(*id));
+            put_varint(&mut buf, block.len() as u64);
+            buf.extend_from_slice(block);
+        }
+        with_length(buf)
+    }
+
+    /// Decodes a block frame from its payload, without the length prefix
+    pub fn decode(payload: &[u8]) -> Result<BlockFrame, FrameError> {
+        let mut reader = Reader { buf: payload, pos: 0 };
+        match reader.byte()? {
+            BLOCKS => {}
+            kind => return Err(FrameError::UnknownKind(kind)),
+        }
+        let mut frame = BlockFrame { seq: reader.u64()?, ..BlockFrame::default() };
+        frame.definitions = reader.definitions()?;
+        for _ in 0..reader.count()? {
+            let id = reader.id()?;
+            let len = reader.count()?;
+            frame.blocks.push((id, reader.take(len)?.to_vec()));
+        }
+        if reader.pos < payload.len() {
+            return Err(FrameError::TrailingBytes);
+        }
+        Ok(frame)
+    }
+
+    /// Returns the decoded blocks of the frame with their series, resolving
+    /// series ids like `Frame::samples`
+    pub fn blocks(&self, dictionary: &mut HashMap<u32, SeriesKey>) -> Result<Vec<(SeriesKey, TSBlock)>, FrameError> {
+        let mut blocks = Vec::new();
+        for (id, bytes) in &self.blocks {
+            let key = resolve(*id, &self.definitions, dictionary)?;
+            let block = decode_block(bytes).ok_or(FrameError::InvalidBlock(*id))?;
+            blocks.push((key.clone(), block));
+        }
+        dictionary.extend(self.definitions.iter().cloned());
+        Ok(blocks)
+    }
+}
+
+/// Encodes `block` for a blocks frame, in the block file format
+pub fn encode_block(block: &TSBlock) -> Vec<u8> {
+    block_file::encode(block)
+}
+
+/// Decodes a block encoded by `encode_block`, returning `None` unless it
+/// holds points ordered by timestamp
+pub fn decode_block(bytes: &[u8]) -> Option<TSBlock> {
+    let block: TSBlock = block_file::decode(bytes)?;
+    let mut last = i64::MIN;
+    for point in block.iter() {
+        if point.ts < last {
+            return None;
+        }
+        last = point.ts;
+    }
+    if block.is_empty() { None } else { Some(block) }
+}
+
+// Returns the key of series `id`, defined by the frame or on its connection
+fn resolve<'a>(id: u32, definitions: &'a [(u32, SeriesKey)], dictionary: &'a HashMap<u32, SeriesKey>)
+               -> Result<&'a SeriesKey, FrameError> {
+    definitions.iter()
+        .find(|&&(defined, _)| defined == id)
+        .map(|(_, key)| key)
+        .or_else(|| dictionary.get(&id))
+        .ok_or(FrameError::UnknownSeries(id))
+}
+
+/// Answer of the server to a data frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Ack(u64),
+    Error(u64, String),
+}
+
+impl Reply {
+    /// Encodes the reply with its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        match *self {
+            Reply::Ack(seq) => {
+                buf.push(ACK);
+                buf.extend_from_slice(&seq.to_be_bytes());
+            }
+            Reply::Error(seq, ref message) => {
+                buf.push(ERROR);
+                buf.extend_from_slice(&seq.to_be_bytes());
+                put_string(&mut buf, message);
+            }
+        }
+        with_length(buf)
+    }
+
+    /// Decodes a reply from its payload, without the length prefix
+    pub fn decode(payload: &[u8]) -> Result<Reply, FrameError> {
+        let mut reader = Reader { buf: payload, pos: 0 };
+        let reply = match reader.byte()? {
+            ACK => Reply::Ack(reader.u64()?),
+            ERROR => Reply::Error(reader.u64()?, reader.string()?),
+            kind => return Err(FrameError::UnknownKind(kind)),
+        };
+        if reader.pos < payload.len() {
+            return Err(FrameError::TrailingBytes);
+        }
+        Ok(reply)
+    }
+}
+
+fn with_length(mut buf: Vec<u8>) -> Vec<u8> {
+    let len = (buf.len() - 4) as u32;
+    buf[..4].copy_from_slice(&len.to_be_bytes());
+    buf
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_definitions(buf: &mut Vec<u8>, definitions: &[(u32, SeriesKey)]) {
+    put_varint(buf, definitions.len() as u64);
+    for (id, key) in definitions {
+        put_varint(buf, u64::from(*id));
+        put_string(buf, &key.metric);
+        put_varint(buf, key.tags.len() as u64);
+        for (name, value) in &key.tags {
+            put_string(buf, name);
+            put_string(buf, value);
+        }
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    put_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    (n >> 1) as i64 ^ -((n & 1) as i64)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FrameError> {
+        let bytes = self.buf.get(self.pos..self.pos + n).ok_or(FrameError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, FrameError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, FrameError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn u64_le(&mut self) -> Result<u64, FrameError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn varint(&mut self) -> Result<u64, FrameError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(FrameError::InvalidVarint)
+    }
+
+    fn id(&mut self) -> Result<u32, FrameError> {
+        let id = self.varint()?;
+        if id > u64::from(u32::MAX) {
+            return Err(FrameError::InvalidVarint);
+        }
+        Ok(id as u32)
+    }
+
+    // A number of items, each at least a byte long, so that a corrupt count
+    // can't allocate more than the frame holds
+    fn count(&mut self) -> Result<usize, FrameError> {
+        let n = self.varint()?;
+        if n > (self.buf.len() - self.pos) as u64 {
+            return Err(FrameError::Truncated);
+        }
+        Ok(n as usize)
+    }
+
+    fn definitions(&mut self) -> Result<Vec<(u32, SeriesKey)>, FrameError> {
+        let mut definitions = Vec::new();
+        for _ in 0..self.count()? {
+            let id = self.id()?;
+            let mut key = SeriesKey::new(self.string()?);
+            for _ in 0..self.count()? {
+                let name = self.string()?;
+                key = key.tag(name, self.string()?);
+            }
+            definitions.push((id, key));
+        }
+        Ok(definitions)
+    }
+
+    fn string(&mut self) -> Result<String, FrameError> {
+        let len = self.count()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| FrameError::InvalidUtf8)
+    }
+}
+
+/// Settings of a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Samples per batch handed to the writer
+    pub batch_size: usize,
+    /// Batches queued up for the writer before connections stop reading
+    pub queue: usize,
+    /// Maximum size of a frame payload
+    pub max_frame: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            batch_size: DEFAULT_BATCH_SIZE,
+            queue: DEFAULT_QUEUE,
+            max_frame: DEFAULT_MAX_FRAME,
+        }
+    }
+}
+
+/// Counters of a listener
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    frames: AtomicU64,
+    points: AtomicU64,
+    blocks: AtomicU64,
+    merged: AtomicU64,
+    rejected: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    /// Returns the number of connections accepted
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of frames received, rejected or not
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of points acknowledged
+    pub fn points(&self) -> u64 {
+        self.points.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of blocks written
+    pub fn blocks(&self) -> u64 {
+        self.blocks.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of blocks written that were merged with
+    /// overlapping blocks rather than added as is
+    pub fn merged(&self) -> u64 {
+        self.merged.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of frames rejected
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Returns a description of the last frame rejected
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+
+    fn report(&self, err: &FrameError) {
+        self.rejected.fetch_add(1, Ordering::SeqCst);
+        *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(err.to_string());
+    }
+}
+
+/// TCP listener publishing the frames it receives into a store
+pub struct Listener {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    handle: Option<JoinHandle<()>>,
+    writer: Option<Writer>,
+}
+
+impl Listener {
+    /// Starts listening on `addr` for frames to publish into `store`
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<RwLock<Store>>, config: Config) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Stats::default());
+        let writer = Writer::spawn(store.clone(), config.queue);
+        let accept = Accept {
+            listener,
+            store,
+            stop: stop.clone(),
+            stats: stats.clone(),
+            writer: writer.handle(),
+            config,
+        };
+        let handle = thread::spawn(move || accept.run());
+        Ok(Listener {
+            addr,
+            stop,
+            stats,
+            handle: Some(handle),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns the address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the number of samples written to the store so far
+    pub fn written(&self) -> u64 {
+        self.writer.as_ref().map_or(0, Writer::written)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wakes up the accepting thread
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.writer.take();
+    }
+}
+
+struct Accept {
+    listener: TcpListener,
+    store: Arc<RwLock<Store>>,
+    stop: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    writer: ingest::Handle,
+    config: Config,
+}
+
+impl Accept {
+    fn run(self) {
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        for stream in self.listener.incoming() {
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            self.stats.connections.fetch_add(1, Ordering::SeqCst);
+            let stop = self.stop.clone();
+            let connection = Connection {
+                store: self.store.clone(),
+                stats: self.stats.clone(),
+                batcher: self.writer.batcher(self.config.batch_size),
+                dictionary: HashMap::new(),
+            };
+            let max_frame = self.config.max_frame;
+            connections.retain(|handle| !handle.is_finished());
+            connections.push(thread::spawn(move || {
+                let _ = serve(stream, &stop, connection, max_frame);
+            }));
+        }
+        for handle in connections {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, stop: &AtomicBool, mut connection: Connection, max_frame: usize) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let stats = connection.stats.clone();
+    let mut buf = Vec::new();
+    let mut chunk = [0; 64 * 1024];
+    while !stop.load(Ordering::SeqCst) {
+        // handles every complete frame buffered so far
+        while buf.len() >= 4 {
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len > max_frame {
+                let err = FrameError::TooLarge(len);
+                stats.report(&err);
+                stream.write_all(&Reply::Error(0, err.to_string()).encode())?;
+                return Ok(());
+            }
+            if buf.len() < 4 + len {
+                break;
+            }
+            stats.frames.fetch_add(1, Ordering::SeqCst);
+            let reply = match connection.handle(&buf[4..4 + len]) {
+                Some(reply) => reply,
+                // the writer has stopped
+                None => return Ok(()),
+            };
+            stream.write_all(&reply.encode())?;
+            buf.drain(..4 + len);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {}
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// State of a connection of a listener
+struct Connection {
+    store: Arc<RwLock<Store>>,
+    stats: Arc<Stats>,
+    batcher: Batcher,
+    // series ids defined on the connection
+    dictionary: HashMap<u32, SeriesKey>,
+}
+
+impl Connection {
+    // Returns the reply to a frame, or `None` once the writer has stopped
+    fn handle(&mut self, payload: &[u8]) -> Option<Reply> {
+        // the sequence number is readable from any frame long enough
+        let seq = payload.get(1..9).map_or(0, |seq| u64::from_be_bytes(seq.try_into().expect("8 bytes")));
+        let handled = match payload.first() {
+            Some(&BLOCKS) => BlockFrame::decode(payload).and_then(|frame| frame.blocks(&mut self.dictionary))
+                .and_then(|blocks| self.append(blocks))
+                .map(Ok),
+            _ => Frame::decode(payload).and_then(|frame| frame.samples(&mut self.dictionary))
+                .and_then(|samples| self.check(&samples).map(|_| samples))
+                .map(|samples| self.publish(samples)),
+        };
+        match handled {
+            Ok(Ok(())) => Some(Reply::Ack(seq)),
+            Ok(Err(ingest::Closed)) => None,
+            Err(err) => {
+                self.stats.report(&err);
+                Some(Reply::Error(seq, err.to_string()))
+            }
+        }
+    }
+
+    // Fails on the first point the validator rejects, counting every
+    // rejected point. The writer counts the verdicts of the others
+    fn check(&self, samples: &[Sample]) -> Result<(), FrameError> {
+        let store = self.store.read().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut rejected = None;
+        for sample in samples {
+            if let verdict @ Verdict::Reject(reason) = store.validator().check(sample, now) {
+                store.validator().record(verdict);
+                rejected = rejected.or_else(|| Some(FrameError::Rejected(sample.key.clone(), sample.ts, reason)));
+            }
+        }
+        rejected.map_or(Ok(()), Err)
+    }
+
+    fn publish(&mut self, samples: Vec<Sample>) -> Result<(), ingest::Closed> {
+        let count = samples.len() as u64;
+        for sample in samples {
+            self.batcher.push(sample)?;
+        }
+        // acknowledged points must not wait in the batcher
+        self.batcher.flush()?;
+        self.stats.points.fetch_add(count, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Appends the points of `blocks` the validator admits, or none of them
+    // if it rejects one
+    fn append(&mut self, blocks: Vec<(SeriesKey, TSBlock)>) -> Result<(), FrameError> {
+        let mut store = self.store.write().unwrap_or_else(|err| err.into_inner());
+        let now = time::get_time().sec;
+        let mut checked = Vec::with_capacity(blocks.len());
+        let mut rejected = None;
+        for (key, block) in blocks {
+            let verdicts: Vec<(Point, Verdict)> = block.iter()
+                .map(|point| {
+                    let sample = Sample { key: key.clone(), value: Value::Float(point.value), ts: point.ts };
+                    (point, store.validator().check(&sample, now))
+                })
+                .collect();
+            for &(point, verdict) in &verdicts {
+                if let Verdict::Reject(reason) = verdict {
+                    store.validator().record(verdict);
+                    rejected = rejected.or_else(|| Some(FrameError::Rejected(key.clone(), point.ts, reason)));
+                }
+            }
+            checked.push((key, block, verdicts));
+        }
+        if let Some(err) = rejected {
+            return Err(err);
+        }
+
+        for (key, block, verdicts) in checked {
+            verdicts.iter().for_each(|&(_, verdict)| store.validator().record(verdict));
+            let block = if verdicts.iter().all(|&(_, verdict)| verdict == Verdict::Accept) {
+                block
+            } else {
+                let mut points: Vec<Point> = Vec::with_capacity(verdicts.len());
+                for (point, verdict) in verdicts {
+                    let ts = match verdict {
+                        Verdict::Accept => point.ts,
+                        Verdict::Clamp(ts) => ts,
+                        Verdict::Drop(_) | Verdict::Reject(_) => continue,
+                    };
+                    // points clamped to the same timestamp replace each other
+                    match points.last_mut() {
+                        Some(last) if last.ts == ts => last.value = point.value,
+                        _ => points.push(Point { ts, value: point.value }),
+                    }
+                }
+                match rebuild(block.header() as i64, &points) {
+                    Some(block) => block,
+                    None => continue,
+                }
+            };
+            if let Appended::Merged(_) = store.append_block(&key, block) {
+                self.stats.merged.fetch_add(1, Ordering::SeqCst);
+            }
+            self.stats.blocks.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+// Builds a block of the time ordered `points` admitted out of a block
+// starting at `header`. Clamping may move points before the header and
+// dropping points may leave the first one too far from it, so the header
+// moves to the first point then
+fn rebuild(header: i64, points: &[Point]) -> Option<TSBlock> {
+    let first = points.first()?.ts;
+    let header = if first >= header && first - header < MAX_FIRST_DELTA { header } else { first };
+    let mut block = TSBlock::at(header);
+    for point in points {
+        block.publish_at(point.value, point.ts);
+    }
+    Some(block)
+}
+
+/// Settings of a client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// Points sent per frame
+    pub batch_size: usize,
+    /// Times a frame is resent after the first attempt failed
+    pub retries: u32,
+    /// Delay before the first retry, doubling with every retry
+    pub backoff: Duration,
+    /// Time to wait for the connection and for every reply
+    pub timeout: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Client batching points into frames for a `Listener`
+///
+/// Points are sent once `batch_size` of them are pending or on `flush`.
+/// Points that couldn't be delivered stay pending and are sent again with
+/// the next frame.
+pub struct Client {
+    addr: SocketAddr,
+    config: ClientConfig,
+    stream: Option<TcpStream>,
+    ids: HashMap<SeriesKey, u32>,
+    keys: Vec<SeriesKey>,
+    // ids defined on the current connection
+    defined: Vec<bool>,
+    pending: Vec<(u32, i64, f64)>,
+    seq: u64,
+}
+
+impl Client {
+    /// Creates a client for the listener at `addr`, connecting on the first
+    /// frame sent
+    pub fn new<A: ToSocketAddrs>(addr: A, config: ClientConfig) -> io::Result<Client> {
+        let addr = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+        Ok(Client {
+            addr,
+            config,
+            stream: None,
+            ids: HashMap::new(),
+            keys: Vec::new(),
+            defined: Vec::new(),
+            pending: Vec::new(),
+            seq: 0,
+        })
+    }
+
+    /// Queues a point, sending the pending points if there are enough of them
+    pub fn publish(&mut self, key: &SeriesKey, value: f64, ts: i64) -> io::Result<()> {
+        let id = self.id(key);
+        self.pending.push((id, ts, value));
+        if self.pending.len() >= self.config.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends a sealed block of the series `key` once the pending points are
+    /// acknowledged, and waits for the listener to acknowledge it, retrying
+    /// on new connections
+    pub fn publish_block(&mut self, key: &SeriesKey, block: &TSBlock) -> io::Result<()> {
+        self.flush()?;
+        let id = self.id(key);
+        let bytes = encode_block(block);
+        let encode = |client: &Client| {
+            let definitions = if client.defined[id as usize] { Vec::new() } else { vec![(id, key.clone())] };
+            BlockFrame { seq: client.seq, definitions, blocks: vec![(id, bytes.clone())] }.encode()
+        };
+        self.deliver(&[id], encode)?.map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    /// Returns the number of points not acknowledged yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sends the pending points and waits for the listener to acknowledge
+    /// them, retrying on new connections. Points the listener rejects are
+    /// dropped
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<u32> = self.pending.iter().map(|&(id, _, _)| id).collect();
+        let delivered = self.deliver(&ids, |client| client.frame().encode())?;
+        self.pending.clear();
+        delivered.map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+
+    // Returns the id of the series `key`, assigning one if needed
+    fn id(&mut self, key: &SeriesKey) -> u32 {
+        if let Some(&id) = self.ids.get(key) {
+            return id;
+        }
+        let id = self.keys.len() as u32;
+        self.ids.insert(key.clone(), id);
+        self.keys.push(key.clone());
+        self.defined.push(false);
+        id
+    }
+
+    // Sends the next frame, as encoded by `encode` for the connection at
+    // hand, until it is acknowledged or rejected with a message. Marks the
+    // series `ids` as defined once the frame is acknowledged
+    fn deliver<F: Fn(&Client) -> Vec<u8>>(&mut self, ids: &[u32], encode: F) -> io::Result<Result<(), String>> {
+        self.seq += 1;
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.send(&encode) {
+                Ok(Reply::Ack(_)) => break,
+                Ok(Reply::Error(_, message)) => return Ok(Err(message)),
+                Err(err) => {
+                    self.disconnect();
+                    if attempt >= self.config.retries {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+        }
+        for &id in ids {
+            self.defined[id as usize] = true;
+        }
+        Ok(Ok(()))
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.defined.iter_mut().for_each(|defined| *defined = false);
+    }
+
+    fn send<F: Fn(&Client) -> Vec<u8>>(&mut self, encode: &F) -> io::Result<Reply> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, self.config.timeout)?;
+            stream.set_read_timeout(Some(self.config.timeout))?;
+            stream.set_write_timeout(Some(self.config.timeout))?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        let frame = encode(self);
+        let stream = self.stream.as_mut().expect("connected");
+        stream.write_all(&frame)?;
+
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        // replies are small, a larger length is garbage rather than a reason
+        // to allocate
+        let len = u32::from_be_bytes(len) as usize;
+        if len > DEFAULT_MAX_FRAME {
+            return Err(invalid(format!("reply of {} bytes exceeds the maximum of {}", len, DEFAULT_MAX_FRAME)));
+        }
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload)?;
+        match Reply::decode(&payload).map_err(|err| invalid(err.to_string()))? {
+            Reply::Ack(seq) | Reply::Error(seq, _) if seq != self.seq => {
+                Err(invalid(format!("reply to frame {} while waiting for {}", seq, self.seq)))
+            }
+            reply => Ok(reply),
+        }
+    }
+
+    // Builds the frame of the pending points, grouped by series id
+    fn frame(&self) -> Frame {
+        let mut frame = Frame { seq: self.seq, ..Frame::default() };
+        let mut groups: HashMap<u32, usize> = HashMap::new();
+        for &(id, ts, value) in &self.pending {
+            let group = *groups.entry(id).or_insert_with(|| {
+                if !self.defined[id as usize] {
+                    frame.definitions.push((id, self.keys[id as usize].clone()));
+                }
+                frame.points.push((id, Vec::new()));
+                frame.points.len() - 1
+            });
+            frame.points[group].1.push((ts, value));
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{decode_block, encode_block, BlockFrame, Client, ClientConfig, Config, Frame, FrameError, Listener,
+                Reply};
+    use block_file::Block;
+    use ingest::Value;
+    use store::{SeriesKey, Store};
+    use time;
+    use validate::{NonFinite, Reason, Validation};
+    use {test_block, TSBlock};
+
+    #[test]
+    fn test_frame() {
+        let cpu = SeriesKey::new("cpu").tag("host", "a");
+        let frame = Frame {
+            seq: 7,
+            definitions: vec![(3, cpu.clone())],
+            points: vec![(3, vec![(1690000000, 0.5), (1690000015, -1.0), (1689999990, f64::INFINITY)]), (1, vec![])],
+        };
+        let encoded = frame.encode();
+        assert_eq!((encoded.len() - 4) as u32, u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]));
+        let decoded = Frame::decode(&encoded[4..]).unwrap();
+        assert_eq!(frame, decoded);
+
+        let mut dictionary = HashMap::new();
+        dictionary.insert(1, SeriesKey::new("up"));
+        let samples = decoded.samples(&mut dictionary).unwrap();
+        assert_eq!(3, samples.len());
+        assert_eq!((cpu.clone(), Value::Float(-1.0), 1690000015), (samples[1].key.clone(), samples[1].value, samples[1].ts));
+        assert_eq!(Some(&cpu), dictionary.get(&3));
+
+        let unknown = Frame { seq: 8, definitions: vec![(4, cpu)], points: vec![(5, vec![(0, 1.0)])] };
+        assert_eq!(Err(FrameError::UnknownSeries(5)), unknown.samples(&mut dictionary));
+        assert_eq!(None, dictionary.get(&4));
+
+        assert_eq!(Err(FrameError::Truncated), Frame::decode(&encoded[4..encoded.len() - 1]));
+        assert_eq!(Err(FrameError::UnknownKind(9)), Frame::decode(&[9]));
+        let mut trailing = encoded[4..].to_vec();
+        trailing.push(0);
+        assert_eq!(Err(FrameError::TrailingBytes), Frame::decode(&trailing));
+
+        for reply in [Reply::Ack(3), Reply::Error(4, "undefined series id 5".to_string())] {
+            assert_eq!(Ok(reply.clone()), Reply::decode(&reply.encode()[4..]));
+        }
+    }
+
+    #[test]
+    fn test_listener() {
+        let store = Arc::new(RwLock::new(Store::new()));
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let config = ClientConfig { batch_size: 3, ..ClientConfig::default() };
+        let mut client = Client::new(listener.local_addr(), config).unwrap();
+        let cpu = SeriesKey::new("cpu").tag("host", "a");
+        let up = SeriesKey::new("up");
+        for i in 0..4 {
+            client.publish(&cpu, i as f64, 1000 + i * 10).unwrap();
+        }
+        // the first three points were sent on their own
+        assert_eq!(1, client.pending());
+        client.publish(&up, 1.0, 1000).unwrap();
+        client.flush().unwrap();
+        assert_eq!(0, client.pending());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.written() < 5 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let store = store.read().unwrap();
+        let values: Vec<f64> = store.get(&cpu).unwrap().points(0, 2000).iter().map(|p| p.value).collect();
+        assert_eq!(vec![0.0, 1.0, 2.0, 3.0], values);
+        assert_eq!(1, store.get(&up).unwrap().points(0, 2000).len());
+        assert_eq!((1, 2, 5, 0), (listener.stats().connections(), listener.stats().frames(), listener.stats().points(),
+                                  listener.stats().rejected()));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let frame = BlockFrame {
+            seq: 1,
+            definitions: vec![(0, SeriesKey::new("up"))],
+            blocks: vec![(0, encode_block(&test_block(10, &[(10, 1.0)]))), (0, vec![1, 2, 3])],
+        };
+        let decoded = BlockFrame::decode(&frame.encode()[4..]).unwrap();
+        assert_eq!(frame, decoded);
+        assert_eq!(Some(FrameError::InvalidBlock(0)), decoded.blocks(&mut HashMap::new()).err());
+        assert!(decode_block(&encode_block(&test_block(20, &[(20, 1.0), (10, 2.0)]))).is_none());
+        assert!(decode_block(&encode_block(&TSBlock::at(0))).is_none());
+        // words are little-endian on the wire, starting with the magic
+        let bytes = encode_block(&test_block(10, &[(10, 1.0)]));
+        assert_eq!(<TSBlock as Block>::MAGIC.to_le_bytes(), bytes[..8]);
+
+        let cpu = SeriesKey::new("cpu").tag("host", "a");
+        let store = Arc::new(RwLock::new(Store::with_block_size(100)));
+        store.write().unwrap().publish_at(&cpu, 1.0, 10);
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let mut client = Client::new(listener.local_addr(), ClientConfig::default()).unwrap();
+        client.publish(&cpu, 2.0, 20).unwrap();
+        client.publish_block(&cpu, &test_block(200, &[(200, 3.0), (210, 4.0)])).unwrap();
+        // sent again after a lost ack, merged without duplicating points
+        client.publish_block(&cpu, &test_block(200, &[(200, 3.0), (210, 4.0)])).unwrap();
+        let err = client.publish_block(&cpu, &test_block(300, &[(300, 5.0), (250, 6.0)])).unwrap_err();
+        assert_eq!("invalid block for series id 0", err.to_string());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.written() < 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let points: Vec<(i64, f64)> = store.read().unwrap().get(&cpu).unwrap().points(0, 1000)
+            .iter()
+            .map(|p| (p.ts, p.value))
+            .collect();
+        assert_eq!(vec![(10, 1.0), (20, 2.0), (200, 3.0), (210, 4.0)], points);
+        let stats = listener.stats();
+        assert_eq!((2, 1, 1), (stats.blocks(), stats.merged(), stats.rejected()));
+    }
+
+    #[test]
+    fn test_validation() {
+        let validation = Validation {
+            non_finite: NonFinite::Drop,
+            max_future_skew: Some(60),
+            clamp_future: true,
+            max_past_age: Some(3600),
+        };
+        let store = Arc::new(RwLock::new(Store::new()));
+        store.write().unwrap().set_validation(validation);
+        let listener = Listener::bind("127.0.0.1:0", store.clone(), Config::default()).unwrap();
+        let mut client = Client::new(listener.local_addr(), ClientConfig::default()).unwrap();
+        let (cpu, up) = (SeriesKey::new("cpu"), SeriesKey::new("up"));
+        let now = time::get_time().sec;
+
+        // a rejected point fails its frame before the ack
+        client.publish(&cpu, 1.0, now).unwrap();
+        client.publish(&cpu, 2.0, now - 7200).unwrap();
+        let err = client.flush().unwrap_err();
+        assert_eq!(format!("point of cpu at {} rejected: too_old", now - 7200), err.to_string());
+        client.publish(&cpu, f64::NAN, now).unwrap();
+        client.publish(&cpu, 3.0, now).unwrap();
+        client.flush().unwrap();
+
+        // blocks are rebuilt without dropped points and with clamped ones
+        let header = now - now % 100;
+        client.publish_block(&up, &test_block(header, &[(header, f64::NAN), (header + 1, 1.0), (now + 600, 2.0)]))
+            .unwrap();
+        // points clamped before the header of their block share one timestamp
+        let load = SeriesKey::new("load");
+        client.publish_block(&load, &test_block(now + 1000, &[(now + 1000, 1.0), (now + 1010, 2.0)])).unwrap();
+        assert!(client.publish_block(&up, &test_block(header - 7200, &[(header - 7200, 1.0)])).is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while listener.written() < 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let store = store.read().unwrap();
+        let points = |key| -> Vec<(i64, f64)> {
+            store.get(key).unwrap().points(0, i64::MAX).iter().map(|p| (p.ts, p.value)).collect()
+        };
+        assert_eq!(vec![(now, 3.0)], points(&cpu));
+        assert_eq!(vec![(header + 1, 1.0), (now + 60, 2.0)], points(&up));
+        assert_eq!(vec![(now + 60, 2.0)], points(&load));
+        let validator = store.validator();
+        assert_eq!((2, 2, 3), (validator.rejected(Reason::TooOld), validator.dropped(Reason::NonFinite),
+                               validator.clamped()));
+    }
+
+    #[test]
+    fn test_retry() {
+        // drops the first connection after reading its frame, then expects
+        // the frame again with its definitions on a new connection
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let received = thread::spawn(move || {
+            let mut frames = Vec::new();
+            for stream in server.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut payload).unwrap();
+                let frame = Frame::decode(&payload).unwrap();
+                if !frames.is_empty() {
+                    stream.write_all(&Reply::Ack(frame.seq).encode()).unwrap();
+                }
+                frames.push(frame);
+            }
+            frames
+        });
+
+        let config = ClientConfig { backoff: Duration::from_millis(1), ..ClientConfig::default() };
+        let mut client = Client::new(addr, config).unwrap();
+        client.publish(&SeriesKey::new("up"), 1.0, 1000).unwrap();
+        client.flush().unwrap();
+        let frames = received.join().unwrap();
+        assert_eq!(frames[0], frames[1]);
+        assert_eq!(1, frames[1].definitions.len());
+    }
+
+    #[test]
+    fn test_oversized_reply() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let replied = thread::spawn(move || {
+            let mut stream = server.incoming().next().unwrap().unwrap();
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut payload).unwrap();
+            stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        });
+
+        let config = ClientConfig { retries: 0, ..ClientConfig::default() };
+        let mut client = Client::new(addr, config).unwrap();
+        client.publish(&SeriesKey::new("up"), 1.0, 1000).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, client.flush().unwrap_err().kind());
+        replied.join().unwrap();
+    }
+}